@@ -6,137 +6,539 @@ extern crate crossbeam;
 extern crate rand;
 extern crate crypto;
 
-use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::env;
-use std::process;
-
 mod io_lib;
 mod net_lib;
+mod dns;
 mod mpmc_queue;
 mod state;
+mod filters;
+mod transfer;
 mod command;
 mod messages;
 mod crypto_lib;
+mod mlkem;
+mod compress;
+mod config_lib;
+mod setup;
+mod json_mode;
+mod hooks;
+mod known_keys;
+mod key_store;
+#[cfg(feature = "conformance")]
+mod conformance;
 
 use net_lib::Net;
-use messages::Message;
-use messages::MessageType;
-use messages::MessageContainer;
-use messages::TextMessage;
-use messages::ToUser;
 use crypto_lib::Crypto;
 use io_lib::IOHandler;
 use state::State;
 use state::User;
+use config_lib::Config;
+use messages::{Priority, SyncSlot, Update};
+use hooks::Hooks;
+use known_keys::KnownKeys;
+use setup::StartupError;
+use transfer::DownloadPolicy;
+use filters::Action;
+
+use std::env;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const RELAY_STATS_SECS: u64 = 60;
+const DEFAULT_SYNC_POLL_SECS: u64 = 30;
 
 fn main() {
 
+    let config = Config::load();
+    if config.get("headless", false) || env::args().any(|a| a == "--headless") {
+        return run_headless(&config);
+    }
+    if config.get("json", false) || env::args().any(|a| a == "--json") {
+        return json_mode::run(&config);
+    }
+    if env::args().any(|a| a == "--relay") {
+        return run_relay();
+    }
+    if let Some(path) = env::args().skip_while(|a| a != "--replay").nth(1) {
+        return replay(&path);
+    }
+    match (env::args().nth(1).as_ref().map(|a| &**a), env::args().nth(2)) {
+        (Some("backup"), Some(path)) => return exit_on_error(setup::backup_identity(&IOHandler::new(), &path)),
+        (Some("restore"), Some(path)) => return exit_on_error(setup::restore_identity(&IOHandler::new(), &path)),
+        (Some("backup"), None) | (Some("restore"), None) => return exit_on_error(Err("usage: client <backup|restore> <path>".to_string())),
+        _ => {},
+    }
+    #[cfg(feature = "conformance")]
+    {
+        if env::args().any(|a| a == "--conformance") {
+            let args: Vec<String> = env::args().skip_while(|a| a != "--conformance").skip(1).collect();
+            process::exit(if conformance::run(&args) { 0 } else { 1 });
+        }
+    }
+
     let io = IOHandler::new();
     let state = State::new();
+    let keys = KnownKeys::load();
 
-    let (priv_key, pub_key) = {
-        let mut keydir = match env::home_dir() {
-            Some(p) => p,
-            None    => {
-                io.print_error("Cannot find home directory.");
-                process::exit(1);
-            }
-        };
+    let first_run = setup::is_first_run(&io);
+    if first_run {
+        setup::run_wizard(&io);
+    }
+
+    let (mut priv_key, pub_key) = setup::load_keys(&io);
+    let config = Config::load();
+    let crypto = Crypto::new(priv_key, pub_key);
+    crypto_lib::zeroize(&mut priv_key);
+    let net = match Net::new(crypto, &config) {
+        Ok(net) => net,
+        Err(e) => {
+            io.print_error(&e);
+            process::exit(1);
+        }
+    };
 
-        keydir.push(".secmsg/keys");
-        if !keydir.join("private").exists() || !keydir.join("public").exists() {
-            fs::create_dir_all(&keydir).unwrap();
+    if let Err(e) = command::open_sessions(&net, &state) {
+        io.print_error(&e);
+    }
+    if let Err(e) = command::open_security_log(&net, &state) {
+        io.print_error(&format!("Could not open the security log: {}", e));
+    }
+    match command::resume_outbox(&net, &state) {
+        Ok(0) => {},
+        Ok(n) => io.print_log(&format!("Resending {} unacknowledged messages from last time.", n)),
+        Err(e) => io.print_error(&format!("Could not open the outbox journal: {}", e)),
+    }
+    state.transfers().set_policy(DownloadPolicy::from_config(&config), &net);
+    if let Err(e) = state.transfers().resume(&net) {
+        io.print_error(&format!("Could not resume file transfers: {}", e));
+    }
+    if let Err(e) = command::open_rules(&net, &state) {
+        io.print_error(&format!("Could not load your message filters: {}", e));
+    }
+    if config.get("history", true) {
+        if let Err(e) = command::open_history(&net, &state) {
+            io.print_error(&format!("Could not open the message history: {}", e));
+        }
+    }
+
+    let sync_poll = config.get("sync_poll_secs", DEFAULT_SYNC_POLL_SECS);
+    crossbeam::scope(|scope| {
+        scope.spawn(|| network_receiver(&io, &net, &state, &keys));
+        
+        scope.spawn(|| display_output(&io, &net, &state));
+
+        scope.spawn(|| receipt_receiver(&io, &net, &state));
 
-            let (priv_key, pub_key) = crypto_lib::gen_key_pair();
-            
-            let mut priv_key_file = File::create(keydir.join("private")).unwrap();
-            priv_key_file.write_all(&priv_key).unwrap();
+        scope.spawn(|| typing_indicator(&io, &net, &state));
 
-            let mut pub_key_file = File::create(keydir.join("public")).unwrap();
-            pub_key_file.write_all(&pub_key).unwrap();
+        scope.spawn(|| gap_checker(&io, &net, &state));
 
-            (priv_key, pub_key)
-        } else {
-            let mut priv_key = [0u8; 32];
-            let mut priv_key_file = File::open(keydir.join("private")).unwrap();
-            priv_key_file.read_exact(&mut priv_key).unwrap();
+        scope.spawn(|| reset_receiver(&io, &net, &state, &keys));
 
-            let mut pub_key = [0u8; 32];
-            let mut pub_key_file = File::open(keydir.join("public")).unwrap();
-            pub_key_file.read_exact(&mut pub_key).unwrap();
+        scope.spawn(|| broadcast_receiver(&io, &net, &state));
 
-            (priv_key, pub_key)
+        scope.spawn(|| ack_receiver(&net, &state));
+
+        scope.spawn(|| connection_watcher(&io, &net, &state));
+
+        scope.spawn(|| file_receiver(&io, &net, &state));
+
+        scope.spawn(|| notice_receiver(&io, &net));
+
+        scope.spawn(|| update_receiver(&io, &net));
+
+        scope.spawn(|| security_receiver(&net, &state));
+
+        scope.spawn(|| alert_receiver(&io, &state));
+
+        if sync_poll > 0 {
+            scope.spawn(|| sync_receiver(&io, &net, &state, sync_poll));
         }
-    };
-    let net = Net::new(Crypto::new(priv_key, pub_key));
-        
-    crossbeam::scope(|scope| {
-        scope.spawn(|| network_receiver(&net, &state));
         
-        scope.spawn(|| display_output(&io, &state));
-        
-        handle_user_input(&io, &net, &state);
+        handle_user_input(&io, &net, &state, &keys, &Hooks::from_config(&config), first_run);
+    });
+}
+
+fn exit_on_error(result: Result<(), String>) {
+    if let Err(e) = result {
+        IOHandler::new().print_error(&e);
+        process::exit(1);
+    }
+}
+
+// Runs without any prompts, printing every message received. Anything that
+// would need user input is a startup error instead.
+fn run_headless(config: &Config) {
+    let io = IOHandler::quiet();
+    let state = State::new();
+    let keys = Arc::new(KnownKeys::load());
+    let fail = |e: StartupError| -> ! {
+        io.print_error(&e.to_string());
+        process::exit(e.exit_code());
+    };
+
+    let (mut priv_key, pub_key) = setup::headless_keys(&config).unwrap_or_else(|e| fail(e));
+    let crypto = Crypto::new(priv_key, pub_key);
+    crypto_lib::zeroize(&mut priv_key);
+    let net = Net::new(crypto, &config)
+        .unwrap_or_else(|e| fail(StartupError::ServerUnreachable(e)));
+    let user = setup::headless_login(&config, &net).unwrap_or_else(|e| fail(e));
+    io.print_log(&format!("Logged in as {}.", user.handle));
+    if let Err(e) = command::open_sessions(&net, &state) {
+        io.print_error(&e);
+    }
+    if let Err(e) = command::open_security_log(&net, &state) {
+        io.print_error(&format!("Could not open the security log: {}", e));
+    }
+    if let Err(e) = command::resume_outbox(&net, &state) {
+        io.print_error(&format!("Could not open the outbox journal: {}", e));
+    }
+
+    let security_net = net.clone();
+    let security_state = state.clone();
+    thread::spawn(move || security_receiver(&security_net, &security_state));
+
+    let gap_net = net.clone();
+    let gap_state = state.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        for (sender, skipped, msgs) in gap_state.take_gaps() {
+            let io = IOHandler::quiet();
+            io.print_error(&command::gap_warning(&sender, skipped));
+            io.print_messages(msgs);
+        }
+        for notice in command::heal_sessions(&gap_net, &gap_state) {
+            IOHandler::quiet().print_error(&notice);
+        }
+    });
+
+    let reset_net = net.clone();
+    let reset_state = state.clone();
+    let reset_keys = keys.clone();
+    thread::spawn(move || loop {
+        let io = IOHandler::quiet();
+        match command::receive_reset(reset_net.get_reset(), &reset_state, &reset_keys) {
+            Ok(Some((notice, msgs))) => {
+                io.print_error(&notice);
+                io.print_messages(msgs);
+            },
+            Ok(None) => {},
+            Err(e) => io.print_error(&e),
+        }
+    });
+
+    let broadcast_net = net.clone();
+    let broadcast_state = state.clone();
+    thread::spawn(move || loop {
+        for (list, msg) in command::receive_broadcast(broadcast_net.get_broadcast(), &broadcast_net, &broadcast_state) {
+            IOHandler::quiet().print_log(&format!("[{}] {}", list, msg.text));
+        }
     });
+
+    let connection_net = net.clone();
+    let connection_state = state.clone();
+    thread::spawn(move || connection_watcher(&IOHandler::quiet(), &connection_net, &connection_state));
+
+    let notice_net = net.clone();
+    thread::spawn(move || notice_receiver(&IOHandler::quiet(), &notice_net));
+
+    loop {
+        match command::receive(net.get_message(), &net, &state, &keys) {
+            Ok(msgs) => io.print_messages(msgs),
+            Err(e) => io.print_error(&e),
+        }
+    }
+}
+
+// Only forwards messages for others, reporting how much it relayed every
+// minute. With a username set it logs in, so the server hands out routes
+// through it.
+fn run_relay() {
+    let io = IOHandler::quiet();
+    let mut config = Config::load();
+    config.set("relay", "true");
+    let fail = |e: StartupError| -> ! {
+        io.print_error(&e.to_string());
+        process::exit(e.exit_code());
+    };
+
+    let (mut priv_key, pub_key) = setup::headless_keys(&config).unwrap_or_else(|e| fail(e));
+    let crypto = Crypto::new(priv_key, pub_key);
+    crypto_lib::zeroize(&mut priv_key);
+    let net = Net::new(crypto, &config)
+        .unwrap_or_else(|e| fail(StartupError::ServerUnreachable(e)));
+    if config.get_str("username").is_some() {
+        let user = setup::headless_login(&config, &net).unwrap_or_else(|e| fail(e));
+        io.print_log(&format!("Relaying as {} on port {}.", user.handle, net.port()));
+    } else {
+        io.print_log(&format!("Relaying on port {}.", net.port()));
+    }
+
+    let mut last = net.relay_stats();
+    loop {
+        thread::sleep(Duration::from_secs(RELAY_STATS_SECS));
+        let (messages, bytes) = net.relay_stats();
+        io.print_log(&format!("Relayed {} messages ({} bytes) in the last {} seconds, {} ({} bytes) in all.",
+            messages - last.0, bytes - last.1, RELAY_STATS_SECS, messages, bytes));
+        last = (messages, bytes);
+    }
+}
+
+// Prints each frame of a session captured with `capture = true`, decoding
+// the ones we received.
+fn replay(path: &str) {
+    let io = IOHandler::new();
+    let (mut priv_key, pub_key) = setup::load_keys(&io);
+    let crypto = Crypto::new(priv_key, pub_key);
+    crypto_lib::zeroize(&mut priv_key);
+    let frames = match net_lib::read_capture(Path::new(path), &crypto) {
+        Ok(f) => f,
+        Err(e) => {
+            io.print_error(&e);
+            process::exit(1);
+        }
+    };
+    for (ms, inbound, frame) in frames {
+        io.print_log(&format!("{:>8}ms {} {} bytes: {}", ms, if inbound { "<-" } else { "->" }, frame.len(),
+            net_lib::describe_frame(&frame, inbound, &crypto)));
+    }
 }
 
 // Gets a TextMessage from the network and adds it to the new_messages queue in state.
-fn network_receiver(net: &Net, state: &State) {
+fn network_receiver(io: &IOHandler, net: &Net, state: &State, keys: &KnownKeys) {
     loop {
-        state.add_new_message(net.get_message());
+        if let Err(e) = command::receive(net.get_message(), &net, &state, &keys) {
+            io.print_error(&e);
+        }
     }
 }
 
-fn display_output(io: &IOHandler, state: &State) {
+// Marks messages delivered as peers acknowledge them, and resends the rest.
+fn ack_receiver(net: &Net, state: &State) {
+    let resend_net = net.clone();
+    let resend_state = state.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(5));
+        for out in command::resend_unacked(&resend_net, &resend_state) {
+            IOHandler::quiet().print_error(&format!(
+                "{} never acknowledged your message \"{}\". Enter /retry to send it again.", out.partner, out.msg.text));
+            resend_state.add_failed(out);
+        }
+    });
+
+    loop {
+        command::acknowledged(net.get_ack(), &net, &state);
+    }
+}
+
+fn display_output(io: &IOHandler, net: &Net, state: &State) {
     for msg in state.get_new_messages() {
-        io.print_message(msg);
+        if state.mark_displayed(msg.id) {
+            net.send_read_receipt(&msg);
+        }
+        match state.get_current_conversation() {
+            Some(conv) => io.print_log(&conv.show(&msg)),
+            None => io.print_message(msg),
+        }
+    }
+}
+
+// Broadcasts are shown with the list they came from, outside any conversation.
+fn broadcast_receiver(io: &IOHandler, net: &Net, state: &State) {
+    loop {
+        for (list, msg) in command::receive_broadcast(net.get_broadcast(), &net, &state) {
+            io.print_log(&format!("[{}] {}", list, msg.text));
+        }
+    }
+}
+
+// Warns about messages that never arrived, releasing those held behind them,
+// and resets conversations found out of step.
+fn gap_checker(io: &IOHandler, net: &Net, state: &State) {
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        for (sender, skipped, _) in state.take_gaps() {
+            io.print_error(&command::gap_warning(&sender, skipped));
+        }
+        for notice in command::heal_sessions(net, state) {
+            io.print_error(&notice);
+        }
+    }
+}
+
+// Starts conversations over when the other side asks. The messages released
+// are shown through state like any other.
+fn reset_receiver(io: &IOHandler, net: &Net, state: &State, keys: &KnownKeys) {
+    loop {
+        match command::receive_reset(net.get_reset(), state, keys) {
+            Ok(Some((notice, _))) => io.print_error(&notice),
+            Ok(None) => {},
+            Err(e) => io.print_error(&e),
+        }
+    }
+}
+
+// Says when the persistent connection to the server drops or comes back. It
+// is reopened by the next request, so the first one isn't announced.
+fn connection_watcher(io: &IOHandler, net: &Net, state: &State) {
+    let mut lost = false;
+    loop {
+        let up = net.get_connection_change();
+        if !state.set_server_connected(up) {
+            continue;
+        }
+        if !up {
+            io.print_error("Lost the connection to the server.");
+            lost = true;
+        } else if lost {
+            io.print_log("Reconnected to the server.");
+            lost = false;
+        }
+    }
+}
+
+// Shows what the server has to say about our account.
+fn notice_receiver(io: &IOHandler, net: &Net) {
+    loop {
+        io.print_error(&format!("Server notice: {}", net.get_notice()));
+    }
+}
+
+// Shows changes to what we watch.
+fn update_receiver(io: &IOHandler, net: &Net) {
+    loop {
+        io.print_log(&match net.get_update() {
+            Update::Online(h) => format!("{} is online.", h),
+            Update::Offline(h) => format!("{} went offline.", h),
+            Update::Listed(h) => format!("{} joined the directory.", h),
+            Update::Unlisted(h) => format!("{} left the directory.", h),
+        });
+    }
+}
+
+fn security_receiver(net: &Net, state: &State) {
+    loop {
+        let (kind, detail) = net.get_security_event();
+        state.log_security(kind, &detail);
+    }
+}
+
+// Picks up what our other devices changed: notes to self, which are shown,
+// message filters and labels. Only the first fetch after logging in is quiet, since
+// those notes were most likely already seen.
+fn sync_receiver(io: &IOHandler, net: &Net, state: &State, poll_secs: u64) {
+    let mut synced = None;
+    loop {
+        thread::sleep(Duration::from_secs(poll_secs));
+        let handle = net.handle();
+        if handle.is_none() {
+            synced = None;
+            continue;
+        }
+        let new = match net.get_synced(SyncSlot::Notes).and_then(|(version, data)| state.merge_notes(version, &data.unwrap_or_default())) {
+            Ok(new) => new,
+            Err(_) => continue,
+        };
+        if synced == handle {
+            for note in new {
+                io.print_log(&command::show_note(&note));
+            }
+        }
+        if let Ok((version, Some(data))) = net.get_synced(SyncSlot::Labels) {
+            state.set_labels(version, state::parse_labels(&data));
+        }
+        // Until a device uploads rules, each keeps its own.
+        if let Ok((version, Some(data))) = net.get_synced(SyncSlot::Rules) {
+            let rules = filters::parse_all(&String::from_utf8_lossy(&data));
+            match state.set_rules(version, rules) {
+                Ok(true) if synced == handle => io.print_log("Your message filters were changed on another device."),
+                Err(e) => io.print_error(&format!("Could not save your message filters: {}", e)),
+                _ => {},
+            }
+        }
+        synced = handle;
+    }
+}
+
+// Carries out what message filters ask of the client.
+fn alert_receiver(io: &IOHandler, state: &State) {
+    loop {
+        match state.get_alert() {
+            (Action::Loud, msg) => io.print_log(&format!("\x07{}", msg.to_string())),
+            (Action::Hook(cmd), msg) => if let Err(e) = hooks::run_rule(&cmd, &msg) {
+                io.print_error(&format!("Filter hook failed: {}", e));
+            },
+            _ => {},
+        }
+    }
+}
+
+// Reports file transfers as they are offered, finish or stall.
+fn file_receiver(io: &IOHandler, net: &Net, state: &State) {
+    let tick_net = net.clone();
+    let tick_state = state.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        for event in tick_state.transfers().tick(&tick_net) {
+            command::show_transfer(event, &IOHandler::quiet());
+        }
+    });
+
+    loop {
+        if let Some(event) = state.transfers().handle(net.get_file_event(), &net) {
+            command::show_transfer(event, io);
+        }
+    }
+}
+
+// Says when the partner in the current conversation starts typing.
+fn typing_indicator(io: &IOHandler, net: &Net, state: &State) {
+    let mut shown: Option<String> = None;
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        let typing = state.get_current_conversation()
+            .map(|c| c.get_partner().handle.clone())
+            .filter(|p| net.is_typing(p));
+        if typing.is_some() && typing != shown {
+            io.print_log(&format!("{} is typing...", typing.as_ref().unwrap()));
+        }
+        shown = typing;
     }
 }
 
-fn handle_user_input(io: &IOHandler, net: &Net, state: &State) {
+fn receipt_receiver(io: &IOHandler, net: &Net, state: &State) {
+    loop {
+        if let Some(out) = state.take_sent(net.get_receipt()) {
+            io.print_log(&format!("{} read \"{}\".", out.partner, out.msg.text));
+        }
+    }
+}
+
+fn handle_user_input(io: &IOHandler, net: &Net, state: &State, keys: &KnownKeys, hooks: &Hooks, first_run: bool) {
     let mut user: Option<User> = None;
     let is_command = |s: &str| {
         s.chars().nth(0).unwrap() == '/'
     };
+
+    // Finish the setup wizard now that the network is up.
+    if first_run {
+        if let Some(cmd) = setup::choose_account_action(&io) {
+            command::handle(&io, &net, &state, &keys, &mut user, &[cmd]);
+        }
+    }
     
     loop {
         let mut line = io.read_prompted_line("> ");
 
         if is_command(&line) {
             let tokens: Vec<&str> = line.split_terminator(' ').collect();
-            command::handle(&io, &net, &state, &mut user, &*tokens);
-
-        } else {
-            let curr_conv = state.get_current_conversation();
-            
-            if curr_conv.is_none() {
-                io.print_error("No current conversation.");
-            } else if user.is_none() {
-                io.print_error("Not logged in");
-            } else {
-                let conv_id = curr_conv.as_ref().unwrap().get_id(); 
-                let tm = TextMessage {
-                    text: line,
-                    sender: user.clone().unwrap(),
-                    conv_id: conv_id,
-                };
-                let partner = curr_conv.as_ref().unwrap().get_partner();
-                let mc = MessageContainer::new(
-                    Message::new(
-                        MessageType::User(ToUser::Text(tm.clone())), 
-                        state.get_route(&partner.handle, &net).unwrap(),
-                        &net.crypto
-                    ),
-                    None,
-                    false
-                );
-                
-                // Send the message off to the network.
-                net.add_message(mc);
-                
-                // Print the user's message to the chat.
-                // state.add_new_message(tm);
-            }
+            command::handle(&io, &net, &state, &keys, &mut user, &*tokens);
+
+        } else if let Some(line) = hooks.outgoing(&io, line) {
+            command::send_text(line, &io, &net, &state, &user, false, Priority::Normal);
         }
     }
 }
@@ -1,16 +1,107 @@
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
 use std::error::Error;
-use std::fs::File;
+use std::fs::{self, File};
 use std::env;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::process;
+use rand;
+use std::path::Path;
+
+use rustc_serialize::json;
+use rustc_serialize::hex::ToHex;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use io_lib::IOHandler;
-use net_lib::Net;
-use messages::{MessageContainer, Message};
-use messages::{MessageType, ResponseType, ToServer, ToUser};
+use crypto_lib::{self, Crypto};
+use known_keys::{KnownKeys, KeyStatus};
+use setup;
+use config_lib::Config;
+use net_lib::{self, Net};
+use messages::{self, MessageContainer, Message, TextMessage, SessionReset, Response, Priority};
+use messages::{MessageType, ResponseType, ToServer, ToUser, NamespaceChange, FileOffer, Attachment};
+use messages::{SharedChange, SharedFile, SharedMeta, SyncSlot, Topic};
+use hooks::Hooks;
+use filters;
 use state::*;
+use transfer::{self, Event, DownloadPolicy};
+
+const RELAY_TEST_SIZE: usize = 16 * 1024;
+const ACK_TIMEOUT_SECS: u64 = 30;
+const MAX_RESENDS: usize = 3;
+const SYNC_TRIES: usize = 3;
+const MAX_FIND_RESULTS: usize = 50;
+const DEFAULT_VOICE_SECS: u32 = 10;
+const DEFAULT_MAX_VOICE_SECS: u32 = 120;
 
-pub fn handle(io: &IOHandler, net: &Net, state: &State, user: &mut Option<User>, tokens: &[&str]) {
+// Every command with its arguments and a description, used by /help.
+const COMMANDS: &'static [(&'static str, &'static str, &'static str)] = &[
+    ("/login", "", "Log in to an existing account."),
+    ("/register", "", "Create a new account."),
+    ("/unregister", "", "Delete your account."),
+    ("/password", "", "Change your password."),
+    ("/passphrase", "", "Change the passphrase your private key is encrypted with."),
+    ("/rename", "<username>", "Change your username."),
+    ("/compromise-recovery", "", "Replace your identity key after it may have been stolen."),
+    ("/connect", "<user>", "Start a conversation with a user."),
+    ("/join", "<user>", "Switch to an existing conversation."),
+    ("/verify", "<user>", "Compare safety numbers with a user and accept their key."),
+    ("/merge", "<user> <other>", "Merge the conversation with other into user's, for one person with two identities."),
+    ("/leave", "", "Leave the current conversation."),
+    ("/list", "[label]", "List your conversations, or those with a label."),
+    ("/label", "<user> <label>", "Label the conversation with a user, on all your devices."),
+    ("/unlabel", "<user> <label>", "Take a label off the conversation with a user."),
+    ("/labels", "", "List your labels."),
+    ("/feed", "[mentions]", "Show recent messages from every conversation."),
+    ("/presence", "<user>", "Show whether a user is online."),
+    ("/relays", "[list]", "Update the relay directory, fetching only what changed, and show its size or every relay."),
+    ("/watch", "[<user>...|directory]", "Be told when users come online or go offline, or when the directory changes."),
+    ("/unwatch", "<user>|directory", "Stop watching a user or the directory."),
+    ("/search", "<prefix> [page]", "Find users who chose to be listed."),
+    ("/find", "<text>", "Search your message history."),
+    ("/export", "<path> [user]", "Save your history with everyone, or one user, to an archive sealed by a passphrase."),
+    ("/import", "<path>", "Add the messages in an exported archive to your history."),
+    ("/block", "<user>", "Stop a user from finding routes to you."),
+    ("/unblock", "<user>", "Let a blocked user reach you again."),
+    ("/contacts", "<push|pull>", "Save your contacts to the server, or fetch them."),
+    ("/tome", "[text]", "Send text to your other devices, or show what was sent."),
+    ("/rules", "[add <rule>|remove <number>]", "Filter incoming messages, e.g. from alice has invoice -> folder bills."),
+    ("/folder", "[name]", "List the folders rules moved messages to, or show one."),
+    ("/broadcast", "<create|send|subscribers> <name> [text]", "Run a list that only you can post to."),
+    ("/namespace", "<name> <create|show|invite|remove|admin|unadmin|search|policy> [user|setting value]",
+        "Run a team namespace, whose members register as name/user."),
+    ("/shared", "<namespace> <list|add|get|remove> [path|id]", "Share files with everyone in a namespace."),
+    ("/subscribe", "<owner> <name>", "Follow someone's broadcast list."),
+    ("/unsubscribe", "<owner> <name>", "Stop following a broadcast list."),
+    ("/reliable", "<text>", "Send text over two disjoint routes."),
+    ("/urgent", "<text>", "Send text ahead of other messages, retrying harder."),
+    ("/undo", "", "Unsend the last message while it is still waiting to go out."),
+    ("/retry", "", "Send the last undelivered message again."),
+    ("/pending", "", "List sent messages that haven't been acknowledged yet."),
+    ("/send-file", "<path>", "Offer a file to the current conversation."),
+    ("/voice", "[seconds]", "Record a voice message and send it to the current conversation."),
+    ("/play", "[path]", "Play the last voice message you received, or the file at path."),
+    ("/send-image", "<path>", "Send an image, with a thumbnail shown before it is fetched."),
+    ("/view", "[id|path]", "View the last image you received, an offered image's thumbnail, or the file at path."),
+    ("/accept-file", "[id]", "Accept the latest file offered to you, or resume a paused one."),
+    ("/cancel-file", "<id>", "Stop sending or receiving a file."),
+    ("/files", "", "List file transfers and how far along they are."),
+    ("/downloads", "[start <id>]", "Show the download queue, or fetch a queued file now."),
+    ("/network", "[profile]", "Show or switch the network profile, which decides what downloads on its own."),
+    ("/carry", "<on|off>", "Hold messages for verified contacts that can't be reached."),
+    ("/receipts", "<on|off>", "Let senders know when you have read their messages."),
+    ("/deniable", "<on|off>", "Authenticate your messages in this conversation so they can't be proven yours afterwards."),
+    ("/power", "<low|normal>", "Save battery by batching messages and pinging less often."),
+    ("/relay-test", "", "Check that the server can relay through you."),
+    ("/nat", "", "Show the address the server sees you at and how peers reach you."),
+    ("/capabilities", "", "Show what the server supports."),
+    ("/security-log", "[count]", "Show the latest key changes, refused messages and other security events."),
+    ("/help", "[command]", "Show this help, or the usage of one command."),
+];
+
+pub fn handle(io: &IOHandler, net: &Net, state: &State, keys: &KnownKeys, user: &mut Option<User>, tokens: &[&str]) {
     let cmd: &str = tokens[0];
     let args: &[&str] = &tokens[1..];
     
@@ -33,25 +124,389 @@ pub fn handle(io: &IOHandler, net: &Net, state: &State, user: &mut Option<User>,
                 },
             };
         },
-        "/connect" => {
-            if let Err(e) = connect(args[0], &net, &state) {
+        "/unregister" => {
+            match unregister(&io, &net, &user) {
+                Ok(()) => {
+                    *user = None;
+                    io.print_log("Your account has been deleted.");
+                },
+                Err(e) => io.print_error(&e),
+            }
+        },
+        "/password" => {
+            match change_password(&io, &net, &user) {
+                Ok(()) => io.print_log("Your password has been changed."),
+                Err(e) => io.print_error(&e),
+            }
+        },
+        "/rename" => {
+            match args.get(0).map(|a| rename(a.trim(), &io, &net, &user)) {
+                Some(Ok(usr)) => {
+                    io.print_log(&format!("You are now {}.", usr.handle));
+                    *user = Some(usr);
+                },
+                Some(Err(e)) => io.print_error(&e),
+                None => io.print_error("usage: /rename <username>"),
+            }
+        },
+        "/passphrase" => {
+            match setup::change_passphrase(&io) {
+                Ok(()) => {
+                    state.log_security(SecurityKind::PassphraseChanged, "The private key was sealed under a new passphrase");
+                    io.print_log("Passphrase changed.");
+                },
+                Err(e) => io.print_error(&e),
+            }
+        },
+        "/compromise-recovery" => {
+            if let Err(e) = compromise_recovery(&io, &net, &state, &keys, &user) {
                 io.print_error(&e);
             }
         },
+        "/connect" => {
+            match connect(args[0], &net, &state, &keys) {
+                // Warn before anything is sent into the void.
+                Ok(()) => {
+                    if net.via_last_resort(args[0]) {
+                        io.print_log(&format!("There are no volunteer relays, so messages to {} go through the server's relay, \
+                            which can see who is talking to whom.", args[0]));
+                    }
+                    if let Ok((false, _)) = net.presence(args[0]) {
+                        io.print_log(&format!("{} is offline, messages may not arrive until they are back.", args[0]));
+                    }
+                },
+                Err(e) => io.print_error(&e),
+            }
+        },
         "/leave" => {
             leave(&state, &io);
         }
+        "/merge" => {
+            match (args.get(0), args.get(1)) {
+                (Some(a), Some(b)) => merge(a.trim(), b.trim(), &net, &state, &io),
+                _ => io.print_error("usage: /merge <user> <other>"),
+            }
+        },
+        "/verify" => {
+            match args.get(0).map(|a| verify(a.trim(), &io, &net, &state, &keys)) {
+                Some(Ok(())) => (),
+                Some(Err(e)) => io.print_error(&e),
+                None => io.print_error("usage: /verify <user>"),
+            }
+        },
         "/join" => {
-            join(args[0], &state, &io);
+            join(args[0], &net, &state, &io);
         },
         "/list" => {
-            list(&state, &io);
+            list(args.get(0).map(|l| l.trim()), &state, &io);
+        },
+        "/labels" => {
+            show_labels(&state, &io);
+        },
+        "/label" | "/unlabel" => {
+            let res = match (args.get(0), args.get(1)) {
+                (Some(partner), Some(label)) =>
+                    change_label(partner.trim(), label.trim(), cmd.trim() == "/label", &net, &state, &io),
+                _ => Err(format!("usage: {} <user> <label>", cmd.trim())),
+            };
+            if let Err(e) = res {
+                io.print_error(&e);
+            }
+        },
+        "/feed" => {
+            feed(args.get(0).map(|a| a.trim()) == Some("mentions"), &state, &user, &io);
+        },
+        "/contacts" => {
+            let res = match args.get(0).map(|a| a.trim()) {
+                Some("push") => push_contacts(&io, &net, &state, &user),
+                Some("pull") => pull_contacts(&io, &net, &state, &keys, &user),
+                _ => Err("usage: /contacts <push|pull>".to_string()),
+            };
+            if let Err(e) = res {
+                io.print_error(&e);
+            }
+        },
+        "/tome" => {
+            let res = if args.is_empty() {
+                show_notes(&net, &state, &io)
+            } else {
+                tome(args.join(" "), &net, &state, &io)
+            };
+            if let Err(e) = res {
+                io.print_error(&e);
+            }
+        },
+        "/rules" => {
+            let res = match args.get(0).map(|a| a.trim()) {
+                None => Ok(list_rules(&state, &io)),
+                Some("add") => filters::Rule::parse(&args[1..].join(" "))
+                    .and_then(|rule| change_rules(&net, &state, &io, |rules| {
+                        rules.push(rule.clone());
+                        Ok(())
+                    })),
+                Some("remove") => match args.get(1).and_then(|n| n.trim().parse::<usize>().ok()) {
+                    Some(n) => change_rules(&net, &state, &io, |rules| if n >= 1 && n <= rules.len() {
+                        rules.remove(n - 1);
+                        Ok(())
+                    } else {
+                        Err(format!("There is no rule {}", n))
+                    }),
+                    None => Err("usage: /rules remove <number>".to_string()),
+                },
+                _ => Err("usage: /rules [add <rule>|remove <number>]".to_string()),
+            };
+            if let Err(e) = res {
+                io.print_error(&e);
+            }
+        },
+        "/folder" => {
+            match args.get(0).map(|a| a.trim()) {
+                Some(name) => match state.folder(name) {
+                    Some(msgs) => io.print_messages(msgs),
+                    None => io.print_error(&format!("No messages have been moved to {}.", name)),
+                },
+                None => for (name, count) in state.folders() {
+                    io.print_log(&format!("{} ({})", name, count));
+                },
+            }
+        },
+        "/broadcast" => {
+            let res = match (args.get(0).map(|a| a.trim()), args.get(1).map(|a| a.trim())) {
+                (Some("create"), Some(name)) => create_broadcast(name, &io, &net, &user),
+                (Some("send"), Some(name)) => broadcast(name, args[2..].join(" "), &io, &net, &state, &user),
+                (Some("subscribers"), Some(name)) => list_subscribers(name, &io, &net, &user),
+                _ => Err("usage: /broadcast <create|send|subscribers> <name> [text]".to_string()),
+            };
+            if let Err(e) = res {
+                io.print_error(&e);
+            }
+        },
+        "/namespace" => {
+            let res = match (args.get(0).map(|a| a.trim()), args.get(1).map(|a| a.trim())) {
+                (Some(name), Some(action)) => namespace(name, action, &args[2..], &io, &net, &user),
+                _ => Err("usage: /namespace <name> <create|show|invite|remove|admin|unadmin|search|policy> [user|setting value]".to_string()),
+            };
+            if let Err(e) = res {
+                io.print_error(&e);
+            }
+        },
+        "/shared" => {
+            let res = match (args.get(0).map(|a| a.trim()), args.get(1).map(|a| a.trim())) {
+                (Some(name), Some(action)) => shared(name, action, args.get(2).map(|a| a.trim()), &io, &net, &state, &user),
+                _ => Err("usage: /shared <namespace> <list|add|get|remove> [path|id]".to_string()),
+            };
+            if let Err(e) = res {
+                io.print_error(&e);
+            }
+        },
+        "/subscribe" | "/unsubscribe" => {
+            let on = cmd.trim() == "/subscribe";
+            let res = match (args.get(0), args.get(1)) {
+                (Some(owner), Some(name)) => subscribe(owner.trim(), name.trim(), on, &io, &net, &user),
+                _ => Err(format!("usage: {} <owner> <name>", cmd.trim())),
+            };
+            if let Err(e) = res {
+                io.print_error(&e);
+            }
+        },
+        "/block" | "/unblock" => {
+            let block = cmd.trim() == "/block";
+            match args.get(0).map(|a| set_blocked(a.trim(), block, &io, &net, &user)) {
+                Some(Ok(())) => io.print_log(if block { "Blocked." } else { "Unblocked." }),
+                Some(Err(e)) => io.print_error(&e),
+                None => io.print_error(&format!("usage: {} <user>", cmd.trim())),
+            }
+        },
+        "/search" => {
+            match args.get(0) {
+                Some(prefix) => search(prefix.trim(), args.get(1).and_then(|p| p.trim().parse().ok()).unwrap_or(1), &net, &io),
+                None => io.print_error("usage: /search <prefix> [page]"),
+            }
+        },
+        "/find" => {
+            if args.is_empty() {
+                io.print_error("usage: /find <text>");
+            } else {
+                find(&args.join(" "), &state, &io);
+            }
+        },
+        "/export" => {
+            let res = match args.get(0) {
+                Some(path) => export(path.trim(), args.get(1).map(|u| u.trim()), &state, &io),
+                None => Err("usage: /export <path> [user]".to_string()),
+            };
+            if let Err(e) = res {
+                io.print_error(&e);
+            }
+        },
+        "/import" => {
+            let res = match args.get(0) {
+                Some(path) => import(path.trim(), &state, &io),
+                None => Err("usage: /import <path>".to_string()),
+            };
+            if let Err(e) = res {
+                io.print_error(&e);
+            }
+        },
+        "/presence" => {
+            match args.get(0) {
+                Some(other) => presence(other.trim(), &net, &io),
+                None => io.print_error("usage: /presence <user>"),
+            }
+        },
+        "/watch" => {
+            if let Err(e) = watch(args, &net, &io) {
+                io.print_error(&e);
+            }
+        },
+        "/unwatch" => {
+            let res = match args.get(0).map(|a| a.trim()) {
+                Some(other) if !other.is_empty() => unwatch(other, &net, &io),
+                _ => Err("usage: /unwatch <user>|directory".to_string()),
+            };
+            if let Err(e) = res {
+                io.print_error(&e);
+            }
+        },
+        "/relays" => {
+            relays(args.get(0) == Some(&"list"), &net, &io);
+        },
+        "/carry" => {
+            carry(args, &net, &keys, &io);
+        },
+        "/power" => {
+            power(args, &net, &io);
+        },
+        "/receipts" => {
+            if let Err(e) = receipts(args, &net, &io) {
+                io.print_error(&e);
+            }
+        },
+        "/deniable" => {
+            if let Err(e) = deniable(args, &state, &io) {
+                io.print_error(&e);
+            }
+        },
+        "/relay-test" => {
+            relay_test(&net, &io);
+        },
+        "/nat" => {
+            nat(&net, &io);
+        },
+        "/reliable" => {
+            send_text(args.join(" "), &io, &net, &state, &user, true, Priority::Normal);
+        },
+        "/urgent" => {
+            send_text(args.join(" "), &io, &net, &state, &user, false, Priority::Urgent);
+        },
+        "/undo" => {
+            match net.cancel(None) {
+                Some(id) => {
+                    if let Some(out) = state.take_unacked(id) {
+                        state.unsend_history(&out.partner, id);
+                    }
+                    io.print_log("Message unsent.");
+                },
+                None => io.print_error("Nothing to undo."),
+            }
+        },
+        "/retry" => {
+            retry(&io, &net, &state);
+        },
+        "/pending" => {
+            pending(&state, &io);
+        },
+        "/send-file" => {
+            match send_file(&args.join(" "), Attachment::File, &net, &state, &user) {
+                Ok(offer) => io.print_log(&format!("Offered {} ({} bytes). It is sent once they accept.", offer.name, offer.size)),
+                Err(e) => io.print_error(&e),
+            }
+        },
+        "/voice" => {
+            match voice(args.get(0).map(|a| a.trim()), &io, &net, &state, &user) {
+                Ok(offer) => io.print_log(&format!("Sent a voice message ({} bytes).", offer.size)),
+                Err(e) => io.print_error(&e),
+            }
+        },
+        "/send-image" => {
+            match send_image(&args.join(" "), &net, &state, &user) {
+                Ok(offer) => io.print_log(&format!("Offered {} ({} bytes){}.", offer.name, offer.size,
+                    match offer.kind {
+                        Attachment::Image(_, ref thumb) if thumb.is_empty() => ", without a thumbnail",
+                        _ => "",
+                    })),
+                Err(e) => io.print_error(&e),
+            }
+        },
+        "/view" => {
+            if let Err(e) = view(args.join(" ").trim(), &state) {
+                io.print_error(&e);
+            }
+        },
+        "/play" => {
+            if let Err(e) = play(&args.join(" "), &state) {
+                io.print_error(&e);
+            }
+        },
+        "/accept-file" => {
+            let res = match args.get(0).map(|a| a.trim().parse::<u64>()) {
+                Some(Ok(id)) => accept_file(Some(id), false, &net, &state, &user),
+                Some(Err(_)) => Err("usage: /accept-file [id]".to_string()),
+                None => accept_file(None, false, &net, &state, &user),
+            };
+            match res {
+                Ok((offer, true)) => io.print_log(&format!("Receiving {} from {}.", offer.name, offer.sender)),
+                Ok((offer, false)) => io.print_log(&format!(
+                    "Queued {} until you are off a metered network. Enter /downloads start {} to fetch it now.",
+                    offer.name, offer.id)),
+                Err(e) => io.print_error(&e),
+            }
+        },
+        "/cancel-file" => {
+            match args.get(0).and_then(|a| a.trim().parse::<u64>().ok()) {
+                Some(id) => match state.transfers().cancel(id, &net) {
+                    Ok(offer) => io.print_log(&format!("Cancelled {}.", offer.name)),
+                    Err(e) => io.print_error(&e),
+                },
+                None => io.print_error("usage: /cancel-file <id>"),
+            }
+        },
+        "/files" => {
+            files(&state, &io);
+        },
+        "/downloads" => {
+            match (args.get(0).map(|a| a.trim()), args.get(1).and_then(|a| a.trim().parse::<u64>().ok())) {
+                (None, _) => downloads(&state, &io),
+                (Some("start"), Some(id)) => match accept_file(Some(id), true, &net, &state, &user) {
+                    Ok((offer, _)) => io.print_log(&format!("Receiving {} from {}.", offer.name, offer.sender)),
+                    Err(e) => io.print_error(&e),
+                },
+                _ => io.print_error("usage: /downloads [start <id>]"),
+            }
+        },
+        "/network" => {
+            if let Err(e) = network(args.get(0).map(|a| a.trim()), &net, &state, &io) {
+                io.print_error(&e);
+            }
+        },
+        "/capabilities" => {
+            capabilities(&net, &state, &io);
+        },
+        "/security-log" => {
+            if let Err(e) = security_log(args.get(0).map(|c| c.trim()), &state, &io) {
+                io.print_error(&e);
+            }
+        },
+        "/help" => {
+            help(args.get(0).map(|a| a.trim()), &io);
         },
         _ => {
-            io.print_error("Command not recognized");
+            io.print_error("Command not recognized, enter '/help' for a list of commands.");
         },
     }
 
+    // Heartbeats follow whichever account we are now logged in as.
+    net.set_handle(user.as_ref().map(|u| u.handle.clone()));
 }
 
 fn login(io: &IOHandler, net: &Net) -> Result<User, String> {
@@ -59,6 +514,11 @@ fn login(io: &IOHandler, net: &Net) -> Result<User, String> {
     let mut username = io.read_prompted_line("Username: ");
     let mut password = io.read_prompted_line("Password: ");
 
+    login_as(username, password, &net)
+}
+
+pub fn login_as(username: String, password: String, net: &Net) -> Result<User, String> {
+
     // Get the public key.
     let mut public_key = [0u8; 32];
     let mut pub_key_file = File::open(env::home_dir().unwrap()
@@ -70,7 +530,7 @@ fn login(io: &IOHandler, net: &Net) -> Result<User, String> {
     net.add_message(
         MessageContainer::new(
             Message::new(
-                MessageType::Server(ToServer::Login(username, password, public_key)),
+                MessageType::Server(ToServer::Login(username, password, public_key, net.port())),
                 net.get_server_route(),
                 &net.crypto
             ),
@@ -84,11 +544,12 @@ fn login(io: &IOHandler, net: &Net) -> Result<User, String> {
         Err(e) => return Err(e.to_string()),
     };
 
-    if let MessageType::User(res) = Net::data_to_type(&res.unwrap().data) {
+    let res = try!(res.ok_or("The server did not reply".to_string()));
+    if let MessageType::User(res) = try!(Net::decode_type(&res.data)) {
         if let ToUser::ServerResponse(res) = res {
             match res {
                 ResponseType::User(u) => Ok(u),
-                ResponseType::Error(e) => Err(e),
+                ResponseType::Error(e) => Err(e.to_string()),
                 _ => Err("Something went wrong".to_string())
             }
         } else {
@@ -103,6 +564,13 @@ fn register(io: &IOHandler, net: &Net) -> Result<User, String> {
 
     let mut username = io.read_prompted_line("Username: ");    
     let mut password = io.read_prompted_line("Password: ");
+    let discoverable = loop {
+        match &*io.read_prompted_line("Let others find you with /search? [y/n]: ") {
+            "y" | "yes" => break true,
+            "n" | "no" => break false,
+            _ => io.print_error("Please enter 'y' or 'n'."),
+        }
+    };
 
     // Get the public key.
     let mut public_key = [0u8; 32];
@@ -116,9 +584,9 @@ fn register(io: &IOHandler, net: &Net) -> Result<User, String> {
         MessageContainer::new(
             Message::new(
                 MessageType::Server(
-                    ToServer::Register(username, password, public_key)
+                    ToServer::Register(username, password, public_key, net.port(), discoverable)
                 ),
-                vec![(Net::server_addr().to_string(), net.get_server_key())],
+                net.get_server_route(),
                 &net.crypto
             ),
             Some(sender),
@@ -134,11 +602,12 @@ fn register(io: &IOHandler, net: &Net) -> Result<User, String> {
         Err(e) => return Err("wtf".to_string() + e.description())
     };
 
-    if let MessageType::User(res) = Net::data_to_type(&res.unwrap().data) {
+    let res = try!(res.ok_or("The server did not reply".to_string()));
+    if let MessageType::User(res) = try!(Net::decode_type(&res.data)) {
         if let ToUser::ServerResponse(res) = res {
             match res {
                 ResponseType::User(u) => Ok(u),
-                ResponseType::Error(e) => Err(e),
+                ResponseType::Error(e) => Err(e.to_string()),
                 _ => Err("Something went wrong".to_string())
             }
         } else {
@@ -149,13 +618,109 @@ fn register(io: &IOHandler, net: &Net) -> Result<User, String> {
     }
 }
 
-fn connect(o_user: &str, net: &Net, state: &State) -> Result<(), String> {
+// Deletes the logged in account after confirming its password.
+fn unregister(io: &IOHandler, net: &Net, user: &Option<User>) -> Result<(), String> {
+    let handle = try!(user.as_ref().map(|u| u.handle.clone()).ok_or("Not logged in".to_string()));
+    let password = io.read_prompted_line("Password: ");
+
+    match try!(net.request(ToServer::Unregister(handle, password, net.crypto.pub_key))) {
+        ResponseType::Unregistered => Ok(()),
+        _ => Err("Something went wrong".to_string()),
+    }
+}
+
+fn change_password(io: &IOHandler, net: &Net, user: &Option<User>) -> Result<(), String> {
+    let handle = try!(user.as_ref().map(|u| u.handle.clone()).ok_or("Not logged in".to_string()));
+    let old_password = io.read_prompted_line("Current password: ");
+    let new_password = io.read_prompted_line("New password: ");
+    if io.read_prompted_line("Confirm new password: ") != new_password {
+        return Err("Passwords do not match".to_string());
+    }
+
+    match try!(net.request(ToServer::ChangePassword(handle, old_password, new_password, net.crypto.pub_key))) {
+        ResponseType::PasswordChanged => Ok(()),
+        _ => Err("Something went wrong".to_string()),
+    }
+}
+
+fn rename(new_handle: &str, io: &IOHandler, net: &Net, user: &Option<User>) -> Result<User, String> {
+    let handle = try!(user.as_ref().map(|u| u.handle.clone()).ok_or("Not logged in".to_string()));
+    let password = io.read_prompted_line("Password: ");
+
+    match try!(net.request(ToServer::Rename(handle, password, new_handle.to_string(), net.crypto.pub_key))) {
+        ResponseType::User(u) => Ok(u),
+        _ => Err("Something went wrong".to_string()),
+    }
+}
+
+// Replaces our identity key everywhere it is used, then exits so the client
+// restarts with it. There are no sessions or prekeys to renew, routes and
+// contacts are all that depend on the key.
+fn compromise_recovery(io: &IOHandler, net: &Net, state: &State, keys: &KnownKeys, user: &Option<User>) -> Result<(), String> {
+    let handle = try!(user.as_ref().map(|u| u.handle.clone()).ok_or("Not logged in".to_string()));
+    io.print_log("This replaces your identity key and exits. Your contacts will have to verify you again.");
+    loop {
+        match &*io.read_prompted_line("Continue? [y/n]: ") {
+            "y" | "yes" => break,
+            "n" | "no" => return Ok(()),
+            _ => io.print_error("Please enter 'y' or 'n'."),
+        }
+    }
+    let password = io.read_prompted_line("Password: ");
+    let passphrase = setup::ask_passphrase(&io, "Passphrase to protect your new key (leave empty for none): ");
+
+    // Keep the old key until the server has taken the new one.
+    let (priv_key, pub_key) = crypto_lib::gen_key_pair();
+    try!(setup::set_aside_keys(&io));
+    if let Err(e) = setup::save_keys(&io, &priv_key, &passphrase) {
+        setup::restore_keys(&io);
+        return Err(e);
+    }
+    match net.request(ToServer::ChangeKey(handle.clone(), password.clone(), pub_key, net.crypto.pub_key)) {
+        Ok(ResponseType::User(_)) => setup::discard_old_keys(&io),
+        res => {
+            setup::restore_keys(&io);
+            return Err(res.err().unwrap_or("Something went wrong".to_string()));
+        },
+    }
+    io.print_log("Your new key is registered and the old one deleted.");
+
+    // Saved contacts are sealed under our key, store them under the new one.
+    let partners = state.partners();
+    let res = crypto_lib::seal_with_key(&Crypto::new(priv_key, pub_key).contacts_key(), partners.join("\n").as_bytes())
+        .map_err(|_| "Failed to encrypt contacts".to_string())
+        .and_then(|blob| net.request(ToServer::PutContacts(handle, password, state.contacts_version(), blob, net.crypto.pub_key)));
+    if let Err(e) = res {
+        io.print_error(&format!("Could not save your contacts under the new key: {}", e));
+    }
+
+    // The keys we verified may have been tampered with along with ours.
+    try!(keys.unverify_all());
+    for p in partners {
+        state.forget_routes(&p);
+    }
+
+    state.log_security(SecurityKind::OwnKeyReplaced, &format!("New safety numbers, fingerprint {}", pub_key[..8].to_hex()));
+    io.print_log("Tell your contacts to expect a new safety number, and /verify them again.");
+    io.print_log("Restart secmsg to use your new key.");
+    process::exit(0);
+}
+
+// Refuses to talk to a user whose key has changed until it is verified.
+pub fn connect(o_user: &str, net: &Net, state: &State, keys: &KnownKeys) -> Result<(), String> {
     let r: Route = match state.get_route(&o_user, net) {
         Ok(r) => r,
         Err(e) => return Err(e),
     };
 
-    let conv = Conversation::new(User::from_addr_pair(o_user.to_string(), &r[r.len()-1]));
+    let partner = User::from_addr_pair(o_user.to_string(), &r[r.len()-1]);
+    if let KeyStatus::Changed(_) = keys.check(&partner) {
+        state.log_security(SecurityKind::KeyChanged, &format!("{} has a new key, found connecting to them", o_user));
+        state.forget_routes(o_user);
+        return Err(format!("{}'s key has changed. Enter /verify {} to compare safety numbers first.", o_user, o_user));
+    }
+
+    let conv = Conversation::new(partner);
     
     let conv_id = conv.get_id();
     state.add_conversation(conv);
@@ -163,20 +728,1306 @@ fn connect(o_user: &str, net: &Net, state: &State) -> Result<(), String> {
     Ok(())
 }
 
+// Adds a message from the network to state, returning the messages that are
+// now in order. Messages whose sender's key has changed since we pinned it
+// are held until the new key is verified, and the first one held from a
+// sender returns a warning. Messages that weren't signed by the sender, or
+// deniably authenticated by them, are dropped.
+pub fn receive(msg: TextMessage, net: &Net, state: &State, keys: &KnownKeys) -> Result<Vec<TextMessage>, String> {
+    if msg.is_deniable() {
+        if !msg.has_valid_mac(&net.crypto) {
+            state.log_security(SecurityKind::BadSignature, &format!("A deniable message claiming to be from {}", msg.sender.handle));
+            return Err(format!("Dropped a message from {} with a bad MAC.", msg.sender.handle));
+        }
+    } else if !msg.has_valid_signature() {
+        state.log_security(SecurityKind::BadSignature, &format!("A message claiming to be from {}", msg.sender.handle));
+        return Err(format!("Dropped a message from {} with a bad signature.", msg.sender.handle));
+    }
+    match keys.check(&msg.sender) {
+        KeyStatus::Trusted => {
+            // A deniable message's signing key is an ephemeral one.
+            if msg.is_deniable() {
+                return Ok(state.add_in_order(msg));
+            }
+            if let Err(e) = keys.check_signer(&msg.sender.handle, &msg.signing_key) {
+                state.log_security(SecurityKind::BadSignature, &format!("A message from {}, {}", msg.sender.handle, e));
+                return Err(format!("Dropped a message from {}, {}.", msg.sender.handle, e));
+            }
+            Ok(state.add_in_order(msg))
+        },
+        KeyStatus::Changed(_) => {
+            let handle = msg.sender.handle.clone();
+            if state.quarantine(msg) {
+                state.log_security(SecurityKind::KeyChanged, &format!("{} sent messages under a new key, held until verified", handle));
+                Err(format!("{} is using a new key. Their messages are held until you enter /verify {}.", handle, handle))
+            } else {
+                Ok(Vec::new())
+            }
+        },
+    }
+}
+
+// A reset made longer ago than this, or as far ahead, is refused as replayed.
+const MAX_RESET_AGE: u64 = 600;
+
+// Tells each sender found out of step with us what our next message to them
+// will be numbered, so their side starts over too, returning a notice for
+// each. Our side already started over from their numbers.
+pub fn heal_sessions(net: &Net, state: &State) -> Vec<String> {
+    let handle = match net.handle() {
+        Some(h) => h,
+        None => return Vec::new(),
+    };
+    let mut notices = Vec::new();
+    for sender in state.take_diverged() {
+        let conv_id = match state.conv_name_to_id(&sender) {
+            Some(id) => id,
+            None => continue,
+        };
+        let reset = SessionReset::new(handle.clone(), conv_id, state.peek_seq(conv_id)).signed(&net.crypto);
+        net.notify(&sender, ToUser::SessionReset(reset));
+        state.log_security(SecurityKind::SessionReset, &format!("The conversation with {} was out of step, asked them to start over", sender));
+        notices.push(format!("The conversation with {} was out of step and has been reset. Some messages may show out of order.", sender));
+    }
+    notices
+}
+
+// Starts a sender's numbering over as their signed reset asks, returning a
+// notice and the messages now in order, or None for a copy of one applied. Resets are never answered with one,
+// so two sides can't keep resetting each other.
+pub fn receive_reset(reset: SessionReset, state: &State, keys: &KnownKeys) -> Result<Option<(String, Vec<TextMessage>)>, String> {
+    if !reset.has_valid_signature() {
+        state.log_security(SecurityKind::BadSignature, &format!("A session reset claiming to be from {}", reset.sender));
+        return Err(format!("Dropped a session reset from {} with a bad signature.", reset.sender));
+    }
+    if let Err(e) = keys.check_signer(&reset.sender, &reset.signing_key) {
+        state.log_security(SecurityKind::BadSignature, &format!("A session reset from {}, {}", reset.sender, e));
+        return Err(format!("Dropped a session reset from {}, {}.", reset.sender, e));
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    if cmp::max(now, reset.at) - cmp::min(now, reset.at) > MAX_RESET_AGE {
+        state.log_security(SecurityKind::Replayed, &format!("A session reset from {} made {}s from now", reset.sender, reset.at as i64 - now as i64));
+        return Err(format!("Dropped an old session reset from {}.", reset.sender));
+    }
+    match state.reset_session(reset.conv_id, &reset.sender, reset.seq, reset.at) {
+        Some(released) => {
+            state.log_security(SecurityKind::SessionReset, &format!("{} asked to start the conversation over from message {}", reset.sender, reset.seq));
+            Ok(Some((format!("{} reset your conversation, which was out of step. Some messages may show out of order.", reset.sender), released)))
+        },
+        None => Ok(None),
+    }
+}
+
+pub fn gap_warning(sender: &str, skipped: u64) -> String {
+    match skipped {
+        1 => format!("A message from {} may have been lost.", sender),
+        n => format!("{} messages from {} may have been lost.", n, sender),
+    }
+}
+
+// Shows the safety number for the key the server has for `other` and
+// accepts it if the user confirms it matches, releasing held messages.
+fn verify(other: &str, io: &IOHandler, net: &Net, state: &State, keys: &KnownKeys) -> Result<(), String> {
+    let route = try!(net.get_route(other));
+    let key = route[route.len()-1].1;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    for (time, _, _) in keys.history(other) {
+        io.print_log(&format!("{} changed their key {} ago.", other, ago(now.saturating_sub(time))));
+    }
+    match keys.get(other) {
+        Some((pinned, _)) if pinned != key => io.print_log(&format!("{}'s key has changed since you last accepted it.", other)),
+        Some((_, true)) => io.print_log(&format!("You have already verified {}.", other)),
+        _ => (),
+    }
+
+    io.print_log(&format!("Safety number with {}: {}", other, crypto_lib::safety_number(&net.crypto.pub_key, &key)));
+    io.print_log(&format!("Compare it with the one {} sees, in person or over a call you trust.", other));
+    loop {
+        match &*io.read_prompted_line("Does it match? [y/n]: ") {
+            "y" | "yes" => break,
+            "n" | "no" => return Err(format!("{} was not verified.", other)),
+            _ => io.print_error("Please enter 'y' or 'n'."),
+        }
+    }
+
+    try!(keys.verify(other, key));
+    state.log_security(SecurityKind::KeyAccepted, &format!("Verified {}'s key, fingerprint {}", other, key[..8].to_hex()));
+    state.forget_routes(other);
+    for msg in state.release(other, &key) {
+        state.add_new_message(msg);
+    }
+    io.print_log(&format!("Verified {}.", other));
+    Ok(())
+}
+
 fn leave(state: &State, io: &IOHandler) {
     state.set_current_conversation(None).unwrap();
-    io.print_conversations(state.list_conversations());
+    io.print_conversations(state.list_conversations(None));
 }
 
-fn join(conv: &str, state: &State, io: &IOHandler) {
+fn join(conv: &str, net: &Net, state: &State, io: &IOHandler) {
     if let Some(id) = state.conv_name_to_id(&conv) {
         state.set_current_conversation(Some(id)).unwrap();
-        io.print_messages(state.get_message_history().unwrap());
+        let history = state.get_message_history().unwrap();
+        for msg in history.iter().filter(|m| state.mark_displayed(m.id)) {
+            net.send_read_receipt(msg);
+        }
+        let conv = state.get_current_conversation().unwrap();
+        for msg in history {
+            io.print_log(&conv.show(&msg));
+        }
     } else {
         io.print_error("invalid conversation id");
     }
 }
 
-fn list(state: &State, io: &IOHandler) {
-    io.print_conversations(state.list_conversations());
+// The merged conversation is joined. Messages are sent to `user`, and ones
+// from either identity are shown with the key they came from.
+fn merge(user: &str, other: &str, net: &Net, state: &State, io: &IOHandler) {
+    match state.merge_conversations(user, other) {
+        Ok(_) => {
+            io.print_log(&format!("Merged the conversation with {} into the one with {}.", other, user));
+            join(user, net, state, io);
+        },
+        Err(e) => io.print_error(e),
+    }
+}
+
+// Contacts are stored sealed under a key only we can derive, so the server
+// can neither read them nor write contacts of its own.
+fn push_contacts(io: &IOHandler, net: &Net, state: &State, user: &Option<User>) -> Result<(), String> {
+    let handle = try!(user.as_ref().map(|u| u.handle.clone()).ok_or("Not logged in".to_string()));
+    let password = io.read_prompted_line("Password: ");
+
+    let mut partners = state.partners();
+    partners.sort();
+    let blob = try!(crypto_lib::seal_with_key(&net.crypto.contacts_key(), partners.join("\n").as_bytes())
+        .map_err(|_| "Failed to encrypt contacts".to_string()));
+
+    let req = ToServer::PutContacts(handle, password, state.contacts_version(), blob, net.crypto.pub_key);
+    match try!(net.request(req)) {
+        ResponseType::Contacts(version, _) => {
+            state.set_contacts_version(version);
+            io.print_log(&format!("Saved {} contacts.", partners.len()));
+            Ok(())
+        },
+        _ => Err("Something went wrong".to_string()),
+    }
+}
+
+// Notes to self are read and written through the server's copy, so one added by
+// another device since we last looked is merged in rather than overwritten.
+fn tome(text: String, net: &Net, state: &State, io: &IOHandler) -> Result<(), String> {
+    let at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let note = Note { id: rand::random(), at: at, text: text };
+    let mut result = Err("Something went wrong".to_string());
+    for _ in 0..SYNC_TRIES {
+        let (version, data) = try!(net.get_synced(SyncSlot::Notes));
+        try!(state.merge_notes(version, &data.unwrap_or_default()));
+        state.add_note(note.clone());
+        result = net.put_synced(SyncSlot::Notes, version, &state.encode_notes());
+        if let Ok(version) = result {
+            state.set_notes_version(version);
+            io.print_log(&show_note(&note));
+            return Ok(());
+        }
+    }
+    result.map(|_| ())
+}
+
+fn list_rules(state: &State, io: &IOHandler) {
+    let rules = state.rules();
+    if rules.is_empty() {
+        io.print_log("No rules. Add one with /rules add, e.g. in acme has urgent -> loud");
+    }
+    for (i, rule) in rules.iter().enumerate() {
+        io.print_log(&format!("{}. {}", i + 1, rule));
+    }
+}
+
+// Changes the server's copy of the rules, which every device follows. The
+// first time, or if the server's copy wasn't written by us, the rules we
+// already have are uploaded.
+fn change_rules<F>(net: &Net, state: &State, io: &IOHandler, change: F) -> Result<(), String>
+    where F: Fn(&mut Vec<filters::Rule>) -> Result<(), String> {
+    let mut result = Err("Something went wrong".to_string());
+    for _ in 0..SYNC_TRIES {
+        let (version, data) = try!(net.get_synced(SyncSlot::Rules));
+        let mut rules = match data {
+            Some(data) => filters::parse_all(&String::from_utf8_lossy(&data)),
+            None => state.rules(),
+        };
+        try!(change(&mut rules));
+        result = net.put_synced(SyncSlot::Rules, version, filters::to_text(&rules).as_bytes());
+        if let Ok(version) = result {
+            try!(state.set_rules(version, rules));
+            list_rules(state, io);
+            return Ok(());
+        }
+    }
+    result.map(|_| ())
+}
+
+// Keeps message filters in ~/.secmsg/rules, sealed under a key derived from
+// our identity key.
+pub fn open_rules(net: &Net, state: &State) -> Result<(), String> {
+    let dir = try!(env::home_dir().ok_or("Cannot find home directory".to_string())).join(".secmsg");
+    try!(fs::create_dir_all(&dir).map_err(|e| e.to_string()));
+    state.open_rules(&dir.join("rules"), net.crypto.rules_key())
+}
+
+fn show_notes(net: &Net, state: &State, io: &IOHandler) -> Result<(), String> {
+    let (version, data) = try!(net.get_synced(SyncSlot::Notes));
+    try!(state.merge_notes(version, &data.unwrap_or_default()));
+    let notes = state.notes();
+    if notes.is_empty() {
+        io.print_log("Nothing sent to your devices yet.");
+    }
+    for note in notes {
+        io.print_log(&show_note(&note));
+    }
+    Ok(())
+}
+
+pub fn show_note(note: &Note) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    format!("[to me] {} ago: {}", ago(now.saturating_sub(note.at)), note.text)
+}
+
+// Starts a conversation with every stored contact we don't have one with yet.
+fn pull_contacts(io: &IOHandler, net: &Net, state: &State, keys: &KnownKeys, user: &Option<User>) -> Result<(), String> {
+    let handle = try!(user.as_ref().map(|u| u.handle.clone()).ok_or("Not logged in".to_string()));
+    let password = io.read_prompted_line("Password: ");
+
+    let (version, blob) = match try!(net.request(ToServer::GetContacts(handle, password, net.crypto.pub_key))) {
+        ResponseType::Contacts(version, blob) => (version, blob),
+        _ => return Err("Something went wrong".to_string()),
+    };
+    state.set_contacts_version(version);
+    if blob.is_empty() {
+        io.print_log("No contacts saved.");
+        return Ok(());
+    }
+
+    let decrypted = try!(crypto_lib::open_with_key(&net.crypto.contacts_key(), &blob)
+        .map_err(|_| "Saved contacts were not sealed by you, enter /contacts push to replace them".to_string()));
+    let text = try!(String::from_utf8(decrypted).map_err(|_| "Saved contacts are corrupt".to_string()));
+    let known = state.partners();
+    for other in text.lines().filter(|h| !h.is_empty() && !known.iter().any(|k| k == h)) {
+        if let Err(e) = connect(other, &net, &state, &keys) {
+            io.print_error(&format!("{}: {}", other, e));
+        }
+    }
+    state.set_current_conversation(None).unwrap();
+    list(None, &state, &io);
+    Ok(())
+}
+
+fn create_broadcast(name: &str, io: &IOHandler, net: &Net, user: &Option<User>) -> Result<(), String> {
+    let handle = try!(user.as_ref().map(|u| u.handle.clone()).ok_or("Not logged in".to_string()));
+    let password = io.read_prompted_line("Password: ");
+
+    match try!(net.request(ToServer::CreateBroadcast(handle.clone(), password, name.to_string(), net.crypto.pub_key))) {
+        ResponseType::Subscribers(_) => {
+            io.print_log(&format!("Created {}/{}. Others can follow it with '/subscribe {} {}'.", handle, name, handle, name));
+            Ok(())
+        },
+        _ => Err("Something went wrong".to_string()),
+    }
+}
+
+// Seals the message once under the list key and lets the server fan it out.
+// Subscribers we haven't told the key yet are sent it over their own routes.
+fn broadcast(name: &str, text: String, io: &IOHandler, net: &Net, state: &State, user: &Option<User>) -> Result<(), String> {
+    let user = try!(user.clone().ok_or("Not logged in".to_string()));
+    if text.is_empty() {
+        return Err("usage: /broadcast send <name> <text>".to_string());
+    }
+    let password = io.read_prompted_line("Password: ");
+
+    let key = net.crypto.broadcast_key(name);
+    let req = ToServer::GetSubscribers(user.handle.clone(), password.clone(), name.to_string(), net.crypto.pub_key);
+    let subscribers = match try!(net.request(req)) {
+        ResponseType::Subscribers(s) => s,
+        _ => return Err("Something went wrong".to_string()),
+    };
+    for s in subscribers.iter().filter(|s| state.mark_keyed(name, s)) {
+        net.notify(s, ToUser::BroadcastKey(user.handle.clone(), name.to_string(), key));
+    }
+
+    let msg = TextMessage::new(text, user.clone(), 0, 0).signed(&net.crypto);
+    let sealed = try!(crypto_lib::seal_with_key(&key, json::encode(&msg).unwrap().as_bytes())
+        .map_err(|_| "Failed to encrypt message".to_string()));
+    state.add_sent_broadcast(messages::sealed_id(&sealed), name, msg);
+    match try!(net.request(ToServer::Broadcast(user.handle, password, name.to_string(), sealed, net.crypto.pub_key))) {
+        ResponseType::BroadcastSent(n) => {
+            io.print_log(&format!("Sent to {} subscribers.", n));
+            Ok(())
+        },
+        _ => Err("Something went wrong".to_string()),
+    }
+}
+
+fn list_subscribers(name: &str, io: &IOHandler, net: &Net, user: &Option<User>) -> Result<(), String> {
+    let handle = try!(user.as_ref().map(|u| u.handle.clone()).ok_or("Not logged in".to_string()));
+    let password = io.read_prompted_line("Password: ");
+
+    match try!(net.request(ToServer::GetSubscribers(handle, password, name.to_string(), net.crypto.pub_key))) {
+        ResponseType::Subscribers(ref s) if s.is_empty() => io.print_log("No subscribers yet."),
+        ResponseType::Subscribers(s) => for h in s {
+            io.print_log(&h);
+        },
+        _ => return Err("Something went wrong".to_string()),
+    }
+    Ok(())
+}
+
+fn subscribe(owner: &str, name: &str, on: bool, io: &IOHandler, net: &Net, user: &Option<User>) -> Result<(), String> {
+    let handle = try!(user.as_ref().map(|u| u.handle.clone()).ok_or("Not logged in".to_string()));
+    let password = io.read_prompted_line("Password: ");
+
+    let req = ToServer::Subscribe(handle, password, owner.to_string(), name.to_string(), on, net.crypto.pub_key);
+    match try!(net.request(req)) {
+        ResponseType::Subscribed(true) => io.print_log(&format!("Following {}/{}. Posts appear once {} sends the next one.", owner, name, owner)),
+        ResponseType::Subscribed(false) => io.print_log(&format!("No longer following {}/{}.", owner, name)),
+        _ => return Err("Something went wrong".to_string()),
+    }
+    Ok(())
+}
+
+// Members are named within the namespace, so "invite bob" lets name/bob
+// register, while admins are named by their full handle. Policy settings
+// are retention_secs, mailbox_quota, contacts_quota, shared_files_quota and
+// registration (open, invite or closed), and leaving out the value goes back
+// to the server's.
+fn namespace(name: &str, action: &str, args: &[&str], io: &IOHandler, net: &Net, user: &Option<User>) -> Result<(), String> {
+    let handle = try!(user.as_ref().map(|u| u.handle.clone()).ok_or("Not logged in".to_string()));
+    let other = || args.get(0).map(|o| o.trim().to_string()).ok_or(format!("usage: /namespace {} {} <user>", name, action));
+    let change = match action {
+        "create" | "search" => None,
+        "show" => Some(NamespaceChange::Show),
+        "invite" => Some(NamespaceChange::Invite(try!(other()))),
+        "remove" => Some(NamespaceChange::Remove(try!(other()))),
+        "admin" => Some(NamespaceChange::AddAdmin(try!(other()))),
+        "unadmin" => Some(NamespaceChange::RemoveAdmin(try!(other()))),
+        "policy" => Some(NamespaceChange::SetPolicy(try!(other().map_err(|_| format!("usage: /namespace {} policy <setting> [value]", name))),
+            args.get(1).map_or(String::new(), |v| v.trim().to_string()))),
+        _ => return Err("usage: /namespace <name> <create|show|invite|remove|admin|unadmin|search> [user]".to_string()),
+    };
+    let password = io.read_prompted_line("Password: ");
+
+    let req = match change {
+        Some(change) => ToServer::ManageNamespace(handle, password, name.to_string(), change, net.crypto.pub_key),
+        None if action == "create" => ToServer::CreateNamespace(handle, password, name.to_string(), net.crypto.pub_key),
+        None => ToServer::SearchNamespace(handle, password, name.to_string(), other().unwrap_or(String::new()), 0, net.crypto.pub_key),
+    };
+    match try!(net.request(req)) {
+        ResponseType::Namespace(admins, members, invited, overrides) => {
+            io.print_log(&format!("Admins: {}", admins.join(", ")));
+            io.print_log(&format!("Members: {}", if members.is_empty() { "none".to_string() } else { members.join(", ") }));
+            if !invited.is_empty() {
+                io.print_log(&format!("Invited: {}", invited.join(", ")));
+            }
+            for (setting, value) in overrides {
+                io.print_log(&format!("Policy: {} = {}", setting, value));
+            }
+        },
+        ResponseType::Handles(ref handles, _) if handles.is_empty() => io.print_log("No members found."),
+        ResponseType::Handles(handles, more) => {
+            for h in handles {
+                io.print_log(&h);
+            }
+            if more {
+                io.print_log("There are more, search with a longer prefix.");
+            }
+        },
+        _ => return Err("Something went wrong".to_string()),
+    }
+    Ok(())
+}
+
+// A namespace's shared files. Adding one seals its name and digest to
+// everyone in the namespace at the time, while the file itself stays here and
+// is sent to members who ask for it with `get`.
+fn shared(name: &str, action: &str, arg: Option<&str>, io: &IOHandler, net: &Net, state: &State,
+          user: &Option<User>) -> Result<(), String> {
+    let handle = try!(user.as_ref().map(|u| u.handle.clone()).ok_or("Not logged in".to_string()));
+    let usage = format!("usage: /shared {} {} <{}>", name, action, if action == "add" { "path" } else { "id" });
+    let id = arg.and_then(|a| a.parse::<u64>().ok());
+    match action {
+        "list" => {},
+        "add" if arg.is_some() => {},
+        "get" | "remove" if id.is_some() => {},
+        "add" | "get" | "remove" => return Err(usage),
+        _ => return Err("usage: /shared <namespace> <list|add|get|remove> [path|id]".to_string()),
+    }
+    let password = io.read_prompted_line("Password: ");
+    let request = |change| net.request(ToServer::SharedFiles(handle.clone(), password.clone(), name.to_string(), change,
+        net.crypto.pub_key));
+
+    match action {
+        "list" => {
+            let files = try!(shared_files(try!(request(SharedChange::List))));
+            if files.is_empty() {
+                io.print_log("No shared files.");
+            }
+            for file in files {
+                match open_shared(&file, net) {
+                    Some(meta) => io.print_log(&format!("{} {} ({} bytes, from {})", file.id, meta.name, file.size, file.owner)),
+                    None => io.print_log(&format!("{} shared by {} before you joined", file.id, file.owner)),
+                }
+            }
+        },
+        "add" => {
+            let path = try!(fs::canonicalize(arg.unwrap()).map_err(|e| e.to_string()));
+            let mut file = try!(File::open(&path).map_err(|e| e.to_string()));
+            let size = try!(file.metadata().map_err(|e| e.to_string())).len();
+            if size == 0 {
+                return Err("The file is empty".to_string());
+            }
+            let meta = SharedMeta {
+                name: try!(path.file_name().and_then(|n| n.to_str()).ok_or("That is not a file".to_string())).to_string(),
+                digest: try!(crypto_lib::hash_reader(&mut file).map_err(|e| e.to_string())),
+                mime: transfer::mime_for(&path).to_string(),
+            };
+            let members = match try!(request(SharedChange::Members)) {
+                ResponseType::Members(m) => m,
+                _ => return Err("Something went wrong".to_string()),
+            };
+
+            let key = crypto_lib::gen_key_pair().0;
+            let mut keys = Vec::new();
+            for (member, public_key) in members {
+                keys.push((member, try!(net.crypto.encrypt(&public_key, &[], &key).map_err(|_| "Failed to encrypt the key".to_string()))));
+            }
+            let entry = SharedFile {
+                id: rand::random::<u64>(),
+                owner: handle.clone(),
+                size: size,
+                meta: try!(crypto_lib::seal_with_key(&key, json::encode(&meta).unwrap().as_bytes())
+                    .map_err(|_| "Failed to encrypt the file's details".to_string())),
+                keys: keys,
+            };
+            try!(shared_files(try!(request(SharedChange::Add(entry.clone())))));
+            try!(state.transfers().share(entry.id, name, &path, entry.keys.iter().map(|k| k.0.clone()).collect()));
+            io.print_log(&format!("Shared {} with {} members as {}.", meta.name, entry.keys.len(), entry.id));
+        },
+        "get" => {
+            let file = try!(try!(shared_files(try!(request(SharedChange::Get(id.unwrap()))))).pop()
+                .ok_or("No such shared file".to_string()));
+            let meta = try!(open_shared(&file, net).ok_or("It was shared before you joined, so you can't open it".to_string()));
+            state.transfers().expect(meta.digest, &file.owner);
+            net.notify(&file.owner, ToUser::SharedFileRequest(name.to_string(), file.id, handle.clone()));
+            io.print_log(&format!("Asked {} to send {}.", file.owner, meta.name));
+        },
+        _ => {
+            try!(shared_files(try!(request(SharedChange::Remove(id.unwrap())))));
+            state.transfers().unshare(id.unwrap());
+            io.print_log("Removed it from the shared files.");
+        },
+    }
+    Ok(())
+}
+
+fn shared_files(res: ResponseType) -> Result<Vec<SharedFile>, String> {
+    match res {
+        ResponseType::SharedFiles(files) => Ok(files),
+        _ => Err("Something went wrong".to_string()),
+    }
+}
+
+// The details of a shared file, if it was sealed to us.
+fn open_shared(file: &SharedFile, net: &Net) -> Option<SharedMeta> {
+    let sealed = match file.keys.first() {
+        Some(&(_, ref sealed)) => sealed,
+        None => return None,
+    };
+    let key = match net.crypto.decrypt(&[], sealed) {
+        Ok(ref k) if k.len() == 32 => {
+            let mut key = [0u8; 32];
+            key.copy_from_slice(k);
+            key
+        },
+        _ => return None,
+    };
+    crypto_lib::open_with_key(&key, &file.meta).ok()
+        .and_then(|m| String::from_utf8(m).ok())
+        .and_then(|m| json::decode(&m).ok())
+}
+
+// Opens broadcasts as they and their keys arrive, returning the list each
+// readable post came from as "owner/name". Posts claiming to be from anyone
+// but the list's owner are dropped.
+// A broadcast we can't open, for want of the key or because it was sealed
+// under one we don't have, is held and the owner told with DecryptFailed. The
+// owner sends the list's key again along with the broadcast sealed under it,
+// which releases what was held.
+pub fn receive_broadcast(note: ToUser, net: &Net, state: &State) -> Vec<(String, TextMessage)> {
+    let (owner, name, sealed) = match note {
+        ToUser::Broadcast(owner, name, sealed) => {
+            let key = state.broadcast_key(&owner, &name);
+            if key.map_or(true, |k| crypto_lib::open_with_key(&k, &sealed).is_err()) {
+                if key.is_some() {
+                    state.log_security(SecurityKind::DecryptFailed, &format!("A broadcast on {}/{}", owner, name));
+                }
+                let id = messages::sealed_id(&sealed);
+                if state.hold_broadcast(owner.clone(), name, sealed) {
+                    if let Some(handle) = net.handle() {
+                        net.notify(&owner, ToUser::DecryptFailed(handle, id));
+                    }
+                }
+                return Vec::new();
+            }
+            (owner, name, vec![sealed])
+        },
+        // Those still unreadable were sealed under an older key, and are
+        // resent by the owner if it still has them.
+        ToUser::BroadcastKey(owner, name, key) => {
+            let sealed = state.set_broadcast_key(&owner, &name, key);
+            (owner, name, sealed)
+        },
+        ToUser::DecryptFailed(subscriber, id) => {
+            resend_broadcast(&subscriber, id, net, state);
+            return Vec::new();
+        },
+        _ => return Vec::new(),
+    };
+
+    let key = match state.broadcast_key(&owner, &name) {
+        Some(k) => k,
+        None => return Vec::new(),
+    };
+    sealed.iter()
+        .filter_map(|s| crypto_lib::open_with_key(&key, s).ok())
+        .filter_map(|p| String::from_utf8(p).ok())
+        .filter_map(|p| json::decode::<TextMessage>(&p).ok())
+        .filter(|m| m.sender.handle == owner && m.has_valid_signature())
+        .map(|m| (format!("{}/{}", owner, name), m))
+        .collect()
+}
+
+// Only subscribers that were given the key before get it again, and then
+// only through their own route, so asking for someone else gains nothing.
+fn resend_broadcast(subscriber: &str, id: u64, net: &Net, state: &State) {
+    let (handle, (name, msg)) = match (net.handle(), state.sent_broadcast(id)) {
+        (Some(h), Some(sent)) => (h, sent),
+        _ => return,
+    };
+    if !state.is_keyed(&name, subscriber) {
+        return;
+    }
+    let key = net.crypto.broadcast_key(&name);
+    net.notify(subscriber, ToUser::BroadcastKey(handle.clone(), name.clone(), key));
+    if let Ok(sealed) = crypto_lib::seal_with_key(&key, json::encode(&msg).unwrap().as_bytes()) {
+        net.notify(subscriber, ToUser::Broadcast(handle, name, sealed));
+    }
+}
+
+fn set_blocked(other: &str, block: bool, io: &IOHandler, net: &Net, user: &Option<User>) -> Result<(), String> {
+    let handle = try!(user.as_ref().map(|u| u.handle.clone()).ok_or("Not logged in".to_string()));
+    let password = io.read_prompted_line("Password: ");
+
+    let req = if block {
+        ToServer::Block(handle, password, other.to_string(), net.crypto.pub_key)
+    } else {
+        ToServer::Unblock(handle, password, other.to_string(), net.crypto.pub_key)
+    };
+    match try!(net.request(req)) {
+        ResponseType::Blocked(_, _) => Ok(()),
+        _ => Err("Something went wrong".to_string()),
+    }
+}
+
+// Shows the newest matches, each with who the conversation was with and when.
+fn find(term: &str, state: &State, io: &IOHandler) {
+    let found = state.search_history(term);
+    if found.is_empty() {
+        io.print_log("No messages found.");
+        return;
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let skipped = found.len().saturating_sub(MAX_FIND_RESULTS);
+    if skipped > 0 {
+        io.print_log(&format!("Showing the newest {} of {} matches.", MAX_FIND_RESULTS, found.len()));
+    }
+    for entry in found.into_iter().skip(skipped) {
+        io.print_log(&format!("[{}, {} ago] {}", entry.partner, ago(now.saturating_sub(entry.at)), entry.msg.to_string()));
+    }
+}
+
+// Archives are sealed by a passphrase rather than our key, so they can be
+// opened on a machine with a new identity.
+fn export(path: &str, partner: Option<&str>, state: &State, io: &IOHandler) -> Result<(), String> {
+    let entries = try!(state.export_history(partner));
+    if entries.is_empty() {
+        return Err("There is no history to export".to_string());
+    }
+    let passphrase = io.read_prompted_line("Passphrase to protect the archive: ");
+    if passphrase.is_empty() {
+        return Err("The archive needs a passphrase".to_string());
+    }
+    let sealed = try!(crypto_lib::seal_with_passphrase(&passphrase, json::encode(&entries).unwrap().as_bytes())
+        .map_err(|_| "Failed to encrypt the archive".to_string()));
+    try!(File::create(path).and_then(|mut f| f.write_all(&sealed)).map_err(|e| e.to_string()));
+    io.print_log(&format!("Exported {} messages to {}.", entries.len(), path));
+    Ok(())
+}
+
+fn import(path: &str, state: &State, io: &IOHandler) -> Result<(), String> {
+    let mut sealed = Vec::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_end(&mut sealed)).map_err(|e| e.to_string()));
+    let passphrase = io.read_prompted_line("Passphrase of the archive: ");
+    let text = try!(crypto_lib::open_with_passphrase(&passphrase, &sealed).ok()
+        .and_then(|t| String::from_utf8(t).ok())
+        .ok_or("The passphrase does not open the archive".to_string()));
+    let entries: Vec<Logged> = try!(json::decode(&text).map_err(|_| "The archive is corrupt".to_string()));
+    let added = try!(state.import_history(entries));
+    io.print_log(&format!("Imported {} messages.", added));
+    Ok(())
+}
+
+// Pages are numbered from 1 for the user and from 0 on the wire.
+fn search(prefix: &str, page: usize, net: &Net, io: &IOHandler) {
+    let page = cmp::max(page, 1);
+    match net.request(ToServer::Search(prefix.to_string(), page - 1, net.crypto.pub_key)) {
+        Ok(ResponseType::Handles(ref handles, _)) if handles.is_empty() => io.print_log("No users found."),
+        Ok(ResponseType::Handles(handles, more)) => {
+            for h in handles {
+                io.print_log(&h);
+            }
+            if more {
+                io.print_log(&format!("Enter '/search {} {}' for more.", prefix, page + 1));
+            }
+        },
+        Ok(_) => io.print_error("Something went wrong"),
+        Err(e) => io.print_error(&e),
+    }
+}
+
+fn presence(other: &str, net: &Net, io: &IOHandler) {
+    match net.presence(other) {
+        Ok((true, _)) => io.print_log(&format!("{} is online.", other)),
+        Ok((false, Some(secs))) => io.print_log(&format!("{} is offline, last seen {} ago.", other, ago(secs))),
+        Ok((false, None)) => io.print_log(&format!("{} has not been seen online.", other)),
+        Err(e) => io.print_error(&e),
+    }
+}
+
+fn relays(list: bool, net: &Net, io: &IOHandler) {
+    let (changed, full) = match net.sync_relays() {
+        Ok(r) => r,
+        Err(e) => return io.print_error(&e),
+    };
+    let (version, relays) = net.relay_directory();
+    let fetched = if full { "fetched in full".to_string() } else { format!("{} changed", changed) };
+    io.print_log(&format!("Relay directory version {}: {} relays, {}.", version, relays.len(), fetched));
+    if list {
+        for (addr, key) in relays {
+            io.print_log(&format!("  {} [{}]", addr, key[..4].to_hex()));
+        }
+    }
+}
+
+fn watched_handles(net: &Net) -> Vec<String> {
+    net.watching().into_iter().filter_map(|t| match t {
+        Topic::Presence(handles) => Some(handles),
+        Topic::Directory => None,
+    }).next().unwrap_or(Vec::new())
+}
+
+// Watched users are added to those already watched, since the server only
+// keeps one list of them for us.
+fn watch(args: &[&str], net: &Net, io: &IOHandler) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().flat_map(|a| a.split_whitespace()).collect();
+    if args.is_empty() {
+        let watching = net.watching();
+        if watching.is_empty() {
+            io.print_log("Not watching anything.");
+        }
+        for topic in watching {
+            match topic {
+                Topic::Presence(handles) => io.print_log(&format!("Users: {}", handles.join(", "))),
+                Topic::Directory => io.print_log("The directory"),
+            }
+        }
+        return Ok(());
+    }
+    if args == ["directory"] {
+        try!(net.watch(Topic::Directory));
+        io.print_log("Watching the directory.");
+        return Ok(());
+    }
+    let mut handles = watched_handles(net);
+    for h in args.iter() {
+        if !handles.iter().any(|w| w == h) {
+            handles.push(h.to_string());
+        }
+    }
+    try!(net.watch(Topic::Presence(handles)));
+    io.print_log(&format!("Watching {}.", args.join(", ")));
+    Ok(())
+}
+
+fn unwatch(other: &str, net: &Net, io: &IOHandler) -> Result<(), String> {
+    if other == "directory" {
+        try!(net.unwatch(Topic::Directory));
+    } else {
+        let mut handles = watched_handles(net);
+        if !handles.iter().any(|h| h == other) {
+            return Err(format!("Not watching {}.", other));
+        }
+        handles.retain(|h| h != other);
+        try!(if handles.is_empty() { net.unwatch(Topic::Presence(handles)) } else { net.watch(Topic::Presence(handles)) });
+    }
+    io.print_log(&format!("No longer watching {}.", other));
+    Ok(())
+}
+
+// Prints the last `count` security events, oldest first, warning if the log
+// was altered before them.
+fn security_log(count: Option<&str>, state: &State, io: &IOHandler) -> Result<(), String> {
+    let count = try!(count.map_or(Ok(20), |c| c.parse::<usize>().map_err(|_| "usage: /security-log [count]".to_string())));
+    let (events, broken) = try!(state.security_log());
+    if let Some(n) = broken {
+        io.print_error(&format!("The security log was altered or damaged at entry {} of {}.", n + 1, events.len()));
+    }
+    if events.is_empty() {
+        io.print_log("No security events.");
+        return Ok(());
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    for event in events.iter().skip(events.len().saturating_sub(count)) {
+        io.print_log(&format!("{} ago, {}: {}", ago(now.saturating_sub(event.at)), event.kind, event.detail));
+    }
+    Ok(())
+}
+
+fn ago(secs: u64) -> String {
+    match secs {
+        s if s < 60 => "less than a minute".to_string(),
+        s if s < 60 * 60 => format!("{} minutes", s / 60),
+        s if s < 60 * 60 * 24 => format!("{} hours", s / (60 * 60)),
+        s => format!("{} days", s / (60 * 60 * 24)),
+    }
+}
+
+fn feed(mentions_only: bool, state: &State, user: &Option<User>, io: &IOHandler) {
+    let mention = user.as_ref().map(|u| format!("@{}", u.handle));
+    let msgs: Vec<TextMessage> = state.get_activity().into_iter()
+        .filter(|m| !mentions_only || mention.as_ref().map_or(false, |h| m.text.contains(&**h)))
+        .collect();
+
+    if msgs.is_empty() {
+        return io.print_log("Nothing new.");
+    }
+    io.print_messages(msgs);
+    io.print_log("Enter '/join <user>' to reply in a conversation.");
+}
+
+fn list(label: Option<&str>, state: &State, io: &IOHandler) {
+    io.print_conversations(state.list_conversations(label));
+}
+
+fn show_labels(state: &State, io: &IOHandler) {
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for label in state.labels().values().flat_map(|l| l.iter()) {
+        *counts.entry(label.clone()).or_insert(0) += 1;
+    }
+    if counts.is_empty() {
+        io.print_log("No labels. Add one with /label <user> <label>.");
+    }
+    for (label, count) in counts {
+        io.print_log(&format!("{} ({})", label, count));
+    }
+}
+
+// Labels are changed on the server's copy, so a change made on another
+// device since we last looked is kept.
+fn change_label(partner: &str, label: &str, add: bool, net: &Net, state: &State, io: &IOHandler) -> Result<(), String> {
+    if label.contains(char::is_whitespace) || label.is_empty() {
+        return Err("A label is a single word".to_string());
+    }
+    let mut result = Err("Something went wrong".to_string());
+    for _ in 0..SYNC_TRIES {
+        let (version, data) = try!(net.get_synced(SyncSlot::Labels));
+        let mut labels = parse_labels(&data.unwrap_or_default());
+        if add {
+            labels.entry(partner.to_string()).or_insert_with(BTreeSet::new).insert(label.to_string());
+        } else if let Some(l) = labels.get_mut(partner) {
+            l.remove(label);
+        }
+        labels.retain(|_, l| !l.is_empty());
+        result = net.put_synced(SyncSlot::Labels, version, &labels_text(&labels));
+        if let Ok(version) = result {
+            state.set_labels(version, labels);
+            io.print_log(&format!("{} {} {}.", if add { "Labelled" } else { "Unlabelled" }, partner, label));
+            return Ok(());
+        }
+    }
+    result.map(|_| ())
+}
+
+fn relay_test(net: &Net, io: &IOHandler) {
+    io.print_log("Testing relay...");
+    match net.relay_test(RELAY_TEST_SIZE) {
+        Ok(report) => io.print_log(&report.to_string()),
+        Err(e) => io.print_error(&e),
+    }
+}
+
+fn nat(net: &Net, io: &IOHandler) {
+    match net.reflexive_addr() {
+        Ok(addr) => io.print_log(&format!("The server sees you at {}, you listen on port {}.", addr, net.port())),
+        Err(e) => io.print_error(&e),
+    }
+    io.print_log(if net.uses_mailbox() {
+        "Peers can't connect to you, so their messages wait on the server until you fetch them."
+    } else {
+        "Peers connect to you directly."
+    });
+}
+
+fn usage(name: &str, args: &str) -> String {
+    if args.is_empty() { name.to_string() } else { format!("{} {}", name, args) }
+}
+
+fn help(cmd: Option<&str>, io: &IOHandler) {
+    match cmd {
+        Some(c) => {
+            let name = if c.starts_with('/') { c.to_string() } else { format!("/{}", c) };
+            match COMMANDS.iter().find(|&&(n, _, _)| n == name) {
+                Some(&(n, a, d)) => io.print_log(&format!("usage: {}\n{}", usage(n, a), d)),
+                None => io.print_error("Command not recognized"),
+            }
+        },
+        None => {
+            let width = COMMANDS.iter().map(|&(n, a, _)| usage(n, a).len()).max().unwrap_or(0);
+            for &(n, a, d) in COMMANDS {
+                io.print_log(&format!("{:width$}  {}", usage(n, a), d, width = width));
+            }
+        },
+    }
+}
+
+fn capabilities(net: &Net, state: &State, io: &IOHandler) {
+    let version = net.server_version();
+    io.print_log(&format!("Server protocol version {}: {}", version, net_lib::capabilities(version).join(", ")));
+    io.print_log(&format!("Cipher suite: {}", net.server_suite().name()));
+    io.print_log(&format!("Persistent connection: {}", if state.is_server_connected() { "open" } else { "closed" }));
+    // Only the first hop of a route negotiates a version, so peers are unknown.
+    io.print_log("Peers do not advertise capabilities.");
+}
+
+fn carry(args: &[&str], net: &Net, keys: &KnownKeys, io: &IOHandler) {
+    match args.get(0).map(|a| a.trim()) {
+        Some("on") => {
+            let contacts = keys.verified_keys();
+            io.print_log(&format!("Carrying messages for your {} verified contacts while they can't be reached.", contacts.len()));
+            net.set_carry_mode(true, contacts);
+        },
+        Some("off") => {
+            net.set_carry_mode(false, Vec::new());
+            io.print_log("No longer carrying messages.");
+        },
+        _ => io.print_error("usage: /carry <on|off>"),
+    }
+}
+
+// The choice is saved to the config so it outlasts this session.
+fn power(args: &[&str], net: &Net, io: &IOHandler) {
+    match args.get(0).map(|a| a.trim()) {
+        Some("low") => {
+            net.set_low_power(true);
+            io.print_log("Low power mode on, messages that aren't urgent may take a few seconds to go out.");
+        },
+        Some("normal") => {
+            net.set_low_power(false);
+            io.print_log("Low power mode off.");
+        },
+        _ => io.print_error("usage: /power <low|normal>"),
+    }
+}
+
+fn receipts(args: &[&str], net: &Net, io: &IOHandler) -> Result<(), String> {
+    let on = match args.get(0).map(|a| a.trim()) {
+        Some("on") => true,
+        Some("off") => false,
+        _ => return Err("usage: /receipts <on|off>".to_string()),
+    };
+    net.set_read_receipts(on);
+
+    let mut config = Config::load();
+    config.set("read_receipts", if on { "true" } else { "false" });
+    try!(config.save());
+    io.print_log(if on { "Senders will see when you read their messages." } else { "Read receipts are off." });
+    Ok(())
+}
+
+// Off the record, our messages to the partner carry MACs under keys that
+// only they and we can derive, instead of signatures anyone can check. The
+// keys are published once the messages arrive, so afterwards anyone could
+// have made them. Their messages to us are checked whichever way they come.
+fn deniable(args: &[&str], state: &State, io: &IOHandler) -> Result<(), String> {
+    let on = match args.get(0).map(|a| a.trim()) {
+        Some("on") => true,
+        Some("off") => false,
+        _ => return Err("usage: /deniable <on|off>".to_string()),
+    };
+    let conv = try!(state.get_current_conversation().ok_or("No current conversation.".to_string()));
+    let partner = conv.get_partner().handle.clone();
+    state.set_deniable(&partner, on);
+    io.print_log(&if on {
+        format!("Your messages to {} can no longer be proven to be yours once they arrive.", partner)
+    } else {
+        format!("Your messages to {} are signed again.", partner)
+    });
+    Ok(())
+}
+
+// Sends text to the current conversation. Reliable messages are duplicated
+// across two disjoint routes and deduplicated by the recipient.
+pub fn send_text(text: String, io: &IOHandler, net: &Net, state: &State, user: &Option<User>, reliable: bool, priority: Priority) {
+    let (status, results) = channel();
+    match queue_text(text, net, state, user, reliable, priority, Some(status)) {
+        Ok(out) => report_failure(out, results, state),
+        Err(e) => io.print_error(&e),
+    }
+}
+
+// Queues text for the current conversation. If `status` is given it hears
+// from every route whether the first hop took the message.
+pub fn queue_text(text: String, net: &Net, state: &State, user: &Option<User>, reliable: bool, priority: Priority,
+                  status: Option<Response>) -> Result<Outgoing, String> {
+    let curr_conv = try!(state.get_current_conversation().ok_or("No current conversation.".to_string()));
+    let user = try!(user.clone().ok_or("Not logged in".to_string()));
+
+    let partner = curr_conv.get_partner();
+    let msg = TextMessage::new(text, user, curr_conv.get_id(), state.next_seq(curr_conv.get_id()));
+    let msg = if state.is_deniable(&partner.handle) {
+        let (msg, key) = msg.deniable(&net.crypto, &partner.public_key);
+        state.add_mac_key(msg.id, &partner.handle, key);
+        msg
+    } else {
+        msg.signed(&net.crypto)
+    };
+    let out = Outgoing {
+        msg: msg,
+        partner: partner.handle.clone(),
+        reliable: reliable,
+        priority: priority,
+    };
+    state.add_unacked(out.clone());
+    if let Err(e) = send_outgoing(&out, net, state, status) {
+        state.take_unacked(out.msg.id);
+        return Err(e);
+    }
+    state.add_sent(out.clone());
+    state.append_history(&out.partner, &out.msg);
+    Ok(out)
+}
+
+fn send_outgoing(out: &Outgoing, net: &Net, state: &State, status: Option<Response>) -> Result<(), String> {
+    let msgs = try!(outgoing_messages(out, net, state, status));
+    net.add_cancelable(out.msg.id, msgs);
+    Ok(())
+}
+
+fn outgoing_messages(out: &Outgoing, net: &Net, state: &State, status: Option<Response>) -> Result<Vec<MessageContainer>, String> {
+    let routes = if out.reliable {
+        state.get_disjoint_routes(&out.partner, &net)
+    } else {
+        state.get_route(&out.partner, &net).map(|r| vec![r])
+    };
+
+    let routes = try!(routes);
+
+    // Send the message off to the network once it can no longer be undone.
+    Ok(routes.into_iter()
+        .map(|route| MessageContainer::new(
+            Message::with_priority(MessageType::User(ToUser::Text(out.msg.clone())), route, &net.crypto, out.priority),
+            status.clone(),
+            false
+        ))
+        .collect())
+}
+
+// Stops tracking a message the recipient acknowledged, returning it. If it
+// was deniable its MAC key is published, since the recipient has checked it.
+pub fn acknowledged(id: u64, net: &Net, state: &State) -> Option<Outgoing> {
+    if let Some((partner, key)) = state.take_mac_key(id) {
+        net.notify(&partner, ToUser::MacKeys(vec![key]));
+    }
+    state.take_unacked(id)
+}
+
+// Resends every message the recipient hasn't acknowledged in time over fresh
+// routes, returning those given up on. The recipient drops repeated copies.
+pub fn resend_unacked(net: &Net, state: &State) -> Vec<Outgoing> {
+    let mut given_up = Vec::new();
+    for (out, resends) in state.overdue(Duration::from_secs(ACK_TIMEOUT_SECS)) {
+        if resends >= MAX_RESENDS {
+            state.take_unacked(out.msg.id);
+            given_up.push(out);
+            continue;
+        }
+
+        // A failed resend is tried again after the next timeout.
+        state.forget_routes(&out.partner);
+        if let Ok(msgs) = outgoing_messages(&out, net, state, None) {
+            for m in msgs {
+                net.add_message(m);
+            }
+        }
+    }
+    given_up
+}
+
+fn pending(state: &State, io: &IOHandler) {
+    let unacked = state.unacked();
+    if unacked.is_empty() {
+        return io.print_log("Every message has been acknowledged.");
+    }
+    for out in unacked {
+        io.print_log(&format!("To {}: {}", out.partner, out.msg.text));
+    }
+}
+
+// Offers the file to the current conversation.
+pub fn send_file(path: &str, kind: Attachment, net: &Net, state: &State, user: &Option<User>) -> Result<FileOffer, String> {
+    let handle = try!(user.as_ref().map(|u| u.handle.clone()).ok_or("Not logged in".to_string()));
+    let conv = try!(state.get_current_conversation().ok_or("No current conversation.".to_string()));
+    let chunk_size = Config::load().get("file_chunk_bytes", transfer::DEFAULT_CHUNK_BYTES);
+    state.transfers().offer(Path::new(path.trim()), &handle, &conv.get_partner().handle, kind, chunk_size, net)
+}
+
+// Records with record_command into ~/.secmsg/voice, in voice_format (ogg by
+// default), and sends it like any other file.
+fn voice(secs: Option<&str>, io: &IOHandler, net: &Net, state: &State, user: &Option<User>) -> Result<FileOffer, String> {
+    let config = Config::load();
+    let max = config.get("max_voice_secs", DEFAULT_MAX_VOICE_SECS);
+    let secs = match secs {
+        Some(s) => try!(s.parse::<u32>().map_err(|_| "usage: /voice [seconds]".to_string())),
+        None => cmp::min(DEFAULT_VOICE_SECS, max),
+    };
+    if secs == 0 || secs > max {
+        return Err(format!("Voice messages can be 1 to {} seconds long", max));
+    }
+    try!(state.get_current_conversation().ok_or("No current conversation.".to_string()));
+
+    let dir = try!(env::home_dir().ok_or("Cannot find home directory".to_string())).join(".secmsg").join("voice");
+    try!(fs::create_dir_all(&dir).map_err(|e| e.to_string()));
+    let path = dir.join(format!("{}.{}", rand::random::<u64>(), config.get_str("voice_format").unwrap_or("ogg".to_string())));
+    io.print_log(&format!("Recording for {} seconds...", secs));
+    try!(Hooks::from_config(&config).record_voice(&path, secs));
+    send_file(&path.to_string_lossy(), Attachment::Voice(secs), net, state, user)
+}
+
+// The thumbnail is made with thumbnail_command in thumbnail_format (jpg by
+// default). Without one the image is offered on its own.
+pub fn send_image(path: &str, net: &Net, state: &State, user: &Option<User>) -> Result<FileOffer, String> {
+    let config = Config::load();
+    let dir = try!(env::home_dir().ok_or("Cannot find home directory".to_string())).join(".secmsg").join("thumbnails");
+    try!(fs::create_dir_all(&dir).map_err(|e| e.to_string()));
+    let thumb_path = dir.join(format!("{}.{}", rand::random::<u64>(), config.get_str("thumbnail_format").unwrap_or("jpg".to_string())));
+
+    let mut thumb = Vec::new();
+    if try!(Hooks::from_config(&config).make_thumbnail(Path::new(path.trim()), &thumb_path)) {
+        try!(File::open(&thumb_path).and_then(|mut f| f.read_to_end(&mut thumb)).map_err(|e| e.to_string()));
+        let _ = fs::remove_file(&thumb_path);
+        if thumb.len() > transfer::MAX_THUMBNAIL_BYTES {
+            return Err(format!("The thumbnail is over {} bytes, make it smaller", transfer::MAX_THUMBNAIL_BYTES));
+        }
+    }
+    send_file(path, Attachment::Image(transfer::mime_for(&thumb_path).to_string(), thumb), net, state, user)
+}
+
+// Shows a received image, or the thumbnail of one still on offer.
+fn view(what: &str, state: &State) -> Result<(), String> {
+    let hooks = Hooks::from_config(&Config::load());
+    let is_image = |o: &FileOffer| match o.kind { Attachment::Image(..) => true, _ => false };
+    if what.is_empty() {
+        let (_, path) = try!(state.transfers().last_received(is_image).ok_or("No images yet".to_string()));
+        return hooks.view_image(&path);
+    }
+    if let Ok(id) = what.parse::<u64>() {
+        let offer = try!(state.transfers().list().into_iter().map(|t| t.offer).find(|o| o.id == id && is_image(o))
+            .ok_or("No image with that id".to_string()));
+        let thumb = try!(transfer::open_thumbnail(&offer).ok_or("That image came without a thumbnail".to_string()));
+        return hooks.view_image(&thumb);
+    }
+    hooks.view_image(Path::new(what))
+}
+
+fn play(path: &str, state: &State) -> Result<(), String> {
+    let path = if path.trim().is_empty() {
+        try!(state.transfers().last_received(|o| match o.kind { Attachment::Voice(_) => true, _ => false })
+            .ok_or("No voice messages yet".to_string())).1
+    } else {
+        Path::new(path.trim()).to_path_buf()
+    };
+    Hooks::from_config(&Config::load()).play_voice(&path)
+}
+
+// Returns the offer and whether it started downloading, or was queued by
+// the network profile.
+pub fn accept_file(id: Option<u64>, force: bool, net: &Net, state: &State, user: &Option<User>) -> Result<(FileOffer, bool), String> {
+    let handle = try!(user.as_ref().map(|u| u.handle.clone()).ok_or("Not logged in".to_string()));
+    state.transfers().accept(id, &handle, force, net)
+}
+
+fn downloads(state: &State, io: &IOHandler) {
+    let queue = state.transfers().queue();
+    if queue.is_empty() {
+        return io.print_log("No downloads.");
+    }
+    for (i, t) in queue.iter().enumerate() {
+        let done = cmp::min(t.chunks_done * t.offer.chunk_size, t.offer.size);
+        io.print_log(&format!("{}. {} {} from {} ({} of {} bytes, {})", i + 1, t.offer.id, t.offer.name, t.peer,
+            done, t.offer.size, t.state));
+    }
+}
+
+// Switches to `profile` and saves it, or shows the one in use.
+pub fn network(profile: Option<&str>, net: &Net, state: &State, io: &IOHandler) -> Result<(), String> {
+    if let Some(profile) = profile {
+        let mut config = Config::load();
+        config.set("network_profile", profile);
+        try!(config.save());
+        state.transfers().set_policy(DownloadPolicy::from_config(&config), net);
+    }
+
+    let policy = state.transfers().policy();
+    let limit = match policy.auto_download_bytes {
+        0 => "asks before every download".to_string(),
+        n => format!("downloads files up to {} KB without asking", n / 1024),
+    };
+    io.print_log(&format!("Network profile {}: {}{}.", policy.profile, limit,
+        if policy.metered { ", and queues bigger ones until you switch" } else { "" }));
+    Ok(())
+}
+
+fn files(state: &State, io: &IOHandler) {
+    let list = state.transfers().list();
+    if list.is_empty() {
+        return io.print_log("No file transfers.");
+    }
+    for t in list {
+        let done = cmp::min(t.chunks_done * t.offer.chunk_size, t.offer.size);
+        io.print_log(&format!("{} {} {} {} ({} of {} bytes, {}){}", t.offer.id, if t.sending { "to" } else { "from" },
+            t.peer, t.offer.name, done, t.offer.size, t.state,
+            if t.state == "waiting" || t.state == "paused" { ", /accept-file to receive it" } else { "" }));
+    }
+}
+
+pub fn show_transfer(event: Event, io: &IOHandler) {
+    match event {
+        Event::Offered(offer) => match offer.kind {
+            Attachment::Image(..) => {
+                let thumb = transfer::open_thumbnail(&offer);
+                io.print_log(&format!("{} sent you an image, {} ({} bytes). Enter /accept-file {} to fetch it{}.",
+                    offer.sender, offer.name, offer.size, offer.id,
+                    if thumb.is_some() { format!(" or /view {} to see the thumbnail", offer.id) } else { String::new() }));
+                if let Some(thumb) = thumb {
+                    if let Err(e) = Hooks::from_config(&Config::load()).preview_image(&thumb) {
+                        io.print_error(&format!("Could not show the thumbnail: {}", e));
+                    }
+                }
+            },
+            Attachment::Voice(secs) => io.print_log(&format!("{} sent you a {} second voice message. Enter /accept-file {} to fetch it.",
+                offer.sender, secs, offer.id)),
+            _ => io.print_log(&format!("{} offers you {} ({} bytes). Enter /accept-file {} to receive it.",
+                offer.sender, offer.name, offer.size, offer.id)),
+        },
+        Event::Downloading(offer) => io.print_log(&format!("Receiving {} ({} bytes) from {}.",
+            offer.name, offer.size, offer.sender)),
+        Event::Received(offer, path) => match offer.kind {
+            Attachment::Voice(secs) => io.print_log(&format!("Voice message from {} ({} seconds). Enter /play to listen.",
+                offer.sender, secs)),
+            Attachment::Image(..) => io.print_log(&format!("Received the image {} from {}. Enter /view to see it.",
+                offer.name, offer.sender)),
+            _ => io.print_log(&format!("Received {} from {}, saved as {}.", offer.name, offer.sender, path.display())),
+        },
+        Event::Delivered(offer, to) => io.print_log(&format!("{} received {}.", to, offer.name)),
+        Event::Shared(offer, to) => io.print_log(&format!("Sending the shared file {} to {}.", offer.name, to)),
+        Event::Paused(offer) => io.print_error(&format!("{} stopped arriving. Enter /accept-file {} to try again.",
+            offer.name, offer.id)),
+        Event::Cancelled(offer) => io.print_log(&format!("The transfer of {} was cancelled.", offer.name)),
+        Event::Failed(offer, e) => io.print_error(&format!("Could not receive {}: {}.", offer.name, e)),
+    }
+}
+
+// Tells the user if every route of `out` failed, keeping it for /retry.
+fn report_failure(out: Outgoing, results: Receiver<Result<Option<Message>, String>>, state: &State) {
+    let state = state.clone();
+    thread::spawn(move || {
+        // Nothing comes back if the message was undone.
+        let results: Vec<_> = results.iter().collect();
+        if results.iter().all(|r| r.is_err()) {
+            if let Some(&Err(ref e)) = results.first() {
+                IOHandler::quiet().print_error(&format!(
+                    "Your message to {} was not delivered: {}. Enter /retry to send it again.", out.partner, e));
+                state.take_unacked(out.msg.id);
+                state.add_failed(out.clone());
+            }
+        }
+    });
+}
+
+// Sends the last undelivered message again over fresh routes. The recipient
+// drops it if an earlier copy did get through.
+fn retry(io: &IOHandler, net: &Net, state: &State) {
+    let out = match state.take_failed() {
+        Some(out) => out,
+        None => return io.print_error("No undelivered messages."),
+    };
+
+    state.forget_routes(&out.partner);
+    let (status, results) = channel();
+    state.add_unacked(out.clone());
+    match send_outgoing(&out, net, state, Some(status)) {
+        Ok(()) => report_failure(out, results, state),
+        Err(e) => {
+            io.print_error(&e);
+            state.take_unacked(out.msg.id);
+            state.add_failed(out);
+        },
+    }
+}
+
+// Keeps conversations in ~/.secmsg/history, sealed under a key derived from
+// our identity key.
+pub fn open_sessions(net: &Net, state: &State) -> Result<(), String> {
+    let dir = try!(env::home_dir().ok_or("Cannot find home directory".to_string())).join(".secmsg");
+    try!(fs::create_dir_all(&dir).map_err(|e| e.to_string()));
+    state.open_sessions(&dir.join("sessions"), net.crypto.sessions_key())
+}
+
+pub fn open_security_log(net: &Net, state: &State) -> Result<(), String> {
+    let dir = try!(env::home_dir().ok_or("Cannot find home directory".to_string())).join(".secmsg");
+    try!(fs::create_dir_all(&dir).map_err(|e| e.to_string()));
+    state.open_security_log(&dir.join("security.log"), net.crypto.security_key())
+}
+
+pub fn open_history(net: &Net, state: &State) -> Result<(), String> {
+    let dir = try!(env::home_dir().ok_or("Cannot find home directory".to_string())).join(".secmsg").join("history");
+    state.open_history(&dir, net.crypto.history_key())
+}
+
+// Resends the messages that were never acknowledged before secmsg last
+// stopped, as kept in ~/.secmsg/outbox. A peer that did get one drops the
+// copy but acknowledges it again. Returns how many were resent.
+pub fn resume_outbox(net: &Net, state: &State) -> Result<usize, String> {
+    let dir = try!(env::home_dir().ok_or("Cannot find home directory".to_string())).join(".secmsg");
+    try!(fs::create_dir_all(&dir).map_err(|e| e.to_string()));
+    let pending = try!(state.open_journal(&dir.join("outbox")));
+    for out in pending.iter() {
+        if let Ok(msgs) = outgoing_messages(out, net, state, None) {
+            for m in msgs {
+                net.add_message(m);
+            }
+        }
+    }
+    Ok(pending.len())
 }
@@ -0,0 +1,120 @@
+use std::cmp;
+
+// A small LZ77 codec in the style of an LZ4 block: each sequence is a token
+// holding the lengths of its literals and of the match after them, the
+// literals, then the match as a two byte little endian offset back into the
+// output. Lengths of 15 or more spill into following bytes of up to 255 each.
+// The last sequence has literals only. It is fast and needs no dictionary,
+// which suits the JSON and text that fill most messages.
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = 0xffff;
+const HASH_BITS: u32 = 12;
+
+fn hash(bytes: &[u8]) -> usize {
+    let v = (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24;
+    (v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2);
+    let mut table = vec![0usize; 1 << HASH_BITS]; // where each hash was last seen, plus one
+    let (mut pos, mut anchor) = (0, 0);
+    while pos + MIN_MATCH <= input.len() {
+        let h = hash(&input[pos..]);
+        let candidate = table[h];
+        table[h] = pos + 1;
+        if candidate > 0 && pos - (candidate - 1) <= MAX_OFFSET
+            && input[candidate - 1..candidate - 1 + MIN_MATCH] == input[pos..pos + MIN_MATCH] {
+            let start = candidate - 1;
+            let mut len = MIN_MATCH;
+            while pos + len < input.len() && input[start + len] == input[pos + len] {
+                len += 1;
+            }
+            write_sequence(&mut out, &input[anchor..pos], Some((pos - start, len)));
+            pos += len;
+            anchor = pos;
+        } else {
+            pos += 1;
+        }
+    }
+    write_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], found: Option<(usize, usize)>) {
+    let extra = found.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push((cmp::min(literals.len(), 15) << 4 | cmp::min(extra, 15)) as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = found {
+        out.push(offset as u8);
+        out.push((offset >> 8) as u8);
+        if extra >= 15 {
+            write_length(out, extra - 15);
+        }
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 255 {
+        out.push(255);
+        n -= 255;
+    }
+    out.push(n as u8);
+}
+
+// Fails rather than produce more than `max_len` bytes, so a small message
+// can't be made to expand without bound.
+pub fn decompress(input: &[u8], max_len: usize) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    loop {
+        let token = *try!(input.get(pos).ok_or("The compressed data was cut short".to_string()));
+        pos += 1;
+
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += try!(read_length(input, &mut pos));
+        }
+        if literals > input.len() - pos || out.len() + literals > max_len {
+            return Err("The compressed data is malformed".to_string());
+        }
+        out.extend_from_slice(&input[pos..pos + literals]);
+        pos += literals;
+        if pos == input.len() {
+            return Ok(out);
+        }
+
+        let offset = try!(input.get(pos..pos + 2).ok_or("The compressed data was cut short".to_string()));
+        let offset = offset[0] as usize | (offset[1] as usize) << 8;
+        pos += 2;
+        let mut len = (token & 0x0f) as usize;
+        if len == 15 {
+            len += try!(read_length(input, &mut pos));
+        }
+        len += MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + len > max_len {
+            return Err("The compressed data is malformed".to_string());
+        }
+        // Matches may overlap what they copy, so go a byte at a time.
+        let start = out.len() - offset;
+        for i in 0..len {
+            let b = out[start + i];
+            out.push(b);
+        }
+    }
+}
+
+fn read_length(input: &[u8], pos: &mut usize) -> Result<usize, String> {
+    let mut n = 0;
+    loop {
+        let b = *try!(input.get(*pos).ok_or("The compressed data was cut short".to_string()));
+        *pos += 1;
+        n += b as usize;
+        if b != 255 {
+            return Ok(n);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Settings are read from `key = value` lines in ~/.secmsg/config. An
+// environment variable named SECMSG_<KEY> overrides the file.
+pub struct Config {
+    values: HashMap<String, String>,
+    path: Option<PathBuf>,
+}
+
+impl Config {
+
+    pub fn load() -> Config {
+        match Config::path() {
+            Some(path) => Config::from_file(&path),
+            None => Config { values: HashMap::new(), path: None },
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Config {
+        let mut contents = String::new();
+        if let Ok(mut file) = File::open(path) {
+            let _ = file.read_to_string(&mut contents);
+        }
+
+        let values = contents.lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .filter_map(|l| {
+                let mut parts = l.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(k), Some(v)) => Some((k.trim().to_string(), v.trim().to_string())),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        Config { values: values, path: Some(path.to_path_buf()) }
+    }
+
+    pub fn path() -> Option<PathBuf> {
+        env::home_dir().map(|home| home.join(".secmsg/config"))
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<String> {
+        env::var(format!("SECMSG_{}", key.to_uppercase())).ok()
+            .or_else(|| self.values.get(key).cloned())
+    }
+
+    // Returns the parsed value of `key`, or `default` if it is missing or invalid.
+    pub fn get<T: FromStr>(&self, key: &str, default: T) -> T {
+        self.get_str(key).and_then(|v| v.parse().ok()).unwrap_or(default)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+
+    // Writes the settings back to the file they were loaded from.
+    pub fn save(&self) -> Result<(), String> {
+        let path = try!(self.path.as_ref().ok_or("No config file to save to".to_string()));
+        let mut keys: Vec<&String> = self.values.keys().collect();
+        keys.sort();
+
+        let contents: String = keys.into_iter()
+            .map(|k| format!("{} = {}\n", k, self.values[k]))
+            .collect();
+        let mut file = try!(File::create(path).map_err(|e| e.to_string()));
+        file.write_all(contents.as_bytes()).map_err(|e| e.to_string())
+    }
+}
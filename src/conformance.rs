@@ -0,0 +1,558 @@
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::collections::BTreeMap;
+use std::str;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::{self, Rng};
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::json;
+
+use crypto_lib::{self, Crypto, Key, Suite, DEFAULT_PADDING_BUCKETS};
+use mlkem;
+use net_lib::{self, Net, ReplayWindow, SecureStream, MIN_PROTOCOL_VERSION};
+use messages::{self, Message, MessageType, Priority, RelayDirectory, TextMessage, ToServer, ToUser};
+use state::{Addr, User};
+
+// Randomized checks of the wire format: envelopes, handshakes and routes are
+// generated fresh on every run, and each property must hold for all of them.
+// Other implementations can check interop by pointing the remote checks at
+// their responder with `client --conformance <host:port> <hex public key>`.
+const RUNS: usize = 50;
+const ACK_TIMEOUT_SECS: u64 = 5;
+
+type Property = fn() -> Result<(), String>;
+
+const PROPERTIES: &'static [(&'static str, Property)] = &[
+    ("envelopes open hop by hop along their route", envelope_round_trip),
+    ("tampered envelopes are rejected", tampered_envelope),
+    ("layers with a changed routing header are rejected", tampered_header),
+    ("envelopes sealed to another key are rejected", wrong_key),
+    ("truncated envelopes are rejected", truncated_envelope),
+    ("ciphertext sizes fall on padding buckets", padded_sizes),
+    ("compressed envelopes open like any other", compressed_envelope),
+    ("addresses round trip", address_round_trip),
+    ("relay directory changes rebuild the signed list", relay_directory_diff),
+    ("deniable messages check out only for their recipient", deniable_message),
+    ("replayed and future layers are rejected", replayed_layer),
+    ("handshakes carry frames both ways", handshake_round_trip),
+    ("hybrid post-quantum handshakes carry frames both ways", hybrid_handshake),
+    ("post-quantum ciphertexts open only to their secret", kem_round_trip),
+    ("handshakes with the wrong key fail", handshake_wrong_key),
+    ("streamed frames arrive whole", streamed_frame),
+    ("oversized frames are refused", oversized_frame),
+    ("one byte frames aren't taken for control frames", one_byte_frame),
+    ("old protocol versions are turned away", old_version),
+    ("garbage hellos are rejected", garbage_hello),
+];
+
+// Returns whether every property held.
+pub fn run(args: &[String]) -> bool {
+    let mut failures = PROPERTIES.iter().filter(|&&(name, property)| !check(name, &property)).count();
+
+    if let (Some(addr), Some(key)) = (args.get(0), args.get(1)) {
+        let target = match remote_target(addr, key) {
+            Ok(t) => t,
+            Err(e) => {
+                println!("FAIL {}", e);
+                return false;
+            },
+        };
+        let results = [
+            check("remote handshake", &|| remote_handshake(&target)),
+            check("remote acknowledges envelopes", &|| remote_envelope(&target)),
+            check("remote drops garbage envelopes", &|| remote_garbage(&target)),
+        ];
+        failures += results.iter().filter(|&&ok| !ok).count();
+    }
+
+    println!("{} of the properties failed", failures);
+    failures == 0
+}
+
+fn check<F: Fn() -> Result<(), String> + ?Sized>(name: &str, property: &F) -> bool {
+    for i in 0..RUNS {
+        if let Err(e) = property() {
+            println!("FAIL {} (run {}): {}", name, i + 1, e);
+            return false;
+        }
+    }
+    println!("ok   {}", name);
+    true
+}
+
+fn random_crypto() -> Crypto {
+    let (priv_key, pub_key) = crypto_lib::gen_key_pair();
+    Crypto::new(priv_key, pub_key)
+}
+
+fn random_addr() -> Addr {
+    let port = rand::random::<u16>();
+    if rand::random() {
+        let ip: [u8; 4] = rand::random();
+        Addr(SocketAddr::from((ip, port)))
+    } else {
+        let ip: [u16; 8] = rand::random();
+        Addr(SocketAddr::from((ip, port)))
+    }
+}
+
+fn random_bytes(max: usize) -> Vec<u8> {
+    let len = rand::thread_rng().gen_range(0, max + 1);
+    rand::thread_rng().gen_iter().take(len).collect()
+}
+
+fn random_string(max: usize) -> String {
+    let len = rand::thread_rng().gen_range(0, max + 1);
+    rand::thread_rng().gen_iter::<char>().take(len).collect()
+}
+
+fn random_user() -> User {
+    User { handle: random_string(16), addr: random_addr(), public_key: random_crypto().pub_key }
+}
+
+fn random_type() -> MessageType {
+    match rand::random::<u8>() % 4 {
+        0 => MessageType::User(ToUser::Text(TextMessage::new(random_string(200), random_user(),
+                                                            rand::random(), rand::random()))),
+        1 => MessageType::User(ToUser::Ack(rand::random())),
+        2 => MessageType::User(ToUser::Typing(random_string(16))),
+        _ => MessageType::Server(ToServer::Cover(random_bytes(512))),
+    }
+}
+
+fn random_route() -> (Vec<Crypto>, Vec<(Addr, Key)>) {
+    let hops: Vec<Crypto> = (0..rand::thread_rng().gen_range(1, 5)).map(|_| random_crypto()).collect();
+    let route = hops.iter().map(|c| (random_addr(), c.pub_key)).collect();
+    (hops, route)
+}
+
+fn random_priority() -> Priority {
+    match rand::random::<u8>() % 3 {
+        0 => Priority::Bulk,
+        1 => Priority::Normal,
+        _ => Priority::Urgent,
+    }
+}
+
+fn open(data: &[u8], crypto: &Crypto) -> Result<Message, String> {
+    Message::open(data, crypto).map_err(|_| "Could not decrypt the layer".to_string())
+}
+
+fn envelope_round_trip() -> Result<(), String> {
+    let (hops, route) = random_route();
+    let msg_type = random_type();
+    let priority = random_priority();
+    let mut msg = Message::with_priority(msg_type.clone(), route.clone(), &random_crypto(), priority);
+
+    // The outermost layer is for the last hop of the route, the innermost for
+    // the destination at its head.
+    for (i, hop) in hops.iter().enumerate().rev() {
+        if msg.next_hop != Some(route[i].0) || msg.next_key != Some(route[i].1) {
+            return Err(format!("Layer {} does not point at its hop", i));
+        }
+        if msg.priority != priority {
+            return Err(format!("Layer {} lost its priority", i));
+        }
+        msg = try!(open(&msg.data, hop));
+    }
+    if msg.next_hop.is_some() || msg.next_key.is_some() {
+        return Err("The innermost layer points somewhere".to_string());
+    }
+    if try!(Net::decode_type(&msg.data)) != msg_type {
+        return Err("The payload changed on the way".to_string());
+    }
+    Ok(())
+}
+
+fn tampered_envelope() -> Result<(), String> {
+    let crypto = random_crypto();
+    let mut msg = Message::new(random_type(), vec![(random_addr(), crypto.pub_key)], &random_crypto());
+    let i = rand::thread_rng().gen_range(0, msg.data.len());
+    msg.data[i] ^= rand::thread_rng().gen_range(1, 256) as u8;
+    match open(&msg.data, &crypto) {
+        Ok(_) => Err(format!("Flipping byte {} went unnoticed", i)),
+        Err(_) => Ok(()),
+    }
+}
+
+fn tampered_header() -> Result<(), String> {
+    let crypto = random_crypto();
+    let mut msg = Message::with_priority(random_type(), vec![(random_addr(), crypto.pub_key)], &random_crypto(), Priority::Normal);
+    if !msg.header_matches() {
+        return Err("A layer's routing header does not match its fields".to_string());
+    }
+    msg.priority = Priority::Urgent;
+    if msg.header_matches() {
+        return Err("Raising a layer's priority went unnoticed".to_string());
+    }
+    let i = rand::thread_rng().gen_range(0, messages::ROUTING_HEADER_LEN);
+    msg.data[i] ^= rand::thread_rng().gen_range(1, 256) as u8;
+    match open(&msg.data, &crypto) {
+        Ok(_) => Err(format!("Flipping byte {} of the routing header went unnoticed", i)),
+        Err(_) => Ok(()),
+    }
+}
+
+fn wrong_key() -> Result<(), String> {
+    let msg = Message::new(random_type(), vec![(random_addr(), random_crypto().pub_key)], &random_crypto());
+    match open(&msg.data, &random_crypto()) {
+        Ok(_) => Err("Another key opened the layer".to_string()),
+        Err(_) => Ok(()),
+    }
+}
+
+fn truncated_envelope() -> Result<(), String> {
+    let crypto = random_crypto();
+    let sealed = try!(crypto.encrypt(&crypto.pub_key, &[], &random_bytes(64)).map_err(|_| "Could not encrypt".to_string()));
+    let cut = rand::thread_rng().gen_range(0, sealed.len());
+    match crypto.decrypt(&[], &sealed[..cut]) {
+        Ok(_) => Err(format!("Cutting the ciphertext to {} bytes went unnoticed", cut)),
+        Err(_) => Ok(()),
+    }
+}
+
+fn padded_sizes() -> Result<(), String> {
+    let crypto = random_crypto();
+    let largest = DEFAULT_PADDING_BUCKETS[DEFAULT_PADDING_BUCKETS.len() - 1];
+    let plain = random_bytes(3 * largest);
+    let sealed = try!(crypto.encrypt(&crypto.pub_key, &[], &plain).map_err(|_| "Could not encrypt".to_string()));
+
+    let body = sealed.len() - 48;
+    if !DEFAULT_PADDING_BUCKETS.contains(&body) && body % largest != 0 {
+        return Err(format!("{} bytes padded to {}, which is not a bucket", plain.len(), body));
+    }
+    if try!(crypto.decrypt(&[], &sealed).map_err(|_| "Could not decrypt".to_string())) != plain {
+        return Err(format!("{} bytes did not survive padding", plain.len()));
+    }
+    Ok(())
+}
+
+fn compressed_envelope() -> Result<(), String> {
+    let mut crypto = random_crypto();
+    crypto.set_compression(true);
+    // Random text barely compresses, so repeat some to give it a chance.
+    let text = random_string(200);
+    let plain = match rand::random::<u8>() % 3 {
+        0 => random_bytes(4096),
+        _ => text.repeat(rand::thread_rng().gen_range(1, 40)).into_bytes(),
+    };
+    let sealed = try!(crypto.encrypt_compressed(&crypto.pub_key, &[], &plain).map_err(|_| "Could not encrypt".to_string()));
+    if try!(crypto.decrypt(&[], &sealed).map_err(|_| "Could not decrypt".to_string())) != plain {
+        return Err(format!("{} bytes did not survive compression", plain.len()));
+    }
+    let uncompressed = try!(crypto.encrypt(&crypto.pub_key, &[], &plain).map_err(|_| "Could not encrypt".to_string()));
+    if sealed.len() > uncompressed.len() {
+        return Err(format!("Compressing {} bytes made them larger", plain.len()));
+    }
+    Ok(())
+}
+
+fn address_round_trip() -> Result<(), String> {
+    let addr = random_addr();
+    let encoded = try!(json::encode(&addr).map_err(|e| e.to_string()));
+    let decoded: Addr = try!(json::decode(&encoded).map_err(|e| e.to_string()));
+    if decoded != addr {
+        return Err(format!("{} came back as {}", addr.0, decoded.0));
+    }
+    Ok(())
+}
+
+fn relay_directory_diff() -> Result<(), String> {
+    let before: BTreeMap<Key, Addr> = (0..rand::random::<usize>() % 20).map(|_| (random_crypto().pub_key, random_addr())).collect();
+    let mut after = before.clone();
+    let removed: Vec<Key> = before.keys().filter(|_| rand::random()).cloned().collect();
+    for key in removed.iter() {
+        after.remove(key);
+    }
+    let added: Vec<(Addr, Key)> = (0..rand::random::<usize>() % 5).map(|_| (random_addr(), random_crypto().pub_key)).collect();
+    for &(addr, key) in added.iter() {
+        after.insert(key, addr);
+    }
+
+    let server = random_crypto();
+    let mut directory = RelayDirectory {
+        version: 2,
+        base: 1,
+        added: added,
+        removed: removed,
+        digest: messages::relay_digest(&after),
+        signing_key: [0u8; 32],
+        signature: Vec::new(),
+    }.signed(&server);
+    if !directory.has_valid_signature() {
+        return Err("A signed directory failed to verify".to_string());
+    }
+    if try!(directory.apply(&before)) != after {
+        return Err("Applying the changes gave another list".to_string());
+    }
+    let mut wrong = before.clone();
+    wrong.insert(random_crypto().pub_key, random_addr());
+    if directory.apply(&wrong).is_ok() {
+        return Err("Changes applied to the wrong list were accepted".to_string());
+    }
+    directory.version += 1;
+    if directory.has_valid_signature() {
+        return Err("A changed directory kept its signature".to_string());
+    }
+    Ok(())
+}
+
+fn deniable_message() -> Result<(), String> {
+    let (sender, recipient) = (random_crypto(), random_crypto());
+    let user = User { handle: random_string(16), addr: random_addr(), public_key: sender.pub_key };
+    let (mut msg, _) = TextMessage::new(random_string(200), user, rand::random(), rand::random()).deniable(&sender, &recipient.pub_key);
+    if !msg.is_deniable() || msg.has_valid_signature() {
+        return Err("A deniable message passed for a signed one".to_string());
+    }
+    if !msg.has_valid_mac(&recipient) {
+        return Err("The recipient could not check a deniable message".to_string());
+    }
+    if msg.has_valid_mac(&random_crypto()) {
+        return Err("Someone other than the recipient checked a deniable message".to_string());
+    }
+    msg.seq = msg.seq.wrapping_add(1);
+    if msg.has_valid_mac(&recipient) {
+        return Err("A changed deniable message kept its MAC".to_string());
+    }
+    Ok(())
+}
+
+fn replayed_layer() -> Result<(), String> {
+    let window = ReplayWindow::new();
+    let mut msg = Message::new(random_type(), vec![(random_addr(), random_crypto().pub_key)], &random_crypto());
+    try!(window.check(&msg));
+    if window.check(&msg).is_ok() {
+        return Err("A layer was accepted twice".to_string());
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    msg.id = rand::random();
+    msg.sent_at = now + 3600 + rand::random::<u32>() as u64;
+    if window.check(&msg).is_ok() {
+        return Err("A layer from the future was accepted".to_string());
+    }
+    msg.sent_at = rand::thread_rng().gen_range(0, now - 24 * 3600);
+    if window.check(&msg).is_ok() {
+        return Err("A stale layer was accepted".to_string());
+    }
+    Ok(())
+}
+
+// Runs `respond` on the responder's side of a local connection.
+fn local_responder<F>(respond: F) -> Result<(Addr, thread::JoinHandle<Result<(), String>>), String>
+    where F: FnOnce(TcpStream) -> Result<(), String> + Send + 'static {
+    let listener = try!(TcpListener::bind("127.0.0.1:0").map_err(|e| e.to_string()));
+    let addr = Addr(try!(listener.local_addr().map_err(|e| e.to_string())));
+    let handle = thread::spawn(move || {
+        let (stream, _) = try!(listener.accept().map_err(|e| e.to_string()));
+        respond(stream)
+    });
+    Ok((addr, handle))
+}
+
+fn handshake_round_trip() -> Result<(), String> {
+    let crypto = random_crypto();
+    let pub_key = crypto.pub_key;
+    let (addr, responder) = try!(local_responder(move |stream| {
+        let mut stream = try!(SecureStream::accept(stream, Some(&crypto)));
+        let frame = try!(stream.read_frame());
+        stream.write_frame(&frame)
+    }));
+
+    let mut stream = try!(SecureStream::connect(&addr, Some(&pub_key)));
+    let frame = random_bytes(4096);
+    try!(stream.write_frame(&frame));
+    if try!(stream.read_frame()) != frame {
+        return Err("The echoed frame changed".to_string());
+    }
+    try!(responder.join().unwrap_or(Err("The responder panicked".to_string())));
+    Ok(())
+}
+
+fn hybrid_handshake() -> Result<(), String> {
+    let crypto = random_crypto();
+    let pub_key = crypto.pub_key;
+    let (addr, responder) = try!(local_responder(move |stream| {
+        let suites = [Suite::ChaChaPolyHybrid, Suite::AesGcmHybrid];
+        let mut stream = try!(SecureStream::accept_from(stream, Some(&crypto), MIN_PROTOCOL_VERSION, &suites));
+        let frame = try!(stream.read_frame());
+        stream.write_frame(&frame)
+    }));
+
+    let mut stream = try!(SecureStream::connect(&addr, Some(&pub_key)));
+    if !stream.suite().is_hybrid() {
+        return Err(format!("{} was chosen instead of a hybrid suite", stream.suite().name()));
+    }
+    let frame = random_bytes(4096);
+    try!(stream.write_frame(&frame));
+    if try!(stream.read_frame()) != frame {
+        return Err("The echoed frame changed".to_string());
+    }
+    try!(responder.join().unwrap_or(Err("The responder panicked".to_string())));
+    Ok(())
+}
+
+fn kem_round_trip() -> Result<(), String> {
+    let (dk, ek) = mlkem::keypair();
+    let (mut ct, shared) = try!(mlkem::encapsulate(&ek));
+    if try!(mlkem::decapsulate(&dk, &ct)) != shared {
+        return Err("The secrets differ".to_string());
+    }
+    let i = rand::thread_rng().gen_range(0, ct.len());
+    ct[i] ^= 1 << rand::thread_rng().gen_range(0, 8);
+    if try!(mlkem::decapsulate(&dk, &ct)) == shared {
+        return Err(format!("A ciphertext changed at byte {} gave the same secret", i));
+    }
+    Ok(())
+}
+
+fn streamed_frame() -> Result<(), String> {
+    let crypto = random_crypto();
+    let pub_key = crypto.pub_key;
+    let (addr, responder) = try!(local_responder(move |stream| {
+        let mut stream = try!(SecureStream::accept(stream, Some(&crypto)));
+        let mut frame = Vec::new();
+        try!(stream.read_stream(&mut frame, net_lib::DEFAULT_MAX_FRAME_SIZE as u64));
+        stream.write_frame(&frame)
+    }));
+
+    // Big enough to need a few fragments each way.
+    let mut stream = try!(SecureStream::connect(&addr, Some(&pub_key)));
+    let frame = random_bytes(900 * 1024);
+    try!(stream.write_stream(&mut &frame[..]));
+    if try!(stream.read_frame()) != frame {
+        return Err(format!("The echoed {} byte frame changed", frame.len()));
+    }
+    try!(responder.join().unwrap_or(Err("The responder panicked".to_string())));
+    Ok(())
+}
+
+fn oversized_frame() -> Result<(), String> {
+    let crypto = random_crypto();
+    let pub_key = crypto.pub_key;
+    let limit = rand::thread_rng().gen_range(64, 8192);
+    let (addr, responder) = try!(local_responder(move |stream| {
+        let mut unread = try!(stream.try_clone().map_err(|e| e.to_string()));
+        let mut stream = try!(SecureStream::accept(stream, Some(&crypto)));
+        stream.set_max_frame_size(limit);
+        let result = match stream.read_frame() {
+            Ok(frame) => Err(format!("A {} byte frame was read past the {} byte limit", frame.len(), limit)),
+            Err(_) => Ok(()),
+        };
+        // Closing with the frame unread could reset the connection before
+        // the refusal is read.
+        let _ = io::copy(&mut unread, &mut io::sink());
+        result
+    }));
+
+    let mut stream = try!(SecureStream::connect(&addr, Some(&pub_key)));
+    let size = rand::thread_rng().gen_range(limit + 17, 2 * limit + 17);
+    let frame: Vec<u8> = rand::thread_rng().gen_iter().take(size).collect();
+    try!(stream.write_frame(&frame));
+    let refused = stream.read_frame();
+    stream.shutdown();
+    match refused {
+        Ok(_) => return Err("The refusal was read as a frame".to_string()),
+        Err(ref e) if !e.contains("too large") => return Err(format!("The frame wasn't refused: {}", e)),
+        Err(_) => {},
+    }
+    try!(responder.join().unwrap_or(Err("The responder panicked".to_string())));
+    Ok(())
+}
+
+fn one_byte_frame() -> Result<(), String> {
+    let crypto = random_crypto();
+    let pub_key = crypto.pub_key;
+    let (addr, responder) = try!(local_responder(move |stream| {
+        let mut stream = try!(SecureStream::accept(stream, Some(&crypto)));
+        let frame = try!(stream.read_frame());
+        stream.write_frame(&frame)
+    }));
+
+    let mut stream = try!(SecureStream::connect(&addr, Some(&pub_key)));
+    let frame = [if rand::random() { net_lib::HOP_ACK } else { rand::random() }];
+    try!(stream.write_frame(&frame));
+    if try!(stream.read_frame()) != frame {
+        return Err(format!("The echoed frame {:?} changed", frame));
+    }
+    try!(responder.join().unwrap_or(Err("The responder panicked".to_string())));
+    Ok(())
+}
+
+fn handshake_wrong_key() -> Result<(), String> {
+    let crypto = random_crypto();
+    let (addr, responder) = try!(local_responder(move |stream| {
+        SecureStream::accept(stream, Some(&crypto)).map(|_| ())
+    }));
+
+    let result = SecureStream::connect(&addr, Some(&random_crypto().pub_key));
+    let _ = responder.join();
+    match result {
+        Ok(_) => Err("The responder was not asked to prove its key".to_string()),
+        Err(_) => Ok(()),
+    }
+}
+
+fn old_version() -> Result<(), String> {
+    let version = rand::thread_rng().gen_range(0, MIN_PROTOCOL_VERSION);
+    expect_rejected(&net_lib::hello(version), &format!("Version {} was accepted", version))
+}
+
+fn garbage_hello() -> Result<(), String> {
+    let mut garbage: Vec<u8> = rand::thread_rng().gen_iter().take(6).collect();
+    garbage.extend(random_bytes(58));
+    expect_rejected(&garbage, "A garbage hello was accepted")
+}
+
+// Sends `hello` and closes the connection, which the responder must refuse.
+fn expect_rejected(hello: &[u8], problem: &str) -> Result<(), String> {
+    let crypto = random_crypto();
+    let (addr, responder) = try!(local_responder(move |stream| {
+        SecureStream::accept(stream, Some(&crypto)).map(|_| ())
+    }));
+
+    let mut stream = try!(TcpStream::connect(addr.0).map_err(|e| e.to_string()));
+    let _ = stream.write_all(hello);
+    let _ = stream.shutdown(Shutdown::Write);
+    match responder.join() {
+        Ok(Ok(())) => Err(problem.to_string()),
+        _ => Ok(()),
+    }
+}
+
+fn remote_target(addr: &str, key: &str) -> Result<(Addr, Key), String> {
+    let addr = try!(net_lib::resolve(addr));
+    let bytes = try!(key.from_hex().map_err(|_| "The key is not hex".to_string()));
+    if bytes.len() != 32 {
+        return Err("Public keys are 32 bytes long".to_string());
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes);
+    Ok((addr, key))
+}
+
+fn remote_handshake(target: &(Addr, Key)) -> Result<(), String> {
+    SecureStream::connect(&target.0, Some(&target.1)).map(|_| ())
+}
+
+fn remote_envelope(target: &(Addr, Key)) -> Result<(), String> {
+    let mut stream = try!(SecureStream::connect(&target.0, Some(&target.1)));
+    let msg = Message::new(MessageType::Server(ToServer::Cover(random_bytes(512))), vec![target.clone()],
+                           &random_crypto());
+    try!(stream.write_frame(&msg.data));
+    if !stream.read_ack(Duration::from_secs(ACK_TIMEOUT_SECS)) {
+        return Err("A valid envelope was not acknowledged".to_string());
+    }
+    Ok(())
+}
+
+fn remote_garbage(target: &(Addr, Key)) -> Result<(), String> {
+    let mut stream = try!(SecureStream::connect(&target.0, Some(&target.1)));
+    try!(stream.write_frame(&random_bytes(1024)));
+    if stream.read_ack(Duration::from_secs(ACK_TIMEOUT_SECS)) {
+        return Err("A garbage envelope was acknowledged".to_string());
+    }
+    Ok(())
+}
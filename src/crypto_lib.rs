@@ -1,15 +1,39 @@
 #![allow(dead_code)]
 
 use std::fmt;
+use std::io::{self, Read};
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{self, Ordering};
 
 use rand::{Rng, OsRng};
 use crypto::curve25519::{curve25519_base, curve25519};
+use crypto::ed25519;
 use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::aes_gcm::AesGcm;
+use crypto::aes::KeySize;
 use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use crypto::sha2::Sha256;
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::scrypt::{scrypt, scrypt_simple, scrypt_check, ScryptParams};
+
+use compress;
 
 
 pub type Key = [u8; 32];
 
+// Sealed plaintexts are padded up to the smallest of these they fit in, or
+// to a multiple of the largest, so their length says little about them.
+pub const DEFAULT_PADDING_BUCKETS: &'static [usize] = &[256, 1024, 4096];
+
+// The top bit of the length in front of a padded plaintext says it is
+// compressed, in which case it starts with its length before compression.
+const COMPRESSED: u32 = 1 << 31;
+const MIN_COMPRESS_BYTES: usize = 64;
+const MAX_DECOMPRESSED_BYTES: usize = 64 * 1024 * 1024;
+
 pub enum EncryptError {
     RngInitializationFailed,
 }
@@ -31,36 +55,628 @@ impl fmt::Debug for DecryptError {
     }
 }
 
+// Overwrites secret bytes before they are dropped, in a way the compiler
+// can't leave out because they are never read again.
+pub fn zeroize(bytes: &mut [u8]) {
+    for b in bytes.iter_mut() {
+        unsafe { ptr::write_volatile(b, 0) };
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+// Compares secrets such as MACs and proofs without returning early, so the
+// time taken doesn't tell how much of a guess was right. Only the lengths,
+// which aren't secret, are compared directly.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+    unsafe { ptr::read_volatile(&diff) == 0 }
+}
+
+pub fn zeroize_string(s: &mut String) {
+    unsafe { zeroize(s.as_mut_vec()) };
+    s.clear();
+}
+
 pub fn gen_key_pair() -> (Key, Key) {
     let mut priv_key = [0u8; 32];
     OsRng::new().unwrap().fill_bytes(&mut priv_key[..]);
     (priv_key, curve25519_base(&priv_key[..]))
 }
 
+// Authenticates `data` under `key` without a signature, so anyone who
+// knows the key could have made it.
+pub fn mac(key: &Key, data: &[u8]) -> Key {
+    hmac(key, &[b"mac", data])
+}
+
+pub fn hash(data: &[&[u8]]) -> Key {
+    let mut hasher = Sha256::new();
+    for d in data {
+        hasher.input(d);
+    }
+    let mut out = [0u8; 32];
+    hasher.result(&mut out);
+    out
+}
+
+// Hashes everything `reader` gives, a block at a time, so a file doesn't
+// have to fit in memory.
+pub fn hash_reader<R: Read>(reader: &mut R) -> io::Result<Key> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = try!(reader.read(&mut buf));
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
+    }
+    let mut out = [0u8; 32];
+    hasher.result(&mut out);
+    Ok(out)
+}
+
+// A number two users can read to each other to check they hold each other's
+// keys. It is the same whichever way round the keys are given.
+pub fn safety_number(a: &Key, b: &Key) -> String {
+    let (first, second) = if a < b { (a, b) } else { (b, a) };
+    let digest = hash(&[b"safety number", &first[..], &second[..]]);
+    digest.chunks(5).take(6)
+        .map(|c| format!("{:05}", c.iter().fold(0u64, |n, &b| n << 8 | b as u64) % 100000))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn hmac(key: &[u8], data: &[&[u8]]) -> Key {
+    let mut mac = Hmac::new(Sha256::new(), key);
+    for d in data {
+        mac.input(d);
+    }
+    let mut out = [0u8; 32];
+    mac.raw_result(&mut out);
+    out
+}
+
+// HKDF as defined by the Noise protocol framework, producing two keys.
+pub fn hkdf(chaining_key: &Key, input_key_material: &[u8]) -> (Key, Key) {
+    let temp_key = hmac(chaining_key, &[input_key_material]);
+    let out1 = hmac(&temp_key, &[&[1u8]]);
+    let out2 = hmac(&temp_key, &[&out1, &[2u8]]);
+    (out1, out2)
+}
+
+// The stream ciphers a handshake can agree on, each named by the identifier
+// sent on the wire. All of them use X25519 and SHA-256. The hybrid suites
+// also mix an ML-KEM-768 secret into the handshake, so recorded traffic stays
+// private even to someone who can later break X25519.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Suite {
+    ChaChaPoly,
+    AesGcm,
+    ChaChaPolyHybrid,
+    AesGcmHybrid,
+}
+
+// Every suite we speak, most preferred first. The hybrid ones add over two
+// kilobytes to each handshake, so they are only chosen by a responder that
+// prefers them, such as a server with them first in `cipher_suites`.
+pub const SUITES: &'static [Suite] = &[Suite::ChaChaPoly, Suite::AesGcm, Suite::ChaChaPolyHybrid, Suite::AesGcmHybrid];
+
+impl Suite {
+    pub fn from_id(id: u8) -> Option<Suite> {
+        SUITES.iter().cloned().find(|s| s.id() == id)
+    }
+
+    pub fn from_name(name: &str) -> Option<Suite> {
+        SUITES.iter().cloned().find(|s| s.name() == name)
+    }
+
+    pub fn id(&self) -> u8 {
+        match *self {
+            Suite::ChaChaPoly => 1,
+            Suite::AesGcm => 2,
+            Suite::ChaChaPolyHybrid => 3,
+            Suite::AesGcmHybrid => 4,
+        }
+    }
+
+    pub fn is_hybrid(&self) -> bool {
+        *self == Suite::ChaChaPolyHybrid || *self == Suite::AesGcmHybrid
+    }
+
+    // The name used in Noise protocol names.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Suite::ChaChaPoly => "25519_ChaChaPoly_SHA256",
+            Suite::AesGcm => "25519_AESGCM_SHA256",
+            Suite::ChaChaPolyHybrid => "25519+MLKEM768_ChaChaPoly_SHA256",
+            Suite::AesGcmHybrid => "25519+MLKEM768_AESGCM_SHA256",
+        }
+    }
+}
+
+// The original ChaCha20-Poly1305 takes a little endian 8 byte nonce.
+fn chacha_nonce(n: u64) -> [u8; 8] {
+    let mut nonce = [0u8; 8];
+    for i in 0..8 {
+        nonce[i] = (n >> (8 * i)) as u8;
+    }
+    nonce
+}
+
+// Noise pads the big endian counter to 12 bytes for AES-GCM.
+fn gcm_nonce(n: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    for i in 0..8 {
+        nonce[11 - i] = (n >> (8 * i)) as u8;
+    }
+    nonce
+}
+
+fn seal<C: AeadEncryptor>(mut c: C, plaintext: &[u8]) -> Vec<u8> {
+    let mut output = vec![0; plaintext.len() + 16];
+    let mut tag = [0u8; 16];
+    c.encrypt(plaintext, &mut output[..plaintext.len()], &mut tag[..]);
+    output[plaintext.len()..].copy_from_slice(&tag);
+    output
+}
+
+fn open<D: AeadDecryptor>(mut d: D, ciphertext: &[u8], tag: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    let mut plaintext = vec![0; ciphertext.len()];
+    if !d.decrypt(ciphertext, &mut plaintext[..], tag) {
+        return Err(DecryptError::Invalid);
+    }
+    Ok(plaintext)
+}
+
+// A symmetric key with an incrementing nonce, used to encrypt a stream of frames.
+pub struct CipherState {
+    key: Key,
+    nonce: u64,
+    suite: Suite,
+}
+
+impl CipherState {
+    pub fn new(key: Key, suite: Suite) -> CipherState {
+        CipherState {
+            key: key,
+            nonce: 0,
+            suite: suite,
+        }
+    }
+
+    fn next_nonce(&mut self) -> u64 {
+        self.nonce += 1;
+        self.nonce - 1
+    }
+
+    // Returns the ciphertext followed by the 16 byte tag.
+    pub fn encrypt(&mut self, ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let n = self.next_nonce();
+        match self.suite {
+            Suite::ChaChaPoly | Suite::ChaChaPolyHybrid => seal(ChaCha20Poly1305::new(&self.key, &chacha_nonce(n), ad), plaintext),
+            Suite::AesGcm | Suite::AesGcmHybrid => seal(AesGcm::new(KeySize::KeySize256, &self.key, &gcm_nonce(n), ad), plaintext),
+        }
+    }
+
+    pub fn decrypt(&mut self, ad: &[u8], message: &[u8]) -> Result<Vec<u8>, DecryptError> {
+        if message.len() < 16 {
+            return Err(DecryptError::Malformed);
+        }
+
+        let (ciphertext, tag) = message.split_at(message.len() - 16);
+        let n = self.next_nonce();
+        match self.suite {
+            Suite::ChaChaPoly | Suite::ChaChaPolyHybrid => open(ChaCha20Poly1305::new(&self.key, &chacha_nonce(n), ad), ciphertext, tag),
+            Suite::AesGcm | Suite::AesGcmHybrid => open(AesGcm::new(KeySize::KeySize256, &self.key, &gcm_nonce(n), ad), ciphertext, tag),
+        }
+    }
+}
+
+impl Drop for CipherState {
+    fn drop(&mut self) {
+        zeroize(&mut self.key);
+    }
+}
+
+// The chaining key and handshake hash of a Noise handshake in progress.
+pub struct SymmetricState {
+    ck: Key,
+    h: Key,
+    cipher: Option<CipherState>,
+    suite: Suite,
+}
+
+impl SymmetricState {
+    pub fn new(pattern: &str, suite: Suite) -> SymmetricState {
+        let protocol_name = format!("Noise_{}_{}", pattern, suite.name());
+        let name = protocol_name.as_bytes();
+        let h = if name.len() <= 32 {
+            let mut h = [0u8; 32];
+            h[..name.len()].copy_from_slice(name);
+            h
+        } else {
+            hash(&[name])
+        };
+
+        SymmetricState {
+            ck: h,
+            h: h,
+            cipher: None,
+            suite: suite,
+        }
+    }
+
+    pub fn mix_hash(&mut self, data: &[u8]) {
+        self.h = hash(&[&self.h, data]);
+    }
+
+    pub fn mix_key(&mut self, input_key_material: &[u8]) {
+        let (ck, temp_key) = hkdf(&self.ck, input_key_material);
+        self.ck = ck;
+        self.cipher = Some(CipherState::new(temp_key, self.suite));
+    }
+
+    pub fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let h = self.h;
+        let ciphertext = match self.cipher {
+            Some(ref mut c) => c.encrypt(&h, plaintext),
+            None => plaintext.to_vec(),
+        };
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    pub fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, DecryptError> {
+        let h = self.h;
+        let plaintext = match self.cipher {
+            Some(ref mut c) => try!(c.decrypt(&h, ciphertext)),
+            None => ciphertext.to_vec(),
+        };
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    // Returns the initiator's sending and the responder's sending cipher states.
+    pub fn split(&self) -> (CipherState, CipherState) {
+        let (k1, k2) = hkdf(&self.ck, &[]);
+        (CipherState::new(k1, self.suite), CipherState::new(k2, self.suite))
+    }
+}
+
+impl Drop for SymmetricState {
+    fn drop(&mut self) {
+        zeroize(&mut self.ck);
+    }
+}
+
+pub fn public_key_of(private_key: &Key) -> Key {
+    curve25519_base(&private_key[..])
+}
+
+// Encrypts `data` under a key derived from `passphrase` with scrypt. The
+// output is the salt, the tag and then the ciphertext.
+pub fn seal_with_passphrase(passphrase: &str, data: &[u8]) -> Result<Vec<u8>, EncryptError> {
+    let mut salt = [0u8; 16];
+    try!(OsRng::new().map_err(|_| EncryptError::RngInitializationFailed)).fill_bytes(&mut salt[..]);
+
+    let mut key = [0u8; 32];
+    scrypt(passphrase.as_bytes(), &salt, &ScryptParams::new(14, 8, 1), &mut key);
+
+    let mut c = ChaCha20Poly1305::new(&key, &[0u8; 8][..], &[]);
+    zeroize(&mut key);
+    let mut output = vec![0; 32 + data.len()];
+    let mut tag = [0u8; 16];
+    c.encrypt(data, &mut output[32..], &mut tag[..]);
+    output[0..16].copy_from_slice(&salt);
+    output[16..32].copy_from_slice(&tag);
+    Ok(output)
+}
+
+pub fn open_with_passphrase(passphrase: &str, sealed: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    if sealed.len() < 32 {
+        return Err(DecryptError::Malformed);
+    }
+
+    let mut key = [0u8; 32];
+    scrypt(passphrase.as_bytes(), &sealed[0..16], &ScryptParams::new(14, 8, 1), &mut key);
+
+    let mut d = ChaCha20Poly1305::new(&key, &[0u8; 8][..], &[]);
+    zeroize(&mut key);
+    let mut plaintext = vec![0; sealed.len() - 32];
+    if !d.decrypt(&sealed[32..], &mut plaintext[..], &sealed[16..32]) {
+        return Err(DecryptError::Invalid);
+    }
+    Ok(plaintext)
+}
+
+// Seals data under a key shared by many messages. Each gets its own key
+// derived from a random salt, laid out like seal_with_passphrase.
+pub fn seal_with_key(key: &Key, data: &[u8]) -> Result<Vec<u8>, EncryptError> {
+    let mut salt = [0u8; 16];
+    try!(OsRng::new().map_err(|_| EncryptError::RngInitializationFailed)).fill_bytes(&mut salt[..]);
+
+    let mut c = ChaCha20Poly1305::new(&hmac(key, &[&salt]), &[0u8; 8][..], &[]);
+    let mut output = vec![0; 32 + data.len()];
+    let mut tag = [0u8; 16];
+    c.encrypt(data, &mut output[32..], &mut tag[..]);
+    output[0..16].copy_from_slice(&salt);
+    output[16..32].copy_from_slice(&tag);
+    Ok(output)
+}
+
+pub fn open_with_key(key: &Key, sealed: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    if sealed.len() < 32 {
+        return Err(DecryptError::Malformed);
+    }
+
+    let mut d = ChaCha20Poly1305::new(&hmac(key, &[&sealed[0..16]]), &[0u8; 8][..], &[]);
+    let mut plaintext = vec![0; sealed.len() - 32];
+    if !d.decrypt(&sealed[32..], &mut plaintext[..], &sealed[16..32]) {
+        return Err(DecryptError::Invalid);
+    }
+    Ok(plaintext)
+}
+
+// Seals one chunk of a file under the transfer's key. The nonce is the
+// chunk's index, so a chunk sent again after a resume seals the same way,
+// and the transfer id and index are authenticated so chunks can't be moved.
+pub fn seal_chunk(key: &Key, transfer: u64, index: u64, data: &[u8]) -> Vec<u8> {
+    seal(ChaCha20Poly1305::new(key, &chacha_nonce(index), &chunk_ad(transfer, index)), data)
+}
+
+pub fn open_chunk(key: &Key, transfer: u64, index: u64, sealed: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    if sealed.len() < 16 {
+        return Err(DecryptError::Malformed);
+    }
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - 16);
+    open(ChaCha20Poly1305::new(key, &chacha_nonce(index), &chunk_ad(transfer, index)), ciphertext, tag)
+}
+
+fn chunk_ad(transfer: u64, index: u64) -> [u8; 16] {
+    let mut ad = [0u8; 16];
+    ad[..8].copy_from_slice(&chacha_nonce(transfer));
+    ad[8..].copy_from_slice(&chacha_nonce(index));
+    ad
+}
+
+// Hashes a password for storage. The result carries its own salt and parameters.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    scrypt_simple(password, &ScryptParams::new(14, 8, 1)).map_err(|e| e.to_string())
+}
+
+// scrypt_check compares the hashes in fixed time itself.
+pub fn check_password(password: &str, hashed: &str) -> bool {
+    scrypt_check(password, hashed).unwrap_or(false)
+}
+
+// Prefixes `message` with its big endian length and pads it with zeros.
+fn pad(message: &[u8], compressed: bool, buckets: &[usize]) -> Vec<u8> {
+    let len = 4 + message.len();
+    let padded_len = match (buckets.iter().find(|&&b| b >= len), buckets.last()) {
+        (Some(&b), _) => b,
+        (None, Some(&b)) if b > 0 => (len + b - 1) / b * b,
+        _ => len,
+    };
+    let n = message.len() as u32 | if compressed { COMPRESSED } else { 0 };
+    let mut padded = vec![(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8];
+    padded.extend_from_slice(message);
+    padded.resize(padded_len, 0);
+    padded
+}
+
+fn unpad(padded: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    if padded.len() < 4 {
+        return Err(DecryptError::Malformed);
+    }
+    let n = padded[..4].iter().fold(0u32, |n, &b| n << 8 | b as u32);
+    let (compressed, n) = (n & COMPRESSED != 0, (n & !COMPRESSED) as usize);
+    if 4 + n > padded.len() {
+        return Err(DecryptError::Malformed);
+    }
+    if !compressed {
+        return Ok(padded[4..4 + n].to_vec());
+    }
+
+    let body = &padded[4..4 + n];
+    if body.len() < 4 {
+        return Err(DecryptError::Malformed);
+    }
+    let len = body[..4].iter().fold(0usize, |n, &b| n << 8 | b as usize);
+    if len > MAX_DECOMPRESSED_BYTES {
+        return Err(DecryptError::Malformed);
+    }
+    match compress::decompress(&body[4..], len) {
+        Ok(ref message) if message.len() == len => Ok(message.clone()),
+        _ => Err(DecryptError::Malformed),
+    }
+}
+
+// `message` compressed behind its length, if that makes it smaller.
+fn compressed(message: &[u8]) -> Option<Vec<u8>> {
+    if message.len() < MIN_COMPRESS_BYTES || message.len() > MAX_DECOMPRESSED_BYTES {
+        return None;
+    }
+    let n = message.len() as u32;
+    let mut packed = vec![(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8];
+    packed.extend(compress::compress(message));
+    if packed.len() < message.len() { Some(packed) } else { None }
+}
+
+// Whether `signature` is one made over `data` by the holder of the Ed25519
+// key `signing_key`.
+pub fn verify(signing_key: &Key, data: &[u8], signature: &[u8]) -> bool {
+    signature.len() == 64 && ed25519::verify(data, signing_key, signature)
+}
+
+// The signing key pair is derived from the identity key, so there is nothing
+// more to store or back up. Clones share the one copy of the secret keys,
+// which is wiped once the last of them is dropped.
 #[derive(Clone)]
 pub struct Crypto {
-    priv_key: Key,
+    secrets: Arc<Secrets>,
     pub pub_key: Key,
+    pub signing_key: Key,
+    buckets: Vec<usize>,
+    compress: bool,
+}
+
+struct Secrets {
+    priv_key: Key,
+    sign_secret: [u8; 64],
+}
+
+impl Drop for Secrets {
+    fn drop(&mut self) {
+        zeroize(&mut self.priv_key);
+        zeroize(&mut self.sign_secret);
+    }
 }
 
 impl Crypto {
     pub fn new(private_key: Key, public_key: Key) -> Crypto {
+        let mut seed = hmac(&private_key, &[b"signing key"]);
+        let (sign_secret, signing_key) = ed25519::keypair(&seed);
+        zeroize(&mut seed);
         Crypto {
-            priv_key: private_key,
+            secrets: Arc::new(Secrets { priv_key: private_key, sign_secret: sign_secret }),
             pub_key: public_key,
+            signing_key: signing_key,
+            buckets: DEFAULT_PADDING_BUCKETS.to_vec(),
+            compress: false,
+        }
+    }
+
+    pub fn set_padding_buckets(&mut self, buckets: &[usize]) {
+        self.buckets = buckets.to_vec();
+        self.buckets.sort();
+    }
+
+    // Older versions can't open compressed layers, so it is for when every
+    // hop is known to be up to date.
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compress = enabled;
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        ed25519::signature(data, &self.secrets.sign_secret).to_vec()
+    }
+
+    pub fn dh(&self, public_key: &Key) -> Key {
+        curve25519(&self.secrets.priv_key, &public_key[..])
+    }
+
+    // Proves to the holder of `public_key`, who can compute the same value,
+    // that we held our private key at `time`.
+    pub fn prove(&self, public_key: &Key, time: u64) -> Key {
+        let time = format!("{}", time);
+        hmac(&self.dh(public_key), &[b"presence", time.as_bytes()])
+    }
+
+    // The key a deniable message to the holder of `public_key` is
+    // authenticated with, and the ephemeral public key it goes out with. It
+    // mixes our static secret with theirs, so only we or they could have
+    // made the MAC, and either of us could have.
+    pub fn deniable_key_to(&self, public_key: &Key) -> (Key, Key) {
+        let (mut ephemeral, ephemeral_pub) = gen_key_pair();
+        let mut shared = curve25519(&ephemeral, &public_key[..]);
+        let key = hmac(&self.dh(public_key), &[b"deniable", &shared]);
+        zeroize(&mut ephemeral);
+        zeroize(&mut shared);
+        (ephemeral_pub, key)
+    }
+
+    // The key a deniable message from the holder of `public_key`, sent with
+    // `ephemeral`, was authenticated with.
+    pub fn deniable_key_from(&self, public_key: &Key, ephemeral: &Key) -> Key {
+        let mut shared = self.dh(ephemeral);
+        let key = hmac(&self.dh(public_key), &[b"deniable", &shared]);
+        zeroize(&mut shared);
+        key
+    }
+
+    // Proves to the user with `public_key` that the server wrote `text`.
+    pub fn prove_notice(&self, public_key: &Key, text: &str) -> Key {
+        hmac(&self.dh(public_key), &[b"notice", text.as_bytes()])
+    }
+
+    // The key our broadcast list `name` is sealed under. Only we can derive
+    // it, so it needn't be stored.
+    pub fn broadcast_key(&self, name: &str) -> Key {
+        hmac(&self.dh(&self.pub_key), &[b"broadcast", name.as_bytes()])
+    }
+
+    // The key local message history is sealed under.
+    pub fn history_key(&self) -> Key {
+        hmac(&self.dh(&self.pub_key), &[b"history"])
+    }
+
+    // The key message filters are sealed under on disk.
+    pub fn rules_key(&self) -> Key {
+        hmac(&self.dh(&self.pub_key), &[b"rules"])
+    }
+
+    // The key entries in the security log are sealed under.
+    pub fn security_key(&self) -> Key {
+        hmac(&self.dh(&self.pub_key), &[b"security log"])
+    }
+
+    // The key state synced between our devices is sealed under, one for each
+    // slot so the server can't pass one slot's state off as another's. The
+    // server knows our public key, so anything merely encrypted to it could
+    // have been written by the server.
+    pub fn sync_key(&self, slot: &str) -> Key {
+        hmac(&self.dh(&self.pub_key), &[b"sync", slot.as_bytes()])
+    }
+
+    // The key contacts saved on the server are sealed under.
+    pub fn contacts_key(&self) -> Key {
+        hmac(&self.dh(&self.pub_key), &[b"contacts"])
+    }
+
+    // The key the state of our conversations is sealed under between runs.
+    pub fn sessions_key(&self) -> Key {
+        hmac(&self.dh(&self.pub_key), &[b"sessions"])
+    }
+
+    // The key session captures are sealed under.
+    pub fn capture_key(&self) -> Key {
+        hmac(&self.dh(&self.pub_key), &[b"capture"])
+    }
+
+    // `ad` is authenticated along with `message` but not sent, so decrypt
+    // must be given the same bytes.
+    pub fn encrypt(&self, public_key: &[u8; 32], ad: &[u8], message: &[u8]) -> Result<Vec<u8>, EncryptError> {
+        self.seal(public_key, ad, &pad(message, false, &self.buckets))
+    }
+
+    // Like encrypt, but compresses `message` first when compression is on and
+    // it helps. It is still padded, so the saving only shows across buckets.
+    pub fn encrypt_compressed(&self, public_key: &[u8; 32], ad: &[u8], message: &[u8]) -> Result<Vec<u8>, EncryptError> {
+        match if self.compress { compressed(message) } else { None } {
+            Some(packed) => self.seal(public_key, ad, &pad(&packed, true, &self.buckets)),
+            None => self.encrypt(public_key, ad, message),
         }
     }
 
-    pub fn encrypt(&self, public_key: &[u8; 32], message: &[u8]) -> Result<Vec<u8>, EncryptError> {
+    fn seal(&self, public_key: &[u8; 32], ad: &[u8], message: &[u8]) -> Result<Vec<u8>, EncryptError> {
         let mut rng = try!(OsRng::new().map_err(|_| EncryptError::RngInitializationFailed));
 
         let mut ephemeral_secret_key = [0u8; 32];
         rng.fill_bytes(&mut ephemeral_secret_key[..]);
 
         let ephemeral_public_key: [u8; 32] = curve25519_base(&ephemeral_secret_key[..]);
-        let symmetric_key = curve25519(&ephemeral_secret_key[..], &public_key[..]);
+        let mut symmetric_key = curve25519(&ephemeral_secret_key[..], &public_key[..]);
+        zeroize(&mut ephemeral_secret_key);
 
-        let mut c = ChaCha20Poly1305::new(&symmetric_key, &[0u8; 8][..], &[]);
+        let mut c = ChaCha20Poly1305::new(&symmetric_key, &[0u8; 8][..], ad);
+        zeroize(&mut symmetric_key);
 
         let mut output = vec![0; 32 + 16 + message.len()];
         let mut tag = [0u8; 16];
@@ -77,7 +693,7 @@ impl Crypto {
         Ok(output)
     }
 
-    pub fn decrypt(&self, message: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    pub fn decrypt(&self, ad: &[u8], message: &[u8]) -> Result<Vec<u8>, DecryptError> {
         if message.len() < 48 {
             return Err(DecryptError::Malformed);
         }
@@ -87,14 +703,17 @@ impl Crypto {
         let ciphertext = &message[48..];
 
         let mut plaintext = vec![0; ciphertext.len()];
-        let symmetric_key = curve25519(&self.priv_key, ephemeral_public_key);
+        let mut symmetric_key = curve25519(&self.secrets.priv_key, ephemeral_public_key);
 
-        let mut decrypter = ChaCha20Poly1305::new(&symmetric_key[..], &[0u8; 8][..], &[]);
+        let mut decrypter = ChaCha20Poly1305::new(&symmetric_key[..], &[0u8; 8][..], ad);
+        zeroize(&mut symmetric_key);
         if !decrypter.decrypt(ciphertext, &mut plaintext[..], tag) {
             return Err(DecryptError::Invalid);
         }
 
-        Ok(plaintext)
+        let unpadded = unpad(&plaintext);
+        zeroize(&mut plaintext);
+        unpadded
     }
 
 }
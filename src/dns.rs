@@ -0,0 +1,181 @@
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use rand::{self, Rng};
+
+const DNS_PORT: u16 = 53;
+const QUERY_TIMEOUT_SECS: u64 = 2;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const NXDOMAIN: u8 = 3;
+const MAX_POINTERS: usize = 16;
+
+// One SRV record, e.g. from _secmsg._tcp.example.com.
+#[derive(Clone)]
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+// The nameservers from /etc/resolv.conf.
+fn nameservers() -> Vec<SocketAddr> {
+    let mut conf = String::new();
+    if File::open("/etc/resolv.conf").and_then(|mut f| f.read_to_string(&mut conf)).is_err() {
+        return Vec::new();
+    }
+    conf.lines()
+        .filter_map(|l| {
+            let mut words = l.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("nameserver"), Some(ip)) => ip.parse::<IpAddr>().ok(),
+                _ => None,
+            }
+        })
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .collect()
+}
+
+// Looks up the SRV records for `name`, in the order they should be tried:
+// by priority, then picked at random weighted by weight as in RFC 2782. A
+// name with no records gives an empty list.
+pub fn lookup_srv(name: &str) -> Result<Vec<Srv>, String> {
+    let servers = nameservers();
+    if servers.is_empty() {
+        return Err("No nameservers in /etc/resolv.conf".to_string());
+    }
+
+    let mut last_error = String::new();
+    for server in servers {
+        match query(server, name) {
+            Ok(records) => return Ok(order(records)),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(format!("Could not look up {}: {}", name, last_error))
+}
+
+fn query(server: SocketAddr, name: &str) -> Result<Vec<Srv>, String> {
+    let id = rand::random::<u16>();
+    let mut packet = vec![(id >> 8) as u8, id as u8, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("{} is not a valid name", name));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&[(TYPE_SRV >> 8) as u8, TYPE_SRV as u8, (CLASS_IN >> 8) as u8, CLASS_IN as u8]);
+
+    let local = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = try!(UdpSocket::bind(local).map_err(|e| e.to_string()));
+    try!(socket.set_read_timeout(Some(Duration::from_secs(QUERY_TIMEOUT_SECS))).map_err(|e| e.to_string()));
+    try!(socket.send_to(&packet, server).map_err(|e| e.to_string()));
+
+    let mut buf = [0u8; 4096];
+    loop {
+        let (len, from) = try!(socket.recv_from(&mut buf).map_err(|_| "The nameserver did not answer".to_string()));
+        // Anything else is a stray or spoofed reply.
+        if from == server && len >= 12 && buf[0] == (id >> 8) as u8 && buf[1] == id as u8 {
+            return parse_response(&buf[..len]);
+        }
+    }
+}
+
+fn parse_response(msg: &[u8]) -> Result<Vec<Srv>, String> {
+    match msg[3] & 0x0f {
+        0 => {},
+        NXDOMAIN => return Ok(Vec::new()),
+        code => return Err(format!("The nameserver returned error {}", code)),
+    }
+    let questions = try!(u16_at(msg, 4)) as usize;
+    let answers = try!(u16_at(msg, 6)) as usize;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = try!(read_name(msg, pos)).1 + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = try!(read_name(msg, pos)).1;
+        let rtype = try!(u16_at(msg, pos));
+        let rdlength = try!(u16_at(msg, pos + 8)) as usize;
+        let rdata = pos + 10;
+        if rdata + rdlength > msg.len() {
+            return Err("The answer was cut short".to_string());
+        }
+        if rtype == TYPE_SRV && rdlength >= 7 {
+            let target = try!(read_name(msg, rdata + 6)).0;
+            // A target of "." means the service is not offered.
+            if !target.is_empty() {
+                records.push(Srv {
+                    priority: try!(u16_at(msg, rdata)),
+                    weight: try!(u16_at(msg, rdata + 2)),
+                    port: try!(u16_at(msg, rdata + 4)),
+                    target: target,
+                });
+            }
+        }
+        pos = rdata + rdlength;
+    }
+    Ok(records)
+}
+
+fn u16_at(msg: &[u8], pos: usize) -> Result<u16, String> {
+    match msg.get(pos..pos + 2) {
+        Some(b) => Ok((b[0] as u16) << 8 | b[1] as u16),
+        None => Err("The answer was cut short".to_string()),
+    }
+}
+
+// Reads a possibly compressed name, returning it and where it ends.
+fn read_name(msg: &[u8], start: usize) -> Result<(String, usize), String> {
+    let mut labels: Vec<String> = Vec::new();
+    let (mut pos, mut end, mut pointers) = (start, None, 0);
+    loop {
+        let len = *try!(msg.get(pos).ok_or("The answer was cut short".to_string())) as usize;
+        if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return Err("The answer has a name that loops".to_string());
+            }
+            end = end.or(Some(pos + 2));
+            pos = (try!(u16_at(msg, pos)) & 0x3fff) as usize;
+            continue;
+        }
+        let label = try!(msg.get(pos + 1..pos + 1 + len).ok_or("The answer was cut short".to_string()));
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+}
+
+fn order(mut records: Vec<Srv>) -> Vec<Srv> {
+    records.sort_by_key(|r| r.priority);
+    let mut ordered = Vec::new();
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let count = records.iter().take_while(|r| r.priority == priority).count();
+        let mut group: Vec<Srv> = records.drain(..count).collect();
+        while !group.is_empty() {
+            // Records of weight 0 still get a small chance.
+            let total: u32 = group.iter().map(|r| r.weight as u32 + 1).sum();
+            let mut pick = rand::thread_rng().gen_range(0, total);
+            let i = group.iter().position(|r| {
+                let w = r.weight as u32 + 1;
+                if pick < w { true } else { pick -= w; false }
+            }).unwrap();
+            ordered.push(group.remove(i));
+        }
+    }
+    ordered
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+
+use messages::TextMessage;
+
+// Rules that incoming messages are run through, one per line such as
+// `from alice has invoice -> folder bills`. A rule matches when all of its
+// conditions do, so one without any matches everything. Every rule that
+// matches acts, so a message can be both loud and passed to a hook.
+#[derive(Clone, PartialEq)]
+pub struct Rule {
+    pub conditions: Vec<Condition>,
+    pub action: Action,
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Condition {
+    From(String), // sender's handle
+    In(String), // namespace of the sender's handle
+    Has(String), // word in the text, ignoring case
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Action {
+    Folder(String), // kept out of its conversation, in the named folder
+    Archive, // added to its conversation without being shown or counted as unseen
+    Loud, // shown with a bell, whichever conversation we are in
+    Hook(String), // command run with {from} replaced and the text on stdin
+}
+
+impl Rule {
+
+    pub fn parse(line: &str) -> Result<Rule, String> {
+        let mut halves = line.splitn(2, "->");
+        let (conditions, action) = match (halves.next(), halves.next()) {
+            (Some(c), Some(a)) => (c, a.trim()),
+            _ => return Err("A rule needs an action after ->".to_string()),
+        };
+
+        let mut words = conditions.split_whitespace();
+        let mut parsed = Vec::new();
+        while let Some(word) = words.next() {
+            let arg = try!(words.next().ok_or(format!("'{}' needs something to match", word))).to_string();
+            parsed.push(match word {
+                "from" => Condition::From(arg),
+                "in" => Condition::In(arg),
+                "has" => Condition::Has(arg.to_lowercase()),
+                _ => return Err(format!("Unknown condition '{}', use from, in or has", word)),
+            });
+        }
+
+        let mut words = action.splitn(2, char::is_whitespace);
+        let action = match (words.next(), words.next().map(|a| a.trim()).filter(|a| !a.is_empty())) {
+            (Some("folder"), Some(name)) if !name.contains(char::is_whitespace) => Action::Folder(name.to_string()),
+            (Some("archive"), None) => Action::Archive,
+            (Some("loud"), None) => Action::Loud,
+            (Some("hook"), Some(cmd)) => Action::Hook(cmd.to_string()),
+            _ => return Err("The action must be folder <name>, archive, loud or hook <command>".to_string()),
+        };
+        Ok(Rule { conditions: parsed, action: action })
+    }
+
+    pub fn matches(&self, msg: &TextMessage) -> bool {
+        let text = msg.text.to_lowercase();
+        self.conditions.iter().all(|c| match *c {
+            Condition::From(ref handle) => msg.sender.handle == *handle,
+            Condition::In(ref ns) => msg.sender.handle.starts_with(&format!("{}/", ns)),
+            Condition::Has(ref word) => text.contains(&**word),
+        })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for c in self.conditions.iter() {
+            match *c {
+                Condition::From(ref handle) => try!(write!(f, "from {} ", handle)),
+                Condition::In(ref ns) => try!(write!(f, "in {} ", ns)),
+                Condition::Has(ref word) => try!(write!(f, "has {} ", word)),
+            }
+        }
+        match self.action {
+            Action::Folder(ref name) => write!(f, "-> folder {}", name),
+            Action::Archive => write!(f, "-> archive"),
+            Action::Loud => write!(f, "-> loud"),
+            Action::Hook(ref cmd) => write!(f, "-> hook {}", cmd),
+        }
+    }
+}
+
+// Reads rules back from their text form, skipping lines that don't parse.
+pub fn parse_all(text: &str) -> Vec<Rule> {
+    text.lines().filter_map(|l| Rule::parse(l).ok()).collect()
+}
+
+pub fn to_text(rules: &[Rule]) -> String {
+    rules.iter().map(|r| r.to_string()).collect::<Vec<String>>().join("\n")
+}
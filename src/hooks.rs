@@ -0,0 +1,139 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use config_lib::Config;
+use io_lib::IOHandler;
+use messages::TextMessage;
+
+// Optional commands that outgoing text is filtered through before it is sent.
+// A filter reads the draft on stdin and writes its suggestion to stdout.
+// Voice messages are recorded and played by commands too, with {file} and
+// {secs} replaced, e.g. `record_command = arecord -q -d {secs} {file}`, and
+// images are thumbnailed and shown the same way, e.g.
+// `thumbnail_command = convert {file} -thumbnail 96x96 {thumb}`.
+pub struct Hooks {
+    spellcheck: Option<String>,
+    record: Option<String>,
+    play: Option<String>,
+    thumbnail: Option<String>,
+    preview: Option<String>, // shows a thumbnail, e.g. in the terminal
+    view: Option<String>,
+}
+
+impl Hooks {
+
+    pub fn from_config(config: &Config) -> Hooks {
+        Hooks {
+            spellcheck: config.get_str("spellcheck").filter(|c| !c.is_empty()),
+            record: config.get_str("record_command").filter(|c| !c.is_empty()),
+            play: config.get_str("play_command").filter(|c| !c.is_empty()),
+            thumbnail: config.get_str("thumbnail_command").filter(|c| !c.is_empty()),
+            preview: config.get_str("preview_command").filter(|c| !c.is_empty()),
+            view: config.get_str("view_command").filter(|c| !c.is_empty()),
+        }
+    }
+
+    // Returns false if there is no thumbnail_command.
+    pub fn make_thumbnail(&self, image: &Path, thumb: &Path) -> Result<bool, String> {
+        match self.thumbnail {
+            Some(ref cmd) => run(&cmd.replace("{file}", &quote(image)).replace("{thumb}", &quote(thumb))).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    // Does nothing without a preview_command.
+    pub fn preview_image(&self, thumb: &Path) -> Result<(), String> {
+        match self.preview {
+            Some(ref cmd) => run(&cmd.replace("{file}", &quote(thumb))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn view_image(&self, path: &Path) -> Result<(), String> {
+        let cmd = try!(self.view.as_ref().ok_or("Set view_command to view images".to_string()));
+        run(&cmd.replace("{file}", &quote(path)))
+    }
+
+    pub fn record_voice(&self, path: &Path, secs: u32) -> Result<(), String> {
+        let cmd = try!(self.record.as_ref().ok_or("Set record_command to record voice messages".to_string()));
+        run(&cmd.replace("{secs}", &secs.to_string()).replace("{file}", &quote(path)))
+    }
+
+    pub fn play_voice(&self, path: &Path) -> Result<(), String> {
+        let cmd = try!(self.play.as_ref().ok_or("Set play_command to play voice messages".to_string()));
+        run(&cmd.replace("{file}", &quote(path)))
+    }
+
+    // Returns the text to send, or None if the user cancelled.
+    pub fn outgoing(&self, io: &IOHandler, text: String) -> Option<String> {
+        let cmd = match self.spellcheck {
+            Some(ref c) => c,
+            None => return Some(text),
+        };
+
+        let suggestion = match run_filter(cmd, &text) {
+            Ok(s) => s,
+            Err(e) => {
+                io.print_error(&format!("Spellcheck failed, sending as written: {}", e));
+                return Some(text);
+            }
+        };
+        if suggestion == text {
+            return Some(text);
+        }
+
+        io.print_log(&format!("- {}\n+ {}", text, suggestion));
+        loop {
+            match &*io.read_prompted_line("Send the suggestion? [y/n/cancel]: ") {
+                "y" | "yes" => return Some(suggestion),
+                "n" | "no" => return Some(text),
+                "c" | "cancel" => return None,
+                _ => io.print_error("Please enter 'y', 'n' or 'cancel'."),
+            }
+        }
+    }
+}
+
+fn run(cmd: &str) -> Result<(), String> {
+    let status = try!(Command::new("sh").arg("-c").arg(cmd).status().map_err(|e| e.to_string()));
+    if !status.success() {
+        return Err(format!("'{}' exited with {}", cmd, status));
+    }
+    Ok(())
+}
+
+fn quote(path: &Path) -> String {
+    quote_str(&path.to_string_lossy())
+}
+
+fn quote_str(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// Runs a message filter's hook, with {from} replaced by the sender and the
+// text on stdin.
+pub fn run_rule(cmd: &str, msg: &TextMessage) -> Result<(), String> {
+    run_filter(&cmd.replace("{from}", &quote_str(&msg.sender.handle)), &msg.text).map(|_| ())
+}
+
+fn run_filter(cmd: &str, text: &str) -> Result<String, String> {
+    let mut child = try!(Command::new("sh").arg("-c").arg(cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string()));
+
+    {
+        let stdin = try!(child.stdin.as_mut().ok_or("Could not open stdin".to_string()));
+        try!(stdin.write_all(text.as_bytes()).map_err(|e| e.to_string()));
+    }
+
+    let output = try!(child.wait_with_output().map_err(|e| e.to_string()));
+    if !output.status.success() {
+        return Err(format!("'{}' exited with {}", cmd, output.status));
+    }
+    String::from_utf8(output.stdout)
+        .map(|s| s.trim().to_string())
+        .map_err(|_| "Output was not valid UTF-8".to_string())
+}
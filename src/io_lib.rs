@@ -14,6 +14,11 @@ impl IOHandler {
         IOHandler { }
     }
 
+    // An IOHandler that skips the welcome banner, for headless mode.
+    pub fn quiet() -> IOHandler {
+        IOHandler { }
+    }
+
     pub fn read_line(&self, mut string: &mut String) {
         io::stdin().read_line(&mut string).expect("Failed to read user input.");
     }
@@ -0,0 +1,345 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam;
+use rustc_serialize::json::{Json, ToJson};
+
+use config_lib::Config;
+use crypto_lib::{self, Crypto};
+use net_lib::Net;
+use messages::{Priority, TextMessage, Attachment, FileOffer};
+use setup::{self, StartupError};
+use known_keys::KnownKeys;
+use state::{State, User};
+use command;
+use transfer::{self, Event, DownloadPolicy};
+
+// Scripting mode. Every event is one JSON object per line on stdout, and
+// commands are read the same way from stdin, e.g.
+//     {"command": "connect", "user": "bob"}
+//     {"command": "send", "text": "hi", "reliable": true, "urgent": false}
+//     {"command": "undo", "id": 1234}
+//     {"command": "typing"}
+//     {"command": "send_file", "path": "notes.txt"}
+//     {"command": "send_file", "path": "clip.ogg", "voice_secs": 8}
+//     {"command": "send_file", "path": "cat.png", "image": true}
+//     {"command": "accept_file", "id": 5678, "now": false}
+//     {"command": "network", "profile": "metered"}
+pub fn run(config: &Config) {
+    let fail = |e: StartupError| -> ! {
+        emit("error", vec![("message", e.to_string().to_json())]);
+        process::exit(e.exit_code());
+    };
+
+    let (mut priv_key, pub_key) = setup::headless_keys(&config).unwrap_or_else(|e| fail(e));
+    let crypto = Crypto::new(priv_key, pub_key);
+    crypto_lib::zeroize(&mut priv_key);
+    let net = Net::new(crypto, &config)
+        .unwrap_or_else(|e| fail(StartupError::ServerUnreachable(e)));
+    let user = Some(setup::headless_login(&config, &net).unwrap_or_else(|e| fail(e)));
+    emit("ready", vec![("handle", user.as_ref().unwrap().handle.to_json())]);
+
+    let state = State::new();
+    let keys = KnownKeys::load();
+    if let Err(e) = command::open_sessions(&net, &state) {
+        emit("error", vec![("message", e.to_json())]);
+    }
+    if let Err(e) = command::open_security_log(&net, &state) {
+        emit("error", vec![("message", format!("Could not open the security log: {}", e).to_json())]);
+    }
+    match command::resume_outbox(&net, &state) {
+        Ok(n) => emit("resumed", vec![("messages", n.to_json())]),
+        Err(e) => emit("error", vec![("message", format!("Could not open the outbox journal: {}", e).to_json())]),
+    }
+    state.transfers().set_policy(DownloadPolicy::from_config(&config), &net);
+    if let Err(e) = state.transfers().resume(&net) {
+        emit("error", vec![("message", format!("Could not resume file transfers: {}", e).to_json())]);
+    }
+    crossbeam::scope(|scope| {
+        scope.spawn(|| loop {
+            let msg = net.get_message();
+            match command::receive(msg.clone(), &net, &state, &keys) {
+                Ok(msgs) => for m in msgs {
+                    emit_message(&m);
+                },
+                Err(e) => emit("held", vec![("from", msg.sender.handle.to_json()), ("message", e.to_json())]),
+            }
+        });
+        scope.spawn(|| loop {
+            thread::sleep(Duration::from_secs(1));
+            for (sender, skipped, msgs) in state.take_gaps() {
+                emit("gap", vec![("from", sender.to_json()), ("missing", skipped.to_json())]);
+                for m in msgs {
+                    emit_message(&m);
+                }
+            }
+            for notice in command::heal_sessions(&net, &state) {
+                emit("reset", vec![("message", notice.to_json())]);
+            }
+        });
+        scope.spawn(|| loop {
+            let reset = net.get_reset();
+            let from = reset.sender.clone();
+            match command::receive_reset(reset, &state, &keys) {
+                Ok(Some((notice, msgs))) => {
+                    emit("reset", vec![("from", from.to_json()), ("message", notice.to_json())]);
+                    for m in msgs {
+                        emit_message(&m);
+                    }
+                },
+                Ok(None) => {},
+                Err(e) => emit("error", vec![("message", e.to_json())]),
+            }
+        });
+
+        scope.spawn(|| loop {
+            if let Some(out) = command::acknowledged(net.get_ack(), &net, &state) {
+                emit("acked", vec![("id", out.msg.id.to_json())]);
+            }
+        });
+        scope.spawn(|| {
+            let mut shown: Option<String> = None;
+            loop {
+                thread::sleep(Duration::from_secs(1));
+                let typing = state.get_current_conversation()
+                    .map(|c| c.get_partner().handle.clone())
+                    .filter(|p| net.is_typing(p));
+                if typing.is_some() && typing != shown {
+                    emit("typing", vec![("user", typing.to_json())]);
+                }
+                shown = typing;
+            }
+        });
+        scope.spawn(|| loop {
+            for (list, msg) in command::receive_broadcast(net.get_broadcast(), &net, &state) {
+                emit("broadcast", vec![("list", list.to_json()), ("id", msg.id.to_json()), ("text", msg.text.to_json())]);
+            }
+        });
+        scope.spawn(|| loop {
+            if let Some(out) = state.take_sent(net.get_receipt()) {
+                emit("read", vec![("id", out.msg.id.to_json())]);
+            }
+        });
+        scope.spawn(|| loop {
+            emit("notice", vec![("text", net.get_notice().to_json())]);
+        });
+        scope.spawn(|| loop {
+            let (kind, detail) = net.get_security_event();
+            state.log_security(kind, &detail);
+            emit("security", vec![("kind", kind.to_string().to_json()), ("detail", detail.to_json())]);
+        });
+        scope.spawn(|| loop {
+            let up = net.get_connection_change();
+            if state.set_server_connected(up) {
+                emit("connection", vec![("up", up.to_json())]);
+            }
+        });
+        scope.spawn(|| loop {
+            if let Some(event) = state.transfers().handle(net.get_file_event(), &net) {
+                emit_transfer(event);
+            }
+        });
+        scope.spawn(|| loop {
+            thread::sleep(Duration::from_secs(1));
+            for event in state.transfers().tick(&net) {
+                emit_transfer(event);
+            }
+        });
+        scope.spawn(|| loop {
+            thread::sleep(Duration::from_secs(5));
+            for out in command::resend_unacked(&net, &state) {
+                emit("failed", vec![("id", out.msg.id.to_json()), ("reason", "Never acknowledged".to_json())]);
+            }
+        });
+
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => break,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Err(e) = handle_command(&line, &net, &state, &keys, &user) {
+                emit("error", vec![("message", e.to_json())]);
+            }
+        }
+        process::exit(0);
+    });
+}
+
+fn handle_command(line: &str, net: &Net, state: &State, keys: &KnownKeys, user: &Option<User>) -> Result<(), String> {
+    let cmd = try!(Json::from_str(line).map_err(|e| e.to_string()));
+    let field = |name: &str| cmd.find(name).and_then(|v| v.as_string()).map(|s| s.to_string())
+        .ok_or(format!("Missing \"{}\"", name));
+
+    match &*try!(field("command")) {
+        "connect" => {
+            let other = try!(field("user"));
+            try!(command::connect(&other, &net, &state, &keys));
+            emit("connected", vec![("user", other.to_json()), ("server_relay", net.via_last_resort(&other).to_json())]);
+        },
+        "join" => {
+            let other = try!(field("user"));
+            let id = try!(state.conv_name_to_id(&other).ok_or("No conversation with that user".to_string()));
+            try!(state.set_current_conversation(Some(id)));
+            emit("joined", vec![("user", other.to_json()), ("conversation", id.to_json())]);
+        },
+        "send" => {
+            let text = try!(field("text"));
+            let reliable = cmd.find("reliable").and_then(|v| v.as_boolean()).unwrap_or(false);
+            let (status, results) = channel();
+            let priority = match cmd.find("urgent").and_then(|v| v.as_boolean()) {
+                Some(true) => Priority::Urgent,
+                _ => Priority::Normal,
+            };
+            let id = try!(command::queue_text(text, &net, &state, &user, reliable, priority, Some(status))).msg.id;
+            emit("queued", vec![("id", id.to_json())]);
+
+            // Every route reports back before the channel closes, unless the
+            // message was undone and never sent.
+            thread::spawn(move || {
+                let results: Vec<_> = results.iter().collect();
+                if results.iter().any(|r| r.is_ok()) {
+                    emit("delivered", vec![("id", id.to_json())]);
+                } else if let Some(&Err(ref e)) = results.first() {
+                    emit("failed", vec![("id", id.to_json()), ("reason", e.to_json())]);
+                }
+            });
+        },
+        "undo" => {
+            let id = cmd.find("id").and_then(|v| v.as_u64());
+            let id = try!(net.cancel(id).ok_or("Nothing to undo".to_string()));
+            state.take_unacked(id);
+            emit("cancelled", vec![("id", id.to_json())]);
+        },
+        // Sent by the front end as the user composes, rate limited by Net.
+        "typing" => {
+            let conv = try!(state.get_current_conversation().ok_or("No current conversation.".to_string()));
+            net.send_typing(&conv.get_partner().handle);
+        },
+        "carry" => {
+            let on = try!(cmd.find("on").and_then(|v| v.as_boolean()).ok_or("Missing \"on\"".to_string()));
+            net.set_carry_mode(on, keys.verified_keys());
+            emit("carry", vec![("on", on.to_json())]);
+        },
+        "power" => {
+            let low = try!(cmd.find("low").and_then(|v| v.as_boolean()).ok_or("Missing \"low\"".to_string()));
+            net.set_low_power(low);
+            emit("power", vec![("low", low.to_json())]);
+        },
+        "send_file" => {
+            let path = try!(field("path"));
+            let offer = if cmd.find("image").and_then(|v| v.as_boolean()).unwrap_or(false) {
+                try!(command::send_image(&path, &net, &state, &user))
+            } else {
+                let kind = match cmd.find("voice_secs").and_then(|v| v.as_u64()) {
+                    Some(secs) => Attachment::Voice(secs as u32),
+                    None => Attachment::File,
+                };
+                try!(command::send_file(&path, kind, &net, &state, &user))
+            };
+            emit("file_offered", vec![("id", offer.id.to_json()), ("name", offer.name.to_json()), ("size", offer.size.to_json())]);
+        },
+        "accept_file" => {
+            let id = cmd.find("id").and_then(|v| v.as_u64());
+            let now = cmd.find("now").and_then(|v| v.as_boolean()).unwrap_or(false);
+            let (offer, started) = try!(command::accept_file(id, now, &net, &state, &user));
+            emit("file_accepted", vec![("id", offer.id.to_json()), ("queued", (!started).to_json())]);
+        },
+        "downloads" => {
+            let queue: Vec<Json> = state.transfers().queue().into_iter().map(|t| {
+                let mut obj = BTreeMap::new();
+                obj.insert("id".to_string(), t.offer.id.to_json());
+                obj.insert("name".to_string(), t.offer.name.to_json());
+                obj.insert("from".to_string(), t.peer.to_json());
+                obj.insert("size".to_string(), t.offer.size.to_json());
+                obj.insert("received".to_string(), cmp::min(t.chunks_done * t.offer.chunk_size, t.offer.size).to_json());
+                obj.insert("state".to_string(), t.state.to_json());
+                Json::Object(obj)
+            }).collect();
+            emit("downloads", vec![("queue", Json::Array(queue))]);
+        },
+        "network" => {
+            let mut config = Config::load();
+            config.set("network_profile", &try!(field("profile")));
+            try!(config.save());
+            state.transfers().set_policy(DownloadPolicy::from_config(&config), &net);
+            let policy = state.transfers().policy();
+            emit("network", vec![
+                ("profile", policy.profile.to_json()),
+                ("metered", policy.metered.to_json()),
+                ("auto_download_kb", (policy.auto_download_bytes / 1024).to_json()),
+            ]);
+        },
+        "cancel_file" => {
+            let id = try!(cmd.find("id").and_then(|v| v.as_u64()).ok_or("Missing \"id\"".to_string()));
+            try!(state.transfers().cancel(id, &net));
+            emit("file_cancelled", vec![("id", id.to_json())]);
+        },
+        _ => return Err("Command not recognized".to_string()),
+    }
+    Ok(())
+}
+
+fn emit_message(msg: &TextMessage) {
+    emit("message", vec![
+        ("id", msg.id.to_json()),
+        ("conversation", msg.conv_id.to_json()),
+        ("from", msg.sender.handle.to_json()),
+        ("text", msg.text.to_json()),
+    ]);
+}
+
+fn emit_transfer(event: Event) {
+    match event {
+        Event::Offered(offer) => emit("file_offer", vec![
+            ("id", offer.id.to_json()),
+            ("from", offer.sender.to_json()),
+            ("name", offer.name.to_json()),
+            ("size", offer.size.to_json()),
+            ("mime", offer.mime.to_json()),
+            ("voice_secs", voice_secs(&offer)),
+            ("thumbnail", transfer::open_thumbnail(&offer).map(|p| p.to_string_lossy().into_owned()).to_json()),
+        ]),
+        Event::Downloading(offer) => emit("file_downloading", vec![("id", offer.id.to_json()), ("from", offer.sender.to_json())]),
+        Event::Received(offer, path) => emit("file_received", vec![
+            ("id", offer.id.to_json()),
+            ("path", path.to_string_lossy().into_owned().to_json()),
+            ("mime", offer.mime.to_json()),
+            ("voice_secs", voice_secs(&offer)),
+        ]),
+        Event::Delivered(offer, to) => emit("file_delivered", vec![("id", offer.id.to_json()), ("to", to.to_json())]),
+        Event::Shared(offer, to) => emit("shared_file_sent", vec![("id", offer.id.to_json()), ("name", offer.name.to_json()),
+            ("to", to.to_json())]),
+        Event::Paused(offer) => emit("file_paused", vec![("id", offer.id.to_json())]),
+        Event::Cancelled(offer) => emit("file_cancelled", vec![("id", offer.id.to_json())]),
+        Event::Failed(offer, e) => emit("file_failed", vec![("id", offer.id.to_json()), ("reason", e.to_json())]),
+    }
+}
+
+fn voice_secs(offer: &FileOffer) -> Json {
+    match offer.kind {
+        Attachment::Voice(secs) => secs.to_json(),
+        _ => Json::Null,
+    }
+}
+
+fn emit(event: &str, fields: Vec<(&str, Json)>) {
+    let mut obj = BTreeMap::new();
+    obj.insert("event".to_string(), event.to_json());
+    for (k, v) in fields {
+        obj.insert(k.to_string(), v);
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let _ = writeln!(out, "{}", Json::Object(obj));
+    let _ = out.flush();
+}
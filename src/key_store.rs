@@ -0,0 +1,231 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use rustc_serialize::hex::{FromHex, ToHex};
+
+use crypto_lib::{self, Key};
+
+// Where the private key is kept. Files work everywhere, including headless
+// servers. The platform keyring is chosen with `key_store = keyring`, and
+// unlocks the key with the login session instead of a passphrase.
+pub trait KeyStore {
+    // None if no key is stored.
+    fn load(&self) -> Result<Option<Key>, String>;
+    fn store(&self, key: &Key) -> Result<(), String>;
+    fn remove(&self) -> Result<(), String>;
+}
+
+// The key in ~/.secmsg/keys, sealed as private.enc if there is a passphrase
+// and as raw bytes in private if not.
+pub struct FileStore {
+    pub dir: PathBuf,
+    pub passphrase: String,
+}
+
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        crypto_lib::zeroize_string(&mut self.passphrase);
+    }
+}
+
+impl KeyStore for FileStore {
+    fn load(&self) -> Result<Option<Key>, String> {
+        if self.dir.join("private.enc").exists() {
+            let _ = make_private(&self.dir.join("private.enc"));
+            let mut sealed = Vec::new();
+            try!(File::open(self.dir.join("private.enc")).and_then(|mut f| f.read_to_end(&mut sealed)).map_err(|e| e.to_string()));
+            match crypto_lib::open_with_passphrase(&self.passphrase, &sealed) {
+                Ok(ref k) if k.len() == 32 => Ok(Some(to_key(k))),
+                _ => Err("Incorrect passphrase.".to_string()),
+            }
+        } else if self.dir.join("private").exists() {
+            let _ = make_private(&self.dir.join("private"));
+            let mut priv_key = [0u8; 32];
+            try!(File::open(self.dir.join("private")).and_then(|mut f| f.read_exact(&mut priv_key)).map_err(|e| e.to_string()));
+            Ok(Some(priv_key))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn store(&self, key: &Key) -> Result<(), String> {
+        // Written aside first so the key on disk is never half replaced.
+        let (name, other, data) = if self.passphrase.is_empty() {
+            ("private", "private.enc", key.to_vec())
+        } else {
+            let sealed = try!(crypto_lib::seal_with_passphrase(&self.passphrase, key)
+                .map_err(|_| "Failed to encrypt private key".to_string()));
+            ("private.enc", "private", sealed)
+        };
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        let mut file = try!(create_private(&tmp).map_err(|e| e.to_string()));
+        try!(file.write_all(&data).and_then(|_| file.sync_all()).map_err(|e| e.to_string()));
+        try!(fs::rename(&tmp, self.dir.join(name)).map_err(|e| e.to_string()));
+        let _ = fs::remove_file(self.dir.join(other));
+        Ok(())
+    }
+
+    fn remove(&self) -> Result<(), String> {
+        for name in ["private", "private.enc"].iter().filter(|n| self.dir.join(n).exists()) {
+            try!(fs::remove_file(self.dir.join(name)).map_err(|e| e.to_string()));
+        }
+        Ok(())
+    }
+}
+
+// The platform's secret store, reached through its command line tool so no
+// native library is needed: secret-tool for the Secret Service, security for
+// the macOS Keychain, and PowerShell's DPAPI wrappers on Windows. Keys are
+// stored per ~/.secmsg directory.
+pub struct Keyring {
+    dir: PathBuf,
+}
+
+const SERVICE: &'static str = "secmsg";
+
+impl Keyring {
+    pub fn new(dir: &Path) -> Keyring {
+        Keyring { dir: dir.to_path_buf() }
+    }
+
+    fn account(&self) -> String {
+        self.dir.display().to_string()
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+impl KeyStore for Keyring {
+    fn load(&self) -> Result<Option<Key>, String> {
+        let account = self.account();
+        let (found, out) = try!(run(Command::new("secret-tool").args(&["lookup", "service", SERVICE, "account", &account]), None));
+        if !found || out.is_empty() {
+            return Ok(None);
+        }
+        parse_key(&out).map(Some)
+    }
+
+    fn store(&self, key: &Key) -> Result<(), String> {
+        let account = self.account();
+        let (stored, _) = try!(run(Command::new("secret-tool")
+            .args(&["store", "--label", "secmsg private key", "service", SERVICE, "account", &account]), Some(&key.to_hex())));
+        if stored { Ok(()) } else { Err("The keyring refused the key".to_string()) }
+    }
+
+    fn remove(&self) -> Result<(), String> {
+        let account = self.account();
+        try!(run(Command::new("secret-tool").args(&["clear", "service", SERVICE, "account", &account]), None));
+        Ok(())
+    }
+}
+
+// The key is passed to security as an argument, since it won't read one from
+// stdin, so it is briefly visible to other processes of the same user.
+#[cfg(target_os = "macos")]
+impl KeyStore for Keyring {
+    fn load(&self) -> Result<Option<Key>, String> {
+        let account = self.account();
+        let (found, out) = try!(run(Command::new("security").args(&["find-generic-password", "-s", SERVICE, "-a", &account, "-w"]), None));
+        if !found {
+            return Ok(None);
+        }
+        parse_key(&out).map(Some)
+    }
+
+    fn store(&self, key: &Key) -> Result<(), String> {
+        let account = self.account();
+        let (stored, _) = try!(run(Command::new("security")
+            .args(&["add-generic-password", "-U", "-s", SERVICE, "-a", &account, "-w", &key.to_hex()]), None));
+        if stored { Ok(()) } else { Err("The keychain refused the key".to_string()) }
+    }
+
+    fn remove(&self) -> Result<(), String> {
+        let account = self.account();
+        try!(run(Command::new("security").args(&["delete-generic-password", "-s", SERVICE, "-a", &account]), None));
+        Ok(())
+    }
+}
+
+// DPAPI only seals the key to the user, so the sealed blob is kept with the
+// other key files.
+#[cfg(windows)]
+impl KeyStore for Keyring {
+    fn load(&self) -> Result<Option<Key>, String> {
+        let mut blob = String::new();
+        if File::open(self.dir.join("keys").join("private.dpapi")).and_then(|mut f| f.read_to_string(&mut blob)).is_err() {
+            return Ok(None);
+        }
+        let script = "$s = ConvertTo-SecureString ([Console]::In.ReadToEnd().Trim()); \
+                      [Runtime.InteropServices.Marshal]::PtrToStringAuto([Runtime.InteropServices.Marshal]::SecureStringToBSTR($s))";
+        let (opened, out) = try!(run(Command::new("powershell").args(&["-NoProfile", "-Command", script]), Some(&blob)));
+        if !opened {
+            return Err("Windows could not unseal the key".to_string());
+        }
+        parse_key(&out).map(Some)
+    }
+
+    fn store(&self, key: &Key) -> Result<(), String> {
+        let script = "ConvertTo-SecureString ([Console]::In.ReadToEnd().Trim()) -AsPlainText -Force | ConvertFrom-SecureString";
+        let (sealed, blob) = try!(run(Command::new("powershell").args(&["-NoProfile", "-Command", script]), Some(&key.to_hex())));
+        if !sealed {
+            return Err("Windows could not seal the key".to_string());
+        }
+        try!(fs::create_dir_all(self.dir.join("keys")).map_err(|e| e.to_string()));
+        let mut file = try!(create_private(&self.dir.join("keys").join("private.dpapi")).map_err(|e| e.to_string()));
+        file.write_all(blob.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn remove(&self) -> Result<(), String> {
+        let _ = fs::remove_file(self.dir.join("keys").join("private.dpapi"));
+        Ok(())
+    }
+}
+
+// Runs a keyring tool, returning whether it succeeded and what it printed.
+fn run(cmd: &mut Command, input: Option<&str>) -> Result<(bool, String), String> {
+    let mut child = try!(cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null()).spawn()
+        .map_err(|e| format!("Could not run the keyring tool: {}", e)));
+    if let Some(input) = input {
+        try!(child.stdin.take().unwrap().write_all(input.as_bytes()).map_err(|e| e.to_string()));
+    } else {
+        drop(child.stdin.take());
+    }
+    let output = try!(child.wait_with_output().map_err(|e| e.to_string()));
+    Ok((output.status.success(), String::from_utf8_lossy(&output.stdout).trim().to_string()))
+}
+
+fn parse_key(hex: &str) -> Result<Key, String> {
+    hex.from_hex().ok().filter(|k| k.len() == 32).map(|k| to_key(&k))
+        .ok_or("The keyring holds something other than a key".to_string())
+}
+
+fn to_key(bytes: &[u8]) -> Key {
+    let mut key = [0u8; 32];
+    key.copy_from_slice(bytes);
+    key
+}
+
+// Creates a file only we can read, for keys.
+pub fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let file = try!(options.open(path));
+    // A file that already existed keeps its mode, so it is set again.
+    try!(make_private(path));
+    Ok(file)
+}
+
+#[cfg(unix)]
+pub fn make_private(path: &Path) -> io::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+pub fn make_private(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rustc_serialize::hex::{FromHex, ToHex};
+
+use crypto_lib::Key;
+use state::User;
+
+pub enum KeyStatus {
+    Trusted,
+    Changed(Key), // the key we had pinned
+}
+
+// The identity key we last accepted for every contact, pinned the first time
+// we see them. Stored in ~/.secmsg/known_keys as `handle key verified` lines,
+// with every change of key appended to ~/.secmsg/key_history. The key each
+// contact signs with is pinned the same way, in ~/.secmsg/signing_keys.
+pub struct KnownKeys {
+    keys: Mutex<HashMap<String, (Key, bool)>>,
+    signers: Mutex<HashMap<String, Key>>,
+    dir: Option<PathBuf>,
+}
+
+impl KnownKeys {
+
+    pub fn load() -> KnownKeys {
+        let dir = env::home_dir().map(|home| home.join(".secmsg"));
+        let mut contents = String::new();
+        if let Some(mut file) = dir.as_ref().and_then(|d| File::open(d.join("known_keys")).ok()) {
+            let _ = file.read_to_string(&mut contents);
+        }
+
+        let keys = contents.lines()
+            .filter_map(|l| {
+                let parts: Vec<&str> = l.split_whitespace().collect();
+                match (parts.get(0), parts.get(1).and_then(|k| parse_key(k)), parts.get(2)) {
+                    (Some(h), Some(k), Some(v)) => Some((h.to_string(), (k, *v == "verified"))),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        let mut contents = String::new();
+        if let Some(mut file) = dir.as_ref().and_then(|d| File::open(d.join("signing_keys")).ok()) {
+            let _ = file.read_to_string(&mut contents);
+        }
+        let signers = contents.lines()
+            .filter_map(|l| {
+                let parts: Vec<&str> = l.split_whitespace().collect();
+                match (parts.get(0), parts.get(1).and_then(|k| parse_key(k))) {
+                    (Some(h), Some(k)) => Some((h.to_string(), k)),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        KnownKeys { keys: Mutex::new(keys), signers: Mutex::new(signers), dir: dir }
+    }
+
+    // Pins the signing key of a contact we haven't had a message from, and
+    // refuses any other key after that.
+    pub fn check_signer(&self, handle: &str, signing_key: &Key) -> Result<(), String> {
+        let mut signers = self.signers.lock().unwrap();
+        match signers.get(handle) {
+            Some(k) if k != signing_key => return Err(format!("signed by a key {} has never used", handle)),
+            Some(_) => return Ok(()),
+            None => {},
+        }
+        signers.insert(handle.to_string(), *signing_key);
+        let _ = self.save_signers(&signers);
+        Ok(())
+    }
+
+    // Pins the key of a user we haven't seen before.
+    pub fn check(&self, user: &User) -> KeyStatus {
+        let mut keys = self.keys.lock().unwrap();
+        let pinned = keys.get(&user.handle).map(|&(k, _)| k);
+        match pinned {
+            Some(k) if k != user.public_key => KeyStatus::Changed(k),
+            Some(_) => KeyStatus::Trusted,
+            None => {
+                keys.insert(user.handle.clone(), (user.public_key, false));
+                let _ = self.save(&keys);
+                KeyStatus::Trusted
+            },
+        }
+    }
+
+    pub fn get(&self, handle: &str) -> Option<(Key, bool)> {
+        self.keys.lock().unwrap().get(handle).cloned()
+    }
+
+    // Accepts `key` for `handle` once the user has compared safety numbers.
+    pub fn verify(&self, handle: &str, key: Key) -> Result<(), String> {
+        let mut keys = self.keys.lock().unwrap();
+        let old = keys.insert(handle.to_string(), (key, true)).map(|(k, _)| k);
+        if let Some(old) = old.filter(|k| *k != key) {
+            try!(self.record_transition(handle, &old, &key));
+
+            // A new identity key signs with a new signing key.
+            let mut signers = self.signers.lock().unwrap();
+            signers.remove(handle);
+            try!(self.save_signers(&signers));
+        }
+        self.save(&keys)
+    }
+
+    // The keys of the contacts we have compared safety numbers with.
+    pub fn verified_keys(&self) -> Vec<Key> {
+        self.keys.lock().unwrap().values().filter(|&&(_, verified)| verified).map(|&(k, _)| k).collect()
+    }
+
+    // Forgets that any key was verified, so each must be checked again.
+    pub fn unverify_all(&self) -> Result<(), String> {
+        let mut keys = self.keys.lock().unwrap();
+        for v in keys.values_mut() {
+            v.1 = false;
+        }
+        self.save(&keys)
+    }
+
+    // Every change of `handle`'s key, oldest first.
+    pub fn history(&self, handle: &str) -> Vec<(u64, Key, Key)> {
+        let mut contents = String::new();
+        if let Some(mut file) = self.dir.as_ref().and_then(|d| File::open(d.join("key_history")).ok()) {
+            let _ = file.read_to_string(&mut contents);
+        }
+
+        contents.lines()
+            .filter_map(|l| {
+                let parts: Vec<&str> = l.split_whitespace().collect();
+                if parts.len() != 4 || parts[1] != handle {
+                    return None;
+                }
+                match (parts[0].parse().ok(), parse_key(parts[2]), parse_key(parts[3])) {
+                    (Some(t), Some(old), Some(new)) => Some((t, old, new)),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    fn record_transition(&self, handle: &str, old: &Key, new: &Key) -> Result<(), String> {
+        let dir = try!(self.dir.as_ref().ok_or("Cannot find home directory".to_string()));
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut file = try!(OpenOptions::new().create(true).append(true).open(dir.join("key_history"))
+            .map_err(|e| e.to_string()));
+        writeln!(file, "{} {} {} {}", time, handle, old.to_hex(), new.to_hex()).map_err(|e| e.to_string())
+    }
+
+    fn save(&self, keys: &HashMap<String, (Key, bool)>) -> Result<(), String> {
+        let dir = try!(self.dir.as_ref().ok_or("Cannot find home directory".to_string()));
+        try!(fs::create_dir_all(dir).map_err(|e| e.to_string()));
+
+        let mut handles: Vec<&String> = keys.keys().collect();
+        handles.sort();
+        let contents: String = handles.into_iter()
+            .map(|h| {
+                let (ref key, verified) = keys[h];
+                format!("{} {} {}\n", h, key.to_hex(), if verified { "verified" } else { "unverified" })
+            })
+            .collect();
+        let mut file = try!(File::create(dir.join("known_keys")).map_err(|e| e.to_string()));
+        file.write_all(contents.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn save_signers(&self, signers: &HashMap<String, Key>) -> Result<(), String> {
+        let dir = try!(self.dir.as_ref().ok_or("Cannot find home directory".to_string()));
+        try!(fs::create_dir_all(dir).map_err(|e| e.to_string()));
+
+        let mut handles: Vec<&String> = signers.keys().collect();
+        handles.sort();
+        let contents: String = handles.into_iter()
+            .map(|h| format!("{} {}\n", h, signers[h].to_hex()))
+            .collect();
+        let mut file = try!(File::create(dir.join("signing_keys")).map_err(|e| e.to_string()));
+        file.write_all(contents.as_bytes()).map_err(|e| e.to_string())
+    }
+}
+
+fn parse_key(hex: &str) -> Option<Key> {
+    match hex.from_hex() {
+        Ok(ref bytes) if bytes.len() == 32 => {
+            let mut key = [0u8; 32];
+            key.copy_from_slice(bytes);
+            Some(key)
+        },
+        _ => None,
+    }
+}
@@ -1,19 +1,183 @@
 #![allow(dead_code)]
 
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::str;
 use std::sync::mpsc::Sender;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use rustc_serialize::json;
+use rustc_serialize::hex::ToHex;
+use rand;
 
 use state::User;
 use state::Route;
-use crypto_lib::Crypto;
+use state::Addr;
+use crypto_lib::{self, Crypto};
 use crypto_lib::Key;
 
 #[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
 pub struct TextMessage {
+    pub id: u64,
     pub text: String,
     pub sender: User,
     pub conv_id: u64,
+    pub seq: u64, // counts up from 0 for each sender in a conversation
+    pub signing_key: Key,
+    pub signature: Vec<u8>, // by signing_key, over the rest of the message
+}
+
+impl TextMessage {
+    pub fn new(text: String, sender: User, conv_id: u64, seq: u64) -> TextMessage {
+        TextMessage {
+            id: rand::random::<u64>(),
+            text: text,
+            sender: sender,
+            conv_id: conv_id,
+            seq: seq,
+            signing_key: [0u8; 32],
+            signature: Vec::new(),
+        }
+    }
+
+    pub fn signed(mut self, crypto: &Crypto) -> TextMessage {
+        self.signing_key = crypto.signing_key;
+        self.signature = crypto.sign(&self.signed_data());
+        self
+    }
+
+    pub fn has_valid_signature(&self) -> bool {
+        crypto_lib::verify(&self.signing_key, &self.signed_data(), &self.signature)
+    }
+
+    // A deniable message carries a MAC only it and the recipient could have
+    // made in place of a signature, and the ephemeral key the MAC's key came
+    // from in place of the signing key. Returns the MAC key, which is
+    // published once the message arrives so the MAC proves nothing after.
+    pub fn deniable(mut self, crypto: &Crypto, recipient: &Key) -> (TextMessage, Key) {
+        let (ephemeral, key) = crypto.deniable_key_to(recipient);
+        self.signing_key = ephemeral;
+        self.signature = crypto_lib::mac(&key, &self.signed_data()).to_vec();
+        (self, key)
+    }
+
+    // Ed25519 signatures are twice as long as a MAC.
+    pub fn is_deniable(&self) -> bool {
+        self.signature.len() == 32
+    }
+
+    pub fn has_valid_mac(&self, crypto: &Crypto) -> bool {
+        let key = crypto.deniable_key_from(&self.sender.public_key, &self.signing_key);
+        crypto_lib::constant_time_eq(&crypto_lib::mac(&key, &self.signed_data()), &self.signature)
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut unsigned = self.clone();
+        unsigned.signature = Vec::new();
+        json::encode(&unsigned).unwrap().into_bytes()
+    }
+}
+
+// Asks a peer whose conversation with us is out of step to start over,
+// expecting `seq` as our next message in it. Signed like a text message so
+// only the sender can reset their own numbering, and stamped so an old
+// reset can't be replayed.
+#[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
+pub struct SessionReset {
+    pub sender: String, // handle
+    pub conv_id: u64,
+    pub seq: u64,
+    pub at: u64, // seconds since the epoch
+    pub signing_key: Key,
+    pub signature: Vec<u8>, // by signing_key, over the rest of the reset
+}
+
+impl SessionReset {
+    pub fn new(sender: String, conv_id: u64, seq: u64) -> SessionReset {
+        SessionReset {
+            sender: sender,
+            conv_id: conv_id,
+            seq: seq,
+            at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            signing_key: [0u8; 32],
+            signature: Vec::new(),
+        }
+    }
+
+    pub fn signed(mut self, crypto: &Crypto) -> SessionReset {
+        self.signing_key = crypto.signing_key;
+        self.signature = crypto.sign(&self.signed_data());
+        self
+    }
+
+    pub fn has_valid_signature(&self) -> bool {
+        crypto_lib::verify(&self.signing_key, &self.signed_data(), &self.signature)
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut unsigned = self.clone();
+        unsigned.signature = Vec::new();
+        json::encode(&unsigned).unwrap().into_bytes()
+    }
+}
+
+// The relays the server knows of, keyed by public key, as the changes since
+// the list at `base`, or the whole list when `base` is 0. The digest is of
+// the list once they are applied, so a client whose copy went wrong finds out
+// and asks for all of it.
+#[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
+pub struct RelayDirectory {
+    pub version: u64,
+    pub base: u64,
+    pub added: Vec<(Addr, Key)>, // new, or moved to another address
+    pub removed: Vec<Key>,
+    pub digest: Key,
+    pub signing_key: Key,
+    pub signature: Vec<u8>, // by signing_key, over the rest of the directory
+}
+
+impl RelayDirectory {
+    pub fn signed(mut self, crypto: &Crypto) -> RelayDirectory {
+        self.signing_key = crypto.signing_key;
+        self.signature = crypto.sign(&self.signed_data());
+        self
+    }
+
+    pub fn has_valid_signature(&self) -> bool {
+        crypto_lib::verify(&self.signing_key, &self.signed_data(), &self.signature)
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut unsigned = self.clone();
+        unsigned.signature = Vec::new();
+        json::encode(&unsigned).unwrap().into_bytes()
+    }
+
+    // The list at `version`, given the one at `base`.
+    pub fn apply(&self, relays: &BTreeMap<Key, Addr>) -> Result<BTreeMap<Key, Addr>, String> {
+        let mut relays = if self.base == 0 { BTreeMap::new() } else { relays.clone() };
+        for key in self.removed.iter() {
+            relays.remove(key);
+        }
+        for &(addr, key) in self.added.iter() {
+            relays.insert(key, addr);
+        }
+        if relay_digest(&relays) != self.digest {
+            return Err("The relay directory does not match its digest".to_string());
+        }
+        Ok(relays)
+    }
+}
+
+pub fn relay_digest(relays: &BTreeMap<Key, Addr>) -> Key {
+    let entries: Vec<String> = relays.iter().map(|(k, a)| format!("{} {}\n", k.to_hex(), a)).collect();
+    crypto_lib::hash(&[entries.concat().as_bytes()])
+}
+
+// Names a sealed broadcast by its ciphertext, so a subscriber that can't
+// open it can still say which one it means.
+pub fn sealed_id(sealed: &[u8]) -> u64 {
+    crypto_lib::hash(&[sealed]).iter().take(8).fold(0, |id, &b| id << 8 | b as u64)
 }
 
 impl ToString for TextMessage {
@@ -22,27 +186,291 @@ impl ToString for TextMessage {
     }
 }
 
+#[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
+pub enum ErrorCode {
+    AuthFailed,
+    UserNotFound,
+    HandleTaken,
+    RateLimited,
+    BadRequest,
+    Conflict,
+    UpgradeRequired (u16), // the oldest protocol version accepted
+    NotFound,
+    Forbidden,
+    Busy (u16), // seconds until the server expects to take requests again
+    Archived, // the account went unused for too long and was closed
+    QuotaExceeded,
+}
+
+impl ToString for ErrorCode {
+    fn to_string(&self) -> String {
+        match *self {
+            ErrorCode::AuthFailed => "Incorrect password.".to_string(),
+            ErrorCode::UserNotFound => "User does not exist.".to_string(),
+            ErrorCode::HandleTaken => "Username already in use.".to_string(),
+            ErrorCode::RateLimited => "Too many requests, try again later.".to_string(),
+            ErrorCode::BadRequest => "The server could not understand the request.".to_string(),
+            ErrorCode::Conflict => "Something else changed this first, fetch it and try again.".to_string(),
+            ErrorCode::NotFound => "That does not exist.".to_string(),
+            ErrorCode::Forbidden => "You are not allowed to do that.".to_string(),
+            ErrorCode::Busy(secs) => format!("The server is busy, try again in {} seconds.", secs),
+            ErrorCode::Archived => "That account was archived after going unused for too long.".to_string(),
+            ErrorCode::QuotaExceeded => "That would go over the quota.".to_string(),
+            ErrorCode::UpgradeRequired(v) => format!(
+                "The server no longer supports this version of secmsg, it requires protocol version {} or newer. \
+                 Update secmsg to keep using it.", v),
+        }
+    }
+}
+
+// Why a layer wasn't passed on, sent back by the hop in place of its
+// acknowledgement as the code after net_lib's UNDELIVERED.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Undelivered {
+    RecipientUnknown,
+    MailboxFull,
+    RouteExhausted,
+    Blocked,
+}
+
+impl Undelivered {
+    pub fn from_code(code: u8) -> Option<Undelivered> {
+        match code {
+            0 => Some(Undelivered::RecipientUnknown),
+            1 => Some(Undelivered::MailboxFull),
+            2 => Some(Undelivered::RouteExhausted),
+            3 => Some(Undelivered::Blocked),
+            _ => None,
+        }
+    }
+}
+
+impl ToString for Undelivered {
+    fn to_string(&self) -> String {
+        match *self {
+            Undelivered::RecipientUnknown => "The server does not know the recipient".to_string(),
+            Undelivered::MailboxFull => "The recipient's mailbox is full".to_string(),
+            Undelivered::RouteExhausted => "No hop on the route could pass the message on".to_string(),
+            Undelivered::Blocked => "The recipient has blocked you".to_string(),
+        }
+    }
+}
+
 #[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
 pub enum ResponseType {
     User (User),
     Connection (Route),
+    RelayedConnection (Route), // through the server's relay of last resort
+    Connections (Vec<Route>),
+    RelayTest (bool), // whether the server could reach the relay
     PublicKey (Key),
-    Error (String),
+    Unregistered,
+    PasswordChanged,
+    Presence (bool, Option<u64>), // online, seconds since last seen
+    Relays (RelayDirectory),
+    Reflexive (Addr), // the address the server saw the request come from
+    Mailbox (Vec<Message>), // messages held for us, each sealed to our key
+    Contacts (u64, Vec<u8>), // version, encrypted contact list
+    Synced (u64, Vec<u8>), // version, client state sealed under a key derived from ours
+    Updates (Vec<(u64, Update)>, u64, bool), // what was missed since the cursor, the latest cursor, whether none were lost
+    Blocked (String, bool), // other user's name, whether they are now blocked
+    Handles (Vec<String>, bool), // search results, whether there are more pages
+    Subscribers (Vec<String>),
+    Subscribed (bool), // whether we are now subscribed
+    BroadcastSent (usize), // how many subscribers it went to
+    Namespace (Vec<String>, Vec<String>, Vec<String>, Vec<(String, String)>), // admins, members, handles invited but not registered, policy overrides
+    SharedFiles (Vec<SharedFile>), // with only the entry keys sealed to us
+    Members (Vec<(String, Key)>), // handle and public key of everyone in a namespace
+    Error (ErrorCode),
 }
 
 #[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
 pub enum ToServer {
-    Login (String, String, Key), // username, password, public key
-    Register (String, String, Key), // username, password, public key
-    Connect (String, Key), // other user's name, public key
+    Login (String, String, Key, u16), // username, password, public key, listening port
+    Register (String, String, Key, u16, bool), // username, password, public key, listening port, discoverable
+    Search (String, usize, Key), // handle prefix, page, public key
+    CreateBroadcast (String, String, String, Key), // username, password, list name, public key
+    Subscribe (String, String, String, String, bool, Key), // username, password, owner, list name, subscribe, public key
+    GetSubscribers (String, String, String, Key), // username, password, list name, public key
+    Broadcast (String, String, String, Vec<u8>, Key), // username, password, list name, sealed message, public key
+    CreateNamespace (String, String, String, Key), // username, password, namespace, public key
+    ManageNamespace (String, String, String, NamespaceChange, Key), // username, password, namespace, change, public key
+    SearchNamespace (String, String, String, String, usize, Key), // username, password, namespace, handle prefix, page, public key
+    SharedFiles (String, String, String, SharedChange, Key), // username, password, namespace, change, public key
+    Unregister (String, String, Key), // username, password, public key
+    ChangePassword (String, String, String, Key), // username, old password, new password, public key
+    ChangeKey (String, String, Key, Key), // username, password, new public key, current public key
+    Rename (String, String, String, Key), // username, password, new username, public key
+    Heartbeat (String, u64, Key), // username, unix time, proof
+    Presence (String, Key), // other user's name, public key
+    GetRelays (u64, Key), // version of the relay directory we have or 0, public key
+    GetContacts (String, String, Key), // username, password, public key
+    Block (String, String, String, Key), // username, password, user to block, public key
+    Unblock (String, String, String, Key), // username, password, user to unblock, public key
+    PutContacts (String, String, u64, Vec<u8>, Key), // username, password, version replaced, encrypted contact list, public key
+    Connect (String, Key, Option<(String, u64, Key)>), // other user's name, public key, asker's username, time, proof
+    ConnectOrRelay (String, Key, Option<(String, u64, Key)>), // as Connect, for a route that may use the server's relay
+    ConnectDisjoint (String, Key, usize, Option<(String, u64, Key)>), // as Connect, with the number of routes
     PublicKey (Key), // public key
+    Deposit (String, u64, Key, Vec<Message>), // username, time, proof, messages carried for unreachable peers
+    RelayTest (Key, u64, usize), // public key, nonce, payload size
+    Cover (Vec<u8>), // random padding, dropped on arrival
+    WhoAmI (Key), // public key
+    Fetch (String, u64, Key), // username, unix time, proof
+    GetSynced (String, u64, Key, SyncSlot), // username, unix time, proof, state to get
+    PutSynced (String, u64, Key, SyncSlot, u64, Vec<u8>), // username, unix time, proof, state to replace, version replaced, encrypted state
+    Watch (String, u64, Key, Topic, u64), // username, unix time, proof, topic, cursor of the last update seen or 0
+    Unwatch (String, u64, Key, Topic), // username, unix time, proof, topic
+}
+
+// What a client can watch over its persistent connection, instead of polling.
+#[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
+pub enum Topic {
+    Presence (Vec<String>), // handles to watch, replacing any watched before
+    Directory, // handles joining or leaving the public directory
+}
+
+#[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
+pub enum Update {
+    Online (String),
+    Offline (String),
+    Listed (String),
+    Unlisted (String),
+}
+
+impl Topic {
+    pub fn covers(&self, update: &Update) -> bool {
+        match (self, update) {
+            (&Topic::Presence(ref handles), &Update::Online(ref h)) |
+            (&Topic::Presence(ref handles), &Update::Offline(ref h)) => handles.contains(h),
+            (&Topic::Directory, &Update::Listed(_)) | (&Topic::Directory, &Update::Unlisted(_)) => true,
+            _ => false,
+        }
+    }
+
+    // Whether this and `other` are the same kind of subscription, so one
+    // replaces the other.
+    pub fn same_kind(&self, other: &Topic) -> bool {
+        match (self, other) {
+            (&Topic::Presence(_), &Topic::Presence(_)) | (&Topic::Directory, &Topic::Directory) => true,
+            _ => false,
+        }
+    }
+}
+
+// Client state the server keeps for every device logged in as a user, each
+// sealed under a key only the user's devices can derive.
+#[derive(Clone, Copy, RustcEncodable, RustcDecodable, PartialEq, Eq, Hash)]
+pub enum SyncSlot {
+    Notes, // notes to self
+    Rules, // message filters
+    Labels, // labels on conversations
+}
+
+impl SyncSlot {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SyncSlot::Notes => "notes",
+            SyncSlot::Rules => "rules",
+            SyncSlot::Labels => "labels",
+        }
+    }
+}
+
+// What members can do with a namespace's shared files.
+#[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
+pub enum SharedChange {
+    List,
+    Members, // who a new entry's key should be sealed to
+    Add (SharedFile),
+    Get (u64), // entry id
+    Remove (u64), // entry id, by the member who added it or an admin
+}
+
+// An entry in a namespace's shared files. The server sees who added it and how
+// big it is, and the rest is sealed under a key of its own, which is sealed to
+// each member it was shared with. The file itself is sent by whoever added it.
+#[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
+pub struct SharedFile {
+    pub id: u64,
+    pub owner: String, // handle
+    pub size: u64,
+    pub meta: Vec<u8>, // SharedMeta sealed under the entry key
+    pub keys: Vec<(String, Vec<u8>)>, // handle, entry key sealed to them
+}
+
+#[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
+pub struct SharedMeta {
+    pub name: String,
+    pub digest: Key, // SHA-256 of the file, which its offer must match
+    pub mime: String,
+}
+
+// Changes an admin can make to a namespace. Members are named by their
+// handle within it, admins by their full handle.
+#[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
+pub enum NamespaceChange {
+    Show,
+    Invite (String), // lets the handle be registered
+    Remove (String), // withdraws an invitation, or deletes the member's account
+    AddAdmin (String),
+    RemoveAdmin (String),
+    SetPolicy (String, String), // setting, value, or an empty value for the server's
 }
 
 #[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
 pub enum ToUser {
     ServerResponse (ResponseType),
     Text (TextMessage),
-    // File
+    Ack (u64), // id of a text message that arrived
+    ReadReceipt (u64), // id of a text message that was shown to the recipient
+    Typing (String), // handle of the user composing a message
+    Broadcast (String, String, Vec<u8>), // owner, list name, message sealed under the list key
+    BroadcastKey (String, String, Key), // owner, list name, list key
+    DecryptFailed (String, u64), // handle of a subscriber who couldn't open a broadcast, its sealed_id
+    SessionReset (SessionReset),
+    MacKeys (Vec<Key>), // keys of deniable messages that arrived, published
+    RelayTest (u64, Vec<u8>), // nonce, payload
+    Cover (Vec<u8>), // random padding, dropped on arrival
+    FileOffer (FileOffer),
+    FileAccept (u64, String, u64), // transfer id, handle of the recipient, first chunk it is missing
+    FileChunk (u64, u64, Vec<u8>), // transfer id, chunk index, chunk sealed under the offer's key
+    FileCancel (u64), // transfer id
+    ServerNotice (String, Key), // text, proof it came from the server
+    SharedFileRequest (String, u64, String), // namespace, shared file id, handle of the member asking for it
+    Update (u64, Update), // cursor, a change to something we watch
+}
+
+// A file someone wants to send. The key only travels inside the offer,
+// which is end to end encrypted like any other message.
+#[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
+pub struct FileOffer {
+    pub id: u64,
+    pub sender: String, // handle
+    pub name: String,
+    pub size: u64,
+    pub chunk_size: u64,
+    pub digest: Key, // SHA-256 of the whole file
+    pub key: Key, // seals every chunk
+    pub mime: String, // e.g. audio/ogg
+    pub kind: Attachment,
+}
+
+// What a file offer is for, so the recipient knows how to show it.
+#[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
+pub enum Attachment {
+    File,
+    Voice (u32), // length in seconds
+    Image (String, Vec<u8>), // MIME type of the thumbnail, thumbnail sealed under the offer's key or empty
+}
+
+impl FileOffer {
+    pub fn chunks(&self) -> u64 {
+        match self.size.checked_div(self.chunk_size) {
+            Some(whole) => whole + (self.size % self.chunk_size != 0) as u64,
+            None => 0,
+        }
+    }
 }
 
 #[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
@@ -51,33 +479,107 @@ pub enum MessageType {
     User(ToUser),
 }
 
-#[derive(Clone, RustcEncodable, RustcDecodable)]
+// Every hop sends higher priority messages first, and retries them harder.
+#[derive(Clone, Copy, RustcEncodable, RustcDecodable, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Bulk,
+    Normal,
+    Urgent,
+}
+
+pub const PRIORITY_LEVELS: usize = 3;
+
+// Every layer's ciphertext follows the layer's routing header, which is
+// sealed along with it as associated data. A hop or carrier that changes
+// where a layer goes, how urgent it is, its id or its age can't do so
+// without the layer failing to open. The header is the next hop's key, its
+// address as IPv6 and port, the priority, the id and the time the layer was
+// made, so it is always the same length.
+pub const ROUTING_HEADER_LEN: usize = 32 + 18 + 1 + 8 + 8;
+
+#[derive(Clone, RustcEncodable, RustcDecodable, PartialEq)]
 pub struct Message {
     pub data: Vec<u8>,
-    pub next_hop: Option<String>,
+    pub next_hop: Option<Addr>,
+    pub next_key: Option<Key>,
+    pub priority: Priority,
+    pub id: u64, // random, so a replayed layer can be recognised
+    pub sent_at: u64, // unix time the layer was made
 }
 
 impl Message {
     pub fn new(msg_type: MessageType, route: Route, crypto: &Crypto) -> Message {
+        Message::with_priority(msg_type, route, crypto, Priority::Normal)
+    }
+
+    // Every layer carries the priority so relays can honor it too.
+    pub fn with_priority(msg_type: MessageType, route: Route, crypto: &Crypto, priority: Priority) -> Message {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
         route.into_iter().fold(Message {
             data: json::encode(&msg_type).unwrap().into_bytes(),
-            next_hop: None
+            next_hop: None,
+            next_key: None,
+            priority: priority,
+            id: rand::random::<u64>(),
+            sent_at: now,
         }, |m, r| {
-            Message {
-                data: crypto.encrypt(&r.1, json::encode(&m).unwrap().as_bytes()).unwrap(),
-                next_hop: Some(r.0)
-            }
+            let mut layer = Message {
+                data: Vec::new(),
+                next_hop: Some(r.0),
+                next_key: Some(r.1),
+                priority: priority,
+                id: rand::random::<u64>(),
+                sent_at: now,
+            };
+            let header = layer.routing_header();
+            layer.data = header.clone();
+            layer.data.extend(crypto.encrypt_compressed(&r.1, &header, json::encode(&m).unwrap().as_bytes()).unwrap());
+            layer
         })
     }
+
+    // Opens the layer in `data`, which was sealed to `crypto`.
+    pub fn open(data: &[u8], crypto: &Crypto) -> Result<Message, String> {
+        if data.len() < ROUTING_HEADER_LEN {
+            return Err("Failed to decrypt message".to_string());
+        }
+        let (header, sealed) = data.split_at(ROUTING_HEADER_LEN);
+        let decrypted = try!(crypto.decrypt(header, sealed).map_err(|_| "Failed to decrypt message".to_string()));
+        let text = try!(str::from_utf8(&decrypted).map_err(|e| e.to_string()));
+        json::decode(text).map_err(|e| e.to_string())
+    }
+
+    pub fn routing_header(&self) -> Vec<u8> {
+        let mut header = self.next_key.unwrap_or([0u8; 32]).to_vec();
+        let (ip, port) = match self.next_hop.map(|a| a.0) {
+            Some(SocketAddr::V4(a)) => (a.ip().to_ipv6_mapped().octets(), a.port()),
+            Some(SocketAddr::V6(a)) => (a.ip().octets(), a.port()),
+            None => ([0u8; 16], 0),
+        };
+        header.extend_from_slice(&ip);
+        header.extend_from_slice(&[(port >> 8) as u8, port as u8]);
+        header.push(self.priority as u8);
+        for n in [self.id, self.sent_at].iter() {
+            header.extend((0..8).map(|i| (n >> (56 - 8 * i)) as u8));
+        }
+        header
+    }
+
+    // Whether the routing fields we were given are the ones the layer in
+    // `data` was sealed with.
+    pub fn header_matches(&self) -> bool {
+        self.data.len() >= ROUTING_HEADER_LEN && self.data[..ROUTING_HEADER_LEN] == self.routing_header()[..]
+    }
 }
 
-type Response = Sender<Result<Option<Message>, String>>;
+pub type Response = Sender<Result<Option<Message>, String>>;
 
 #[derive(Clone)]
 pub struct MessageContainer {
     pub msg: Message,
     pub response: Option<Response>,
     pub needs_response: bool,
+    pub reply_crypto: Option<Crypto>, // opens the response when it isn't sent to our own key
 }
 
 impl MessageContainer {
@@ -86,6 +588,7 @@ impl MessageContainer {
             msg: msg,
             response: res,
             needs_response: need_res,
+            reply_crypto: None,
         }
     }
 }
\ No newline at end of file
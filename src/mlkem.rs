@@ -0,0 +1,385 @@
+use rand::{Rng, OsRng};
+use crypto::digest::Digest;
+use crypto::sha3::Sha3;
+
+use crypto_lib::{self, Key};
+
+// ML-KEM-768 from FIPS 203, the lattice KEM standardized from Kyber. It is
+// only used alongside X25519, so a break in either still leaves the other.
+// Polynomials have N coefficients mod Q and vectors K of them. Coefficients
+// are kept reduced, in 0..Q, and secret ones are never branched on.
+const N: usize = 256;
+const Q: u32 = 3329;
+const K: usize = 3;
+const ETA1: usize = 2;
+const ETA2: usize = 2;
+const DU: usize = 10;
+const DV: usize = 4;
+
+const POLY_BYTES: usize = 384;
+pub const PUBLIC_KEY_LEN: usize = POLY_BYTES * K + 32;
+pub const SECRET_KEY_LEN: usize = 2 * POLY_BYTES * K + 96;
+pub const CIPHERTEXT_LEN: usize = 32 * (DU * K + DV);
+
+type Poly = [u32; N];
+type PolyVec = [Poly; K];
+
+// Returns the decapsulation and encapsulation keys, in the order of
+// crypto_lib::gen_key_pair.
+pub fn keypair() -> (Vec<u8>, Vec<u8>) {
+    let mut rng = OsRng::new().unwrap();
+    let mut d = [0u8; 32];
+    let mut z = [0u8; 32];
+    rng.fill_bytes(&mut d);
+    rng.fill_bytes(&mut z);
+
+    let (ek, mut dk) = pke_keypair(&d);
+    dk.extend_from_slice(&ek);
+    dk.extend_from_slice(&h(&ek));
+    dk.extend_from_slice(&z);
+    crypto_lib::zeroize(&mut d);
+    crypto_lib::zeroize(&mut z);
+    (dk, ek)
+}
+
+// Returns the ciphertext to send and the shared secret.
+pub fn encapsulate(ek: &[u8]) -> Result<(Vec<u8>, Key), String> {
+    if ek.len() != PUBLIC_KEY_LEN {
+        return Err("The post-quantum key is the wrong size".to_string());
+    }
+    // The key must decode to coefficients that were already reduced.
+    for i in 0..K {
+        let chunk = &ek[i * POLY_BYTES..(i + 1) * POLY_BYTES];
+        if encode(&decode(chunk, 12), 12) != chunk {
+            return Err("The post-quantum key is malformed".to_string());
+        }
+    }
+
+    let mut m = [0u8; 32];
+    OsRng::new().unwrap().fill_bytes(&mut m);
+    let (shared, mut r) = g(&[&m, &h(ek)]);
+    let c = pke_encrypt(ek, &m, &r);
+    crypto_lib::zeroize(&mut m);
+    crypto_lib::zeroize(&mut r);
+    Ok((c, shared))
+}
+
+// A ciphertext that wasn't made for this key gives a secret derived from z
+// instead of an error, so nothing is learned from a forged one.
+pub fn decapsulate(dk: &[u8], c: &[u8]) -> Result<Key, String> {
+    if dk.len() != SECRET_KEY_LEN || c.len() != CIPHERTEXT_LEN {
+        return Err("The post-quantum ciphertext is the wrong size".to_string());
+    }
+    let (dk_pke, rest) = dk.split_at(POLY_BYTES * K);
+    let (ek, rest) = rest.split_at(PUBLIC_KEY_LEN);
+    let (hash, z) = rest.split_at(32);
+
+    let mut m = pke_decrypt(dk_pke, c);
+    let (mut shared, mut r) = g(&[&m, hash]);
+    let rejected = j(&[z, c]);
+    let same = crypto_lib::constant_time_eq(&pke_encrypt(ek, &m, &r), c);
+    let mask = (same as u8).wrapping_sub(1); // all ones if the ciphertext was forged
+    for (s, r) in shared.iter_mut().zip(rejected.iter()) {
+        *s ^= mask & (*s ^ r);
+    }
+    crypto_lib::zeroize(&mut m);
+    crypto_lib::zeroize(&mut r);
+    Ok(shared)
+}
+
+fn pke_keypair(d: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let (rho, sigma) = g(&[d, &[K as u8]]);
+    let a = matrix(&rho, false);
+    let mut s = noise_vec(&sigma, 0, ETA1);
+    let mut e = noise_vec(&sigma, K as u8, ETA1);
+    for i in 0..K {
+        ntt(&mut s[i]);
+        ntt(&mut e[i]);
+    }
+
+    let mut ek = Vec::with_capacity(PUBLIC_KEY_LEN);
+    for i in 0..K {
+        let t = add(&dot(&a[i], &s), &e[i]);
+        ek.extend(encode(&t, 12));
+    }
+    ek.extend_from_slice(&rho);
+    let dk = s.iter().flat_map(|p| encode(p, 12)).collect();
+    (ek, dk)
+}
+
+fn pke_encrypt(ek: &[u8], m: &[u8], r: &[u8]) -> Vec<u8> {
+    let mut t = [[0u32; N]; K];
+    for i in 0..K {
+        t[i] = decode(&ek[i * POLY_BYTES..(i + 1) * POLY_BYTES], 12);
+    }
+    let a = matrix(&ek[POLY_BYTES * K..], true);
+    let mut y = noise_vec(r, 0, ETA1);
+    let e1 = noise_vec(r, K as u8, ETA2);
+    let e2 = cbd(&prf(r, 2 * K as u8, ETA2), ETA2);
+    for i in 0..K {
+        ntt(&mut y[i]);
+    }
+
+    let mut c = Vec::with_capacity(CIPHERTEXT_LEN);
+    for i in 0..K {
+        let mut u = dot(&a[i], &y);
+        inv_ntt(&mut u);
+        c.extend(encode(&compress(&add(&u, &e1[i]), DU), DU));
+    }
+    let mut v = dot(&t, &y);
+    inv_ntt(&mut v);
+    let msg = decompress(&decode(m, 1), 1);
+    c.extend(encode(&compress(&add(&add(&v, &e2), &msg), DV), DV));
+    c
+}
+
+fn pke_decrypt(dk_pke: &[u8], c: &[u8]) -> Vec<u8> {
+    let mut s = [[0u32; N]; K];
+    let mut u = [[0u32; N]; K];
+    let chunk = 32 * DU;
+    for i in 0..K {
+        s[i] = decode(&dk_pke[i * POLY_BYTES..(i + 1) * POLY_BYTES], 12);
+        u[i] = decompress(&decode(&c[i * chunk..(i + 1) * chunk], DU), DU);
+        ntt(&mut u[i]);
+    }
+    let v = decompress(&decode(&c[K * chunk..], DV), DV);
+    let mut su = dot(&s, &u);
+    inv_ntt(&mut su);
+    encode(&compress(&sub(&v, &su), 1), 1)
+}
+
+// The public matrix, sampled from rho straight into the NTT domain.
+// Encryption uses its transpose.
+fn matrix(rho: &[u8], transpose: bool) -> [PolyVec; K] {
+    let mut a = [[[0u32; N]; K]; K];
+    for i in 0..K {
+        for j in 0..K {
+            let (x, y) = if transpose { (i, j) } else { (j, i) };
+            a[i][j] = sample_ntt(rho, x as u8, y as u8);
+        }
+    }
+    a
+}
+
+fn sample_ntt(rho: &[u8], x: u8, y: u8) -> Poly {
+    let mut xof = Sha3::shake_128();
+    xof.input(rho);
+    xof.input(&[x, y]);
+    let mut p = [0u32; N];
+    let mut n = 0;
+    let mut block = [0u8; 168];
+    while n < N {
+        xof.result(&mut block);
+        for c in block.chunks(3) {
+            let d1 = c[0] as u32 | (c[1] as u32 & 0x0f) << 8;
+            let d2 = (c[1] as u32) >> 4 | (c[2] as u32) << 4;
+            for &d in [d1, d2].iter() {
+                if d < Q && n < N {
+                    p[n] = d;
+                    n += 1;
+                }
+            }
+        }
+    }
+    p
+}
+
+fn noise_vec(seed: &[u8], first: u8, eta: usize) -> PolyVec {
+    let mut v = [[0u32; N]; K];
+    for i in 0..K {
+        v[i] = cbd(&prf(seed, first + i as u8, eta), eta);
+    }
+    v
+}
+
+// The centered binomial distribution, each coefficient the difference of
+// two sums of eta bits.
+fn cbd(bytes: &[u8], eta: usize) -> Poly {
+    let bit = |i: usize| (bytes[i / 8] >> (i % 8) & 1) as u32;
+    let mut p = [0u32; N];
+    for i in 0..N {
+        let (mut x, mut y) = (0, 0);
+        for k in 0..eta {
+            x += bit(2 * i * eta + k);
+            y += bit(2 * i * eta + eta + k);
+        }
+        p[i] = (x + Q - y) % Q;
+    }
+    p
+}
+
+fn prf(seed: &[u8], n: u8, eta: usize) -> Vec<u8> {
+    let mut out = vec![0u8; 64 * eta];
+    let mut shake = Sha3::shake_256();
+    shake.input(seed);
+    shake.input(&[n]);
+    shake.result(&mut out);
+    out
+}
+
+fn g(parts: &[&[u8]]) -> (Key, Key) {
+    let mut sha = Sha3::sha3_512();
+    for p in parts {
+        sha.input(p);
+    }
+    let mut out = [0u8; 64];
+    sha.result(&mut out);
+    let (mut a, mut b) = ([0u8; 32], [0u8; 32]);
+    a.copy_from_slice(&out[..32]);
+    b.copy_from_slice(&out[32..]);
+    crypto_lib::zeroize(&mut out);
+    (a, b)
+}
+
+fn h(data: &[u8]) -> Key {
+    let mut sha = Sha3::sha3_256();
+    sha.input(data);
+    let mut out = [0u8; 32];
+    sha.result(&mut out);
+    out
+}
+
+fn j(parts: &[&[u8]]) -> Key {
+    let mut shake = Sha3::shake_256();
+    for p in parts {
+        shake.input(p);
+    }
+    let mut out = [0u8; 32];
+    shake.result(&mut out);
+    out
+}
+
+// 17 is a primitive 256th root of unity mod Q. The NTT visits its powers
+// in bit reversed order.
+fn zeta(i: usize, odd: bool) -> u32 {
+    let rev = (i as u8).reverse_bits() as u32 >> 1;
+    pow(17, if odd { 2 * rev + 1 } else { rev })
+}
+
+fn pow(mut base: u32, mut exp: u32) -> u32 {
+    let mut result = 1;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = result * base % Q;
+        }
+        base = base * base % Q;
+        exp >>= 1;
+    }
+    result
+}
+
+fn ntt(f: &mut Poly) {
+    let mut k = 1;
+    let mut len = 128;
+    while len >= 2 {
+        for start in (0..N).step_by(2 * len) {
+            let z = zeta(k, false);
+            k += 1;
+            for j in start..start + len {
+                let t = z * f[j + len] % Q;
+                f[j + len] = (f[j] + Q - t) % Q;
+                f[j] = (f[j] + t) % Q;
+            }
+        }
+        len /= 2;
+    }
+}
+
+fn inv_ntt(f: &mut Poly) {
+    let mut k = 127;
+    let mut len = 2;
+    while len <= 128 {
+        for start in (0..N).step_by(2 * len) {
+            let z = zeta(k, false);
+            k -= 1;
+            for j in start..start + len {
+                let t = f[j];
+                f[j] = (t + f[j + len]) % Q;
+                f[j + len] = z * ((f[j + len] + Q - t) % Q) % Q;
+            }
+        }
+        len *= 2;
+    }
+    for c in f.iter_mut() {
+        *c = *c * 3303 % Q; // 128^-1
+    }
+}
+
+// The product of two polynomials in the NTT domain, which pairs up their
+// coefficients as degree one polynomials mod X^2 - zeta.
+fn multiply(f: &Poly, g: &Poly) -> Poly {
+    let mut h = [0u32; N];
+    for i in 0..N / 2 {
+        let z = zeta(i, true);
+        let (a0, a1, b0, b1) = (f[2 * i], f[2 * i + 1], g[2 * i], g[2 * i + 1]);
+        h[2 * i] = (a0 * b0 % Q + a1 * b1 % Q * z) % Q;
+        h[2 * i + 1] = (a0 * b1 + a1 * b0) % Q;
+    }
+    h
+}
+
+fn dot(a: &PolyVec, b: &PolyVec) -> Poly {
+    let mut sum = [0u32; N];
+    for i in 0..K {
+        sum = add(&sum, &multiply(&a[i], &b[i]));
+    }
+    sum
+}
+
+fn add(a: &Poly, b: &Poly) -> Poly {
+    let mut c = [0u32; N];
+    for i in 0..N {
+        c[i] = (a[i] + b[i]) % Q;
+    }
+    c
+}
+
+fn sub(a: &Poly, b: &Poly) -> Poly {
+    let mut c = [0u32; N];
+    for i in 0..N {
+        c[i] = (a[i] + Q - b[i]) % Q;
+    }
+    c
+}
+
+fn compress(p: &Poly, d: usize) -> Poly {
+    let mut c = [0u32; N];
+    for i in 0..N {
+        c[i] = ((p[i] << d) + Q / 2) / Q & ((1 << d) - 1);
+    }
+    c
+}
+
+fn decompress(p: &Poly, d: usize) -> Poly {
+    let mut c = [0u32; N];
+    for i in 0..N {
+        c[i] = (p[i] * Q + (1 << (d - 1))) >> d;
+    }
+    c
+}
+
+// Packs d bits of each coefficient, least significant first.
+fn encode(p: &Poly, d: usize) -> Vec<u8> {
+    let mut out = vec![0u8; 32 * d];
+    for i in 0..N {
+        for b in 0..d {
+            let bit = i * d + b;
+            out[bit / 8] |= ((p[i] >> b & 1) as u8) << (bit % 8);
+        }
+    }
+    out
+}
+
+fn decode(bytes: &[u8], d: usize) -> Poly {
+    let mut p = [0u32; N];
+    for i in 0..N {
+        for b in 0..d {
+            let bit = i * d + b;
+            p[i] |= ((bytes[bit / 8] >> (bit % 8) & 1) as u32) << b;
+        }
+        if d == 12 {
+            p[i] %= Q;
+        }
+    }
+    p
+}
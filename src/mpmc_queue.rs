@@ -34,3 +34,35 @@ impl<T> MpmcQueue<T> {
         cvar.notify_one();
     }
 }
+
+// Like MpmcQueue, but pop returns the oldest element of the highest level.
+#[derive(Clone)]
+pub struct MpmcPriorityQueue<T> {
+    data: Arc<(Mutex<Vec<VecDeque<T>>>, Condvar)>,
+}
+
+impl<T> MpmcPriorityQueue<T> {
+
+    pub fn new(levels: usize) -> MpmcPriorityQueue<T> {
+        MpmcPriorityQueue {
+            data: Arc::new( (Mutex::new((0..levels).map(|_| VecDeque::new()).collect()), Condvar::new()) )
+        }
+    }
+
+    pub fn pop(&self) -> T {
+        let &(ref queues, ref cvar) = &*self.data;
+        let mut queues = queues.lock().unwrap();
+        loop {
+            if let Some(element) = queues.iter_mut().rev().filter_map(|q| q.pop_front()).next() {
+                return element;
+            }
+            queues = cvar.wait(queues).unwrap();
+        }
+    }
+
+    pub fn push(&self, element: T, level: usize) {
+        let &(ref queues, ref cvar) = &*self.data;
+        { queues.lock().unwrap()[level].push_back(element); }
+        cvar.notify_one();
+    }
+}
@@ -1,270 +1,2790 @@
 #![allow(dead_code)]
 
-use std::net::{TcpListener, TcpStream};
+use std::net::{TcpListener, TcpStream, SocketAddr, IpAddr, Shutdown, ToSocketAddrs};
 use std::thread::{self};
-use std::sync::{Arc};
-use std::sync::mpsc::{channel};
-use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::io::{self, Read, Write};
 use std::str;
-use std::mem;
+use std::cmp;
+use std::env;
+use std::fs::{self, File};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet, BTreeMap, BTreeSet};
 
 use rustc_serialize::json;
+use rustc_serialize::hex::FromHex;
+use rand;
+use crypto::curve25519::curve25519;
 
-use mpmc_queue::MpmcQueue;
-use state::Route;
-use crypto_lib::Crypto;
+use mpmc_queue::{MpmcQueue, MpmcPriorityQueue};
+use dns;
+use mlkem;
+use state::{Route, Addr, SecurityKind};
+use crypto_lib::{self, Crypto, CipherState, SymmetricState, Suite, SUITES};
 use crypto_lib::Key;
-use messages::{MessageContainer, Message, TextMessage};
-use messages::{MessageType, ResponseType, ToServer, ToUser};
+use config_lib::Config;
+use messages::{MessageContainer, Message, TextMessage, SessionReset, Priority, Undelivered, PRIORITY_LEVELS};
+use messages::{MessageType, ResponseType, ToServer, ToUser, ErrorCode, SyncSlot, Topic, Update, RelayDirectory};
 
 
-const SERVER_ADDR: &'static str = "138.197.153.113:5001";
-const SERVER_KEY_ADDR: &'static str = "138.197.153.113:5002";
+pub const DEFAULT_SERVER_ADDR: &'static str = "138.197.153.113:5001";
+pub const DEFAULT_SERVER_KEY_ADDR: &'static str = "138.197.153.113:5002";
+pub const DEFAULT_PORT: u16 = 5000;
 
-#[derive(Clone)]
-pub struct Net {
-    send_work: Arc<MpmcQueue<MessageContainer>>,
-    recv_work: Arc<MpmcQueue<TcpStream>>,
-    new_messages: Arc<MpmcQueue<TextMessage>>,
-    pub crypto: Crypto,
-    server_key: Key,
+// Every connection opens with a hello of the magic bytes and a big endian
+// protocol version. The responder replies with the version it selected,
+// or VERSION_REJECTED followed by the oldest version it accepts if there is
+// none in common.
+const MAGIC: &'static [u8; 4] = b"SMSG";
+pub const PROTOCOL_VERSION: u16 = 9;
+pub const MIN_PROTOCOL_VERSION: u16 = 9;
+const VERSION_REJECTED: u16 = 0;
+
+// From this version the initiator then offers a count and the ids of the
+// cipher suites it speaks, and the responder picks one or SUITE_REJECTED.
+// Older versions always use ChaChaPoly.
+const SUITE_VERSION: u16 = 2;
+const SUITE_REJECTED: u8 = 0;
+
+// From this version sealed payloads are padded, which older versions can't
+// read, so it is also the oldest version accepted.
+const PADDING_VERSION: u16 = 3;
+
+// From this version a client may open a persistent connection to the server
+// by sending SESSION_START, after which every frame is a request id followed
+// by a sealed layer, and the server may push layers tagged PUSH_ID.
+pub const SESSION_VERSION: u16 = 4;
+
+// From this version a client may ask for a route that goes through the
+// server's own relay when no volunteer relays are registered. Such routes
+// come back as RelayedConnection so the client knows the server sees both
+// ends, and are only asked for with the `server_relay` setting.
+pub const LAST_RESORT_VERSION: u16 = 5;
+
+// From this version an overloaded server may answer a request, or a layer
+// it would otherwise acknowledge, with SERVER_BUSY followed by a big endian
+// u16 of how many seconds to wait before trying again.
+pub const BUSY_VERSION: u16 = 6;
+pub const SERVER_BUSY: u8 = 0x19;
+// How many times a request the server was too busy for is sent again, and
+// the longest we wait before each.
+const BUSY_RETRIES: usize = 3;
+const MAX_BUSY_WAIT_SECS: u64 = 30;
+
+pub fn busy_frame(retry_after: u16) -> Vec<u8> {
+    vec![SERVER_BUSY, (retry_after >> 8) as u8, retry_after as u8]
+}
+
+// From this version a frame bigger than FRAGMENT_SIZE is sent as fragments,
+// so it has no length limit and can be streamed. The length in front of
+// every fragment but the last has its top bit set, and each of those is
+// sealed with MORE_FRAGMENTS as associated data, so fragments can't be cut
+// apart or run together without the receiver noticing.
+pub const STREAM_VERSION: u16 = 7;
+const FRAGMENT_SIZE: usize = 256 * 1024;
+const MORE_FRAGMENTS: u32 = 1 << 31;
+
+// From this version every layer is sealed with its routing header as
+// associated data, which older versions can't open, so it is also the oldest
+// version accepted.
+const HEADER_VERSION: u16 = 8;
+
+// From this version hop acknowledgements and refusals of oversized frames
+// are control frames: their length has CONTROL_FRAME set and their byte is
+// sealed with it as associated data, so no payload, even of one byte, can
+// pass for one. It is also the oldest version accepted.
+const CONTROL_VERSION: u16 = 9;
+const CONTROL_FRAME: u32 = 1 << 30;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Control {
+    HopAck,
+    FrameTooLarge,
+    Undelivered(Undelivered),
+}
+
+impl Control {
+    fn bytes(self) -> Vec<u8> {
+        match self {
+            Control::HopAck => vec![HOP_ACK],
+            Control::FrameTooLarge => vec![FRAME_TOO_LARGE],
+            Control::Undelivered(reason) => undelivered_frame(reason),
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Control, String> {
+        match bytes {
+            [HOP_ACK] => Ok(Control::HopAck),
+            [FRAME_TOO_LARGE] => Ok(Control::FrameTooLarge),
+            b => parse_undelivered(b).map(Control::Undelivered).ok_or("Unknown control frame".to_string()),
+        }
+    }
+}
+
+// The error for a control frame read where a frame was expected.
+fn control_error(control: Control) -> String {
+    match control {
+        Control::FrameTooLarge => "Message was rejected for being too large".to_string(),
+        Control::HopAck => "Expected a frame but got an acknowledgement".to_string(),
+        Control::Undelivered(reason) => reason.to_string(),
+    }
+}
+
+// Why a hop didn't acknowledge a layer, when it said.
+enum Refusal {
+    Busy(u16), // seconds to wait
+    Undelivered(Undelivered),
+}
+
+// What read_fragment read: part of a frame and whether more of it follow, or
+// a control frame.
+enum Fragment {
+    Data(Vec<u8>, bool),
+    Control(Control),
+}
+
+fn parse_busy(frame: &[u8]) -> Option<u16> {
+    match frame {
+        [SERVER_BUSY, a, b] => Some(decode_u16(&[*a, *b])),
+        _ => None,
+    }
+}
+
+// A hop that couldn't pass a layer on answers UNDELIVERED and the code of
+// why in place of the acknowledgement, as a control frame or, on a
+// persistent connection, as the reply.
+const UNDELIVERED: u8 = 0x1a;
+
+pub fn undelivered_frame(reason: Undelivered) -> Vec<u8> {
+    vec![UNDELIVERED, reason as u8]
+}
+
+fn parse_undelivered(frame: &[u8]) -> Option<Undelivered> {
+    match frame {
+        [UNDELIVERED, code] => Undelivered::from_code(*code),
+        _ => None,
+    }
+}
+
+// Features available at each protocol version.
+pub fn capabilities(version: u16) -> Vec<&'static str> {
+    let mut features = match version {
+        0 => return vec![],
+        _ => vec!["reliable delivery", "relay tests", "carried messages"],
+    };
+    if version >= SUITE_VERSION {
+        features.push("cipher suite negotiation");
+    }
+    if version >= PADDING_VERSION {
+        features.push("padded payloads");
+    }
+    if version >= SESSION_VERSION {
+        features.push("persistent connections");
+    }
+    if version >= LAST_RESORT_VERSION {
+        features.push("relay of last resort");
+    }
+    if version >= BUSY_VERSION {
+        features.push("load shedding");
+    }
+    if version >= STREAM_VERSION {
+        features.push("streamed frames");
+    }
+    if version >= HEADER_VERSION {
+        features.push("bound routing headers");
+    }
+    if version >= CONTROL_VERSION {
+        features.push("control frames");
+    }
+    features
+}
+
+// The padding buckets from a comma separated list of sizes in bytes, such as
+// "256,1024,4096". An empty list turns padding off.
+pub fn padding_buckets(config: &Config) -> Vec<usize> {
+    let sizes = match config.get_str("padding_buckets") {
+        Some(s) => s,
+        None => return crypto_lib::DEFAULT_PADDING_BUCKETS.to_vec(),
+    };
+    match sizes.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).map(|s| s.parse()).collect() {
+        Ok(buckets) => buckets,
+        Err(_) => crypto_lib::DEFAULT_PADDING_BUCKETS.to_vec(),
+    }
+}
+
+pub fn hello(version: u16) -> [u8; 6] {
+    let v = encode_u16(version);
+    [MAGIC[0], MAGIC[1], MAGIC[2], MAGIC[3], v[0], v[1]]
+}
+
+fn parse_hello(hello: &[u8; 6]) -> Option<u16> {
+    if &hello[..4] != &MAGIC[..] {
+        return None;
+    }
+    Some(decode_u16(&[hello[4], hello[5]]))
+}
+
+// The wire codec. All integers are big endian, and every frame is a u32
+// length followed by that many bytes.
+pub fn encode_u16(n: u16) -> [u8; 2] {
+    [(n >> 8) as u8, n as u8]
+}
+
+pub fn decode_u16(buf: &[u8; 2]) -> u16 {
+    ((buf[0] as u16) << 8) | buf[1] as u16
+}
+
+pub fn encode_u32(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+pub fn decode_u32(buf: &[u8; 4]) -> u32 {
+    ((buf[0] as u32) << 24) | ((buf[1] as u32) << 16) | ((buf[2] as u32) << 8) | buf[3] as u32
+}
+
+pub fn encode_u64(n: u64) -> [u8; 8] {
+    let (high, low) = (encode_u32((n >> 32) as u32), encode_u32(n as u32));
+    [high[0], high[1], high[2], high[3], low[0], low[1], low[2], low[3]]
+}
+
+pub fn decode_u64(buf: &[u8; 8]) -> u64 {
+    (decode_u32(&[buf[0], buf[1], buf[2], buf[3]]) as u64) << 32 | decode_u32(&[buf[4], buf[5], buf[6], buf[7]]) as u64
+}
+
+// A frame on a persistent connection, tagged with the request it belongs to.
+pub fn session_frame(id: u64, data: &[u8]) -> Vec<u8> {
+    let mut frame = encode_u64(id).to_vec();
+    frame.extend_from_slice(data);
+    frame
+}
+
+pub fn parse_session_frame(frame: &[u8]) -> Result<(u64, &[u8]), String> {
+    if frame.len() < 8 {
+        return Err("Frame has no request id".to_string());
+    }
+    let id = decode_u64(&[frame[0], frame[1], frame[2], frame[3], frame[4], frame[5], frame[6], frame[7]]);
+    Ok((id, &frame[8..]))
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, frame: &[u8]) -> Result<(), String> {
+    if frame.len() > u32::max_value() as usize {
+        return Err("Frame is too long".to_string());
+    }
+    try!(writer.write_all(&encode_u32(frame.len() as u32)).map_err(|e| e.to_string()));
+    writer.write_all(frame).map_err(|e| e.to_string())
+}
+
+// Reads until `buf` is full or `reader` runs out, returning how much it read.
+fn fill<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, String> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    Ok(filled)
+}
+
+pub fn read_frame_len<R: Read>(reader: &mut R) -> Result<usize, String> {
+    let mut len = [0u8; 4];
+    try!(reader.read_exact(&mut len).map_err(|e| e.to_string()));
+    Ok(decode_u32(&len) as usize)
+}
+
+// Records every frame sent or received on our connections, and when, so a
+// session can be replayed offline with read_capture. Frames hold messages,
+// so each record is sealed under a key only we can derive, and written as
+// a length prefixed frame.
+pub struct Capture {
+    file: Mutex<File>,
+    key: Key,
+    started: Instant,
+}
+
+impl Capture {
+    pub fn create(path: &Path, crypto: &Crypto) -> Result<Capture, String> {
+        if let Some(dir) = path.parent() {
+            try!(fs::create_dir_all(dir).map_err(|e| e.to_string()));
+        }
+        let file = try!(File::create(path).map_err(|e| e.to_string()));
+        Ok(Capture { file: Mutex::new(file), key: crypto.capture_key(), started: Instant::now() })
+    }
+
+    fn record(&self, inbound: bool, frame: &[u8]) {
+        let elapsed = self.started.elapsed();
+        let ms = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1000000) as u64;
+        let mut record = Vec::with_capacity(9 + frame.len());
+        record.extend_from_slice(&encode_u32((ms >> 32) as u32));
+        record.extend_from_slice(&encode_u32(ms as u32));
+        record.push(inbound as u8);
+        record.extend_from_slice(frame);
+        if let Ok(sealed) = crypto_lib::seal_with_key(&self.key, &record) {
+            let _ = write_frame(&mut *self.file.lock().unwrap(), &sealed);
+        }
+    }
+}
+
+// The frames of a capture, in order, as milliseconds into the session,
+// whether they were received, and their contents.
+pub fn read_capture(path: &Path, crypto: &Crypto) -> Result<Vec<(u64, bool, Vec<u8>)>, String> {
+    let mut file = try!(File::open(path).map_err(|e| e.to_string()));
+    let key = crypto.capture_key();
+    let mut frames = Vec::new();
+    while let Ok(len) = read_frame_len(&mut file) {
+        let mut sealed = vec![0; len];
+        if file.read_exact(&mut sealed).is_err() {
+            break; // cut short by a crash
+        }
+        let record = try!(crypto_lib::open_with_key(&key, &sealed)
+            .map_err(|_| "The capture was made with another key".to_string()));
+        if record.len() < 9 {
+            return Err("The capture is corrupt".to_string());
+        }
+        let ms = (decode_u32(&[record[0], record[1], record[2], record[3]]) as u64) << 32
+            | decode_u32(&[record[4], record[5], record[6], record[7]]) as u64;
+        frames.push((ms, record[8] == 1, record[9..].to_vec()));
+    }
+    Ok(frames)
+}
+
+// What a captured frame holds, found by parsing it the way a received
+// message is parsed.
+pub fn describe_frame(frame: &[u8], inbound: bool, crypto: &Crypto) -> String {
+    if !inbound {
+        return "sealed for the next hop".to_string();
+    }
+    match Net::parse_message(frame, crypto) {
+        Ok(ref msg) if msg.next_hop.is_some() => format!("to relay to {}", msg.next_hop.unwrap()),
+        Ok(msg) => match Net::decode_type(&msg.data) {
+            Ok(t) => json::encode(&t).unwrap(),
+            Err(e) => format!("undecodable payload: {}", e),
+        },
+        Err(e) => e,
+    }
+}
+
+// Streams to a known key are authenticated, the public key port is only
+// encrypted. The key it serves is trusted on first use and pinned in
+// ~/.secmsg/keys/server, so whoever answers the very first fetch could pose
+// as the server until the pin is removed. Setting server_key to the server's
+// key in hex skips the fetch and authenticates the server from the start.
+const NOISE_NK: &'static str = "NK";
+const NOISE_NN: &'static str = "NN";
+
+// Limits on messages carried for unreachable peers in carry mode.
+const CARRY_TTL_SECS: u64 = 60 * 60 * 24;
+const MAX_CARRIED_SIZE: usize = 64 * 1024;
+const MAX_CARRIED_MESSAGES: usize = 256;
+
+// Hop-by-hop acknowledgements of one way messages.
+pub const HOP_ACK: u8 = 0x06;
+
+// Sent in place of a response when an incoming frame is over the size limit.
+const FRAME_TOO_LARGE: u8 = 0x15;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+const HOP_ACK_TIMEOUT_MS: u64 = 2000;
+
+// Persistent connections. SESSION_START is the first frame a client sends to
+// open one, and REQUEST_DROPPED is the reply to a request the server refused.
+pub const SESSION_START: u8 = 0x16;
+pub const REQUEST_DROPPED: u8 = 0x18;
+pub const PUSH_ID: u64 = 0;
+const SESSION_TIMEOUT_SECS: u64 = 30;
+
+// Either side of a persistent connection may send KEEPALIVE_PING, on its own
+// in a frame, and the other answers KEEPALIVE_PONG. Clients ping every
+// keepalive_secs and give up on the connection after keepalive_timeout_secs
+// without hearing anything.
+pub const KEEPALIVE_PING: u8 = 0x05;
+pub const KEEPALIVE_PONG: u8 = 0x07;
+pub const DEFAULT_KEEPALIVE_SECS: u64 = 30;
+pub const DEFAULT_KEEPALIVE_TIMEOUT_SECS: u64 = 90;
+const HOP_RETRIES: usize = 3;
+
+// Connections to peers are kept open after a message for the next one to the
+// same hop, closed after pool_idle_secs unused (0 turns pooling off), and at
+// most pool_max_per_hop are in use to a hop at once. Peers read further
+// messages on a connection until it has been idle as long.
+const DEFAULT_POOL_IDLE_SECS: u64 = 30;
+const DEFAULT_POOL_MAX_PER_HOP: usize = 4;
+const POOL_REAP_SECS: u64 = 5;
+
+// A hop that fails to take a message is tried again after a jittered
+// exponential backoff, from RECONNECT_BASE_MS up to max_reconnect_secs, and
+// messages for it wait until it answers. After reconnect_attempts (0 turns
+// this off) they are carried or reported as failed.
+const RECONNECT_BASE_MS: u64 = 500;
+const DEFAULT_MAX_RECONNECT_SECS: u64 = 60;
+const DEFAULT_RECONNECT_ATTEMPTS: u32 = 8;
+const RECONNECT_POLL_MS: u64 = 250;
+const INITIAL_HOP_WINDOW: f64 = 2.0;
+const MAX_HOP_WINDOW: f64 = 64.0;
+
+const RELAY_TEST_TIMEOUT_SECS: u64 = 10;
+
+// How often a logged in client tells the server it is still online.
+pub const HEARTBEAT_SECS: u64 = 60;
+
+// How long a typing indicator lasts, and so how often one is sent.
+const TYPING_SECS: u64 = 5;
+
+// Cover messages are padded to about the size of a text message.
+const DEFAULT_COVER_INTERVAL_SECS: u64 = 30;
+const COVER_MIN_BYTES: usize = 64;
+const COVER_MAX_BYTES: usize = 512;
+const COVER_ROUTE_REUSE: usize = 20;
+
+// The longest a relay holds a message unless configured otherwise.
+const MAX_RELAY_DELAY_MS: u64 = 2000;
+
+// How often a client that peers can't connect to fetches from its mailbox.
+const MAILBOX_POLL_SECS: u64 = 10;
+
+// In low power mode the persistent connection is pinged every
+// low_power_keepalive_secs, which stays within the server's default session
+// timeout, cover traffic stops, and messages that aren't urgent go out
+// together every low_power_batch_secs.
+const DEFAULT_LOW_POWER_KEEPALIVE_SECS: u64 = 75;
+const DEFAULT_LOW_POWER_BATCH_SECS: u64 = 15;
+
+// The wall clock keeps going while the machine is suspended but sleeping
+// threads don't, so a check that wakes up much later than asked tells us
+// we were suspended.
+const SUSPEND_CHECK_SECS: u64 = 5;
+const SUSPEND_GAP_SECS: u64 = 30;
+
+// Layers older than this are refused. It outlasts carried messages, which
+// reach their destination up to CARRY_TTL_SECS late.
+const MAX_MESSAGE_AGE_SECS: u64 = 2 * CARRY_TTL_SECS;
+const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+const MAX_REMEMBERED_MESSAGES: usize = 100000;
+const MAX_FUTURE_MESSAGES: usize = 1000;
+const REPLAYED: &'static str = "Message was replayed";
+
+// The layers received recently, so a replayed one is refused. Once the
+// window is full new layers are refused until old ones age out, rather than
+// forgetting any, which would let those be replayed. Layers stamped ahead of
+// our clock have a small share of it, so they can't be used to fill it.
+pub struct ReplayWindow {
+    seen: Mutex<(BTreeSet<(u64, u64)>, u64)>, // (sent at, id) of each layer, oldest sent at accepted
+}
+
+impl ReplayWindow {
+    pub fn new() -> ReplayWindow {
+        ReplayWindow { seen: Mutex::new((BTreeSet::new(), 0)) }
+    }
+
+    pub fn check(&self, msg: &Message) -> Result<(), String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        if msg.sent_at > now + MAX_CLOCK_SKEW_SECS {
+            return Err("Message is from the future".to_string());
+        }
+
+        let (ref mut seen, ref mut floor) = *self.seen.lock().unwrap();
+        *floor = cmp::max(*floor, now.saturating_sub(MAX_MESSAGE_AGE_SECS));
+        if msg.sent_at < *floor {
+            return Err("Message is too old".to_string());
+        }
+        while seen.iter().next().map_or(false, |&(t, _)| t < *floor) {
+            let oldest = *seen.iter().next().unwrap();
+            seen.remove(&oldest);
+        }
+        if seen.contains(&(msg.sent_at, msg.id)) {
+            return Err(REPLAYED.to_string());
+        }
+        if seen.len() >= MAX_REMEMBERED_MESSAGES {
+            return Err("Too many recent messages to check this one for replay".to_string());
+        }
+        if msg.sent_at > now && seen.range((now + 1, 0)..).count() >= MAX_FUTURE_MESSAGES {
+            return Err("Too many messages from the future".to_string());
+        }
+        seen.insert((msg.sent_at, msg.id));
+        Ok(())
+    }
+}
+
+pub struct RelayReport {
+    pub reachable: bool,
+    pub round_trip: Option<Duration>,
+    pub bytes: usize,
+    pub violations: Vec<String>,
+}
+
+impl ToString for RelayReport {
+    fn to_string(&self) -> String {
+        let mut report = format!("Reachable: {}", if self.reachable { "yes" } else { "no" });
+        if let Some(rtt) = self.round_trip {
+            let millis = rtt.as_secs() * 1000 + (rtt.subsec_nanos() / 1000000) as u64;
+            report += &format!("\nRound trip: {} ms", millis);
+            report += &format!("\nThroughput: {} bytes/s", self.bytes as u64 * 1000 / cmp::max(millis, 1));
+        }
+        for v in &self.violations {
+            report += &format!("\nProblem: {}", v);
+        }
+        report
+    }
+}
+
+struct CarriedMessage {
+    msg: Message,
+    expires: Instant,
+}
+
+// A TCP stream whose frames are encrypted with keys from a Noise handshake.
+pub struct SecureStream {
+    stream: TcpStream,
+    send: Option<CipherState>, // None in the reading half of a split stream
+    recv: Option<CipherState>, // None in the writing half
+    version: u16,
+    suite: Suite,
+    max_frame_size: usize,
+    capture: Option<Arc<Capture>>,
 }
 
-impl Net {
+impl SecureStream {
+
+    // Connects as the initiator. The responder must prove it owns `remote_key`
+    // if one is given.
+    pub fn connect(addr: &Addr, remote_key: Option<&Key>) -> Result<SecureStream, String> {
+        SecureStream::connect_via(addr, remote_key, None)
+    }
+
+    // Connects as the initiator, through `proxy` if given.
+    pub fn connect_via(addr: &Addr, remote_key: Option<&Key>, proxy: Option<&Proxy>) -> Result<SecureStream, String> {
+        let stream = try!(match proxy {
+            Some(p) => p.connect(addr),
+            None => TcpStream::connect(addr.0).map_err(|_| "Could not connect to destination".to_string()),
+        });
+        SecureStream::initiate(stream, remote_key)
+    }
+
+    // Connects as the initiator, giving up on a destination that takes longer
+    // than `timeout` to answer. Every read and write on the stream gives up
+    // after `timeout` too.
+    pub fn connect_within(addr: &Addr, remote_key: Option<&Key>, timeout: Duration) -> Result<SecureStream, String> {
+        let stream = try!(TcpStream::connect_timeout(&addr.0, timeout).map_err(|_| "Could not connect to destination".to_string()));
+        try!(stream.set_read_timeout(Some(timeout)).and_then(|_| stream.set_write_timeout(Some(timeout))).map_err(|e| e.to_string()));
+        SecureStream::initiate(stream, remote_key)
+    }
+
+    fn initiate(mut stream: TcpStream, remote_key: Option<&Key>) -> Result<SecureStream, String> {
+
+        // Negotiate the protocol version.
+        let our_hello = hello(PROTOCOL_VERSION);
+        try!(stream.write_all(&our_hello).map_err(|_| "Handshake failed".to_string()));
+        let mut their_hello = [0u8; 6];
+        try!(stream.read_exact(&mut their_hello).map_err(|_| "Handshake failed".to_string()));
+        let version = match parse_hello(&their_hello) {
+            Some(VERSION_REJECTED) => {
+                // Older responders don't say which version they want.
+                let mut min = [0u8; 2];
+                return Err(match stream.read_exact(&mut min) {
+                    Ok(()) => ErrorCode::UpgradeRequired(decode_u16(&min)).to_string(),
+                    Err(_) => format!("Protocol version {} is not supported by the destination", PROTOCOL_VERSION),
+                });
+            },
+            Some(v) if v >= MIN_PROTOCOL_VERSION && v <= PROTOCOL_VERSION => v,
+            Some(v) => return Err(format!("Destination selected unsupported protocol version {}", v)),
+            None => return Err("Destination is not speaking the secmsg protocol".to_string()),
+        };
+
+        // Negotiate the cipher suite.
+        let mut offer = Vec::new();
+        let mut choice = [SUITE_REJECTED];
+        let suite = if version >= SUITE_VERSION {
+            offer.push(SUITES.len() as u8);
+            offer.extend(SUITES.iter().map(|s| s.id()));
+            try!(stream.write_all(&offer).map_err(|_| "Handshake failed".to_string()));
+            try!(stream.read_exact(&mut choice).map_err(|_| "Handshake failed".to_string()));
+            try!(Suite::from_id(choice[0]).ok_or("No cipher suite in common with the destination".to_string()))
+        } else {
+            Suite::ChaChaPoly
+        };
+
+        let mut state = SymmetricState::new(if remote_key.is_some() { NOISE_NK } else { NOISE_NN }, suite);
+        state.mix_hash(&our_hello);
+        state.mix_hash(&their_hello);
+        // Hashing the offer stops a downgrade to a weaker suite.
+        if version >= SUITE_VERSION {
+            state.mix_hash(&offer);
+            state.mix_hash(&choice);
+        }
+        if let Some(rs) = remote_key {
+            state.mix_hash(rs);
+        }
+
+        // -> e, es, and a KEM key in a hybrid suite
+        let (e_priv, e_pub) = crypto_lib::gen_key_pair();
+        let mut out = e_pub.to_vec();
+        state.mix_hash(&e_pub);
+        if let Some(rs) = remote_key {
+            state.mix_key(&curve25519(&e_priv, rs));
+        }
+        let mut kem = None;
+        if suite.is_hybrid() {
+            let (dk, ek) = mlkem::keypair();
+            state.mix_hash(&ek);
+            out.extend(ek);
+            kem = Some(dk);
+        }
+        out.extend(state.encrypt_and_hash(&[]));
+        try!(stream.write_all(&out).map_err(|_| "Handshake failed".to_string()));
+
+        // <- e, ee, and the KEM ciphertext
+        let mut re = [0u8; 32];
+        let mut tag = [0u8; 16];
+        try!(stream.read_exact(&mut re).map_err(|_| "Handshake failed".to_string()));
+        state.mix_hash(&re);
+        state.mix_key(&curve25519(&e_priv, &re));
+        if let Some(mut dk) = kem {
+            let mut ct = vec![0u8; mlkem::CIPHERTEXT_LEN];
+            try!(stream.read_exact(&mut ct).map_err(|_| "Handshake failed".to_string()));
+            state.mix_hash(&ct);
+            let mut shared = try!(mlkem::decapsulate(&dk, &ct));
+            state.mix_key(&shared);
+            crypto_lib::zeroize(&mut shared);
+            crypto_lib::zeroize(&mut dk);
+        }
+        try!(stream.read_exact(&mut tag).map_err(|_| "Handshake failed".to_string()));
+        try!(state.decrypt_and_hash(&tag).map_err(|_| "Server authentication failed".to_string()));
+
+        let (send, recv) = state.split();
+        Ok(SecureStream {
+            stream: stream,
+            send: Some(send),
+            recv: Some(recv),
+            version: version,
+            suite: suite,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            capture: None,
+        })
+    }
+
+    // Accepts as the responder, authenticating with `crypto`'s static key if given.
+    pub fn accept(stream: TcpStream, crypto: Option<&Crypto>) -> Result<SecureStream, String> {
+        SecureStream::accept_from(stream, crypto, MIN_PROTOCOL_VERSION, SUITES)
+    }
+
+    // Accepts as the responder, turning away initiators older than `min_version`
+    // and choosing the first of `suites` that the initiator speaks.
+    pub fn accept_from(mut stream: TcpStream, crypto: Option<&Crypto>, min_version: u16,
+                       suites: &[Suite]) -> Result<SecureStream, String> {
+
+        // Negotiate the protocol version.
+        let mut their_hello = [0u8; 6];
+        try!(stream.read_exact(&mut their_hello).map_err(|_| "Handshake failed".to_string()));
+        let min_version = cmp::max(min_version, MIN_PROTOCOL_VERSION);
+        let version = match parse_hello(&their_hello) {
+            Some(v) if v >= min_version => cmp::min(v, PROTOCOL_VERSION),
+            Some(v) => {
+                let mut rejection = hello(VERSION_REJECTED).to_vec();
+                rejection.extend_from_slice(&encode_u16(min_version));
+                let _ = stream.write_all(&rejection);
+                return Err(format!("Unsupported protocol version {}", v));
+            },
+            None => return Err("Peer is not speaking the secmsg protocol".to_string()),
+        };
+        let our_hello = hello(version);
+        try!(stream.write_all(&our_hello).map_err(|_| "Handshake failed".to_string()));
+
+        // Negotiate the cipher suite.
+        let mut offer = Vec::new();
+        let mut choice = [SUITE_REJECTED];
+        let suite = if version >= SUITE_VERSION {
+            let mut count = [0u8];
+            try!(stream.read_exact(&mut count).map_err(|_| "Handshake failed".to_string()));
+            offer = vec![0u8; 1 + count[0] as usize];
+            try!(stream.read_exact(&mut offer[1..]).map_err(|_| "Handshake failed".to_string()));
+            offer[0] = count[0];
+
+            let suite = suites.iter().cloned().find(|s| offer[1..].contains(&s.id()));
+            choice[0] = suite.map_or(SUITE_REJECTED, |s| s.id());
+            try!(stream.write_all(&choice).map_err(|_| "Handshake failed".to_string()));
+            try!(suite.ok_or("No cipher suite in common with the peer".to_string()))
+        } else {
+            Suite::ChaChaPoly
+        };
+
+        let mut state = SymmetricState::new(if crypto.is_some() { NOISE_NK } else { NOISE_NN }, suite);
+        state.mix_hash(&their_hello);
+        state.mix_hash(&our_hello);
+        if version >= SUITE_VERSION {
+            state.mix_hash(&offer);
+            state.mix_hash(&choice);
+        }
+        if let Some(c) = crypto {
+            state.mix_hash(&c.pub_key);
+        }
+
+        // -> e, es, and a KEM key in a hybrid suite
+        let mut re = [0u8; 32];
+        try!(stream.read_exact(&mut re).map_err(|_| "Handshake failed".to_string()));
+        state.mix_hash(&re);
+        let mut rek = Vec::new();
+        if suite.is_hybrid() {
+            rek = vec![0u8; mlkem::PUBLIC_KEY_LEN];
+            try!(stream.read_exact(&mut rek).map_err(|_| "Handshake failed".to_string()));
+            state.mix_hash(&rek);
+        }
+        if let Some(c) = crypto {
+            let mut tag = [0u8; 16];
+            try!(stream.read_exact(&mut tag).map_err(|_| "Handshake failed".to_string()));
+            state.mix_key(&c.dh(&re));
+            try!(state.decrypt_and_hash(&tag).map_err(|_| "Handshake failed".to_string()));
+        }
+
+        // <- e, ee, and the KEM ciphertext
+        let (e_priv, e_pub) = crypto_lib::gen_key_pair();
+        let mut out = e_pub.to_vec();
+        state.mix_hash(&e_pub);
+        state.mix_key(&curve25519(&e_priv, &re));
+        if suite.is_hybrid() {
+            let (ct, mut shared) = try!(mlkem::encapsulate(&rek));
+            state.mix_hash(&ct);
+            state.mix_key(&shared);
+            crypto_lib::zeroize(&mut shared);
+            out.extend(ct);
+        }
+        out.extend(state.encrypt_and_hash(&[]));
+        try!(stream.write_all(&out).map_err(|_| "Handshake failed".to_string()));
+
+        let (recv, send) = state.split();
+        Ok(SecureStream {
+            stream: stream,
+            send: Some(send),
+            recv: Some(recv),
+            version: version,
+            suite: suite,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            capture: None,
+        })
+    }
+
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.max_frame_size = size;
+    }
+
+    pub fn set_capture(&mut self, capture: Option<Arc<Capture>>) {
+        self.capture = capture;
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn suite(&self) -> Suite {
+        self.suite
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    // Splits the stream into a half that only writes and a half that only
+    // reads, so one thread can wait for frames while another sends them.
+    pub fn split(self) -> Result<(SecureStream, SecureStream), String> {
+        let reader = try!(self.stream.try_clone().map_err(|e| e.to_string()));
+        Ok((SecureStream {
+            stream: self.stream,
+            send: self.send,
+            recv: None,
+            version: self.version,
+            suite: self.suite,
+            max_frame_size: self.max_frame_size,
+            capture: self.capture.clone(),
+        }, SecureStream {
+            stream: reader,
+            send: None,
+            recv: self.recv,
+            version: self.version,
+            suite: self.suite,
+            max_frame_size: self.max_frame_size,
+            capture: self.capture,
+        }))
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), String> {
+        self.stream.set_read_timeout(timeout).map_err(|e| e.to_string())
+    }
+
+    pub fn shutdown(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    pub fn send_ack(&mut self) -> Result<(), String> {
+        self.write_control(Control::HopAck)
+    }
+
+    // Tells the sender of the last layer why it wasn't passed on.
+    pub fn send_undelivered(&mut self, reason: Undelivered) -> Result<(), String> {
+        self.write_control(Control::Undelivered(reason))
+    }
+
+    // Control frames aren't payloads, so they aren't captured either.
+    fn write_control(&mut self, control: Control) -> Result<(), String> {
+        let ad = encode_u32(CONTROL_FRAME);
+        let frame = match self.send {
+            Some(ref mut send) => send.encrypt(&ad, &control.bytes()),
+            None => return Err("This half of the stream only reads".to_string()),
+        };
+        try!(self.stream.write_all(&encode_u32(frame.len() as u32 | CONTROL_FRAME)).map_err(|e| e.to_string()));
+        self.stream.write_all(&frame).map_err(|e| e.to_string())
+    }
+
+    // Waits up to `timeout` for the other side to acknowledge the last frame.
+    pub fn read_ack(&mut self, timeout: Duration) -> bool {
+        self.read_ack_or_refusal(timeout).unwrap_or(false)
+    }
+
+    // Like read_ack, but a hop that said why it didn't take the layer, such
+    // as a busy server with the seconds it asked us to wait, gives Err.
+    fn read_ack_or_refusal(&mut self, timeout: Duration) -> Result<bool, Refusal> {
+        if self.stream.set_read_timeout(Some(timeout)).is_err() {
+            return Ok(false);
+        }
+        let frame = self.read_frame_or_control();
+        let _ = self.stream.set_read_timeout(None);
+        match frame {
+            Ok(Err(Control::HopAck)) => Ok(true),
+            Ok(Err(Control::Undelivered(reason))) => Err(Refusal::Undelivered(reason)),
+            Ok(Ok(ref f)) => match parse_busy(f) {
+                Some(secs) => Err(Refusal::Busy(secs)),
+                None => Ok(false),
+            },
+            _ => Ok(false),
+        }
+    }
+
+    pub fn write_frame(&mut self, data: &[u8]) -> Result<(), String> {
+        if let Some(ref c) = self.capture {
+            c.record(false, data);
+        }
+        if self.version < STREAM_VERSION || data.len() <= FRAGMENT_SIZE {
+            return self.write_fragment(data, false);
+        }
+        let mut fragments = data.chunks(FRAGMENT_SIZE).peekable();
+        while let Some(fragment) = fragments.next() {
+            try!(self.write_fragment(fragment, fragments.peek().is_some()));
+        }
+        Ok(())
+    }
+
+    // Sends all of `reader` as one frame, a fragment at a time, so it needn't
+    // fit in memory. Returns how many bytes it sent. Streamed frames are too
+    // big to capture, so they aren't.
+    pub fn write_stream<R: Read>(&mut self, reader: &mut R) -> Result<u64, String> {
+        if self.version < STREAM_VERSION {
+            return Err(format!("Streamed frames need protocol version {}", STREAM_VERSION));
+        }
+        let mut fragment = vec![0; FRAGMENT_SIZE];
+        let mut len = try!(fill(reader, &mut fragment));
+        let mut sent = 0;
+        loop {
+            // Reading a fragment ahead tells us whether this one is the last.
+            let mut next = vec![0; FRAGMENT_SIZE];
+            let next_len = if len < FRAGMENT_SIZE { 0 } else { try!(fill(reader, &mut next)) };
+            try!(self.write_fragment(&fragment[..len], next_len > 0));
+            sent += len as u64;
+            if next_len == 0 {
+                return Ok(sent);
+            }
+            fragment = next;
+            len = next_len;
+        }
+    }
+
+    fn write_fragment(&mut self, data: &[u8], more: bool) -> Result<(), String> {
+        let ad = encode_u32(MORE_FRAGMENTS);
+        let frame = match self.send {
+            Some(ref mut send) => send.encrypt(if more { &ad[..] } else { &[] }, data),
+            None => return Err("This half of the stream only reads".to_string()),
+        };
+        if !more {
+            return write_frame(&mut self.stream, &frame);
+        }
+        try!(self.stream.write_all(&encode_u32(frame.len() as u32 | MORE_FRAGMENTS)).map_err(|e| e.to_string()));
+        self.stream.write_all(&frame).map_err(|e| e.to_string())
+    }
+
+    pub fn read_frame(&mut self) -> Result<Vec<u8>, String> {
+        match try!(self.read_frame_or_control()) {
+            Ok(frame) => Ok(frame),
+            Err(control) => Err(control_error(control)),
+        }
+    }
+
+    // Reads the next frame, or the control frame sent instead.
+    fn read_frame_or_control(&mut self) -> Result<Result<Vec<u8>, Control>, String> {
+        let mut frame = Vec::new();
+        loop {
+            let limit = self.max_frame_size - cmp::min(frame.len(), self.max_frame_size);
+            match try!(self.read_fragment(limit as u64)) {
+                Fragment::Data(fragment, more) => {
+                    frame.extend(fragment);
+                    if !more {
+                        break;
+                    }
+                },
+                Fragment::Control(control) => return Ok(Err(control)),
+            }
+        }
+        if let Some(ref c) = self.capture {
+            c.record(true, &frame);
+        }
+        Ok(Ok(frame))
+    }
+
+    // Writes a streamed frame to `writer` as it arrives, failing once it is
+    // over `limit` bytes. Returns how many bytes it had.
+    pub fn read_stream<W: Write>(&mut self, writer: &mut W, limit: u64) -> Result<u64, String> {
+        let mut received = 0;
+        loop {
+            let (fragment, more) = match try!(self.read_fragment(limit - received)) {
+                Fragment::Data(fragment, more) => (fragment, more),
+                Fragment::Control(control) => return Err(control_error(control)),
+            };
+            try!(writer.write_all(&fragment).map_err(|e| e.to_string()));
+            received += fragment.len() as u64;
+            if !more {
+                return Ok(received);
+            }
+        }
+    }
+
+    // Reads the next fragment, of at most `limit` bytes, and whether more
+    // of the frame follow.
+    fn read_fragment(&mut self, limit: u64) -> Result<Fragment, String> {
+        let len = try!(read_frame_len(&mut self.stream)) as u32;
+        let more = len & MORE_FRAGMENTS != 0;
+        let control = len & CONTROL_FRAME != 0;
+        let frame_size = (len & !(MORE_FRAGMENTS | CONTROL_FRAME)) as usize;
+
+        // Refuse oversized frames before allocating anything for them.
+        if control && (more || frame_size > 2 + 16) {
+            return Err("Malformed control frame".to_string());
+        }
+        if !control && (frame_size > self.max_frame_size.saturating_add(16) || frame_size as u64 > limit.saturating_add(16)) {
+            let _ = self.write_control(Control::FrameTooLarge);
+            return Err(format!("Frame of {} bytes is over the {} byte limit", frame_size, cmp::min(limit, self.max_frame_size as u64)));
+        }
+
+        let mut frame = vec![0; frame_size];
+        try!(self.stream.read_exact(frame.as_mut_slice()).map_err(|e| e.to_string()));
+
+        let ad = encode_u32(if control { CONTROL_FRAME } else { MORE_FRAGMENTS });
+        let frame = match self.recv {
+            Some(ref mut recv) => try!(recv.decrypt(if more || control { &ad[..] } else { &[] }, &frame)
+                .map_err(|_| "Failed to decrypt frame".to_string())),
+            None => return Err("This half of the stream only writes".to_string()),
+        };
+        if control {
+            return Control::parse(&frame).map(Fragment::Control);
+        }
+        Ok(Fragment::Data(frame, more))
+    }
+}
+
+// The congestion window and smoothed ack latency of a single next hop.
+struct HopWindow {
+    in_flight: usize,
+    window: f64,
+    srtt: Option<Duration>,
+}
+
+// How long a relay holds each message before forwarding it, so the times
+// messages arrive and leave are harder to match up.
+#[derive(Clone, Copy)]
+enum RelayDelay {
+    None,
+    Uniform(u64), // up to this many milliseconds
+    Exponential(u64, u64), // mean and cap in milliseconds
+}
+
+impl RelayDelay {
+    fn from_config(config: &Config) -> RelayDelay {
+        let mean = config.get("relay_delay_ms", 0u64);
+        let cap = config.get("relay_delay_cap_ms", MAX_RELAY_DELAY_MS);
+        match (mean, &*config.get_str("relay_delay").unwrap_or("exponential".to_string())) {
+            (0, _) => RelayDelay::None,
+            (m, "uniform") => RelayDelay::Uniform(cmp::min(2 * m, cap)),
+            (m, _) => RelayDelay::Exponential(m, cap),
+        }
+    }
+
+    fn sample(&self) -> Duration {
+        let ms = match *self {
+            RelayDelay::None => 0,
+            RelayDelay::Uniform(max) => (rand::random::<f64>() * max as f64) as u64,
+            RelayDelay::Exponential(mean, cap) =>
+                cmp::min((-(1.0 - rand::random::<f64>()).ln() * mean as f64) as u64, cap),
+        };
+        Duration::from_millis(ms)
+    }
+}
+
+// Limits the number of unacknowledged messages in flight to each next hop.
+// Windows grow by one message per window of timely acks and halve when an
+// ack is lost or arrives much later than usual (AIMD).
+struct HopWindows {
+    hops: Mutex<HashMap<Addr, HopWindow>>,
+    cvar: Condvar,
+}
+
+impl HopWindows {
+    fn new() -> HopWindows {
+        HopWindows {
+            hops: Mutex::new(HashMap::new()),
+            cvar: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self, hop: &Addr) {
+        let mut hops = self.hops.lock().unwrap();
+        loop {
+            let w = hops.entry(*hop).or_insert(HopWindow {
+                in_flight: 0,
+                window: INITIAL_HOP_WINDOW,
+                srtt: None,
+            });
+            if (w.in_flight as f64) < w.window.floor() {
+                w.in_flight += 1;
+                return;
+            }
+            hops = self.cvar.wait(hops).unwrap();
+        }
+    }
+
+    fn on_ack(&self, hop: &Addr, latency: Duration) {
+        if let Some(w) = self.hops.lock().unwrap().get_mut(hop) {
+            let delayed = w.srtt.map_or(false, |srtt| latency > srtt * 2);
+            w.srtt = Some(match w.srtt {
+                Some(srtt) => (srtt * 7 + latency) / 8,
+                None => latency,
+            });
+
+            if delayed {
+                w.window = (w.window / 2.0).max(1.0);
+            } else {
+                w.window = (w.window + 1.0 / w.window).min(MAX_HOP_WINDOW);
+            }
+        }
+        self.cvar.notify_all();
+    }
+
+    fn on_loss(&self, hop: &Addr) {
+        if let Some(w) = self.hops.lock().unwrap().get_mut(hop) {
+            w.window = (w.window / 2.0).max(1.0);
+        }
+    }
+
+    fn release(&self, hop: &Addr) {
+        if let Some(w) = self.hops.lock().unwrap().get_mut(hop) {
+            w.in_flight -= 1;
+        }
+        self.cvar.notify_all();
+    }
+}
+
+struct ConnectionPool {
+    idle: Mutex<HashMap<Addr, Vec<(Option<Key>, SecureStream, Instant)>>>, // by hop, with when each was last used
+    in_use: Mutex<HashMap<Addr, usize>>,
+    cvar: Condvar,
+    max_idle: Duration,
+    max_per_hop: usize,
+}
+
+impl ConnectionPool {
+    fn from_config(config: &Config) -> ConnectionPool {
+        ConnectionPool {
+            idle: Mutex::new(HashMap::new()),
+            in_use: Mutex::new(HashMap::new()),
+            cvar: Condvar::new(),
+            max_idle: Duration::from_secs(config.get("pool_idle_secs", DEFAULT_POOL_IDLE_SECS)),
+            max_per_hop: cmp::max(config.get("pool_max_per_hop", DEFAULT_POOL_MAX_PER_HOP), 1),
+        }
+    }
+
+    // Waits for a free slot to `hop`, then takes an idle connection made with
+    // the same key or opens a new one. Also says whether it was reused.
+    fn acquire(&self, hop: &Addr, key: Option<&Key>, proxy: Option<&Proxy>) -> Result<(SecureStream, bool), String> {
+        {
+            let mut in_use = self.in_use.lock().unwrap();
+            while *in_use.get(hop).unwrap_or(&0) >= self.max_per_hop {
+                in_use = self.cvar.wait(in_use).unwrap();
+            }
+            *in_use.entry(*hop).or_insert(0) += 1;
+        }
+
+        let reused = self.idle.lock().unwrap().get_mut(hop).and_then(|streams| {
+            streams.iter().rposition(|&(ref k, _, used)| k.as_ref() == key && used.elapsed() < self.max_idle)
+                .map(|i| streams.remove(i).1)
+        });
+        match reused {
+            Some(stream) => Ok((stream, true)),
+            None => SecureStream::connect_via(hop, key, proxy).map(|s| (s, false)).map_err(|e| {
+                self.release(hop, key, None);
+                e
+            }),
+        }
+    }
+
+    // Frees the slot, keeping the connection for later unless it is None.
+    fn release(&self, hop: &Addr, key: Option<&Key>, stream: Option<SecureStream>) {
+        if let Some(stream) = stream.filter(|_| self.max_idle > Duration::from_secs(0)) {
+            self.idle.lock().unwrap().entry(*hop).or_insert_with(Vec::new)
+                .push((key.cloned(), stream, Instant::now()));
+        }
+        if let Some(n) = self.in_use.lock().unwrap().get_mut(hop) {
+            *n -= 1;
+        }
+        self.cvar.notify_all();
+    }
+
+    fn clear(&self) {
+        self.idle.lock().unwrap().clear();
+    }
+
+    // Closes connections that have been idle too long.
+    fn reap(&self) {
+        let mut idle = self.idle.lock().unwrap();
+        for streams in idle.values_mut() {
+            streams.retain(|&(_, _, used)| used.elapsed() < self.max_idle);
+        }
+        idle.retain(|_, streams| !streams.is_empty());
+    }
+}
+
+struct Link {
+    failures: u32,
+    retry_at: Instant,
+    queued: Vec<MessageContainer>,
+}
+
+// The hops that are down, with the messages waiting for each.
+struct Links {
+    down: Mutex<HashMap<Addr, Link>>,
+    attempts: u32,
+    max_backoff: Duration,
+}
+
+impl Links {
+    fn from_config(config: &Config) -> Links {
+        Links {
+            down: Mutex::new(HashMap::new()),
+            attempts: config.get("reconnect_attempts", DEFAULT_RECONNECT_ATTEMPTS),
+            max_backoff: Duration::from_secs(config.get("max_reconnect_secs", DEFAULT_MAX_RECONNECT_SECS)),
+        }
+    }
+
+    // Equal jitter, so everyone who lost the same hop doesn't retry at once.
+    fn backoff(&self, failures: u32) -> Duration {
+        let delay = cmp::min(Duration::from_millis(RECONNECT_BASE_MS) * 2u32.pow(cmp::min(failures, 16)), self.max_backoff);
+        delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+    }
+
+    // Queues `work` if its hop is down, handing it back otherwise. Requests
+    // waiting on a response fail straight away rather than hang.
+    fn hold(&self, work: MessageContainer) -> Option<MessageContainer> {
+        let hop = match work.msg.next_hop {
+            Some(h) => h,
+            None => return Some(work),
+        };
+        let mut down = self.down.lock().unwrap();
+        let link = match down.get_mut(&hop) {
+            Some(link) => link,
+            None => return Some(work),
+        };
+        if work.needs_response {
+            let wait = link.retry_at.checked_duration_since(Instant::now()).unwrap_or(Duration::from_secs(0));
+            if let Some(res) = work.response {
+                let _ = res.send(Err(format!("Not connected, trying again in {} seconds", wait.as_secs() + 1)));
+            }
+        } else {
+            link.queued.push(work);
+        }
+        None
+    }
+
+    // Marks `hop` down after a failed send, queuing `work` with it. Returns
+    // the work instead if reconnecting is turned off.
+    fn on_failure(&self, hop: &Addr, work: Option<MessageContainer>) -> Option<MessageContainer> {
+        if self.attempts == 0 {
+            return work;
+        }
+        let retry_at = Instant::now() + self.backoff(0);
+        let mut down = self.down.lock().unwrap();
+        let link = down.entry(*hop).or_insert_with(|| Link { failures: 0, retry_at: retry_at, queued: Vec::new() });
+        link.queued.extend(work);
+        None
+    }
+
+    fn retry_now(&self) {
+        let now = Instant::now();
+        for link in self.down.lock().unwrap().values_mut() {
+            link.retry_at = now;
+        }
+    }
+
+    fn due(&self) -> Vec<Addr> {
+        let now = Instant::now();
+        self.down.lock().unwrap().iter().filter(|&(_, l)| l.retry_at <= now).map(|(hop, _)| *hop).collect()
+    }
+
+    // The hop answered, returns what was waiting for it.
+    fn restore(&self, hop: &Addr) -> Vec<MessageContainer> {
+        self.down.lock().unwrap().remove(hop).map_or(Vec::new(), |l| l.queued)
+    }
+
+    // Schedules the next attempt, or returns what was waiting once there
+    // are none left.
+    fn retry_later(&self, hop: &Addr) -> Option<Vec<MessageContainer>> {
+        let mut down = self.down.lock().unwrap();
+        let failures = match down.get_mut(hop) {
+            Some(link) => {
+                link.failures += 1;
+                link.failures
+            },
+            None => return None,
+        };
+        if failures >= self.attempts {
+            return down.remove(hop).map(|l| l.queued);
+        }
+        let retry_at = Instant::now() + self.backoff(failures);
+        down.get_mut(hop).unwrap().retry_at = retry_at;
+        None
+    }
+}
+
+// A persistent connection to the server. Requests are tagged with an id so
+// several can wait for their replies at once.
+struct Session {
+    writer: Mutex<SecureStream>,
+    waiting: Mutex<HashMap<u64, Sender<Result<Vec<u8>, String>>>>,
+    next_id: AtomicUsize,
+    heard: Mutex<Instant>, // when the server last sent anything
+    closed: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct Net {
+    send_work: Arc<MpmcPriorityQueue<MessageContainer>>,
+    recv_work: Arc<MpmcQueue<TcpStream>>,
+    new_messages: Arc<MpmcQueue<TextMessage>>,
+    acks: Arc<MpmcQueue<u64>>,
+    receipts: Arc<MpmcQueue<u64>>,
+    broadcasts: Arc<MpmcQueue<ToUser>>,
+    resets: Arc<MpmcQueue<SessionReset>>,
+    files: Arc<MpmcQueue<ToUser>>,
+    notices: Arc<MpmcQueue<String>>,
+    replays: Arc<ReplayWindow>,
+    read_receipts: Arc<AtomicBool>,
+    typing: Arc<Mutex<HashMap<String, Instant>>>, // when each peer last said they were typing
+    typing_sent: Arc<Mutex<HashMap<String, Instant>>>,
+    send_typing: bool,
+    show_typing: bool,
+    sealed_sender: bool,
+    carry_mode: Arc<AtomicBool>,
+    carry_for: Arc<Mutex<HashSet<Key>>>, // the contacts whose messages are carried
+    carried: Arc<Mutex<Vec<CarriedMessage>>>,
+    windows: Arc<HopWindows>,
+    pool: Arc<ConnectionPool>,
+    links: Arc<Links>,
+    max_frame_size: usize,
+    relay_tests: Arc<Mutex<HashMap<u64, Sender<usize>>>>,
+    relay: bool,
+    server_relay: bool, // whether routes may go through the server's relay
+    last_resort: Arc<Mutex<HashSet<String>>>, // peers whose route does
+    busy_until: Arc<Mutex<Option<Instant>>>, // when the server said it would take requests again
+    relay_delay: RelayDelay,
+    relayed: Arc<(AtomicUsize, AtomicUsize)>, // messages and bytes forwarded
+    capture: Option<Arc<Capture>>,
+    mailbox: Arc<AtomicBool>, // whether peers reach us through the server
+    nat_auto: bool,
+    pending: Arc<Mutex<Vec<(u64, Vec<MessageContainer>)>>>,
+    send_delay: Duration,
+    handle: Arc<Mutex<Option<String>>>,
+    pub crypto: Crypto,
+    proxy: Option<Proxy>,
+    persistent: bool,
+    session: Arc<Mutex<Option<Arc<Session>>>>,
+    keepalive: Duration,
+    keepalive_timeout: Duration,
+    low_power: Arc<AtomicBool>,
+    low_power_keepalive: Duration,
+    low_power_batch: Duration,
+    batch: Arc<Mutex<Vec<MessageContainer>>>, // waiting to go out together in low power mode
+    connection_changes: Arc<MpmcQueue<bool>>, // whether the persistent connection is up
+    watching: Arc<Mutex<Vec<(Topic, u64)>>>, // each with the cursor of the last update heard
+    updates: Arc<MpmcQueue<Update>>,
+    security: Arc<MpmcQueue<(SecurityKind, String)>>, // refused messages, for the security log
+    relays: Arc<Mutex<(u64, BTreeMap<Key, Addr>, Option<Key>)>>, // directory version, relays, the key it is signed with
+    server_addr: Addr,
+    port: u16,
+    server_key: Key,
+    server_version: u16,
+    server_suite: Suite,
+}
+
+impl Net {
+
+    pub fn new(mut crypto: Crypto, config: &Config) -> Result<Net, String> {
+        crypto.set_padding_buckets(&padding_buckets(config));
+        crypto.set_compression(config.get("compress_messages", false));
+
+        // Sessions are only captured when asked, to ~/.secmsg/captures.
+        let capture = if config.get("capture", false) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let path = try!(env::home_dir().ok_or("Cannot find home directory.".to_string()))
+                .join(format!(".secmsg/captures/{}.cap", now));
+            Some(Arc::new(try!(Capture::create(&path, &crypto))))
+        } else {
+            None
+        };
+
+        // Get the server's public key from the first address that answers.
+        // Behind a proxy the server's name is looked up by the proxy too, so
+        // nothing leaks to the local resolver.
+        let proxy = try!(Proxy::from_config(config));
+        let resolve = |addr: &str| match proxy {
+            Some(ref p) => p.resolve(addr),
+            None => resolve(addr),
+        };
+        let candidates = try!(server_candidates(config, proxy.is_some()));
+        let configured = match config.get_str("server_key") {
+            Some(hex) => Some(try!(hex.from_hex().ok().filter(|k| k.len() == 32)
+                .map(|k| { let mut key = [0u8; 32]; key.copy_from_slice(&k); key })
+                .ok_or("server_key is not a key in hex".to_string()))),
+            None => None,
+        };
+        let mut errors = Vec::new();
+        let mut found = None;
+        for (server, key_server) in candidates {
+            let fetched = resolve(&server).and_then(|server_addr| match configured {
+                Some(key) => Net::check_server_key(&server_addr, &key, &crypto, proxy.as_ref()).map(|fetched| (server_addr, fetched)),
+                None => resolve(&key_server)
+                    .and_then(|key_addr| Net::fetch_server_key(&key_addr, &crypto, proxy.as_ref(), capture.clone()))
+                    .map(|fetched| (server_addr, fetched)),
+            });
+            match fetched {
+                Ok(f) => {
+                    found = Some(f);
+                    break;
+                },
+                Err(e) => errors.push(format!("{}: {}", server, e)),
+            }
+        }
+        let (server_addr, (server_pub_key, server_version, server_suite)) = try!(found.ok_or(errors.join(", ")));
+
+        // Pin the server's key on first use so later fetches can't be swapped.
+        let pinned = try!(env::home_dir().ok_or("Cannot find home directory.".to_string()))
+            .join(".secmsg/keys/server");
+        if pinned.exists() {
+            let mut pinned_key = [0u8; 32];
+            try!(File::open(&pinned).and_then(|mut f| f.read_exact(&mut pinned_key)).map_err(|e| e.to_string()));
+            if pinned_key != server_pub_key {
+                return Err("Server public key does not match the pinned key.".to_string());
+            }
+        } else {
+            try!(File::create(&pinned).and_then(|mut f| f.write_all(&server_pub_key)).map_err(|e| e.to_string()));
+        }
+
+        // The net struct to be returned.
+        let net = Net {
+            send_work: Arc::new(MpmcPriorityQueue::new(PRIORITY_LEVELS)),
+            recv_work: Arc::new(MpmcQueue::new()),
+            new_messages: Arc::new(MpmcQueue::new()),
+            acks: Arc::new(MpmcQueue::new()),
+            receipts: Arc::new(MpmcQueue::new()),
+            broadcasts: Arc::new(MpmcQueue::new()),
+            resets: Arc::new(MpmcQueue::new()),
+            files: Arc::new(MpmcQueue::new()),
+            notices: Arc::new(MpmcQueue::new()),
+            replays: Arc::new(ReplayWindow::new()),
+            read_receipts: Arc::new(AtomicBool::new(config.get("read_receipts", false))),
+            typing: Arc::new(Mutex::new(HashMap::new())),
+            typing_sent: Arc::new(Mutex::new(HashMap::new())),
+            send_typing: config.get("send_typing", true),
+            show_typing: config.get("show_typing", true),
+            sealed_sender: config.get("sealed_sender", false),
+            carry_mode: Arc::new(AtomicBool::new(false)),
+            carry_for: Arc::new(Mutex::new(HashSet::new())),
+            carried: Arc::new(Mutex::new(Vec::new())),
+            windows: Arc::new(HopWindows::new()),
+            pool: Arc::new(ConnectionPool::from_config(config)),
+            links: Arc::new(Links::from_config(config)),
+            max_frame_size: config.get("max_frame_size", DEFAULT_MAX_FRAME_SIZE),
+            relay_tests: Arc::new(Mutex::new(HashMap::new())),
+            relay: config.get("relay", true),
+            server_relay: config.get("server_relay", false),
+            last_resort: Arc::new(Mutex::new(HashSet::new())),
+            busy_until: Arc::new(Mutex::new(None)),
+            relay_delay: RelayDelay::from_config(config),
+            relayed: Arc::new((AtomicUsize::new(0), AtomicUsize::new(0))),
+            capture: capture,
+            mailbox: Arc::new(AtomicBool::new(config.get_str("nat").map_or(false, |n| n == "mailbox"))),
+            nat_auto: config.get_str("nat").map_or(true, |n| n == "auto"),
+            pending: Arc::new(Mutex::new(Vec::new())),
+            send_delay: Duration::from_secs(config.get("undo_secs", 0)),
+            handle: Arc::new(Mutex::new(None)),
+            crypto: crypto,
+            proxy: proxy,
+            persistent: config.get("persistent_connection", true),
+            session: Arc::new(Mutex::new(None)),
+            keepalive: Duration::from_secs(config.get("keepalive_secs", DEFAULT_KEEPALIVE_SECS)),
+            keepalive_timeout: Duration::from_secs(config.get("keepalive_timeout_secs", DEFAULT_KEEPALIVE_TIMEOUT_SECS)),
+            low_power: Arc::new(AtomicBool::new(config.get("low_power", false))),
+            low_power_keepalive: Duration::from_secs(config.get("low_power_keepalive_secs", DEFAULT_LOW_POWER_KEEPALIVE_SECS)),
+            low_power_batch: Duration::from_secs(config.get("low_power_batch_secs", DEFAULT_LOW_POWER_BATCH_SECS)),
+            batch: Arc::new(Mutex::new(Vec::new())),
+            connection_changes: Arc::new(MpmcQueue::new()),
+            watching: Arc::new(Mutex::new(Vec::new())),
+            updates: Arc::new(MpmcQueue::new()),
+            security: Arc::new(MpmcQueue::new()),
+            relays: Arc::new(Mutex::new((0, BTreeMap::new(), None))),
+            server_addr: server_addr,
+            port: config.get("port", DEFAULT_PORT),
+            server_key: server_pub_key,
+            server_version: server_version,
+            server_suite: server_suite,
+        };
+       
+        // Spawn main receiver.
+        let recv_net = net.clone();
+        thread::spawn(move|| Net::listener(recv_net));
+
+        // Spawning all receiver threads.
+        for _ in 0..4 {
+            let recv_net = net.clone();
+            thread::spawn(move|| Net::receiver(recv_net));
+        }
+        
+        // Spawning all sender threads.
+        for _ in 0..4 {
+            let send_net = net.clone();
+            thread::spawn(move|| Net::sender(send_net));
+        }
+
+        let heartbeat_net = net.clone();
+        thread::spawn(move|| Net::heartbeat(heartbeat_net));
+
+        let mailbox_net = net.clone();
+        thread::spawn(move|| Net::fetch_mailbox(mailbox_net));
+
+        let reconnect_net = net.clone();
+        thread::spawn(move|| Net::reconnect(reconnect_net));
+
+        let batch_net = net.clone();
+        thread::spawn(move|| loop {
+            thread::sleep(batch_net.low_power_batch);
+            batch_net.flush_batch();
+        });
+
+        let suspend_net = net.clone();
+        thread::spawn(move|| Net::watch_suspend(suspend_net));
+
+        let pool = net.pool.clone();
+        thread::spawn(move|| loop {
+            thread::sleep(Duration::from_secs(POOL_REAP_SECS));
+            pool.reap();
+        });
+
+        if config.get("cover_traffic", false) {
+            let cover_net = net.clone();
+            let mean_secs = config.get("cover_interval_secs", DEFAULT_COVER_INTERVAL_SECS);
+            thread::spawn(move|| Net::cover_traffic(cover_net, mean_secs));
+        }
+
+        Ok(net)
+    }
+
+    // Asks the public key service for the server's key, returning it with the
+    // protocol version and cipher suite agreed on the way.
+    fn fetch_server_key(addr: &Addr, crypto: &Crypto, proxy: Option<&Proxy>,
+                        capture: Option<Arc<Capture>>) -> Result<(Key, u16, Suite), String> {
+        let mut stream = try!(SecureStream::connect_via(addr, None, proxy));
+        stream.set_capture(capture);
+        let mut key_request = Message::new(
+            MessageType::Server(
+                ToServer::PublicKey(crypto.pub_key)
+            ),
+            vec![],
+            &crypto
+        );
+        try!(Net::send_message(&mut stream, &mut key_request).map_err(|e| e.to_string()));
+        match try!(Net::decode_type(&try!(Net::receive_message(&mut stream, &crypto)).data)) {
+            MessageType::User(ToUser::ServerResponse(ResponseType::PublicKey(pk))) => Ok((pk, stream.version(), stream.suite())),
+            _ => Err("Unable to get server public key.".to_string()),
+        }
+    }
+
+    // Proves the server at `addr` owns `key`, returning the protocol version
+    // and cipher suite agreed on the way. The server is sent cover traffic so
+    // it has something to drop.
+    fn check_server_key(addr: &Addr, key: &Key, crypto: &Crypto, proxy: Option<&Proxy>) -> Result<(Key, u16, Suite), String> {
+        let mut stream = try!(SecureStream::connect_via(addr, Some(key), proxy));
+        let cover = Message::new(MessageType::Server(ToServer::Cover(Vec::new())), vec![(*addr, *key)], crypto);
+        try!(stream.write_frame(&cover.data));
+        stream.read_ack(Duration::from_millis(HOP_ACK_TIMEOUT_MS));
+        Ok((*key, stream.version(), stream.suite()))
+    }
+
+    pub fn get_server_key(&self) -> Key {
+        self.server_key.clone()
+    }
+
+    pub fn get_server_route(&self) -> Route {
+        vec![(self.server_addr, self.server_key)]
+    }
+    
+    pub fn get_message(&self) -> TextMessage {
+        self.new_messages.pop()
+    }
+
+    // Blocks until a peer acknowledges one of our messages, returning its id.
+    pub fn get_ack(&self) -> u64 {
+        self.acks.pop()
+    }
+
+    // Blocks until a peer reports reading one of our messages, returning its id.
+    pub fn get_receipt(&self) -> u64 {
+        self.receipts.pop()
+    }
+
+    // Blocks until a broadcast or the key to a broadcast list arrives.
+    pub fn get_broadcast(&self) -> ToUser {
+        self.broadcasts.pop()
+    }
+
+    // Blocks until a peer asks to start a conversation's numbering over.
+    pub fn get_reset(&self) -> SessionReset {
+        self.resets.pop()
+    }
+
+    // Blocks until an offer, acceptance, chunk or cancellation of a file
+    // transfer arrives.
+    pub fn get_file_event(&self) -> ToUser {
+        self.files.pop()
+    }
+
+    // Blocks until the server sends a notice about our account, such as a
+    // warning that it will be archived.
+    pub fn get_notice(&self) -> String {
+        self.notices.pop()
+    }
+
+    // Blocks until the server pushes a change to something we watch.
+    pub fn get_update(&self) -> Update {
+        self.updates.pop()
+    }
+
+    // Blocks until a message is refused in a way the security log records.
+    pub fn get_security_event(&self) -> (SecurityKind, String) {
+        self.security.pop()
+    }
+
+    pub fn set_read_receipts(&self, enabled: bool) {
+        self.read_receipts.store(enabled, Ordering::SeqCst);
+    }
+
+    // Tells the sender of `msg` it was shown to the user, if they allow it.
+    pub fn send_read_receipt(&self, msg: &TextMessage) {
+        if self.read_receipts.load(Ordering::SeqCst) {
+            self.notify(&msg.sender.handle, ToUser::ReadReceipt(msg.id));
+        }
+    }
+
+    // Tells `to` we are composing a message, at most once every TYPING_SECS.
+    pub fn send_typing(&self, to: &str) {
+        let handle = match *self.handle.lock().unwrap() {
+            Some(ref h) if self.send_typing => h.clone(),
+            _ => return,
+        };
+
+        let mut sent = self.typing_sent.lock().unwrap();
+        if sent.get(to).map_or(false, |t| t.elapsed() < Duration::from_secs(TYPING_SECS)) {
+            return;
+        }
+        sent.insert(to.to_string(), Instant::now());
+        self.notify(to, ToUser::Typing(handle));
+    }
+
+    pub fn is_typing(&self, handle: &str) -> bool {
+        self.typing.lock().unwrap().get(handle)
+            .map_or(false, |t| t.elapsed() < Duration::from_secs(TYPING_SECS))
+    }
+
+    fn send_ack(&self, msg: &TextMessage) {
+        self.notify(&msg.sender.handle, ToUser::Ack(msg.id));
+    }
+
+    // Sends `note` to `handle` over a route of its own, so the sender's
+    // address isn't revealed to the hops.
+    pub fn notify(&self, handle: &str, note: ToUser) {
+        let (net, handle) = (self.clone(), handle.to_string());
+        thread::spawn(move || {
+            if let Ok(route) = net.get_route(&handle) {
+                net.add_message(MessageContainer::new(
+                    Message::new(MessageType::User(note), route, &net.crypto),
+                    None,
+                    false
+                ));
+            }
+        });
+    }
+
+    pub fn add_message(&self, msg: MessageContainer) {
+        // Requests are waited on, so only the rest are batched.
+        if self.is_low_power() && !msg.needs_response {
+            if msg.msg.priority != Priority::Urgent {
+                self.batch.lock().unwrap().push(msg);
+                return;
+            }
+            // The radio is woken anyway, so everything goes along.
+            self.flush_batch();
+        }
+        let level = msg.msg.priority as usize;
+        self.send_work.push(msg, level);
+    }
+
+    fn flush_batch(&self) {
+        let batch: Vec<MessageContainer> = self.batch.lock().unwrap().drain(..).collect();
+        for msg in batch {
+            let level = msg.msg.priority as usize;
+            self.send_work.push(msg, level);
+        }
+    }
+
+    pub fn set_low_power(&self, enabled: bool) {
+        self.low_power.store(enabled, Ordering::SeqCst);
+        if !enabled {
+            self.flush_batch();
+        }
+    }
+
+    pub fn is_low_power(&self) -> bool {
+        self.low_power.load(Ordering::SeqCst)
+    }
+
+    fn watch_suspend(net: Net) {
+        let mut last = SystemTime::now();
+        loop {
+            thread::sleep(Duration::from_secs(SUSPEND_CHECK_SECS));
+            let now = SystemTime::now();
+            if now.duration_since(last).map_or(false, |gap| gap > Duration::from_secs(SUSPEND_CHECK_SECS + SUSPEND_GAP_SECS)) {
+                net.resumed();
+            }
+            last = now;
+        }
+    }
+
+    // Connections from before a suspend are likely dead without either end
+    // knowing, so they are dropped and made again, and hops that were down
+    // are tried straight away.
+    fn resumed(&self) {
+        let session = self.session.lock().unwrap().clone();
+        if let Some(session) = session {
+            self.close_session(&session);
+        }
+        self.pool.clear();
+        self.links.retry_now();
+        self.flush_batch();
+        if self.persistent && self.server_version >= SESSION_VERSION {
+            let _ = self.open_session();
+        }
+    }
+
+    // Sends `msgs` once the undo grace period has passed, unless `cancel` is
+    // called with `id` first.
+    pub fn add_cancelable(&self, id: u64, msgs: Vec<MessageContainer>) {
+        if self.send_delay == Duration::from_secs(0) {
+            for m in msgs {
+                self.add_message(m);
+            }
+            return;
+        }
+
+        self.pending.lock().unwrap().push((id, msgs));
+        let net = self.clone();
+        thread::spawn(move || {
+            thread::sleep(net.send_delay);
+            let msgs = {
+                let mut pending = net.pending.lock().unwrap();
+                match pending.iter().position(|&(i, _)| i == id) {
+                    Some(pos) => pending.remove(pos).1,
+                    None => return,
+                }
+            };
+            for m in msgs {
+                net.add_message(m);
+            }
+        });
+    }
+
+    // Cancels the pending message `id`, or the latest one if `id` is None.
+    // Returns the id of the message cancelled.
+    pub fn cancel(&self, id: Option<u64>) -> Option<u64> {
+        let mut pending = self.pending.lock().unwrap();
+        let pos = match id {
+            Some(id) => pending.iter().position(|&(i, _)| i == id),
+            None => if pending.is_empty() { None } else { Some(pending.len() - 1) },
+        };
+        pos.map(|p| pending.remove(p).0)
+    }
+
+    pub fn get_route(&self, user: &str) -> Result<Route, String> {
+        let res = if self.server_relay && self.server_version >= LAST_RESORT_VERSION {
+            try!(self.lookup(|key, asker| ToServer::ConnectOrRelay(user.to_string(), key, asker)))
+        } else {
+            try!(self.lookup(|key, asker| ToServer::Connect(user.to_string(), key, asker)))
+        };
+        match res {
+            ResponseType::Connection(u) => {
+                self.last_resort.lock().unwrap().remove(user);
+                Ok(u)
+            },
+            ResponseType::RelayedConnection(u) => {
+                self.last_resort.lock().unwrap().insert(user.to_string());
+                Ok(u)
+            },
+            _ => Err("Something went wrong".to_string())
+        }
+    }
+
+    // Notes that the server asked us to wait `secs`, returning the error
+    // for the request it turned away.
+    fn set_busy(&self, secs: u16) -> String {
+        *self.busy_until.lock().unwrap() = Some(Instant::now() + Duration::from_secs(secs as u64));
+        ErrorCode::Busy(secs).to_string()
+    }
+
+    // How much longer the server asked us to wait, if at all.
+    pub fn busy_for(&self) -> Option<Duration> {
+        let until = match *self.busy_until.lock().unwrap() {
+            Some(until) => until,
+            None => return None,
+        };
+        let now = Instant::now();
+        if until > now { Some(until - now) } else { None }
+    }
+
+    // Whether the last route to `user` went through the server's relay.
+    pub fn via_last_resort(&self, user: &str) -> bool {
+        self.last_resort.lock().unwrap().contains(user)
+    }
+
+    // Sends a request about another user, with proof of who is asking if we
+    // are logged in. With sealed_sender set, it is sent without one under a
+    // throwaway key so the server can't tell who is asking, though then it
+    // can't tell whether the asker is blocked either, so users who have
+    // blocked anyone can't be found.
+    fn lookup<F: FnOnce(Key, Option<(String, u64, Key)>) -> ToServer>(&self, req: F) -> Result<ResponseType, String> {
+        if !self.sealed_sender {
+            return self.request(req(self.crypto.pub_key, self.sync_proof().ok()));
+        }
+        let (priv_key, pub_key) = crypto_lib::gen_key_pair();
+        self.request_as(req(pub_key, None), Some(Crypto::new(priv_key, pub_key)))
+    }
+
+    // Sends a request to the server and waits for its response. Error
+    // responses are returned as `Err`.
+    pub fn request(&self, req: ToServer) -> Result<ResponseType, String> {
+        self.request_as(req, None)
+    }
+
+    // A request the server is too busy for is sent again once it said it
+    // would be ready, a few times.
+    fn request_as(&self, req: ToServer, reply_crypto: Option<Crypto>) -> Result<ResponseType, String> {
+        let mut attempt = 0;
+        let res = loop {
+            if let Some(wait) = self.busy_for() {
+                thread::sleep(cmp::min(wait, Duration::from_secs(MAX_BUSY_WAIT_SECS)));
+            }
+            let (sender, receiver) = channel();
+            let mut container = MessageContainer::new(
+                Message::new(MessageType::Server(req.clone()), self.get_server_route(), &self.crypto),
+                Some(sender),
+                true
+            );
+            container.reply_crypto = reply_crypto.clone();
+            self.add_message(container);
+
+            match receiver.recv().unwrap() {
+                Ok(r) => break r.unwrap(),
+                Err(_) if self.busy_for().is_some() && attempt < BUSY_RETRIES => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        };
+
+        let reply = match Net::decode_type(&res.data) {
+            Ok(t) => t,
+            Err(e) => {
+                self.security.push((SecurityKind::DecryptFailed, format!("Unreadable reply from the server: {}", e)));
+                return Err("The server's reply could not be read".to_string());
+            },
+        };
+        if let MessageType::User(res) = reply {
+            if let ToUser::ServerResponse(res) = res {
+                match res {
+                    ResponseType::Error(e) => Err(e.to_string()),
+                    res => Ok(res),
+                }
+            } else {
+                Err("Reply was not of type ServerResponse".to_string())
+            }
+        } else {
+            Err("Reply was not of type User".to_string())
+        }
+    }
+
+    // Asks the server for `count` routes to `user` that share no relays.
+    pub fn get_disjoint_routes(&self, user: &str, count: usize) -> Result<Vec<Route>, String> {
+        match try!(self.lookup(|key, asker| ToServer::ConnectDisjoint(user.to_string(), key, count, asker))) {
+            ResponseType::Connections(routes) => Ok(routes),
+            _ => Err("Something went wrong".to_string())
+        }
+    }
+
+    // Has the server send a test message through our relay and back to us.
+    pub fn relay_test(&self, size: usize) -> Result<RelayReport, String> {
+        let nonce = rand::random::<u64>();
+        let (sender, receiver) = channel();
+        self.relay_tests.lock().unwrap().insert(nonce, sender);
+
+        let started = Instant::now();
+        let reachable = match self.request(ToServer::RelayTest(self.crypto.pub_key.clone(), nonce, size)) {
+            Ok(ResponseType::RelayTest(reachable)) => Ok(reachable),
+            Ok(_) => Err("Something went wrong".to_string()),
+            Err(e) => Err(e),
+        };
+
+        let mut report = RelayReport {
+            reachable: *reachable.as_ref().unwrap_or(&false),
+            round_trip: None,
+            bytes: size,
+            violations: Vec::new(),
+        };
+        if reachable.is_ok() && !report.reachable {
+            report.violations.push(
+                format!("The server could not connect to this relay, port {} may be closed.", self.port));
+        } else if report.reachable {
+            match receiver.recv_timeout(Duration::from_secs(RELAY_TEST_TIMEOUT_SECS)) {
+                Ok(len) => {
+                    report.round_trip = Some(started.elapsed());
+                    if len != size {
+                        report.violations.push("Test payload was altered in transit.".to_string());
+                    }
+                },
+                Err(_) => report.violations.push("Test message was not forwarded.".to_string()),
+            }
+        }
+
+        self.relay_tests.lock().unwrap().remove(&nonce);
+        reachable.map(|_| report)
+    }
+
+    // In carry mode, messages that can't be delivered are held until the
+    // destination connects to us or we next reach the server. Only messages
+    // whose next hop is one of `contacts` are held, so strangers can't use
+    // us to store layers for each other.
+    pub fn set_carry_mode(&self, enabled: bool, contacts: Vec<Key>) {
+        self.carry_mode.store(enabled, Ordering::SeqCst);
+        *self.carry_for.lock().unwrap() = if enabled { contacts.into_iter().collect() } else { HashSet::new() };
+        if !enabled {
+            self.carried.lock().unwrap().clear();
+        }
+    }
+
+    // Returns false if the message could not be held.
+    fn carry(&self, msg: Message) -> bool {
+        if !self.carry_mode.load(Ordering::SeqCst) || msg.data.len() > MAX_CARRIED_SIZE {
+            return false;
+        }
+        if !msg.next_key.map_or(false, |k| self.carry_for.lock().unwrap().contains(&k)) {
+            return false;
+        }
+
+        let mut carried = self.carried.lock().unwrap();
+        let now = Instant::now();
+        carried.retain(|c| c.expires > now);
+        if carried.len() < MAX_CARRIED_MESSAGES {
+            carried.push(CarriedMessage {
+                msg: msg,
+                expires: now + Duration::from_secs(CARRY_TTL_SECS),
+            });
+            true
+        } else {
+            false
+        }
+    }
+
+    // Queues all carried messages destined for `host` for sending.
+    fn hand_off(&self, host: IpAddr) {
+        let mut carried = self.carried.lock().unwrap();
+        let now = Instant::now();
+        carried.retain(|c| c.expires > now);
+
+        let (ready, kept): (Vec<CarriedMessage>, Vec<CarriedMessage>) = carried.drain(..)
+            .partition(|c| c.msg.next_hop.map_or(false, |a| a.0.ip() == host));
+        *carried = kept;
+
+        for c in ready {
+            self.add_message(MessageContainer::new(c.msg, None, false));
+        }
+    }
+
+    // Gives all carried messages to the server to deliver, taking them back if it can't be reached.
+    fn deposit_carried(&self) {
+        let msgs: Vec<Message> = {
+            let mut carried = self.carried.lock().unwrap();
+            let now = Instant::now();
+            carried.drain(..).filter(|c| c.expires > now).map(|c| c.msg).collect()
+        };
+        if msgs.is_empty() {
+            return;
+        }
+        let (handle, time, proof) = match self.sync_proof() {
+            Ok(p) => p,
+            Err(_) => {
+                for m in msgs {
+                    self.carry(m);
+                }
+                return;
+            },
+        };
+
+        let (sender, receiver) = channel();
+        self.add_message(
+            MessageContainer::new(
+                Message::with_priority(
+                    MessageType::Server(ToServer::Deposit(handle, time, proof, msgs.clone())),
+                    self.get_server_route(),
+                    &self.crypto,
+                    Priority::Bulk
+                ),
+                Some(sender),
+                false
+            )
+        );
+
+        let net = self.clone();
+        thread::spawn(move || {
+            if let Ok(Err(_)) = receiver.recv() {
+                for m in msgs {
+                    net.carry(m);
+                }
+            }
+        });
+    }
+
+    pub fn server_addr(&self) -> Addr {
+        self.server_addr
+    }
+
+    // The handle we are logged in as, which heartbeats are sent for. With
+    // `nat = auto`, logging in checks whether the server can connect to us,
+    // and if not has peers reach us through a mailbox on the server.
+    pub fn set_handle(&self, handle: Option<String>) {
+        let logged_in = handle.is_some();
+        *self.handle.lock().unwrap() = handle;
+        if logged_in && self.nat_auto {
+            let net = self.clone();
+            thread::spawn(move || if let Ok(report) = net.relay_test(0) {
+                net.mailbox.store(!report.reachable, Ordering::SeqCst);
+            });
+        }
+    }
+
+    pub fn handle(&self) -> Option<String> {
+        self.handle.lock().unwrap().clone()
+    }
+
+    pub fn uses_mailbox(&self) -> bool {
+        self.mailbox.load(Ordering::SeqCst)
+    }
+
+    // Our address as the server sees it, which differs from our own when we
+    // are behind NAT.
+    pub fn reflexive_addr(&self) -> Result<Addr, String> {
+        match try!(self.request(ToServer::WhoAmI(self.crypto.pub_key))) {
+            ResponseType::Reflexive(addr) => Ok(addr),
+            _ => Err("Something went wrong".to_string()),
+        }
+    }
+
+    // State shared by our devices and the version the server holds. Like
+    // heartbeats it is proven with our key, so syncing needs no password.
+    // None if no device has stored anything. State that doesn't open under
+    // our key for the slot wasn't written by one of our devices, so it is
+    // logged and treated the same, and the next change replaces it.
+    pub fn get_synced(&self, slot: SyncSlot) -> Result<(u64, Option<Vec<u8>>), String> {
+        let (handle, now, proof) = try!(self.sync_proof());
+        match try!(self.request(ToServer::GetSynced(handle, now, proof, slot))) {
+            ResponseType::Synced(version, ref blob) if blob.is_empty() => Ok((version, None)),
+            ResponseType::Synced(version, blob) => match crypto_lib::open_with_key(&self.crypto.sync_key(slot.name()), &blob) {
+                Ok(data) => Ok((version, Some(data))),
+                Err(_) => {
+                    self.security.push((SecurityKind::DecryptFailed, format!("Synced {} that none of our devices wrote", slot.name())));
+                    Ok((version, None))
+                },
+            },
+            _ => Err("Something went wrong".to_string()),
+        }
+    }
+
+    // Returns the new version, or fails if the state changed since `version`.
+    pub fn put_synced(&self, slot: SyncSlot, version: u64, data: &[u8]) -> Result<u64, String> {
+        let (handle, now, proof) = try!(self.sync_proof());
+        let blob = try!(crypto_lib::seal_with_key(&self.crypto.sync_key(slot.name()), data)
+            .map_err(|_| "Failed to encrypt synced state".to_string()));
+        match try!(self.request(ToServer::PutSynced(handle, now, proof, slot, version, blob))) {
+            ResponseType::Synced(version, _) => Ok(version),
+            _ => Err("Something went wrong".to_string()),
+        }
+    }
+
+    fn sync_proof(&self) -> Result<(String, u64, Key), String> {
+        let handle = try!(self.handle().ok_or("Not logged in".to_string()));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Ok((handle, now, self.crypto.prove(&self.server_key, now)))
+    }
+
+    // Has the server push changes to `topic` over the persistent connection,
+    // replacing whatever of the same kind we watched. It is watched again
+    // whenever the connection reopens, asking for anything missed meanwhile.
+    pub fn watch(&self, topic: Topic) -> Result<(), String> {
+        if !self.persistent || self.server_version < SESSION_VERSION {
+            return Err("Watching needs a persistent connection to the server".to_string());
+        }
+        let cursor = self.watching.lock().unwrap().iter()
+            .find(|&&(ref t, _)| t.same_kind(&topic))
+            .map_or(0, |&(_, c)| c);
+        try!(self.send_watch(topic, cursor));
+        Ok(())
+    }
+
+    pub fn unwatch(&self, topic: Topic) -> Result<(), String> {
+        self.watching.lock().unwrap().retain(|&(ref t, _)| !t.same_kind(&topic));
+        let (handle, now, proof) = try!(self.sync_proof());
+        try!(self.request(ToServer::Unwatch(handle, now, proof, topic)));
+        Ok(())
+    }
+
+    // What we watch, for showing.
+    pub fn watching(&self) -> Vec<Topic> {
+        self.watching.lock().unwrap().iter().map(|&(ref t, _)| t.clone()).collect()
+    }
+
+    fn send_watch(&self, topic: Topic, cursor: u64) -> Result<(), String> {
+        let (handle, now, proof) = try!(self.sync_proof());
+        let (missed, latest, complete) = match try!(self.request(ToServer::Watch(handle, now, proof, topic.clone(), cursor))) {
+            ResponseType::Updates(missed, latest, complete) => (missed, latest, complete),
+            _ => return Err("Something went wrong".to_string()),
+        };
+        if cursor > 0 && !complete && topic == Topic::Directory {
+            self.notices.push("Some changes to the directory were missed while disconnected, search it again to catch up.".to_string());
+        }
+        {
+            let mut watching = self.watching.lock().unwrap();
+            watching.retain(|&(ref t, _)| !t.same_kind(&topic));
+            watching.push((topic, latest));
+        }
+        for (_, update) in missed {
+            self.updates.push(update);
+        }
+        Ok(())
+    }
+
+    // Watches everything again on a new connection, from where we left off.
+    fn rewatch(&self) {
+        let watching = self.watching.lock().unwrap().clone();
+        for (topic, cursor) in watching {
+            let _ = self.send_watch(topic, cursor);
+        }
+    }
+
+    // Brings our copy of the relay directory up to date, returning how many
+    // relays changed and whether all of it had to be fetched.
+    pub fn sync_relays(&self) -> Result<(usize, bool), String> {
+        let (have, current, signer) = self.relays.lock().unwrap().clone();
+        let directory = try!(self.fetch_relays(have, signer));
+        let (directory, relays) = match directory.apply(&current) {
+            Ok(relays) => (directory, relays),
+            // Our copy went wrong, or the server started over from the same version.
+            Err(_) if directory.base != 0 => {
+                let directory = try!(self.fetch_relays(0, signer));
+                let relays = try!(directory.apply(&BTreeMap::new()));
+                (directory, relays)
+            },
+            Err(e) => return Err(e),
+        };
+        let changed = directory.added.len() + directory.removed.len();
+        *self.relays.lock().unwrap() = (directory.version, relays, Some(directory.signing_key));
+        Ok((changed, directory.base == 0))
+    }
+
+    // The relay directory as last synced, and its version.
+    pub fn relay_directory(&self) -> (u64, Vec<(Addr, Key)>) {
+        let relays = self.relays.lock().unwrap();
+        (relays.0, relays.1.iter().map(|(k, a)| (*a, *k)).collect())
+    }
+
+    // The first key the directory is signed with is kept, since the server
+    // signs with the same one for as long as it keeps its identity.
+    fn fetch_relays(&self, have: u64, signer: Option<Key>) -> Result<RelayDirectory, String> {
+        let directory = match try!(self.lookup(|key, _| ToServer::GetRelays(have, key))) {
+            ResponseType::Relays(directory) => directory,
+            _ => return Err("Something went wrong".to_string()),
+        };
+        if !directory.has_valid_signature() || signer.map_or(false, |s| s != directory.signing_key) {
+            return Err("The relay directory is not signed by the server".to_string());
+        }
+        if directory.base != 0 && directory.base != have {
+            return Err("The server sent changes to another version of the relay directory".to_string());
+        }
+        Ok(directory)
+    }
+
+    // Whether `user` is online, and how many seconds ago they were last seen.
+    pub fn presence(&self, user: &str) -> Result<(bool, Option<u64>), String> {
+        match try!(self.lookup(|key, _| ToServer::Presence(user.to_string(), key))) {
+            ResponseType::Presence(online, last_seen) => Ok((online, last_seen)),
+            _ => Err("Something went wrong".to_string()),
+        }
+    }
+
+    // How many messages, and how many bytes, we have forwarded for others.
+    pub fn relay_stats(&self) -> (usize, usize) {
+        (self.relayed.0.load(Ordering::Relaxed), self.relayed.1.load(Ordering::Relaxed))
+    }
+
+    // The protocol version negotiated with the server.
+    pub fn server_version(&self) -> u16 {
+        self.server_version
+    }
+
+    // The cipher suite negotiated with the server.
+    pub fn server_suite(&self) -> Suite {
+        self.server_suite
+    }
+
+    // The port our listener accepts peers on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+
+    fn listener(net: Net) {
+        // Prefer a dual stack socket, falling back to IPv4 where IPv6 is unavailable.
+        let server = TcpListener::bind(("::", net.port))
+            .or_else(|_| TcpListener::bind(("0.0.0.0", net.port)))
+            .unwrap();
+
+        for stream in server.incoming() {
+            match stream {
+                Ok(stream) => {
+                    // The peer is reachable again, hand over anything carried for it.
+                    if let Ok(addr) = stream.peer_addr() {
+                        net.hand_off(canonical_ip(addr.ip()));
+                    }
+                    net.recv_work.push(stream)
+                },
+                Err(_) => continue,
+            }
+        }
+    }
+
+    fn receiver(net: Net) {
+
+        loop {
+            // Grab the connection stream to handle.
+            let mut stream = match SecureStream::accept(net.recv_work.pop(), Some(&net.crypto)) {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            stream.set_max_frame_size(net.max_frame_size);
+            stream.set_capture(net.capture.clone());
+            let message = match stream.read_frame() {
+                Ok(frame) => match net.parse_received(&frame, &stream) {
+                    Some(m) => m,
+                    None => continue,
+                },
+                Err(_) => continue,
+            };
+            let _ = stream.send_ack();
+            net.handle_incoming(message);
+
+            // The sender may keep the connection for its next message, which
+            // is read on a thread of its own to keep the receivers free.
+            if net.pool.max_idle > Duration::from_secs(0) {
+                let pooled_net = net.clone();
+                thread::spawn(move|| pooled_net.receive_pooled(stream));
+            }
+        }
+    }
 
-    pub fn new(crypto: Crypto) -> Net {
+    fn receive_pooled(&self, mut stream: SecureStream) {
+        if stream.set_read_timeout(Some(self.pool.max_idle)).is_err() {
+            return;
+        }
+        while let Ok(frame) = stream.read_frame() {
+            let message = match self.parse_received(&frame, &stream) {
+                Some(m) => m,
+                None => break,
+            };
+            if stream.send_ack().is_err() {
+                break;
+            }
+            self.handle_incoming(message);
+        }
+    }
 
-        // Get the server's public key.
-        let mut stream: TcpStream = TcpStream::connect(SERVER_KEY_ADDR).unwrap();
-        let mut key_request = Message::new(
-            MessageType::Server(
-                ToServer::PublicKey(crypto.pub_key)
-            ),
-            vec![],
-            &crypto
-        );
-        Net::send_message(&mut stream, &mut key_request).unwrap();
-        let msg_type = Net::data_to_type(&Net::receive_message(&mut stream, &crypto).data);
-
-        let server_pub_key = match msg_type {
-            MessageType::User(m) => {
-                match m {
-                    ToUser::ServerResponse(sr) => {
-                        match sr {
-                            ResponseType::PublicKey(pk) => pk,
-                            _ => panic!("Unable to get server public key.")
-                        }
-                    },
-                    _ => panic!("Unable to get server public key.")
-                }
+    // A frame from a peer that can't be read as a message is reported to the
+    // security log with the peer's address.
+    fn parse_received(&self, frame: &[u8], stream: &SecureStream) -> Option<Message> {
+        match Net::parse_message(frame, &self.crypto) {
+            Ok(m) => Some(m),
+            Err(e) => {
+                let from = stream.peer_addr().map(|a| a.to_string()).unwrap_or("an unknown address".to_string());
+                self.security.push((SecurityKind::DecryptFailed, format!("{} from {}", e, from)));
+                None
             },
-            _ => panic!("Unable to get server public key.")
-        };
-
-        // The net struct to be returned.
-        let net = Net {
-            send_work: Arc::new(MpmcQueue::new()),
-            recv_work: Arc::new(MpmcQueue::new()),
-            new_messages: Arc::new(MpmcQueue::new()),
-            crypto: crypto,
-            server_key: server_pub_key,
-        };
-       
-        // Spawn main receiver.
-        let recv_net = net.clone();
-        thread::spawn(move|| Net::listener(recv_net));
+        }
+    }
 
-        // Spawning all receiver threads.
-        for _ in 0..4 {
-            let recv_net = net.clone();
-            thread::spawn(move|| Net::receiver(recv_net));
+    // Handles a message received directly or fetched from our mailbox.
+    fn handle_incoming(&self, message: Message) {
+        // A resent layer is acknowledged again but only handled once, so only
+        // layers refused for their age are taken as replays.
+        match self.replays.check(&message) {
+            Err(ref e) if e == REPLAYED => return,
+            Err(e) => {
+                self.security.push((SecurityKind::Replayed, e));
+                return;
+            },
+            Ok(()) => {},
         }
-        
-        // Spawning all sender threads.
-        for _ in 0..4 {
-            let send_net = net.clone();
-            thread::spawn(move|| Net::sender(send_net));
+
+        // Handle the message.
+        if message.next_hop == None { // This message is for us.
+            match Net::decode_type(&message.data) {
+                Ok(MessageType::User(mtu)) => match mtu {
+                    ToUser::Text(ref msg) => {
+                        // The message they were typing has arrived.
+                        self.typing.lock().unwrap().remove(&msg.sender.handle);
+                        self.new_messages.push(msg.clone());
+                        self.send_ack(msg);
+                    },
+                    ToUser::Ack(id) => self.acks.push(id),
+                    ToUser::ReadReceipt(id) => self.receipts.push(id),
+                    ToUser::Broadcast(..) | ToUser::BroadcastKey(..) | ToUser::DecryptFailed(..) => self.broadcasts.push(mtu.clone()),
+                    ToUser::SessionReset(ref reset) => self.resets.push(reset.clone()),
+                    ToUser::FileOffer(..) | ToUser::FileAccept(..) | ToUser::FileChunk(..) | ToUser::FileCancel(..) |
+                    ToUser::SharedFileRequest(..) => self.files.push(mtu.clone()),
+                    ToUser::Typing(ref handle) => if self.show_typing {
+                        self.typing.lock().unwrap().insert(handle.clone(), Instant::now());
+                    },
+                    ToUser::RelayTest(nonce, ref payload) => {
+                        if let Some(test) = self.relay_tests.lock().unwrap().get(&nonce) {
+                            let _ = test.send(payload.len());
+                        }
+                    },
+                    // Anyone could send one, so only the server's are shown.
+                    ToUser::ServerNotice(ref text, ref proof) => {
+                        if crypto_lib::constant_time_eq(&self.crypto.prove_notice(&self.server_key, text), proof) {
+                            self.notices.push(text.clone());
+                        }
+                    },
+                    // Cover traffic only needed acknowledging.
+                    ToUser::Cover(_) => {},
+                    // Published so that deniable messages prove nothing once
+                    // read, there is nothing to do with them.
+                    ToUser::MacKeys(_) => {},
+                    _ => {},
+                },
+                Ok(MessageType::Server(_)) => {},
+                // Anyone can seal a layer to our key, so its contents may be anything.
+                Err(e) => self.security.push((SecurityKind::DecryptFailed, format!("Unreadable message: {}", e))),
+            }
+        } else if self.relay { // Forward the message along.
+            self.relayed.0.fetch_add(1, Ordering::Relaxed);
+            self.relayed.1.fetch_add(message.data.len(), Ordering::Relaxed);
+            match self.relay_delay {
+                RelayDelay::None => self.add_message(MessageContainer::new(message, None, false)),
+                delay => {
+                    let delay_net = self.clone();
+                    thread::spawn(move || {
+                        thread::sleep(delay.sample());
+                        delay_net.add_message(MessageContainer::new(message, None, false));
+                    });
+                },
+            }
         }
+    }
+
+    fn receive_message(stream: &mut SecureStream, crypto: &Crypto) -> Result<Message, String> {
 
-        net
+        // Read the raw message bytes.
+        let msg_buf = try!(stream.read_frame());
+        Net::parse_message(&msg_buf, crypto)
     }
 
-    pub fn get_server_key(&self) -> Key {
-        self.server_key.clone()
+    fn parse_message(msg_buf: &[u8], crypto: &Crypto) -> Result<Message, String> {
+        Message::open(msg_buf, crypto)
     }
 
-    pub fn get_server_route(&self) -> Route {
-        vec![(SERVER_ADDR.to_string(), self.server_key)]
+    fn heartbeat(net: Net) {
+        loop {
+            thread::sleep(Duration::from_secs(HEARTBEAT_SECS));
+            let handle = match *net.handle.lock().unwrap() {
+                Some(ref h) => h.clone(),
+                None => continue,
+            };
+
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let proof = net.crypto.prove(&net.server_key, now);
+            net.add_message(MessageContainer::new(
+                Message::with_priority(
+                    MessageType::Server(ToServer::Heartbeat(handle, now, proof)),
+                    net.get_server_route(),
+                    &net.crypto,
+                    Priority::Bulk
+                ),
+                None,
+                false
+            ));
+        }
     }
-    
-    pub fn get_message(&self) -> TextMessage {
-        self.new_messages.pop()
+
+    // Sends dummy messages at random intervals so an observer can't tell when
+    // real ones are sent. Each goes either to the server or through relays
+    // back to us, and is dropped on arrival.
+    fn cover_traffic(net: Net, mean_secs: u64) {
+        let mut route: Option<Route> = None;
+        let mut uses = 0;
+        loop {
+            // Exponential gaps, so the times carry no pattern.
+            let gap = -(1.0 - rand::random::<f64>()).ln() * mean_secs as f64;
+            thread::sleep(Duration::from_millis((gap * 1000.0) as u64));
+            if net.is_low_power() {
+                continue;
+            }
+
+            let len = COVER_MIN_BYTES + rand::random::<usize>() % (COVER_MAX_BYTES - COVER_MIN_BYTES);
+            let padding: Vec<u8> = (0..len).map(|_| rand::random()).collect();
+            let handle = net.handle.lock().unwrap().clone();
+            let msg = match handle {
+                Some(ref h) if rand::random() => {
+                    if route.is_none() || uses >= COVER_ROUTE_REUSE {
+                        route = net.get_route(h).ok();
+                        uses = 0;
+                    }
+                    uses += 1;
+                    match route.clone() {
+                        Some(r) => Message::new(MessageType::User(ToUser::Cover(padding)), r, &net.crypto),
+                        None => continue,
+                    }
+                },
+                _ => Message::new(MessageType::Server(ToServer::Cover(padding)), net.get_server_route(), &net.crypto),
+            };
+            net.add_message(MessageContainer::new(msg, None, false));
+        }
     }
 
-    pub fn add_message(&self, msg: MessageContainer) {
-        self.send_work.push(msg);
+    // Fetches what the server held for us while in mailbox mode. The server
+    // only keeps routing through itself while we keep fetching.
+    fn fetch_mailbox(net: Net) {
+        loop {
+            thread::sleep(Duration::from_secs(MAILBOX_POLL_SECS));
+            let handle = match *net.handle.lock().unwrap() {
+                Some(ref h) if net.uses_mailbox() => h.clone(),
+                _ => continue,
+            };
+
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let proof = net.crypto.prove(&net.server_key, now);
+            if let Ok(ResponseType::Mailbox(held)) = net.request(ToServer::Fetch(handle, now, proof)) {
+                // The server may not change how the layers it held are routed.
+                for msg in held.into_iter().filter(|m| m.header_matches()).filter_map(|m| Net::parse_message(&m.data, &net.crypto).ok()) {
+                    net.handle_incoming(msg);
+                }
+            }
+        }
     }
 
-    pub fn get_route(&self, user: &str) -> Result<Route, String> {
-        let (sender, receiver) = channel();
-        self.add_message(
-            MessageContainer::new(
-                Message::new(
-                    MessageType::Server(
-                        ToServer::Connect(user.to_string(), self.crypto.pub_key.clone())
-                    ),
-                    vec![(SERVER_ADDR.to_string(), self.server_key)],
-                    &self.crypto
-                ),
-                Some(sender),
-                true            )
-        );
+    // Server requests share the persistent connection, except sealed ones,
+    // which would be linked to us by it.
+    fn uses_session(&self, msg: &Message, reply_crypto: &Option<Crypto>) -> bool {
+        self.persistent && self.server_version >= SESSION_VERSION
+            && reply_crypto.is_none() && msg.next_hop == Some(self.server_addr)
+    }
+
+    fn open_session(&self) -> Result<Arc<Session>, String> {
+        let mut current = self.session.lock().unwrap();
+        if let Some(ref session) = *current {
+            return Ok(session.clone());
+        }
 
-        let res = match receiver.recv().unwrap(){
-            Ok(r) => r.unwrap(),
+        let mut stream = match SecureStream::connect_via(&self.server_addr, Some(&self.server_key), self.proxy.as_ref()) {
+            Ok(s) => s,
             Err(e) => {
+                self.links.on_failure(&self.server_addr, None);
                 return Err(e);
-            }
+            },
         };
+        stream.set_capture(self.capture.clone());
+        try!(stream.write_frame(&[SESSION_START]));
+        let (writer, reader) = try!(stream.split());
+        let session = Arc::new(Session {
+            writer: Mutex::new(writer),
+            waiting: Mutex::new(HashMap::new()),
+            next_id: AtomicUsize::new(PUSH_ID as usize + 1),
+            heard: Mutex::new(Instant::now()),
+            closed: AtomicBool::new(false),
+        });
 
-        if let MessageType::User(res) = Net::data_to_type(&res.data) {
-            if let ToUser::ServerResponse(res) = res {
-                match res {
-                    ResponseType::Connection(u) => Ok(u),
-                    ResponseType::Error(e) => Err(e),
-                    _ => Err("Something went wrong".to_string())
-                }
+        let net = self.clone();
+        let read_session = session.clone();
+        thread::spawn(move|| net.read_session(read_session, reader));
+        let net = self.clone();
+        let ping_session = session.clone();
+        thread::spawn(move|| net.keep_alive(ping_session));
+        *current = Some(session.clone());
+        self.connection_changes.push(true);
+        if !self.watching.lock().unwrap().is_empty() {
+            let net = self.clone();
+            thread::spawn(move|| net.rewatch());
+        }
+        Ok(session)
+    }
+
+    // Pings the server so a dead connection, or one only open at our end, is
+    // noticed before a request is lost on it.
+    fn keep_alive(&self, session: Arc<Session>) {
+        while !session.closed.load(Ordering::SeqCst) {
+            let (interval, timeout) = if self.is_low_power() {
+                (self.low_power_keepalive, cmp::max(self.keepalive_timeout, self.low_power_keepalive * 3))
             } else {
-                Err("Reply was not of type ServerResponse".to_string())
+                (self.keepalive, self.keepalive_timeout)
+            };
+            thread::sleep(interval);
+            if session.heard.lock().unwrap().elapsed() > timeout
+                    || session.writer.lock().unwrap().write_frame(&[KEEPALIVE_PING]).is_err() {
+                self.close_session(&session);
             }
-        } else {
-            Err("Reply was not of type User".to_string())
         }
     }
 
-    pub fn server_addr() -> &'static str {
-        SERVER_ADDR
+    // Blocks until the persistent connection to the server opens or closes,
+    // returning whether it is now open.
+    pub fn get_connection_change(&self) -> bool {
+        self.connection_changes.pop()
     }
 
+    // Hands each reply to the request waiting for it, and pushed layers to
+    // the receiver, until the connection drops.
+    fn read_session(&self, session: Arc<Session>, mut reader: SecureStream) {
+        while let Ok(frame) = reader.read_frame() {
+            *session.heard.lock().unwrap() = Instant::now();
+            if frame == [KEEPALIVE_PONG] {
+                continue;
+            }
+            if frame == [KEEPALIVE_PING] {
+                if session.writer.lock().unwrap().write_frame(&[KEEPALIVE_PONG]).is_err() {
+                    break;
+                }
+                continue;
+            }
+            let (id, data) = match parse_session_frame(&frame) {
+                Ok(f) => f,
+                Err(_) => break,
+            };
+            if id == PUSH_ID {
+                if let Ok(msg) = Net::parse_message(data, &self.crypto) {
+                    // Only trusted from the server, so not handled with the
+                    // rest of what peers can send.
+                    if let Ok(MessageType::User(ToUser::Update(cursor, update))) = Net::decode_type(&msg.data) {
+                        self.heard_update(cursor, update);
+                    } else {
+                        self.handle_incoming(msg);
+                    }
+                }
+            } else if let Some(waiting) = session.waiting.lock().unwrap().remove(&id) {
+                let _ = waiting.send(match data {
+                    [REQUEST_DROPPED] => Err("The server dropped the request".to_string()),
+                    data => match (parse_busy(data), parse_undelivered(data)) {
+                        (Some(secs), _) => Err(self.set_busy(secs)),
+                        (_, Some(reason)) => Err(reason.to_string()),
+                        _ => Ok(data.to_vec()),
+                    },
+                });
+            }
+        }
+        self.close_session(&session);
+    }
 
-    fn listener(net: Net) {
-        let server = TcpListener::bind("0.0.0.0:5000").unwrap();
+    fn heard_update(&self, cursor: u64, update: Update) {
+        let mut watching = self.watching.lock().unwrap();
+        if let Some(w) = watching.iter_mut().find(|&&mut (ref t, _)| t.covers(&update)) {
+            w.1 = cmp::max(w.1, cursor);
+            self.updates.push(update);
+        }
+    }
 
-        for stream in server.incoming() {
-            match stream {
-                Ok(stream) => net.recv_work.push(stream),
-                Err(_) => continue,
+    fn close_session(&self, session: &Arc<Session>) {
+        if session.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        {
+            let mut current = self.session.lock().unwrap();
+            if current.as_ref().map_or(false, |s| Arc::ptr_eq(s, session)) {
+                *current = None;
             }
         }
+        session.writer.lock().unwrap().shutdown();
+        for (_, waiting) in session.waiting.lock().unwrap().drain() {
+            let _ = waiting.send(Err("The connection to the server closed".to_string()));
+        }
+        self.connection_changes.push(false);
     }
 
-    fn receiver(net: Net) {
+    // Sends a request over the persistent connection, opening it if needed,
+    // and waits for the reply.
+    fn session_request(&self, msg: &Message) -> Result<Vec<u8>, String> {
+        let session = try!(self.open_session());
+        let id = session.next_id.fetch_add(1, Ordering::SeqCst) as u64;
+        let (sender, receiver) = channel();
+        session.waiting.lock().unwrap().insert(id, sender);
+
+        let sent = session.writer.lock().unwrap().write_frame(&session_frame(id, &msg.data));
+        if let Err(e) = sent {
+            self.close_session(&session);
+            return Err(e);
+        }
+        match receiver.recv_timeout(Duration::from_secs(SESSION_TIMEOUT_SECS)) {
+            Ok(reply) => reply,
+            Err(_) => {
+                session.waiting.lock().unwrap().remove(&id);
+                Err("The server did not respond".to_string())
+            },
+        }
+    }
+
+    fn sender(net: Net) {
 
         loop {
-            // Grab the connection stream to handle.
-            let message = Net::receive_message(&mut net.recv_work.pop(), &net.crypto);
-            
-            // Handle the message.
-            if message.next_hop == None { // This message is for us.
-                match Net::data_to_type(&message.data) {
-                    MessageType::User(mtu) => match mtu {
-                        ToUser::Text(ref msg) => net.new_messages.push(msg.clone()),
-                        _ => continue, // Can't be anything other than text yet.
+            // Grab message from queue, unless its hop is down.
+            let work = match net.links.hold(net.send_work.pop()) {
+                Some(w) => w,
+                None => continue,
+            };
+            let MessageContainer{mut msg, response, needs_response, reply_crypto} = work;
+
+            if needs_response && net.uses_session(&msg, &reply_crypto) {
+                let reply = net.session_request(&msg).and_then(|data| Net::parse_message(&data, &net.crypto));
+                if let Some(res) = response {
+                    res.send(reply.map(Some)).unwrap();
+                }
+            } else if needs_response {
+                // The response doubles as the acknowledgement.
+                let mut stream = match SecureStream::connect_via(&msg.next_hop.unwrap(), msg.next_key.as_ref(), net.proxy.as_ref()) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        net.links.on_failure(&msg.next_hop.unwrap(), None);
+                        if let Some(res) = response {
+                            res.send(Err(e)).unwrap();
+                        }
+                        continue;
+                    }
+                };
+                stream.set_capture(net.capture.clone());
+
+                if let Err(e) = Net::send_message(&mut stream, &mut msg) { 
+                    if let Some(res) = response {
+                        res.send(Err(e.to_string())).unwrap();
+                    }
+                    continue; 
+                } 
+
+                if let Some(res) = response {
+                    let crypto = reply_crypto.as_ref().unwrap_or(&net.crypto);
+                    let reply = stream.read_frame().and_then(|frame| match parse_busy(&frame) {
+                        Some(secs) => Err(net.set_busy(secs)),
+                        None => Net::parse_message(&frame, crypto),
+                    });
+                    res.send(reply.map(Some)).unwrap();
+                }
+            } else {
+                // If the next hop is dead, recovery is left to the carry store or the requester.
+                match net.send_with_retransmit(&mut msg) {
+                    Ok(()) => if let Some(res) = response {
+                        res.send(Ok(None)).unwrap();
+                    },
+                    Err(Undelivered::RouteExhausted) => {
+                        // It waits for the hop to come back, if reconnecting is on.
+                        let hop = msg.next_hop.unwrap();
+                        let work = MessageContainer{msg: msg, response: response, needs_response: false, reply_crypto: reply_crypto};
+                        if let Some(work) = net.links.on_failure(&hop, Some(work)) {
+                            net.give_up(work, Undelivered::RouteExhausted.to_string());
+                        }
+                        continue;
+                    },
+                    // The hop is up but said where the layer went wrong.
+                    Err(reason) => {
+                        if let Some(res) = response {
+                            let _ = res.send(Err(reason.to_string()));
+                        }
+                        continue;
                     },
-                    MessageType::Server(_) => continue,
                 }
-            } else { // Forward the message along.
-                net.send_work.push(MessageContainer::new(message, None, false));
             }
+
+            if msg.next_hop == Some(net.server_addr) {
+                net.deposit_carried();
+            }
+        }
+    }
+
+    // Reports a message as undeliverable. Server requests are recovered by the
+    // requester, anything else may be carried.
+    fn give_up(&self, work: MessageContainer, e: String) {
+        let MessageContainer{msg, response, ..} = work;
+        let carried = msg.next_hop != Some(self.server_addr) && self.carry(msg);
+        if let Some(res) = response {
+            let _ = res.send(Err(if carried { format!("{}, carrying it until the hop is back", e) } else { e }));
         }
     }
 
-    // TODO: Just to be safe, should this not maybe be an optional Message or maybe result?
-    fn receive_message(stream: &mut TcpStream, crypto: &Crypto) -> Message {
+    // Checks on hops that are down once their backoff has passed. A hop that
+    // takes a connection gets its waiting messages back in the send queue.
+    fn reconnect(net: Net) {
+        loop {
+            thread::sleep(Duration::from_millis(RECONNECT_POLL_MS));
+            for hop in net.links.due() {
+                let reachable = match net.proxy {
+                    Some(ref p) => p.connect(&hop).is_ok(),
+                    None => TcpStream::connect_timeout(&hop.0, Duration::from_millis(HOP_ACK_TIMEOUT_MS)).is_ok(),
+                };
+                if reachable {
+                    for work in net.links.restore(&hop) {
+                        net.add_message(work);
+                    }
+                } else if let Some(queued) = net.links.retry_later(&hop) {
+                    for work in queued {
+                        net.give_up(work, Undelivered::RouteExhausted.to_string());
+                    }
+                }
+            }
+        }
+    }
 
-        // Read the message size.
-        let mut size_buf: [u8; 4] = [0; 4]; // 32 bit message size field.
-        stream.read_exact(&mut size_buf).unwrap();
-        let msg_size: u32 = unsafe { mem::transmute(size_buf) };
+    // Sends `msg` to its next hop, retransmitting with backoff until the hop
+    // acknowledges it or says why it won't.
+    fn send_with_retransmit(&self, msg: &mut Message) -> Result<(), Undelivered> {
+        let hop = msg.next_hop.unwrap();
+        self.windows.acquire(&hop);
 
-        // Read the raw message bytes.
-        let mut msg_buf = vec![0; msg_size as usize];
-        stream.read_exact(msg_buf.as_mut_slice()).unwrap();
+        let mut timeout = Duration::from_millis(HOP_ACK_TIMEOUT_MS);
+        let mut acked = false;
+        let retries = match msg.priority {
+            Priority::Bulk => HOP_RETRIES - 1,
+            Priority::Normal => HOP_RETRIES,
+            Priority::Urgent => 2 * HOP_RETRIES,
+        };
+        for attempt in 0..retries {
+            if attempt > 0 {
+                let busy = if hop == self.server_addr { self.busy_for() } else { None };
+                thread::sleep(cmp::max(timeout, busy.unwrap_or(timeout)));
+                timeout = timeout * 2;
+            }
 
-        // Decrypt the message.
-        // TODO: this should be a match that can return an error
-        let decrypted_message = crypto.decrypt(&msg_buf).unwrap();
+            let sent_at = Instant::now();
+            match self.send_pooled(&hop, msg, timeout) {
+                Ok(true) => {
+                    self.windows.on_ack(&hop, sent_at.elapsed());
+                    acked = true;
+                    break;
+                },
+                Ok(false) => self.windows.on_loss(&hop),
+                Err(reason) => {
+                    self.windows.release(&hop);
+                    return Err(reason);
+                },
+            }
+        }
 
-        // Create the message from the raw bytes.
-        json::decode(str::from_utf8(&decrypted_message).unwrap()).unwrap()
+        self.windows.release(&hop);
+        if acked {
+            Ok(())
+        } else {
+            Err(Undelivered::RouteExhausted)
+        }
     }
 
-    fn sender(net: Net) {
-
+    // Sends `msg` to `hop` on a pooled connection and waits for the ack. A
+    // reused connection the peer has since closed is replaced by a new one,
+    // without counting as a loss. The server takes one message a connection.
+    fn send_pooled(&self, hop: &Addr, msg: &mut Message, timeout: Duration) -> Result<bool, Undelivered> {
+        let key = msg.next_key;
         loop {
-            // Grab message from queue.
-            let MessageContainer{mut msg, response, needs_response} = net.send_work.pop(); 
-            
-            // Connect to the destination.
-            let mut stream = match TcpStream::connect(&*msg.clone().next_hop.unwrap()) {
+            let (mut stream, reused) = match self.pool.acquire(hop, key.as_ref(), self.proxy.as_ref()) {
                 Ok(s) => s,
-                Err(_) => {
-                    if let Some(res) = response {
-                        res.send(Err("Could not connect to destination".to_string())).unwrap();
-                    }
-                    continue;
-                }
+                Err(_) => return Ok(false),
             };
-
-            // Generate encryption function.
-            // let enc_func = |&m| match key {
-            //     Some(key) => net.crypto.encrypt(&key, &m),
-            //     None      => Ok(m.to_vec())
-            // };
-
-            // Send the message.
-            // TODO: Do something with the error.
-            if let Err(_) = Net::send_message(&mut stream, &mut msg) { 
-                continue; 
-            } 
-
-            // Get the response message if there will be one.
-            if let Some(res) = response {
-                if needs_response {
-                    res.send(Ok(Some(Net::receive_message(&mut stream, &net.crypto)))).unwrap();
-                } else {
-                    res.send(Ok(None)).unwrap();
+            stream.set_capture(self.capture.clone());
+            if Net::send_message(&mut stream, msg).is_ok() {
+                match stream.read_ack_or_refusal(timeout) {
+                    Ok(true) => {
+                        let keep = *hop != self.server_addr;
+                        self.pool.release(hop, key.as_ref(), if keep { Some(stream) } else { None });
+                        return Ok(true);
+                    },
+                    Ok(false) => {},
+                    Err(Refusal::Busy(secs)) => {
+                        self.set_busy(secs);
+                        self.pool.release(hop, key.as_ref(), None);
+                        return Ok(false);
+                    },
+                    Err(Refusal::Undelivered(reason)) => {
+                        self.pool.release(hop, key.as_ref(), None);
+                        return Err(reason);
+                    },
                 }
             }
+            self.pool.release(hop, key.as_ref(), None);
+            if !reused {
+                return Ok(false);
+            }
         }
     }
 
-    fn send_message(stream: &mut TcpStream, msg: &mut Message) -> Result<(), &'static str> {
+    fn send_message(stream: &mut SecureStream, msg: &mut Message) -> Result<(), &'static str> {
 
         // Check the message size.
-        if msg.data.len() >= u32::max_value() as usize {
+        if msg.data.len() >= u32::max_value() as usize - 16 {
             return Err("Message is too long."); 
         }
 
-        // Send the message size.
-        let msg_size: [u8; 4] = unsafe { // TODO: should this be encrypted too?
-            mem::transmute(msg.data.len() as u32)
-        };
-        stream.write(&msg_size).unwrap();
-
         // Send the message.
-        stream.write(&msg.data).unwrap();
-
-        Ok(())
+        stream.write_frame(&msg.data).map_err(|_| "Failed to send message.")
     }
 
-    pub fn data_to_type(data: &[u8]) -> MessageType {
-        json::decode(str::from_utf8(&data).unwrap()).unwrap()
+    pub fn decode_type(data: &[u8]) -> Result<MessageType, String> {
+        let text = try!(str::from_utf8(&data).map_err(|e| e.to_string()));
+        json::decode(text).map_err(|e| e.to_string())
     }
 
     fn data_to_message(data: &[u8], crypto: &Crypto) -> Message {
-        let decrypted = crypto.decrypt(&data).unwrap();
-        json::decode(str::from_utf8(&decrypted).unwrap()).unwrap()
+        Message::open(data, crypto).unwrap()
     }
 
     fn needs_response(msg_type: &MessageType) -> bool {
@@ -274,3 +2794,261 @@ impl Net {
         }
     }
 }
+
+// IPv4 peers on a dual stack socket show up as mapped IPv6 addresses.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => IpAddr::V4(v6.to_ipv4().unwrap()),
+        ip => ip,
+    }
+}
+
+// Resolves an address from the config, which may be a host name or a
+// bracketed IPv6 address such as [::1]:5001.
+// The server and public key service addresses to try in order. The `server`
+// setting is a comma separated list of host:port addresses, the first one
+// preferred, or a domain whose _secmsg._tcp SRV records list them. Each key
+// service is on the next port up unless `key_server` or _secmsg-key._tcp
+// says otherwise.
+fn server_candidates(config: &Config, proxied: bool) -> Result<Vec<(String, String)>, String> {
+    let setting = config.get_str("server").unwrap_or(DEFAULT_SERVER_ADDR.to_string());
+    let key_server = match config.get_str("key_server") {
+        Some(k) => Some(k),
+        None if config.get_str("server").is_none() => Some(DEFAULT_SERVER_KEY_ADDR.to_string()),
+        None => None,
+    };
+
+    let mut candidates = Vec::new();
+    for entry in setting.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        match split_port(entry) {
+            Some((host, port)) =>
+                candidates.push((join_port(host, port), key_server.clone().unwrap_or(join_port(host, port.wrapping_add(1))))),
+            // SRV lookups go to the local resolver, which a proxy is there to avoid.
+            None if proxied => return Err(format!("{} needs a port when connecting through a proxy", entry)),
+            None => {
+                let servers = try!(dns::lookup_srv(&format!("_secmsg._tcp.{}", entry)));
+                if servers.is_empty() {
+                    return Err(format!("{} has no port and no _secmsg._tcp records", entry));
+                }
+                let keys = dns::lookup_srv(&format!("_secmsg-key._tcp.{}", entry)).unwrap_or(Vec::new());
+                for s in servers {
+                    let key = keys.iter().find(|k| k.target == s.target).map_or(s.port.wrapping_add(1), |k| k.port);
+                    candidates.push((join_port(&s.target, s.port), key_server.clone().unwrap_or(join_port(&s.target, key))));
+                }
+            },
+        }
+    }
+    if candidates.is_empty() {
+        return Err("The server setting is empty".to_string());
+    }
+    Ok(candidates)
+}
+
+// Splits "host:port" or "[v6]:port", or returns None if there is no port.
+fn split_port(addr: &str) -> Option<(&str, u16)> {
+    let i = match addr.rfind(':') {
+        Some(i) => i,
+        None => return None,
+    };
+    let host = &addr[..i];
+    if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+        return None; // a bare IPv6 address
+    }
+    addr[i + 1..].parse().ok().map(|port| (host.trim_start_matches('[').trim_end_matches(']'), port))
+}
+
+fn join_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+pub fn resolve(addr: &str) -> Result<Addr, String> {
+    try!(addr.to_socket_addrs().map_err(|e| format!("Could not resolve {}: {}", addr, e)))
+        .next()
+        .map(Addr)
+        .ok_or(format!("Could not resolve {}", addr))
+}
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_RESOLVE: u8 = 0xf0; // Tor's extension for looking up a name
+const SOCKS_IPV4: u8 = 1;
+const SOCKS_DOMAIN: u8 = 3;
+const SOCKS_IPV6: u8 = 4;
+
+// A SOCKS5 proxy, such as Tor, that outbound connections are made through.
+// It is set with the `proxy` setting, e.g. proxy = 127.0.0.1:9050.
+#[derive(Clone, Copy)]
+pub struct Proxy(SocketAddr);
+
+impl Proxy {
+
+    pub fn from_config(config: &Config) -> Result<Option<Proxy>, String> {
+        match config.get_str("proxy").filter(|p| !p.is_empty()) {
+            Some(p) => resolve(&p).map(|a| Some(Proxy(a.0))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn connect(&self, addr: &Addr) -> Result<TcpStream, String> {
+        let mut target = match addr.0.ip() {
+            IpAddr::V4(ip) => { let mut t = vec![SOCKS_IPV4]; t.extend_from_slice(&ip.octets()); t },
+            IpAddr::V6(ip) => { let mut t = vec![SOCKS_IPV6]; t.extend_from_slice(&ip.octets()); t },
+        };
+        target.extend_from_slice(&encode_u16(addr.0.port()));
+        self.request(SOCKS_CONNECT, &target).map(|(stream, _)| stream)
+    }
+
+    // Looks up a host name through the proxy. Plain SOCKS5 has no way to
+    // do this, so it needs Tor.
+    pub fn resolve(&self, addr: &str) -> Result<Addr, String> {
+        if let Ok(a) = addr.parse() {
+            return Ok(Addr(a));
+        }
+        let (host, port) = match addr.rfind(':') {
+            Some(i) => (&addr[..i], try!(addr[i + 1..].parse::<u16>().map_err(|_| format!("Invalid port in {}", addr)))),
+            None => return Err(format!("{} has no port", addr)),
+        };
+        if host.is_empty() || host.len() > 255 {
+            return Err(format!("Could not resolve {}", addr));
+        }
+
+        let mut target = vec![SOCKS_DOMAIN, host.len() as u8];
+        target.extend_from_slice(host.as_bytes());
+        target.extend_from_slice(&encode_u16(port));
+        match try!(self.request(SOCKS_RESOLVE, &target).map_err(|e| format!("Could not resolve {}: {}", addr, e))) {
+            (_, Some(ip)) => Ok(Addr(SocketAddr::new(ip, port))),
+            (_, None) => Err(format!("The proxy did not resolve {} to an address", addr)),
+        }
+    }
+
+    // Runs one SOCKS5 command, returning the stream and the address the
+    // proxy replied with, if it was an IP address.
+    fn request(&self, command: u8, target: &[u8]) -> Result<(TcpStream, Option<IpAddr>), String> {
+        let mut stream = try!(TcpStream::connect(self.0).map_err(|_| "Could not connect to the proxy".to_string()));
+        let failed = |_| "The proxy closed the connection".to_string();
+
+        // Offer no authentication, which is all Tor needs.
+        try!(stream.write_all(&[SOCKS_VERSION, 1, 0]).map_err(&failed));
+        let mut method = [0u8; 2];
+        try!(stream.read_exact(&mut method).map_err(&failed));
+        if method != [SOCKS_VERSION, 0] {
+            return Err("The proxy requires authentication".to_string());
+        }
+
+        let mut req = vec![SOCKS_VERSION, command, 0];
+        req.extend_from_slice(target);
+        try!(stream.write_all(&req).map_err(&failed));
+
+        let mut reply = [0u8; 4];
+        try!(stream.read_exact(&mut reply).map_err(&failed));
+        if reply[0] != SOCKS_VERSION {
+            return Err("The proxy is not a SOCKS5 proxy".to_string());
+        }
+        if reply[1] != 0 {
+            return Err(socks_error(reply[1]).to_string());
+        }
+        let ip = match reply[3] {
+            SOCKS_IPV4 => {
+                let mut ip = [0u8; 4];
+                try!(stream.read_exact(&mut ip).map_err(&failed));
+                Some(IpAddr::from(ip))
+            },
+            SOCKS_IPV6 => {
+                let mut ip = [0u8; 16];
+                try!(stream.read_exact(&mut ip).map_err(&failed));
+                Some(IpAddr::from(ip))
+            },
+            SOCKS_DOMAIN => {
+                let mut len = [0u8];
+                try!(stream.read_exact(&mut len).map_err(&failed));
+                try!(stream.read_exact(&mut vec![0u8; len[0] as usize]).map_err(&failed));
+                None
+            },
+            _ => return Err("The proxy sent an unknown address type".to_string()),
+        };
+        let mut port = [0u8; 2];
+        try!(stream.read_exact(&mut port).map_err(&failed));
+        Ok((stream, ip))
+    }
+}
+
+fn socks_error(code: u8) -> &'static str {
+    match code {
+        2 => "The proxy does not allow the connection",
+        3 => "The network is unreachable from the proxy",
+        4 => "The host is unreachable from the proxy",
+        5 => "The connection was refused",
+        6 => "The proxy timed out",
+        7 => "The proxy does not support the command",
+        8 => "The proxy does not support the address type",
+        _ => "The proxy failed to connect",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, SocketAddr};
+    use std::thread;
+    use std::time::Duration;
+
+    use state::Addr;
+    use messages::Undelivered;
+    use super::{SecureStream, Control, Refusal};
+
+    // A client and server stream joined over loopback.
+    fn pair() -> (SecureStream, SecureStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            SecureStream::accept(stream, None).unwrap()
+        });
+        let client = SecureStream::connect(&Addr(addr), None).unwrap();
+        (client, server.join().unwrap())
+    }
+
+    #[test]
+    fn oversized_frame_is_refused() {
+        let (mut client, mut server) = pair();
+        server.set_max_frame_size(64);
+        client.write_frame(&[0u8; 100]).unwrap();
+        assert!(server.read_frame().is_err());
+        assert_eq!(client.read_frame_or_control().unwrap(), Err(Control::FrameTooLarge));
+    }
+
+    #[test]
+    fn frame_at_the_limit_is_read() {
+        let (mut client, mut server) = pair();
+        server.set_max_frame_size(64);
+        client.write_frame(&[7u8; 64]).unwrap();
+        assert_eq!(server.read_frame().unwrap(), vec![7u8; 64]);
+    }
+
+    #[test]
+    fn ack_is_a_control_frame() {
+        let (mut client, mut server) = pair();
+        server.write_frame(b"before").unwrap();
+        server.send_ack().unwrap();
+        server.write_frame(b"after").unwrap();
+        assert_eq!(client.read_frame().unwrap(), b"before".to_vec());
+        assert!(client.read_ack(Duration::from_secs(5)));
+        assert_eq!(client.read_frame().unwrap(), b"after".to_vec());
+    }
+
+    #[test]
+    fn refusal_is_a_control_frame() {
+        let (mut client, mut server) = pair();
+        server.send_undelivered(Undelivered::MailboxFull).unwrap();
+        server.send_undelivered(Undelivered::Blocked).unwrap();
+        match client.read_ack_or_refusal(Duration::from_secs(5)) {
+            Err(Refusal::Undelivered(reason)) => assert_eq!(reason, Undelivered::MailboxFull),
+            _ => panic!("the refusal was not read as one"),
+        }
+        // A refusal isn't a frame to whoever wanted one.
+        assert!(client.read_frame().is_err());
+    }
+}
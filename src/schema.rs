@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+use std::process;
+
+extern crate rustc_serialize;
+use rustc_serialize::{Decodable, Decoder};
+use rustc_serialize::json::{Json, ToJson};
+extern crate crossbeam;
+extern crate crypto;
+extern crate rand;
+
+mod io_lib;
+mod net_lib;
+mod dns;
+mod messages;
+mod mpmc_queue;
+mod state;
+mod filters;
+mod transfer;
+mod crypto_lib;
+mod mlkem;
+mod compress;
+mod config_lib;
+
+use messages::{Message, MessageType, ROUTING_HEADER_LEN};
+use net_lib::{PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+
+// Prints a JSON schema of the wire format. It is read off the same derived
+// Decodable impls that parse messages, so it cannot drift from the code.
+
+// Sequences are read with this many elements. It has to match the fixed size
+// arrays on the wire, which are all keys.
+const SAMPLE_LEN: usize = 32;
+
+type Found = Vec<(Option<String>, Json)>; // schemas read so far, with their field names
+
+// A Decoder that makes up values and notes the schema of everything it is
+// asked for. Every variant of an enum is tried in turn, and a named type is
+// described once in `definitions` and referred to after that.
+struct SchemaDecoder {
+    found: Found,
+    definitions: BTreeMap<String, Json>,
+    skipping: usize, // nonzero while reading values whose schema is already known
+}
+
+impl SchemaDecoder {
+
+    fn new() -> SchemaDecoder {
+        SchemaDecoder { found: Vec::new(), definitions: BTreeMap::new(), skipping: 0 }
+    }
+
+    fn describe<T: Decodable>(&mut self) -> Result<Json, String> {
+        let (_, mut found) = try!(self.collect(|d| T::decode(d)));
+        found.pop().map(|(_, s)| s).ok_or("Nothing was read".to_string())
+    }
+
+    fn push(&mut self, name: Option<&str>, schema: Json) {
+        if self.skipping == 0 {
+            self.found.push((name.map(|n| n.to_string()), schema));
+        }
+    }
+
+    fn leaf<T>(&mut self, schema: Json, value: T) -> Result<T, String> {
+        self.push(None, schema);
+        Ok(value)
+    }
+
+    // Runs `f`, returning what it read along with the schemas it found.
+    fn collect<T, F>(&mut self, f: F) -> Result<(T, Found), String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<T, String> {
+        let start = self.found.len();
+        let value = f(self);
+        let found = self.found.split_off(start);
+        value.map(|v| (v, found))
+    }
+
+    fn skip<T, F>(&mut self, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<T, String> {
+        self.skipping += 1;
+        let value = f(self);
+        self.skipping -= 1;
+        value
+    }
+
+    // Reads a value of the type `name`, describing it the first time.
+    fn define<T, F>(&mut self, name: &str, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<(T, Json), String> {
+        let value = if self.skipping > 0 || self.definitions.contains_key(name) {
+            try!(self.skip(|d| f(d).map(|(v, _)| v)))
+        } else {
+            self.definitions.insert(name.to_string(), Json::Null);
+            let (value, schema) = try!(f(self));
+            self.definitions.insert(name.to_string(), schema);
+            value
+        };
+        self.push(None, object(vec![("$ref", format!("#/definitions/{}", name).to_json())]));
+        Ok(value)
+    }
+
+    fn named<T, F>(&mut self, name: Option<&str>, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<T, String> {
+        let (value, mut found) = try!(self.collect(f));
+        if let Some((_, schema)) = found.pop() {
+            self.push(name, schema);
+        }
+        Ok(value)
+    }
+}
+
+fn object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+fn of_type(kind: &str) -> Json {
+    object(vec![("type", kind.to_json())])
+}
+
+fn integer(min: i64, max: u64) -> Json {
+    object(vec![("type", "integer".to_json()), ("minimum", min.to_json()), ("maximum", max.to_json())])
+}
+
+fn tuple(items: Found) -> Json {
+    let len = items.len();
+    object(vec![
+        ("type", "array".to_json()),
+        ("items", Json::Array(items.into_iter().map(|(_, s)| s).collect())),
+        ("minItems", len.to_json()),
+        ("maxItems", len.to_json()),
+    ])
+}
+
+// The JSON encoding writes a variant without fields as its name, and one
+// with fields as {"variant": name, "fields": [...]}.
+fn variant(name: &str, fields: Found) -> Json {
+    if fields.is_empty() {
+        return object(vec![("enum", Json::Array(vec![name.to_json()]))]);
+    }
+    object(vec![
+        ("type", "object".to_json()),
+        ("properties", object(vec![
+            ("variant", object(vec![("enum", Json::Array(vec![name.to_json()]))])),
+            ("fields", tuple(fields)),
+        ])),
+        ("required", Json::Array(vec!["variant".to_json(), "fields".to_json()])),
+        ("additionalProperties", false.to_json()),
+    ])
+}
+
+impl Decoder for SchemaDecoder {
+    type Error = String;
+
+    fn read_nil(&mut self) -> Result<(), String> { self.leaf(of_type("null"), ()) }
+    fn read_usize(&mut self) -> Result<usize, String> { self.leaf(integer(0, u64::max_value()), 0) }
+    fn read_u64(&mut self) -> Result<u64, String> { self.leaf(integer(0, u64::max_value()), 0) }
+    fn read_u32(&mut self) -> Result<u32, String> { self.leaf(integer(0, u32::max_value() as u64), 0) }
+    fn read_u16(&mut self) -> Result<u16, String> { self.leaf(integer(0, u16::max_value() as u64), 0) }
+    fn read_u8(&mut self) -> Result<u8, String> { self.leaf(integer(0, u8::max_value() as u64), 0) }
+    fn read_isize(&mut self) -> Result<isize, String> { self.leaf(integer(i64::min_value(), i64::max_value() as u64), 0) }
+    fn read_i64(&mut self) -> Result<i64, String> { self.leaf(integer(i64::min_value(), i64::max_value() as u64), 0) }
+    fn read_i32(&mut self) -> Result<i32, String> { self.leaf(integer(i32::min_value() as i64, i32::max_value() as u64), 0) }
+    fn read_i16(&mut self) -> Result<i16, String> { self.leaf(integer(i16::min_value() as i64, i16::max_value() as u64), 0) }
+    fn read_i8(&mut self) -> Result<i8, String> { self.leaf(integer(i8::min_value() as i64, i8::max_value() as u64), 0) }
+    fn read_bool(&mut self) -> Result<bool, String> { self.leaf(of_type("boolean"), false) }
+    fn read_f64(&mut self) -> Result<f64, String> { self.leaf(of_type("number"), 0.0) }
+    fn read_f32(&mut self) -> Result<f32, String> { self.leaf(of_type("number"), 0.0) }
+
+    fn read_char(&mut self) -> Result<char, String> {
+        self.leaf(object(vec![("type", "string".to_json()), ("minLength", 1.to_json()), ("maxLength", 1.to_json())]), ' ')
+    }
+
+    // Strings are made up as a socket address, which Addr needs to parse.
+    fn read_str(&mut self) -> Result<String, String> {
+        self.leaf(of_type("string"), "0.0.0.0:0".to_string())
+    }
+
+    fn read_enum<T, F>(&mut self, name: &str, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<T, String> {
+        self.define(name, |d| {
+            let (value, mut found) = try!(d.collect(f));
+            Ok((value, found.pop().map(|(_, s)| s).unwrap_or(Json::Null)))
+        })
+    }
+
+    fn read_enum_variant<T, F>(&mut self, names: &[&str], mut f: F) -> Result<T, String>
+        where F: FnMut(&mut SchemaDecoder, usize) -> Result<T, String> {
+        if self.skipping > 0 {
+            return f(self, 0);
+        }
+
+        let mut value = None;
+        let mut variants = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            let (v, fields) = try!(self.collect(|d| f(d, i)));
+            variants.push(variant(name, fields));
+            value = Some(v);
+        }
+        self.push(None, object(vec![("oneOf", Json::Array(variants))]));
+        value.ok_or("An enum has no variants".to_string())
+    }
+
+    fn read_enum_variant_arg<T, F>(&mut self, _: usize, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<T, String> {
+        f(self)
+    }
+
+    fn read_enum_struct_variant<T, F>(&mut self, names: &[&str], f: F) -> Result<T, String>
+        where F: FnMut(&mut SchemaDecoder, usize) -> Result<T, String> {
+        self.read_enum_variant(names, f)
+    }
+
+    // Field names are not written for struct variants.
+    fn read_enum_struct_variant_field<T, F>(&mut self, _: &str, _: usize, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<T, String> {
+        f(self)
+    }
+
+    fn read_struct<T, F>(&mut self, name: &str, _: usize, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<T, String> {
+        self.define(name, |d| {
+            let (value, fields) = try!(d.collect(f));
+            let required: Vec<Json> = fields.iter().filter_map(|&(ref n, _)| n.clone()).map(Json::String).collect();
+            let properties = fields.into_iter().filter_map(|(n, s)| n.map(|n| (n, s))).collect();
+            Ok((value, object(vec![
+                ("type", "object".to_json()),
+                ("properties", Json::Object(properties)),
+                ("required", Json::Array(required)),
+            ])))
+        })
+    }
+
+    fn read_struct_field<T, F>(&mut self, name: &str, _: usize, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<T, String> {
+        self.named(Some(name), f)
+    }
+
+    fn read_tuple<T, F>(&mut self, _: usize, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<T, String> {
+        let (value, items) = try!(self.collect(f));
+        self.push(None, tuple(items));
+        Ok(value)
+    }
+
+    fn read_tuple_arg<T, F>(&mut self, _: usize, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<T, String> {
+        f(self)
+    }
+
+    fn read_tuple_struct<T, F>(&mut self, _: &str, len: usize, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<T, String> {
+        self.read_tuple(len, f)
+    }
+
+    fn read_tuple_struct_arg<T, F>(&mut self, _: usize, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<T, String> {
+        f(self)
+    }
+
+    fn read_option<T, F>(&mut self, mut f: F) -> Result<T, String>
+        where F: FnMut(&mut SchemaDecoder, bool) -> Result<T, String> {
+        let (value, mut found) = try!(self.collect(|d| f(d, true)));
+        let inner = found.pop().map(|(_, s)| s).unwrap_or(Json::Null);
+        self.push(None, object(vec![("anyOf", Json::Array(vec![of_type("null"), inner]))]));
+        Ok(value)
+    }
+
+    fn read_seq<T, F>(&mut self, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder, usize) -> Result<T, String> {
+        let (value, mut found) = try!(self.collect(|d| f(d, SAMPLE_LEN)));
+        let items = found.pop().map(|(_, s)| s).unwrap_or(object(vec![]));
+        self.push(None, object(vec![("type", "array".to_json()), ("items", items)]));
+        Ok(value)
+    }
+
+    // Only the first element is described, the rest are the same.
+    fn read_seq_elt<T, F>(&mut self, idx: usize, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<T, String> {
+        if idx == 0 { f(self) } else { self.skip(f) }
+    }
+
+    // Maps are written as objects, with their keys as strings.
+    fn read_map<T, F>(&mut self, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder, usize) -> Result<T, String> {
+        let (value, mut found) = try!(self.collect(|d| f(d, 1)));
+        let values = found.pop().map(|(_, s)| s).unwrap_or(object(vec![]));
+        self.push(None, object(vec![("type", "object".to_json()), ("additionalProperties", values)]));
+        Ok(value)
+    }
+
+    fn read_map_elt_key<T, F>(&mut self, _: usize, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<T, String> {
+        self.skip(f)
+    }
+
+    fn read_map_elt_val<T, F>(&mut self, _: usize, f: F) -> Result<T, String>
+        where F: FnOnce(&mut SchemaDecoder) -> Result<T, String> {
+        f(self)
+    }
+
+    fn error(&mut self, err: &str) -> String {
+        err.to_string()
+    }
+}
+
+fn schema() -> Result<Json, String> {
+    let mut d = SchemaDecoder::new();
+    let layer = try!(d.describe::<Message>());
+    let payload = try!(d.describe::<MessageType>());
+
+    Ok(object(vec![
+        ("$schema", "http://json-schema.org/draft-07/schema#".to_json()),
+        ("title", "secmsg wire format".to_json()),
+        ("description", concat!(
+            "Each frame on a secure stream is a layer sealed to the next hop's key, after the layer's ",
+            "routing header, which is sealed with it as associated data. A layer opens to a Message, ",
+            "and the data of the innermost Message, which has no next_hop, is a MessageType. ",
+            "Both are UTF-8 JSON. Byte strings are arrays of integers, and keys are 32 of them.").to_json()),
+        ("routing_header_len", ROUTING_HEADER_LEN.to_json()),
+        ("protocol_version", PROTOCOL_VERSION.to_json()),
+        ("min_protocol_version", MIN_PROTOCOL_VERSION.to_json()),
+        ("layer", layer),
+        ("payload", payload),
+        ("definitions", Json::Object(d.definitions)),
+    ]))
+}
+
+fn main() {
+    match schema() {
+        Ok(s) => println!("{}", s.pretty()),
+        Err(e) => {
+            eprintln!("Could not describe the wire format: {}", e);
+            process::exit(1);
+        }
+    }
+}
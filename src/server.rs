@@ -1,60 +1,565 @@
-use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::net::{TcpListener, TcpStream, SocketAddr, IpAddr};
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::collections::{HashMap, HashSet, VecDeque, BTreeMap, BTreeSet};
 use std::thread;
-use std::mem;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::str;
 use std::cmp;
 use std::env;
 use std::fs::{self, File};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::hash::Hash;
 
 extern crate rustc_serialize;
-use rustc_serialize::json;
 extern crate crossbeam;
 extern crate crypto;
 extern crate rand;
+use rand::Rng;
 
 mod io_lib;
 mod net_lib;
+mod dns;
 mod messages;
 mod mpmc_queue;
 mod state;
+mod filters;
+mod transfer;
 mod crypto_lib;
+mod mlkem;
+mod compress;
+mod config_lib;
 
-use messages::{Message, MessageType, ResponseType};
-use messages::{ToUser, ToServer};
-use net_lib::Net;
+use messages::{Message, MessageType, ResponseType, ErrorCode, Priority, RelayDirectory, PRIORITY_LEVELS};
+use messages::{ToUser, ToServer, NamespaceChange, SharedChange, SharedFile, SyncSlot, Topic, Update, Undelivered};
+use net_lib::{Net, SecureStream, ReplayWindow};
+use mpmc_queue::MpmcPriorityQueue;
 use crypto_lib::Crypto;
-use crypto_lib::Key;
-use state::User;
+use crypto_lib::{Key, Suite, SUITES};
+use state::{User, Addr};
+use config_lib::Config;
 
-const SERVER_ADDR: &'static str = "0.0.0.0:5001";
-const PUB_KEY_ADDR: &'static str = "0.0.0.0:5002";
+const SERVER_PORT: u16 = 5001;
+const PUB_KEY_PORT: u16 = 5002;
+const MAX_RELAY_TEST_SIZE: usize = 64 * 1024;
+const MAX_LOGIN_BACKOFF_SECS: u64 = 60;
+const MAX_TRACKED_FAILURES: usize = 10000;
+const ONLINE_WINDOW_SECS: u64 = 2 * net_lib::HEARTBEAT_SECS;
+const MAX_HEARTBEAT_SKEW_SECS: u64 = 60;
+const MAX_CONTACTS_SIZE: usize = 64 * 1024;
+const MAX_SYNCED_SIZE: usize = 256 * 1024;
+const SEARCH_PAGE_SIZE: usize = 20;
+const MIN_SEARCH_PREFIX: usize = 2;
+const MAILBOX_TTL_SECS: u64 = 5 * 60;
+const MAX_MAILBOX_MESSAGES: usize = 256;
+const SHARED_FILES_QUOTA: usize = 1024 * 1024 * 1024;
+const MAX_SHARED_FILES: usize = 1000;
+const MAX_SHARED_ENTRY_SIZE: usize = 64 * 1024;
+const DAY_SECS: u64 = 24 * 60 * 60;
+const RECLAIM_CHECK_SECS: u64 = 60 * 60;
+const MAX_UPDATES: usize = 4096;
+const MAX_WATCHED: usize = 500;
+const PRESENCE_SWEEP_SECS: u64 = 10;
+const RELAY_DIRECTORY_SECS: u64 = 5 * 60;
+const MAX_RELAY_DIFFS: usize = 48;
+const MAX_DISJOINT_ROUTES: usize = 8;
+const RELAYS_PER_DISJOINT_ROUTE: usize = 3;
+const MAX_DEPOSITS_PER_MINUTE: usize = 64;
+const MAX_DEPOSITED_SIZE: usize = 64 * 1024;
+const MAX_WAITING_FORWARDS: usize = 4096;
+// How long a forward worker waits on a hop before moving on.
+const FORWARD_TIMEOUT_SECS: u64 = 5;
 
 #[derive(Clone, RustcEncodable, RustcDecodable, Hash, PartialEq, Eq)]
 pub struct KnownUser {
     pub handle: String,
     pub password: String,
-    pub addr: String,
+    pub addr: Addr,
     pub public_key: Key,
+    pub discoverable: bool,
 }
 
 impl KnownUser {
 
-    pub fn new(handle: String, password: String, addr: String, key: &Key) -> KnownUser {
+    pub fn new(handle: String, password: String, addr: Addr, key: &Key, discoverable: bool) -> KnownUser {
         KnownUser{
             handle: handle, 
             password: password, 
             addr: addr, 
-            public_key: key.clone()
+            public_key: key.clone(),
+            discoverable: discoverable,
         }
     }
 }
 type UserMap = Arc<Mutex<HashMap<String, KnownUser>>>;
 
+// When a handle last logged in or sent a heartbeat. The key tells apart a
+// new account that took the handle of an old one.
+struct Seen {
+    at: u64,
+    key: Key,
+    warned: u64, // when the account was last warned it is inactive, or 0
+}
+type PresenceMap = Arc<Mutex<HashMap<String, Seen>>>;
+
+// Each user's contact list, encrypted by the client, and its version.
+type ContactMap = Arc<Mutex<HashMap<String, (u64, Vec<u8>)>>>;
+type SyncedMap = Arc<Mutex<HashMap<(String, SyncSlot), (u64, Vec<u8>)>>>;
+
+// The keys each user has blocked, by the blocker's key. Both are the keys
+// registered with the accounts, so a new handle doesn't get around a block.
+type BlockMap = Arc<Mutex<HashMap<Key, HashSet<Key>>>>;
+
+// The subscribers of each broadcast list, keyed by "owner/name".
+type BroadcastMap = Arc<Mutex<HashMap<String, HashSet<String>>>>;
+
+// Accounts closed for going unused, by handle. The key is kept so its owner
+// can take the handle back during the cooldown, and so the handle is reported
+// as archived rather than as never having existed.
+struct Archived {
+    key: Key,
+    at: u64,
+}
+type ArchiveMap = Arc<Mutex<HashMap<String, Archived>>>;
+
+// Who may take a handle: anyone, those invited by a namespace admin, or no one.
+// Handles outside namespaces can't be invited, so only open lets them in.
+#[derive(Clone, Copy, PartialEq)]
+enum Registration {
+    Open,
+    Invite,
+    Closed,
+}
+
+impl Registration {
+    fn parse(s: &str) -> Option<Registration> {
+        match s {
+            "open" => Some(Registration::Open),
+            "invite" => Some(Registration::Invite),
+            "closed" => Some(Registration::Closed),
+            _ => None,
+        }
+    }
+}
+
+// The limits on an account. The server's come from its config, and a
+// namespace may override any of them for its members, though quotas and
+// retention can only be made stricter.
+#[derive(Clone)]
+struct Policy {
+    retention_secs: u64, // how long a held message is kept, or 0 until it is fetched
+    mailbox_quota: usize, // messages held at once
+    contacts_quota: usize, // bytes of contact list
+    shared_files_quota: usize, // bytes of files in a namespace's shared files
+    registration: Registration,
+}
+
+impl Policy {
+    fn from_config(config: &Config) -> Policy {
+        Policy {
+            retention_secs: config.get("mailbox_retention_secs", 0),
+            mailbox_quota: config.get("mailbox_quota", MAX_MAILBOX_MESSAGES),
+            contacts_quota: config.get("contacts_quota", MAX_CONTACTS_SIZE),
+            shared_files_quota: config.get("shared_files_quota", SHARED_FILES_QUOTA),
+            registration: config.get_str("registration").and_then(|r| Registration::parse(&r)).unwrap_or(Registration::Open),
+        }
+    }
+
+    fn set(&mut self, setting: &str, value: &str) -> Result<(), ErrorCode> {
+        match setting {
+            "retention_secs" => self.retention_secs = try!(value.parse().map_err(|_| ErrorCode::BadRequest)),
+            "mailbox_quota" => self.mailbox_quota = try!(value.parse().map_err(|_| ErrorCode::BadRequest)),
+            "contacts_quota" => self.contacts_quota = try!(value.parse().map_err(|_| ErrorCode::BadRequest)),
+            "shared_files_quota" => self.shared_files_quota = try!(value.parse().map_err(|_| ErrorCode::BadRequest)),
+            "registration" => self.registration = try!(Registration::parse(value).ok_or(ErrorCode::BadRequest)),
+            _ => return Err(ErrorCode::BadRequest),
+        }
+        Ok(())
+    }
+
+    fn within(&self, limits: &Policy) -> bool {
+        self.mailbox_quota <= limits.mailbox_quota && self.contacts_quota <= limits.contacts_quota
+            && self.shared_files_quota <= limits.shared_files_quota
+            && (limits.retention_secs == 0 || (self.retention_secs != 0 && self.retention_secs <= limits.retention_secs))
+    }
+}
+
+// The policy for `handle`, with its namespace's overrides.
+fn policy_for(handle: &str, namespaces: &HashMap<String, Namespace>, defaults: &Policy) -> Policy {
+    match handle.find('/').and_then(|i| namespaces.get(&handle[..i])) {
+        Some(ns) => ns.policy(defaults),
+        None => defaults.clone(),
+    }
+}
+
+// Teams that own the handles "name/..." on this server. Admins choose who may
+// register there and can remove members. A namespace can't share its name
+// with a handle, so "name/" only ever prefixes the handles of its members.
+struct Namespace {
+    admins: HashSet<String>,
+    invited: HashSet<String>, // handles within the namespace not registered yet
+    overrides: BTreeMap<String, String>, // policy settings and their values
+    files: BTreeMap<u64, SharedFile>,
+}
+
+impl Namespace {
+    fn new(admin: &str) -> Namespace {
+        let mut ns = Namespace { admins: HashSet::new(), invited: HashSet::new(), overrides: BTreeMap::new(), files: BTreeMap::new() };
+        ns.admins.insert(admin.to_string());
+        ns.overrides.insert("registration".to_string(), "invite".to_string());
+        ns
+    }
+
+    // Overrides the server has since tightened past are left out.
+    fn policy(&self, defaults: &Policy) -> Policy {
+        let mut policy = defaults.clone();
+        for (setting, value) in &self.overrides {
+            let mut next = policy.clone();
+            if next.set(setting, value).is_ok() && next.within(defaults) {
+                policy = next;
+            }
+        }
+        policy
+    }
+}
+type NamespaceMap = Arc<Mutex<HashMap<String, Namespace>>>;
+
+// Messages held for users that peers can't connect to, such as those behind
+// NAT. Routes to a user pass through the server while they keep fetching.
+struct Mailbox {
+    held: VecDeque<Message>,
+    fetched: u64,
+    push: Option<SessionQueue>, // the persistent connection it was last fetched over
+}
+type MailboxMap = Arc<Mutex<HashMap<String, Mailbox>>>;
+
+// The id of a persistent connection, and the queue of frames to send on it.
+type SessionQueue = (u64, Sender<Vec<u8>>);
+
+// Presence and directory changes, numbered so a client that lost its
+// connection can ask for what it missed. Only the last MAX_UPDATES are kept.
+struct Updates {
+    next: u64,
+    log: VecDeque<(u64, Update)>,
+    watchers: Vec<Watcher>,
+    online: HashSet<String>, // handles last announced as online
+}
+type UpdateMap = Arc<Mutex<Updates>>;
+
+struct Watcher {
+    handle: String,
+    route: Vec<(Addr, Key)>,
+    topic: Topic,
+    session: SessionQueue,
+}
+
+// The relay directory is remade from the known users every
+// RELAY_DIRECTORY_SECS, getting a new version when it changed. The changes
+// that made each of the last few versions are kept so clients can catch up
+// from theirs without fetching all of it.
+struct Relays {
+    version: u64,
+    current: BTreeMap<Key, Addr>,
+    diffs: VecDeque<(u64, Vec<(Addr, Key)>, Vec<Key>)>, // version, relays added or moved, relays removed
+}
+type RelayMap = Arc<Mutex<Relays>>;
+
+// Messages the server passes on by itself rather than along a stream:
+// deposits, broadcasts and last resort relaying. A few workers send them,
+// most urgent first, and past MAX_WAITING_FORWARDS more are dropped.
+struct Forwards {
+    queue: MpmcPriorityQueue<Message>,
+    waiting: AtomicUsize,
+    deposited: Mutex<HashMap<String, (u64, usize)>>, // depositor -> the minute, messages deposited in it
+}
+
+impl Forwards {
+    fn new() -> Forwards {
+        Forwards { queue: MpmcPriorityQueue::new(PRIORITY_LEVELS), waiting: AtomicUsize::new(0), deposited: Mutex::new(HashMap::new()) }
+    }
+
+    // Returns how many were queued.
+    fn push(&self, msgs: Vec<Message>) -> usize {
+        let mut queued = 0;
+        for msg in msgs {
+            if self.waiting.fetch_add(1, Ordering::SeqCst) >= MAX_WAITING_FORWARDS {
+                self.waiting.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+            let level = msg.priority as usize;
+            self.queue.push(msg, level);
+            queued += 1;
+        }
+        queued
+    }
+
+    fn send_next(&self) {
+        let msg = self.queue.pop();
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        if let Some(addr) = msg.next_hop {
+            let timeout = Duration::from_secs(FORWARD_TIMEOUT_SECS);
+            if let Ok(mut stream) = SecureStream::connect_within(&addr, msg.next_key.as_ref(), timeout) {
+                // Waiting for the ack keeps the connection open until the layer is taken.
+                if stream.write_frame(&msg.data).is_ok() {
+                    stream.read_ack(timeout);
+                }
+            }
+        }
+    }
+
+    // Counts `count` more deposits by `username` unless that would be too many this minute.
+    fn allow_deposits(&self, username: &str, count: usize) -> bool {
+        let minute = now() / 60;
+        let mut deposited = self.deposited.lock().unwrap();
+        let entry = deposited.entry(username.to_string()).or_insert((minute, 0));
+        if entry.0 != minute {
+            *entry = (minute, 0);
+        }
+        if entry.1 + count > MAX_DEPOSITS_PER_MINUTE {
+            return false;
+        }
+        entry.1 += count;
+        true
+    }
+}
+
+// Everything the request handlers share.
+#[derive(Clone)]
+struct Shared {
+    users: UserMap,
+    limiter: Arc<LoginLimiter>,
+    presence: PresenceMap,
+    contacts: ContactMap,
+    synced: SyncedMap,
+    blocks: BlockMap,
+    broadcasts: BroadcastMap,
+    replays: Arc<ReplayWindow>,
+    mailboxes: MailboxMap,
+    namespaces: NamespaceMap,
+    archived: ArchiveMap,
+    load: Arc<Load>,
+    updates: UpdateMap,
+    relays: RelayMap,
+    forwards: Arc<Forwards>,
+}
+
+#[derive(Clone)]
+struct ServerConfig {
+    max_frame_size: usize,
+    max_login_failures: u32,
+    lockout: Duration,
+    min_version: u16,
+    suites: Vec<Suite>,
+    session_timeout: Duration,
+    policy: Policy,
+    last_resort_relay: bool,
+    max_in_flight: usize,
+    max_latency: Duration,
+    busy_retry_secs: u16,
+    inactive_after: u64, // 0 never archives accounts
+    inactive_grace: u64,
+    inactive_warning_every: u64,
+    handle_cooldown: u64,
+    forward_workers: usize,
+}
+
+impl ServerConfig {
+    fn from_config(config: &Config) -> ServerConfig {
+        ServerConfig {
+            max_frame_size: config.get("max_frame_size", net_lib::DEFAULT_MAX_FRAME_SIZE),
+            max_login_failures: config.get("max_login_failures", 10),
+            lockout: Duration::from_secs(config.get("lockout_secs", 15 * 60)),
+            // Raising this turns away clients that don't speak a newer protocol.
+            min_version: cmp::min(config.get("min_protocol_version", net_lib::MIN_PROTOCOL_VERSION), net_lib::PROTOCOL_VERSION),
+            suites: allowed_suites(config),
+            // Clients ping well within this, so a quiet connection is dead.
+            session_timeout: Duration::from_secs(config.get("session_timeout_secs", net_lib::DEFAULT_KEEPALIVE_TIMEOUT_SECS)),
+            policy: Policy::from_config(config),
+            // Clients still have to ask for it with their `server_relay` setting.
+            last_resort_relay: config.get("last_resort_relay", true),
+            max_in_flight: config.get("max_in_flight", 256),
+            max_latency: Duration::from_millis(config.get("max_latency_ms", 250)),
+            busy_retry_secs: config.get("busy_retry_secs", 5),
+            inactive_after: config.get("inactive_days", 0u64) * DAY_SECS,
+            inactive_grace: config.get("inactive_grace_days", 30u64) * DAY_SECS,
+            inactive_warning_every: config.get("inactive_warning_days", 7u64) * DAY_SECS,
+            // Meanwhile only the archived account's key can register the handle,
+            // so no one can take it to pose as its owner to their old contacts.
+            handle_cooldown: config.get("handle_cooldown_days", 90u64) * DAY_SECS,
+            forward_workers: cmp::max(1, config.get("forward_workers", 8)),
+        }
+    }
+}
+
+// The cipher suites clients may use, from a comma separated list of names
+// such as "25519_ChaChaPoly_SHA256" in order of preference.
+fn allowed_suites(config: &Config) -> Vec<Suite> {
+    let names = match config.get_str("cipher_suites") {
+        Some(n) => n,
+        None => return SUITES.to_vec(),
+    };
+    let suites: Vec<Suite> = names.split(',').filter_map(|n| Suite::from_name(n.trim())).collect();
+    if suites.is_empty() {
+        eprintln!("No known cipher suites in '{}', allowing all of them.", names);
+        return SUITES.to_vec();
+    }
+    suites
+}
+
+// Consecutive failed logins per key, with the time of the most recent one.
+struct FailureLog<K: Hash + Eq + Clone> {
+    entries: Mutex<HashMap<K, (u32, Instant)>>,
+}
+
+impl<K: Hash + Eq + Clone> FailureLog<K> {
+
+    fn new() -> FailureLog<K> {
+        FailureLog { entries: Mutex::new(HashMap::new()) }
+    }
+
+    // Each failure doubles the wait before the next attempt, until too many
+    // failures lock the key out entirely.
+    fn is_blocked(&self, key: &K, config: &ServerConfig) -> bool {
+        match self.entries.lock().unwrap().get(key) {
+            Some(&(count, last)) => {
+                let wait = if count >= config.max_login_failures {
+                    config.lockout
+                } else {
+                    Duration::from_secs(cmp::min(1 << cmp::min(count - 1, 16), MAX_LOGIN_BACKOFF_SECS))
+                };
+                last.elapsed() < wait
+            },
+            None => false,
+        }
+    }
+
+    fn failed(&self, key: K, config: &ServerConfig) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_TRACKED_FAILURES {
+            entries.retain(|_, &mut (_, last)| last.elapsed() < config.lockout);
+        }
+        // Past the cap even recent failures are forgotten, the oldest first.
+        if entries.len() >= MAX_TRACKED_FAILURES && !entries.contains_key(&key) {
+            let mut by_age: Vec<(Instant, K)> = entries.iter().map(|(k, &(_, last))| (last, k.clone())).collect();
+            by_age.sort_by_key(|&(last, _)| last);
+            for (_, k) in by_age.into_iter().take(entries.len() + 1 - MAX_TRACKED_FAILURES) {
+                entries.remove(&k);
+            }
+        }
+
+        let entry = entries.entry(key).or_insert((0, Instant::now()));
+        // Failures older than the lockout are forgiven.
+        if entry.1.elapsed() >= config.lockout {
+            entry.0 = 0;
+        }
+        *entry = (entry.0 + 1, Instant::now());
+    }
+
+    fn succeeded(&self, key: &K) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+// How loaded the server is, as the requests being handled at once and a
+// moving average of how long each takes. Past either limit it sheds the
+// requests that can wait until both are back under three quarters of it.
+struct Load {
+    in_flight: AtomicUsize,
+    latency_us: AtomicUsize,
+    shedding: AtomicBool,
+}
+
+impl Load {
+
+    fn new() -> Load {
+        Load { in_flight: AtomicUsize::new(0), latency_us: AtomicUsize::new(0), shedding: AtomicBool::new(false) }
+    }
+
+    fn started(&self) {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn finished(&self, took: Duration) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        let us = took.as_secs() as usize * 1000000 + took.subsec_nanos() as usize / 1000;
+        let avg = self.latency_us.load(Ordering::Relaxed);
+        self.latency_us.store(avg - avg / 8 + us / 8, Ordering::Relaxed);
+    }
+
+    fn is_overloaded(&self, config: &ServerConfig) -> bool {
+        let depth = self.in_flight.load(Ordering::SeqCst);
+        let latency = Duration::from_micros(self.latency_us.load(Ordering::Relaxed) as u64);
+        let was = self.shedding.load(Ordering::SeqCst);
+        let now = if was {
+            depth * 4 > config.max_in_flight * 3 || latency * 4 > config.max_latency * 3
+        } else {
+            depth > config.max_in_flight || latency > config.max_latency
+        };
+        if now != was {
+            self.shedding.store(now, Ordering::SeqCst);
+            eprintln!("{} shedding load: {} requests in flight, {}ms average latency.",
+                if now { "Started" } else { "Stopped" }, depth, latency.as_secs() * 1000 + latency.subsec_millis() as u64);
+        }
+        now
+    }
+}
+
+// Whether to turn `msg` away while overloaded. Urgent layers never are, bulk
+// ones always, and normal ones if they are requests that can wait: lookups,
+// contact sync, relay tests, deposits and cover.
+fn should_shed(msg: &Message, shared: &Shared, config: &ServerConfig) -> bool {
+    if msg.priority == Priority::Urgent || !shared.load.is_overloaded(config) {
+        return false;
+    }
+    if msg.priority == Priority::Bulk {
+        return true;
+    }
+    if msg.next_hop.is_some() {
+        return false;
+    }
+    match Net::decode_type(&msg.data) {
+        Ok(MessageType::Server(req)) => match req {
+            ToServer::Search(..) | ToServer::SearchNamespace(..) | ToServer::Presence(..) |
+            ToServer::GetContacts(..) | ToServer::PutContacts(..) | ToServer::GetSynced(..) | ToServer::GetSubscribers(..) |
+            ToServer::RelayTest(..) | ToServer::WhoAmI(..) | ToServer::Deposit(..) | ToServer::Cover(..) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// Login attempts are limited per address, and per handle from each address.
+// Failures only lock a handle out for the address they came from, so no one
+// can lock someone else out of their account by guessing at it.
+struct LoginLimiter {
+    by_ip: FailureLog<IpAddr>,
+    by_handle: FailureLog<(String, IpAddr)>,
+}
+
+impl LoginLimiter {
+
+    fn new() -> LoginLimiter {
+        LoginLimiter { by_ip: FailureLog::new(), by_handle: FailureLog::new() }
+    }
+
+    fn is_blocked(&self, ip: IpAddr, handle: &str, config: &ServerConfig) -> bool {
+        self.by_ip.is_blocked(&ip, config) || self.by_handle.is_blocked(&(handle.to_string(), ip), config)
+    }
+
+    fn failed(&self, ip: IpAddr, handle: &str, config: &ServerConfig) {
+        self.by_ip.failed(ip, config);
+        self.by_handle.failed((handle.to_string(), ip), config);
+    }
+
+    fn succeeded(&self, ip: IpAddr, handle: &str) {
+        self.by_ip.succeeded(&ip);
+        self.by_handle.succeeded(&(handle.to_string(), ip));
+    }
+}
+
 fn main() {
-    let (priv_key, pub_key) = {
+    let (mut priv_key, pub_key) = {
         let mut keydir = env::home_dir().unwrap();
 
         keydir.push(".secmsg/keys");
@@ -82,181 +587,647 @@ fn main() {
             (priv_key, pub_key)
         }
     };
-    let crypto = Crypto::new(priv_key, pub_key);
+    let file_config = Config::load();
+    let mut crypto = Crypto::new(priv_key, pub_key);
+    crypto_lib::zeroize(&mut priv_key);
+    crypto.set_padding_buckets(&net_lib::padding_buckets(&file_config));
+    crypto.set_compression(file_config.get("compress_messages", false));
+    let config = ServerConfig::from_config(&file_config);
 
-    let users: UserMap = Arc::new(Mutex::new(HashMap::new()));
-    let server = TcpListener::bind(SERVER_ADDR).unwrap();
+    let shared = Shared {
+        users: Arc::new(Mutex::new(HashMap::new())),
+        limiter: Arc::new(LoginLimiter::new()),
+        presence: Arc::new(Mutex::new(HashMap::new())),
+        contacts: Arc::new(Mutex::new(HashMap::new())),
+        synced: Arc::new(Mutex::new(HashMap::new())),
+        blocks: Arc::new(Mutex::new(HashMap::new())),
+        broadcasts: Arc::new(Mutex::new(HashMap::new())),
+        replays: Arc::new(ReplayWindow::new()),
+        mailboxes: Arc::new(Mutex::new(HashMap::new())),
+        namespaces: Arc::new(Mutex::new(HashMap::new())),
+        archived: Arc::new(Mutex::new(HashMap::new())),
+        load: Arc::new(Load::new()),
+        updates: Arc::new(Mutex::new(Updates { next: 1, log: VecDeque::new(), watchers: Vec::new(), online: HashSet::new() })),
+        relays: Arc::new(Mutex::new(Relays { version: 0, current: BTreeMap::new(), diffs: VecDeque::new() })),
+        forwards: Arc::new(Forwards::new()),
+    };
+    let server = bind(SERVER_PORT).unwrap();
     
     crossbeam::scope(|scope| {
         scope.spawn(|| {
             for stream in server.incoming() {
                 if let Ok(stream) = stream {
-                    let users = users.clone();
-                    let crypto = crypto.clone(); // TODO: Can this be avoided?
+                    let shared = shared.clone();
+                    let crypto = crypto.clone(); // shares the one copy of the secret keys
+                    let config = config.clone();
                     thread::spawn(move || {
-                        handler(stream, users, crypto);
+                        handler(stream, shared, crypto, config);
                     });
                 }
             }
         });
 
         scope.spawn(|| {
-            for stream in TcpListener::bind(PUB_KEY_ADDR).unwrap().incoming() {
+            for stream in bind(PUB_KEY_PORT).unwrap().incoming() {
                 if let Ok(stream) = stream {
-                    pub_key_handler(stream, pub_key.clone(), &crypto);
+                    pub_key_handler(stream, pub_key.clone(), &crypto, &config);
                 }
             }
         });
-    });
-}
 
-// TODO: Just to be safe, should this not maybe be an optional Message or maybe result?
-fn receive_unencrypted_message_type(stream: &mut TcpStream) -> MessageType {
+        for _ in 0..config.forward_workers {
+            scope.spawn(|| loop {
+                shared.forwards.send_next();
+            });
+        }
 
-    // Read the message size.
-    let mut size_buf: [u8; 4] = [0; 4]; // 32 bit message size field.
-    stream.read_exact(&mut size_buf).unwrap();
-    let msg_size: u32 = unsafe { mem::transmute(size_buf) };
+        scope.spawn(|| loop {
+            update_relays(&shared.users, &shared.relays);
+            thread::sleep(Duration::from_secs(RELAY_DIRECTORY_SECS));
+        });
 
-    // Read the raw message bytes.
-    let mut msg_buf = vec![0; msg_size as usize];
-    stream.read_exact(msg_buf.as_mut_slice()).unwrap();
+        scope.spawn(|| loop {
+            thread::sleep(Duration::from_secs(PRESENCE_SWEEP_SECS));
+            announce_offline(&shared, &crypto);
+        });
 
-    // Create the message from the raw bytes.
-    json::decode(str::from_utf8(&msg_buf).unwrap()).unwrap()
+        if config.inactive_after > 0 {
+            scope.spawn(|| loop {
+                thread::sleep(Duration::from_secs(RECLAIM_CHECK_SECS));
+                reclaim_inactive(&shared, &crypto, &config);
+            });
+        }
+    });
 }
 
-// TODO: Just to be safe, should this not maybe be an optional Message or maybe result?
-fn receive_message(stream: &mut TcpStream, crypto: &Crypto) -> Message {
+// Listens on both IPv6 and IPv4 where the system allows it.
+fn bind(port: u16) -> io::Result<TcpListener> {
+    TcpListener::bind(("::", port)).or_else(|_| TcpListener::bind(("0.0.0.0", port)))
+}
 
-    // Read the message size.
-    let mut size_buf: [u8; 4] = [0; 4]; // 32 bit message size field.
-    stream.read_exact(&mut size_buf).unwrap();
-    let msg_size: u32 = unsafe { mem::transmute(size_buf) };
+fn receive_unencrypted_message_type(stream: &mut SecureStream) -> Result<MessageType, String> {
 
     // Read the raw message bytes.
-    let mut msg_buf = vec![0; msg_size as usize];
-    stream.read_exact(msg_buf.as_mut_slice()).unwrap();
-
-    // Decrypt the message.
-    // TODO: this should be a match that can return an error
-    let decrypted_message = crypto.decrypt(&msg_buf).unwrap();
+    let msg_buf = try!(stream.read_frame());
 
     // Create the message from the raw bytes.
-    json::decode(str::from_utf8(&decrypted_message).unwrap()).unwrap()
+    Net::decode_type(&msg_buf)
 }
 
+fn receive_message(stream: &mut SecureStream, crypto: &Crypto) -> Result<Message, String> {
 
-fn send_response(mut stream: TcpStream, res: Message) {
+    // Read the raw message bytes.
+    let msg_buf = try!(stream.read_frame());
+    parse_message(&msg_buf, crypto)
+}
 
-    // Check the message size.
-    if res.data.len() >= u32::max_value() as usize { return; }
+fn parse_message(msg_buf: &[u8], crypto: &Crypto) -> Result<Message, String> {
+    Message::open(msg_buf, crypto)
+}
 
-    // Send the message size.
-    let msg_size: [u8; 4] = unsafe {
-        mem::transmute(res.data.len() as u32)
-    };
-    stream.write(&msg_size).unwrap();
 
-    // Send the message.
-    stream.write(&res.data).unwrap();
+// Clients from STREAM_VERSION on take a response of any size, in fragments.
+fn send_response(mut stream: SecureStream, res: Message) -> Result<(), String> {
+    if stream.version() < net_lib::STREAM_VERSION && res.data.len() >= u32::max_value() as usize - 16 {
+        return Err("Response is too long".to_string());
+    }
+    stream.write_frame(&res.data)
 }
 
-fn addr_to_string(stream: &TcpStream) -> String {
-    match stream.peer_addr().unwrap() {
-        SocketAddr::V4(v) => {
-            let o = v.ip().octets();
-            format!("{}.{}.{}.{}:5000", o[0], o[1], o[2], o[3])
-        },
-        SocketAddr::V6(v) => {
-            let s = v.ip().segments();
-            format!("{}.{}.{}.{}.{}.{}.{}.{}:5000", 
-                s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7])
-        }
-    }
+// The address the peer listens on: its IP as we see it, and the port it reported.
+fn listen_addr(stream: &SecureStream, port: u16) -> Result<Addr, String> {
+    let peer = try!(stream.peer_addr().map_err(|e| e.to_string()));
+    Ok(Addr(SocketAddr::new(net_lib::canonical_ip(peer.ip()), port)))
 }
 
-fn gen_route(user_ip: &str, key: &Key) -> Vec<(String, Key)> {
-    vec![(user_ip.to_string(), key.clone())]
+fn gen_route(user_addr: &Addr, key: &Key) -> Vec<(Addr, Key)> {
+    vec![(*user_addr, key.clone())]
 }
 
 // TODO: This does not generate a random route. Implement a new HashMap to allow for random indexing.
-fn generate_route(users: &HashMap<String, KnownUser>, dest: (String, Key)) -> Vec<(String, Key)> {
+fn generate_route(users: &HashMap<String, KnownUser>, dest: (Addr, Key)) -> Vec<(Addr, Key)> {
     let mut r = vec![dest];
     let n = cmp::min(3, users.len());
     for v in users.values().take(n) {
-        r.push((v.addr.clone(), v.public_key.clone()))
+        r.push((v.addr, v.public_key.clone()))
     }
     r
 }
 
-fn login_response(username: String, password: String, users: &UserMap, usr_ip: String, crypto: &Crypto, key: &Key) -> Message {
-    let route = gen_route(&usr_ip, &key);
-    match users.lock().unwrap().get(&username) {
+// Splits the known relays between `count` routes so that no two routes share a hop.
+fn generate_disjoint_routes(users: &HashMap<String, KnownUser>, dest: (Addr, Key), count: usize) -> Vec<Vec<(Addr, Key)>> {
+    let mut relays: Vec<(Addr, Key)> = users.values()
+        .filter(|v| v.addr != dest.0)
+        .map(|v| (v.addr, v.public_key.clone()))
+        .collect();
+    rand::thread_rng().shuffle(&mut relays);
+
+    let mut routes: Vec<Vec<(Addr, Key)>> = (0..count).map(|_| vec![dest.clone()]).collect();
+    for (i, relay) in relays.into_iter().take(count * RELAYS_PER_DISJOINT_ROUTE).enumerate() {
+        routes[i % count].push(relay);
+    }
+    routes
+}
+
+// Checks a handle's password, counting failures against the limiter. The
+// hash is checked without holding the lock, so callers that change the user
+// must make sure the entry is still the one returned.
+fn authenticate(users: &UserMap, username: &str, password: &str, ip: IpAddr,
+                limiter: &LoginLimiter, config: &ServerConfig) -> Result<KnownUser, ErrorCode> {
+    if limiter.is_blocked(ip, username, config) {
+        return Err(ErrorCode::RateLimited);
+    }
+
+    let user = users.lock().unwrap().get(username).cloned();
+    match user {
         Some(u) => {
-            if *password == u.password {
-                Message::new(
-                    MessageType::User(
-                        ToUser::ServerResponse(
-                            ResponseType::User ( 
-                                User {
-                                    handle: u.handle.clone(),
-                                    addr: usr_ip,
-                                    public_key: u.public_key.clone(),
-                                }
-                            )
-                        )
-                    ),
-                    route,
-                    &crypto
-                )
+            if crypto_lib::check_password(password, &u.password) {
+                limiter.succeeded(ip, username);
+                Ok(u)
             } else {
-                Message::new(
-                    MessageType::User(
-                        ToUser::ServerResponse(
-                            ResponseType::Error("Incorrect password.".to_string())
-                        )
-                    ),
-                    route,
-                    &crypto
-                )
+                limiter.failed(ip, username, config);
+                Err(ErrorCode::AuthFailed)
             }
         },
         None => {
-            Message::new(
-                MessageType::User(
-                    ToUser::ServerResponse(
-                        ResponseType::Error("User does not exist.".to_string())
-                    )
-                ),
-                route,
-                &crypto
+            limiter.failed(ip, username, config);
+            Err(ErrorCode::UserNotFound)
+        },
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn login_response(username: String, password: String, users: &UserMap, presence: &PresenceMap, archived: &ArchiveMap,
+                  updates: &UpdateMap, usr_addr: Addr, crypto: &Crypto, key: &Key, limiter: &LoginLimiter, config: &ServerConfig) -> Message {
+    let route = gen_route(&usr_addr, &key);
+    let response = match authenticate(users, &username, &password, usr_addr.0.ip(), limiter, config) {
+        Ok(u) => {
+            presence.lock().unwrap().insert(u.handle.clone(), Seen { at: now(), key: u.public_key, warned: 0 });
+            // Peers reach the user wherever they last logged in from.
+            if let Some(known) = users.lock().unwrap().get_mut(&u.handle) {
+                known.addr = usr_addr;
+            }
+            announce_online(&u.handle, updates, crypto);
+            ResponseType::User(
+            User {
+                handle: u.handle,
+                addr: usr_addr,
+                public_key: u.public_key,
+            }
             )
+        },
+        Err(ErrorCode::UserNotFound) if archived.lock().unwrap().contains_key(&username) =>
+            ResponseType::Error(ErrorCode::Archived),
+        Err(e) => ResponseType::Error(e),
+    };
+    Message::new(MessageType::User(ToUser::ServerResponse(response)), route, &crypto)
+}
+
+// Checks a proof that the request came from `username` at `time`, returning
+// their key.
+fn check_proof(username: &str, time: u64, proof: &Key, users: &UserMap, crypto: &Crypto) -> Result<Key, String> {
+    let key = try!(users.lock().unwrap().get(username).map(|u| u.public_key).ok_or("Unknown user".to_string()));
+    let now = now();
+    if time + MAX_HEARTBEAT_SKEW_SECS < now || time > now + MAX_HEARTBEAT_SKEW_SECS {
+        return Err("Proof is too old".to_string());
+    }
+    if !crypto_lib::constant_time_eq(&crypto.prove(&key, time), proof) {
+        return Err("Proof is invalid".to_string());
+    }
+    Ok(key)
+}
+
+// Heartbeats carry no password, so they are proven with the user's key and
+// must be newer than the last one to stop replays.
+fn heartbeat(username: &str, time: u64, proof: &Key, users: &UserMap, presence: &PresenceMap, crypto: &Crypto) -> Result<(), String> {
+    let key = try!(check_proof(username, time, proof, users, crypto));
+
+    let mut presence = presence.lock().unwrap();
+    if presence.get(username).map_or(false, |s| s.key == key && s.at >= time) {
+        return Err("Heartbeat was replayed".to_string());
+    }
+    presence.insert(username.to_string(), Seen { at: time, key: key, warned: 0 });
+    Ok(())
+}
+
+fn presence_response(name: String, users: &UserMap, presence: &PresenceMap, archived: &ArchiveMap,
+                     route: Vec<(Addr, Key)>, crypto: &Crypto) -> Message {
+    let key = users.lock().unwrap().get(&name).map(|u| u.public_key);
+    let response = match key {
+        Some(key) => {
+            let last_seen = presence.lock().unwrap().get(&name)
+                .filter(|s| s.key == key)
+                .map(|s| now().saturating_sub(s.at));
+            ResponseType::Presence(last_seen.map_or(false, |s| s <= ONLINE_WINDOW_SECS), last_seen)
+        },
+        None if archived.lock().unwrap().contains_key(&name) => ResponseType::Error(ErrorCode::Archived),
+        None => ResponseType::Error(ErrorCode::UserNotFound),
+    };
+    Message::new(MessageType::User(ToUser::ServerResponse(response)), route, &crypto)
+}
+
+fn update_relays(users: &UserMap, relays: &RelayMap) {
+    let listed: BTreeMap<Key, Addr> = users.lock().unwrap().values().map(|u| (u.public_key, u.addr)).collect();
+    let mut relays = relays.lock().unwrap();
+    if listed == relays.current {
+        return;
+    }
+    let added = listed.iter().filter(|&(k, a)| relays.current.get(k) != Some(a)).map(|(k, a)| (*a, *k)).collect();
+    let removed = relays.current.keys().filter(|k| !listed.contains_key(*k)).cloned().collect();
+    relays.version += 1;
+    let version = relays.version;
+    if relays.diffs.len() == MAX_RELAY_DIFFS {
+        relays.diffs.pop_front();
+    }
+    relays.diffs.push_back((version, added, removed));
+    relays.current = listed;
+}
+
+// The changes since `have`, folded together, or the whole directory if the
+// client has none or is too far behind.
+fn relays_response(have: u64, relays: &RelayMap, route: Vec<(Addr, Key)>, crypto: &Crypto) -> Message {
+    let relays = relays.lock().unwrap();
+    let caught_up = have > 0 && have <= relays.version
+        && relays.diffs.front().map_or(have == relays.version, |&(first, _, _)| have + 1 >= first);
+    let (base, added, removed) = if caught_up {
+        let (mut added, mut removed) = (BTreeMap::new(), BTreeSet::new());
+        for &(_, ref a, ref r) in relays.diffs.iter().filter(|&&(v, _, _)| v > have) {
+            for key in r.iter() {
+                added.remove(key);
+                removed.insert(*key);
+            }
+            for &(addr, key) in a.iter() {
+                removed.remove(&key);
+                added.insert(key, addr);
+            }
+        }
+        (have, added.into_iter().map(|(k, a)| (a, k)).collect(), removed.into_iter().collect())
+    } else {
+        (0, relays.current.iter().map(|(k, a)| (*a, *k)).collect(), Vec::new())
+    };
+    let directory = RelayDirectory {
+        version: relays.version,
+        base: base,
+        added: added,
+        removed: removed,
+        digest: messages::relay_digest(&relays.current),
+        signing_key: [0u8; 32],
+        signature: Vec::new(),
+    }.signed(crypto);
+    Message::new(MessageType::User(ToUser::ServerResponse(ResponseType::Relays(directory))), route, &crypto)
+}
+
+fn get_contacts_response(username: String, password: String, users: &UserMap, contacts: &ContactMap, usr_addr: Addr,
+                         crypto: &Crypto, key: &Key, limiter: &LoginLimiter, config: &ServerConfig) -> Message {
+    let route = gen_route(&usr_addr, &key);
+    let response = match authenticate(users, &username, &password, usr_addr.0.ip(), limiter, config) {
+        Ok(u) => {
+            let (version, blob) = contacts.lock().unwrap().get(&u.handle).cloned().unwrap_or((0, Vec::new()));
+            ResponseType::Contacts(version, blob)
+        },
+        Err(e) => ResponseType::Error(e),
+    };
+    Message::new(MessageType::User(ToUser::ServerResponse(response)), route, &crypto)
+}
+
+// The upload only replaces the stored list if it was based on the current
+// version, so two devices can't silently overwrite each other.
+fn put_contacts_response(username: String, password: String, version: u64, blob: Vec<u8>, users: &UserMap,
+                         contacts: &ContactMap, namespaces: &NamespaceMap, usr_addr: Addr, crypto: &Crypto, key: &Key,
+                         limiter: &LoginLimiter, config: &ServerConfig) -> Message {
+    let route = gen_route(&usr_addr, &key);
+    let response = match authenticate(users, &username, &password, usr_addr.0.ip(), limiter, config) {
+        Ok(ref u) if blob.len() > policy_for(&u.handle, &namespaces.lock().unwrap(), &config.policy).contacts_quota =>
+            ResponseType::Error(ErrorCode::BadRequest),
+        Ok(u) => {
+            let mut contacts = contacts.lock().unwrap();
+            let current = contacts.get(&u.handle).map_or(0, |c| c.0);
+            if version == current {
+                contacts.insert(u.handle, (current + 1, blob));
+                ResponseType::Contacts(current + 1, Vec::new())
+            } else {
+                ResponseType::Error(ErrorCode::Conflict)
+            }
+        },
+        Err(e) => ResponseType::Error(e),
+    };
+    Message::new(MessageType::User(ToUser::ServerResponse(response)), route, &crypto)
+}
+
+fn unregister_response(username: String, password: String, shared: &Shared, usr_addr: Addr,
+                       crypto: &Crypto, key: &Key, limiter: &LoginLimiter, config: &ServerConfig) -> Message {
+    let route = gen_route(&usr_addr, &key);
+    let response = match authenticate(&shared.users, &username, &password, usr_addr.0.ip(), limiter, config) {
+        Ok(u) => {
+            let ref mut users = *shared.users.lock().unwrap();
+            if users.get(&username) == Some(&u) {
+                users.remove(&username);
+                forget_user(&u, shared, &mut shared.namespaces.lock().unwrap(), crypto);
+                ResponseType::Unregistered
+            } else {
+                ResponseType::Error(ErrorCode::AuthFailed)
+            }
+        },
+        Err(e) => ResponseType::Error(e),
+    };
+    Message::new(MessageType::User(ToUser::ServerResponse(response)), route, &crypto)
+}
+
+// Every device logged in as a user sees the same synced state. As with
+// contacts, an upload must be based on the current version.
+fn synced_response(username: &str, slot: SyncSlot, put: Option<(u64, Vec<u8>)>, synced: &SyncedMap) -> ResponseType {
+    let mut synced = synced.lock().unwrap();
+    let key = (username.to_string(), slot);
+    let (current, blob) = synced.get(&key).cloned().unwrap_or((0, Vec::new()));
+    match put {
+        None => ResponseType::Synced(current, blob),
+        Some((_, ref blob)) if blob.len() > MAX_SYNCED_SIZE => ResponseType::Error(ErrorCode::BadRequest),
+        Some((version, blob)) => if version == current {
+            synced.insert(key, (current + 1, blob));
+            ResponseType::Synced(current + 1, Vec::new())
+        } else {
+            ResponseType::Error(ErrorCode::Conflict)
+        },
+    }
+}
+
+// Drops everything kept for a user whose account is gone.
+fn forget_user(user: &KnownUser, shared: &Shared, namespaces: &mut HashMap<String, Namespace>, crypto: &Crypto) {
+    let username = &*user.handle;
+    if shared.updates.lock().unwrap().online.remove(username) {
+        publish(Update::Offline(user.handle.clone()), &shared.updates, crypto);
+    }
+    if user.discoverable {
+        publish(Update::Unlisted(user.handle.clone()), &shared.updates, crypto);
+    }
+    shared.contacts.lock().unwrap().remove(username);
+    shared.synced.lock().unwrap().retain(|&(ref user, _), _| user != username);
+    shared.blocks.lock().unwrap().remove(&user.public_key);
+    shared.mailboxes.lock().unwrap().remove(username);
+    let owned = format!("{}/", username);
+    let mut broadcasts = shared.broadcasts.lock().unwrap();
+    broadcasts.retain(|list, _| !list.starts_with(&owned));
+    for subscribers in broadcasts.values_mut() {
+        subscribers.remove(username);
+    }
+    for ns in namespaces.values_mut() {
+        ns.admins.remove(username);
+        ns.files.retain(|_, f| f.owner != username);
+    }
+}
+
+// Moves everything forget_user would clear for `old` over to `new`, with all
+// of it locked at once so nothing is seen under both handles or neither. The
+// caller holds the users and namespaces locks.
+fn move_user(old: &str, new: &str, shared: &Shared, namespaces: &mut HashMap<String, Namespace>) {
+    let mut updates = shared.updates.lock().unwrap();
+    let mut contacts = shared.contacts.lock().unwrap();
+    let mut synced = shared.synced.lock().unwrap();
+    let mut mailboxes = shared.mailboxes.lock().unwrap();
+    let mut presence = shared.presence.lock().unwrap();
+    let mut broadcasts = shared.broadcasts.lock().unwrap();
+
+    if updates.online.remove(old) {
+        updates.online.insert(new.to_string());
+    }
+    for watcher in updates.watchers.iter_mut().filter(|w| w.handle == old) {
+        watcher.handle = new.to_string();
+    }
+    rekey(&mut contacts, old, new);
+    let slots: Vec<SyncSlot> = synced.keys().filter(|k| k.0 == old).map(|k| k.1).collect();
+    for slot in slots {
+        let blob = synced.remove(&(old.to_string(), slot)).unwrap();
+        synced.insert((new.to_string(), slot), blob);
+    }
+    rekey(&mut mailboxes, old, new);
+    rekey(&mut presence, old, new);
+    let owned = format!("{}/", old);
+    let lists: Vec<String> = broadcasts.keys().filter(|l| l.starts_with(&owned)).cloned().collect();
+    for list in lists {
+        let subscribers = broadcasts.remove(&list).unwrap();
+        broadcasts.insert(format!("{}/{}", new, &list[owned.len()..]), subscribers);
+    }
+    for subscribers in broadcasts.values_mut() {
+        if subscribers.remove(old) {
+            subscribers.insert(new.to_string());
+        }
+    }
+    for ns in namespaces.values_mut() {
+        if ns.admins.remove(old) {
+            ns.admins.insert(new.to_string());
+        }
+        for file in ns.files.values_mut() {
+            if file.owner == old {
+                file.owner = new.to_string();
+            }
+            for k in file.keys.iter_mut().filter(|k| k.0 == old) {
+                k.0 = new.to_string();
+            }
+        }
+    }
+}
+
+fn rekey<V>(map: &mut HashMap<String, V>, old: &str, new: &str) {
+    if let Some(v) = map.remove(old) {
+        map.insert(new.to_string(), v);
+    }
+}
+
+// The new password is only hashed once the old one checks out, so guesses
+// cost the server no more than a failed login. Other persistent connections
+// of the user are closed, so one opened with the old password stops getting
+// their messages.
+fn change_password_response(username: String, old_password: String, new_password: String, shared: &Shared,
+                            usr_addr: Addr, crypto: &Crypto, key: &Key, session: Option<&SessionQueue>,
+                            limiter: &LoginLimiter, config: &ServerConfig) -> Message {
+    let route = gen_route(&usr_addr, &key);
+    let response = match authenticate(&shared.users, &username, &old_password, usr_addr.0.ip(), limiter, config) {
+        Ok(u) => match crypto_lib::hash_password(&new_password) {
+            Ok(hashed) => {
+                let ref mut users = *shared.users.lock().unwrap();
+                // Fails if the password was changed while we were checking it.
+                match users.get_mut(&username) {
+                    Some(entry) if *entry == u => {
+                        entry.password = hashed;
+                        revoke_sessions(&username, session.map(|s| s.0), shared);
+                        ResponseType::PasswordChanged
+                    },
+                    _ => ResponseType::Error(ErrorCode::AuthFailed),
+                }
+            },
+            Err(_) => ResponseType::Error(ErrorCode::BadRequest),
+        },
+        Err(e) => ResponseType::Error(e),
+    };
+    Message::new(MessageType::User(ToUser::ServerResponse(response)), route, &crypto)
+}
+
+// Closes the persistent connections `username` fetches or watches over,
+// other than the one with id `keep`.
+fn revoke_sessions(username: &str, keep: Option<u64>, shared: &Shared) {
+    let mut sessions = Vec::new();
+    if let Some(mailbox) = shared.mailboxes.lock().unwrap().get_mut(username) {
+        if mailbox.push.as_ref().map_or(false, |s| Some(s.0) != keep) {
+            sessions.extend(mailbox.push.take());
         }
     }
+    let mut updates = shared.updates.lock().unwrap();
+    for watcher in updates.watchers.iter().filter(|w| w.handle == username && Some(w.session.0) != keep) {
+        sessions.push(watcher.session.clone());
+    }
+    updates.watchers.retain(|w| !(w.handle == username && Some(w.session.0) != keep));
+    for session in sessions {
+        let _ = session.1.send(Vec::new());
+    }
+}
+
+// Replaces the user's identity key, so the server stops handing out the old one.
+fn change_key_response(username: String, password: String, new_key: Key, users: &UserMap, blocks: &BlockMap, usr_addr: Addr,
+                       crypto: &Crypto, key: &Key, limiter: &LoginLimiter, config: &ServerConfig) -> Message {
+    let route = gen_route(&usr_addr, &key);
+    let response = match authenticate(users, &username, &password, usr_addr.0.ip(), limiter, config) {
+        Ok(u) => {
+            let ref mut users = *users.lock().unwrap();
+            match users.get_mut(&username) {
+                Some(user) if *user == u => {
+                    user.public_key = new_key;
+                    let mut blocks = blocks.lock().unwrap();
+                    if let Some(blocked) = blocks.remove(&u.public_key) {
+                        blocks.insert(new_key, blocked);
+                    }
+                    for blocked in blocks.values_mut() {
+                        if blocked.remove(&u.public_key) {
+                            blocked.insert(new_key);
+                        }
+                    }
+                    ResponseType::User(
+                        User {
+                            handle: user.handle.clone(),
+                            addr: user.addr,
+                            public_key: new_key,
+                        }
+                    )
+                },
+                _ => ResponseType::Error(ErrorCode::AuthFailed),
+            }
+        },
+        Err(e) => ResponseType::Error(e),
+    };
+    Message::new(MessageType::User(ToUser::ServerResponse(response)), route, &crypto)
 }
 
-fn register_response(user: KnownUser, users: &UserMap, crypto: &Crypto) -> Message {
-    let route = gen_route(&user.addr, &user.public_key);
-    let ref mut users = *users.lock().unwrap();
-    // this can probably be simplified using users.entry()
-    match users.get(&user.handle) {
-        Some(_) => Message::new(
-            MessageType::User(ToUser::ServerResponse(ResponseType::Error (
-                "Username already in use.".to_string()
-            ))),
+// `new_handle` must be free, and the entry is moved under one lock so no
+// one can take either handle in between.
+fn rename_response(username: String, password: String, new_handle: String, shared: &Shared,
+                   usr_addr: Addr, crypto: &Crypto, key: &Key, limiter: &LoginLimiter, config: &ServerConfig) -> Message {
+    let route = gen_route(&usr_addr, &key);
+    let response = match authenticate(&shared.users, &username, &password, usr_addr.0.ip(), limiter, config) {
+        Ok(u) => {
+            let ref mut users = *shared.users.lock().unwrap();
+            if users.contains_key(&new_handle) || in_cooldown(&new_handle, &u.public_key, &shared.archived, config) {
+                ResponseType::Error(ErrorCode::HandleTaken)
+            } else if users.get(&username) != Some(&u) {
+                ResponseType::Error(ErrorCode::AuthFailed)
+            } else if let Err(e) = claim_handle(&new_handle, &shared.namespaces, &config.policy) {
+                ResponseType::Error(e)
+            } else {
+                move_user(&username, &new_handle, shared, &mut shared.namespaces.lock().unwrap());
+                let mut user = users.remove(&username).unwrap();
+                user.handle = new_handle.clone();
+                users.insert(new_handle, user.clone());
+                let online = shared.updates.lock().unwrap().online.contains(&user.handle);
+                if online {
+                    publish(Update::Offline(username.clone()), &shared.updates, crypto);
+                    publish(Update::Online(user.handle.clone()), &shared.updates, crypto);
+                }
+                if user.discoverable {
+                    publish(Update::Unlisted(username), &shared.updates, crypto);
+                    publish(Update::Listed(user.handle.clone()), &shared.updates, crypto);
+                }
+                ResponseType::User(
+                    User {
+                        handle: user.handle,
+                        addr: usr_addr,
+                        public_key: user.public_key,
+                    }
+                )
+            }
+        },
+        Err(e) => ResponseType::Error(e),
+    };
+    Message::new(MessageType::User(ToUser::ServerResponse(response)), route, &crypto)
+}
+
+// Checks that a free handle may be taken under the registration policy,
+// using up its invitation if it has one. The caller holds the users lock.
+fn claim_handle(handle: &str, namespaces: &NamespaceMap, defaults: &Policy) -> Result<(), ErrorCode> {
+    let mut namespaces = namespaces.lock().unwrap();
+    let (name, local) = match handle.find('/') {
+        Some(i) => (&handle[..i], &handle[i + 1..]),
+        None if namespaces.contains_key(handle) => return Err(ErrorCode::HandleTaken),
+        None if defaults.registration == Registration::Open => return Ok(()),
+        None => return Err(ErrorCode::Forbidden),
+    };
+    if local.is_empty() || local.contains('/') {
+        return Err(ErrorCode::BadRequest);
+    }
+    let ns = try!(namespaces.get_mut(name).ok_or(ErrorCode::NotFound));
+    let invited = ns.invited.remove(local);
+    match ns.policy(defaults).registration {
+        Registration::Open => Ok(()),
+        Registration::Invite if invited => Ok(()),
+        _ => {
+            if invited {
+                ns.invited.insert(local.to_string());
+            }
+            Err(ErrorCode::Forbidden)
+        },
+    }
+}
+
+// Each registration holds off the next from the same address as a failed
+// login would, since hashing the password is what costs the server.
+fn register_response(handle: String, password: String, key: Key, addr: Addr, discoverable: bool, shared: &Shared,
+                     config: &ServerConfig, crypto: &Crypto) -> Message {
+    let route = gen_route(&addr, &key);
+    let ip = addr.0.ip();
+    let taken = |users: &HashMap<String, KnownUser>|
+        users.contains_key(&handle) || in_cooldown(&handle, &key, &shared.archived, config);
+    let claimed = if shared.limiter.by_ip.is_blocked(&ip, config) {
+        Err(ErrorCode::RateLimited)
+    } else if taken(&shared.users.lock().unwrap()) {
+        Err(ErrorCode::HandleTaken)
+    } else {
+        shared.limiter.by_ip.failed(ip, config);
+        crypto_lib::hash_password(&password).map_err(|_| ErrorCode::BadRequest)
+    };
+    let ref mut users = *shared.users.lock().unwrap();
+    let claimed = claimed.and_then(|hashed| {
+        let user = KnownUser::new(handle.clone(), hashed, addr, &key, discoverable);
+        // The handle may have been taken while we were hashing.
+        if taken(users) { Err(ErrorCode::HandleTaken) } else { Ok(user) }
+    }).and_then(|user| claim_handle(&user.handle, &shared.namespaces, &config.policy).map(|_| user));
+    match claimed {
+        Err(e) => Message::new(
+            MessageType::User(ToUser::ServerResponse(ResponseType::Error(e))),
             route,
             &crypto
         ),
-        None => {
+        Ok(user) => {
             users.insert(user.handle.clone(), user.clone());
+            if user.discoverable {
+                publish(Update::Listed(user.handle.clone()), &shared.updates, crypto);
+            }
             Message::new(
                 MessageType::User(
                     ToUser::ServerResponse(
                         ResponseType::User(
                             User {
                                 handle: user.handle.clone(),
-                                addr: user.addr.clone(),
+                                addr: user.addr,
                                 public_key: user.public_key.clone()
                             }
                         )
@@ -269,78 +1240,932 @@ fn register_response(user: KnownUser, users: &UserMap, crypto: &Crypto) -> Messa
     }
 }
 
-fn connect_response(name: String, users: &UserMap, route: Vec<(String, Key)>, crypto: &Crypto) -> Message {
+// Whether `handle` belonged to an account archived too recently for anyone
+// but the holder of its key to take it.
+fn in_cooldown(handle: &str, key: &Key, archived: &ArchiveMap, config: &ServerConfig) -> bool {
+    archived.lock().unwrap().get(handle)
+        .map_or(false, |a| a.key != *key && a.at + config.handle_cooldown > now())
+}
+
+// Warns accounts no one has logged in to for `inactive_after`, through their
+// mailbox, and archives those still unused once the grace period is over.
+fn reclaim_inactive(shared: &Shared, crypto: &Crypto, config: &ServerConfig) {
+    let now = now();
+    let (mut warn, mut archive) = (Vec::new(), Vec::new());
+    {
+        let users = shared.users.lock().unwrap();
+        let mut presence = shared.presence.lock().unwrap();
+        for user in users.values() {
+            // An account not seen since the server started counts from now.
+            let seen = presence.entry(user.handle.clone())
+                .or_insert(Seen { at: now, key: user.public_key, warned: 0 });
+            if seen.key != user.public_key {
+                *seen = Seen { at: now, key: user.public_key, warned: 0 };
+            }
+            let idle = now.saturating_sub(seen.at);
+            if idle < config.inactive_after {
+                continue;
+            }
+            let left = (config.inactive_after + config.inactive_grace).saturating_sub(idle);
+            if left == 0 {
+                archive.push(user.clone());
+            } else if now.saturating_sub(seen.warned) >= config.inactive_warning_every {
+                seen.warned = now;
+                warn.push((user.clone(), idle / DAY_SECS, (left + DAY_SECS - 1) / DAY_SECS));
+            }
+        }
+    }
+
+    for (user, idle, left) in warn {
+        let text = format!("No one has logged in to {} for {} days. Log in within {} days to keep it, \
+                            or the account will be archived and the handle freed.", user.handle, idle, left);
+        let proof = crypto.prove_notice(&user.public_key, &text);
+        let notice = Message::new(MessageType::User(ToUser::ServerNotice(text, proof)),
+            gen_route(&user.addr, &user.public_key), crypto);
+        shared.mailboxes.lock().unwrap().entry(user.handle.clone())
+            .or_insert(Mailbox { held: VecDeque::new(), fetched: 0, push: None })
+            .held.push_back(notice);
+    }
+    for user in archive {
+        archive_user(user, shared, crypto);
+    }
+}
+
+// Closes an account that went unused, keeping only its key.
+fn archive_user(user: KnownUser, shared: &Shared, crypto: &Crypto) {
+    let ref mut users = *shared.users.lock().unwrap();
+    // It may have logged in or changed since it was found idle.
+    if users.get(&user.handle) != Some(&user) {
+        return;
+    }
+    users.remove(&user.handle);
+    forget_user(&user, shared, &mut shared.namespaces.lock().unwrap(), crypto);
+    shared.presence.lock().unwrap().remove(&user.handle);
+    shared.archived.lock().unwrap().insert(user.handle.clone(), Archived { key: user.public_key, at: now() });
+    eprintln!("Archived {} after it went unused.", user.handle);
+}
+
+// The registered key of whoever asks, if they proved who they are.
+fn asker_key(asker: Option<(String, u64, Key)>, users: &UserMap, crypto: &Crypto) -> Result<Option<Key>, String> {
+    match asker {
+        Some((username, time, proof)) => check_proof(&username, time, &proof, users, crypto).map(Some),
+        None => Ok(None),
+    }
+}
+
+// Whether `name` has blocked the asker. One who didn't prove who they are
+// could be anyone, so is kept from everyone who has blocked someone.
+fn is_blocked_by(name: &str, asker: Option<Key>, users: &HashMap<String, KnownUser>, blocks: &BlockMap) -> bool {
+    let key = match users.get(name) {
+        Some(u) => u.public_key,
+        None => return false,
+    };
+    match blocks.lock().unwrap().get(&key) {
+        Some(blocked) if !blocked.is_empty() => asker.map_or(true, |k| blocked.contains(&k)),
+        _ => false,
+    }
+}
+
+// Whether a layer came straight from the registered address of someone the
+// user with `key` has blocked. Layers through relays can't be told apart,
+// but a blocked user can't hand one to the mailbox themselves.
+fn sent_by_blocked(key: &Key, from: IpAddr, users: &HashMap<String, KnownUser>, blocks: &BlockMap) -> bool {
+    match blocks.lock().unwrap().get(key) {
+        Some(blocked) => users.values().any(|u| u.addr.0.ip() == from && blocked.contains(&u.public_key)),
+        None => false,
+    }
+}
+
+fn block_response(username: String, password: String, other: String, block: bool, users: &UserMap, blocks: &BlockMap,
+                  usr_addr: Addr, crypto: &Crypto, key: &Key, limiter: &LoginLimiter, config: &ServerConfig) -> Message {
+    let route = gen_route(&usr_addr, &key);
+    let response = match authenticate(users, &username, &password, usr_addr.0.ip(), limiter, config) {
+        Ok(u) => match users.lock().unwrap().get(&other).map(|o| o.public_key) {
+            Some(other_key) => {
+                let mut blocks = blocks.lock().unwrap();
+                let blocked = blocks.entry(u.public_key).or_insert(HashSet::new());
+                if block {
+                    blocked.insert(other_key);
+                } else {
+                    blocked.remove(&other_key);
+                }
+                ResponseType::Blocked(other, block)
+            },
+            None => ResponseType::Error(ErrorCode::UserNotFound),
+        },
+        Err(e) => ResponseType::Error(e),
+    };
+    Message::new(MessageType::User(ToUser::ServerResponse(response)), route, &crypto)
+}
+
+// A blocked user is told the blocker doesn't exist, so the block isn't revealed.
+// With `relay`, the server's own hop, a route with no volunteer relays to
+// hide the two ends goes through the server instead and is labeled as such.
+fn connect_response(name: String, asker: Option<Key>, users: &UserMap, blocks: &BlockMap, hop: Option<(Addr, Key)>,
+                    relay: Option<(Addr, Key)>, route: Vec<(Addr, Key)>, crypto: &Crypto) -> Message {
     let ref users = *users.lock().unwrap();
-    match users.get(&*name) {
-        Some(user) => Message::new(
+    match users.get(&*name).filter(|_| !is_blocked_by(&name, asker, users, blocks)) {
+        Some(user) => {
+            let dest = (user.addr, user.public_key.clone());
+            let generated = generate_route(users, dest.clone());
+            let volunteers = generated[1..].iter().any(|h| h.0 != dest.0);
+            let response = match relay {
+                Some(relay) if !volunteers && hop.is_none() => ResponseType::RelayedConnection(vec![dest, relay]),
+                _ => ResponseType::Connection(through(generated, hop)),
+            };
+            Message::new(MessageType::User(ToUser::ServerResponse(response)), route, &crypto)
+        },
+        None => Message::new(
             MessageType::User(
                 ToUser::ServerResponse(
-                    ResponseType::Connection(
-                        generate_route(users, (user.addr.clone(), user.public_key.clone())),
-                    )
+                    ResponseType::Error(ErrorCode::UserNotFound)
                 )
             ),
             route,
             &crypto
+        )
+    }
+}
+
+fn connect_disjoint_response(name: String, asker: Option<Key>, users: &UserMap, blocks: &BlockMap, hop: Option<(Addr, Key)>,
+                             count: usize, route: Vec<(Addr, Key)>, crypto: &Crypto) -> Message {
+    if count == 0 || count > MAX_DISJOINT_ROUTES {
+        return Message::new(MessageType::User(ToUser::ServerResponse(ResponseType::Error(ErrorCode::BadRequest))), route, &crypto);
+    }
+    let ref users = *users.lock().unwrap();
+    let response = match users.get(&*name).filter(|_| !is_blocked_by(&name, asker, users, blocks)) {
+        Some(user) => ResponseType::Connections(
+            generate_disjoint_routes(users, (user.addr, user.public_key.clone()), count)
+                .into_iter().map(|r| through(r, hop)).collect(),
         ),
-        None => Message::new(
-            MessageType::User(
-                ToUser::ServerResponse(
-                    ResponseType::Error(format!("Could not find user {}.", name))
-                )
-            ),
+        None => ResponseType::Error(ErrorCode::UserNotFound),
+    };
+    Message::new(MessageType::User(ToUser::ServerResponse(response)), route, &crypto)
+}
+
+// Sends a test message that the user's relay must forward back to the user.
+fn relay_test_response(addr: Addr, key: Key, nonce: u64, size: usize, crypto: &Crypto) -> Message {
+    let route = gen_route(&addr, &key);
+    if size > MAX_RELAY_TEST_SIZE {
+        return Message::new(
+            MessageType::User(ToUser::ServerResponse(ResponseType::Error(ErrorCode::BadRequest))),
             route,
             &crypto
-        )
+        );
     }
+
+    let mut payload = vec![0u8; size];
+    rand::thread_rng().fill_bytes(&mut payload);
+    let test = Message::new(
+        MessageType::User(ToUser::RelayTest(nonce, payload)),
+        vec![(addr, key.clone()), (addr, key.clone())],
+        &crypto
+    );
+
+    let reachable = match SecureStream::connect(&addr, Some(&key)) {
+        Ok(mut s) => s.write_frame(&test.data).is_ok() && s.read_ack(Duration::from_secs(5)),
+        Err(_) => false,
+    };
+
+    Message::new(
+        MessageType::User(ToUser::ServerResponse(ResponseType::RelayTest(reachable))),
+        route,
+        &crypto
+    )
 }
 
-fn create_response(msg: Message, users: &UserMap, stream: &TcpStream, crypto: &Crypto) -> Result<Message, ()> {
-    let addr = addr_to_string(&stream);
-    if let MessageType::Server(msg) = Net::data_to_type(&msg.data) {
-        match msg {
-            ToServer::Login(username, password, key) =>
-                Ok(login_response(username, password, &users, addr, &crypto, &key)),
-            ToServer::Register(handle, password, key) =>
-                Ok(register_response(KnownUser::new(handle, password, addr, &key), &users, &crypto)),
-            ToServer::Connect(name, public_key) =>
-                Ok(connect_response(name, &users, gen_route(&addr, &public_key), &crypto)),
-            ToServer::PublicKey(_) =>
-                Err(())
+// One page of the discoverable handles starting with `prefix`, in order. Short
+// prefixes are refused so the directory can't be listed a letter at a time.
+fn search_response(prefix: String, page: usize, users: &UserMap, route: Vec<(Addr, Key)>, crypto: &Crypto) -> Message {
+    let response = if prefix.chars().count() < MIN_SEARCH_PREFIX {
+        ResponseType::Error(ErrorCode::BadRequest)
+    } else {
+        let handles: Vec<String> = users.lock().unwrap().values()
+            .filter(|u| u.discoverable && u.handle.starts_with(&*prefix))
+            .map(|u| u.handle.clone())
+            .collect();
+        page_of(handles, page)
+    };
+    Message::new(MessageType::User(ToUser::ServerResponse(response)), route, &crypto)
+}
+
+fn page_of(mut handles: Vec<String>, page: usize) -> ResponseType {
+    handles.sort();
+    let start = cmp::min(page.saturating_mul(SEARCH_PAGE_SIZE), handles.len());
+    let more = handles.len() > start + SEARCH_PAGE_SIZE;
+    handles.truncate(start + SEARCH_PAGE_SIZE);
+    ResponseType::Handles(handles.split_off(start), more)
+}
+
+// Runs a request once `username` has logged in. Broadcast lists belong to
+// whoever created them, and only the owner may see or post to a list.
+fn authenticated_response<F>(username: String, password: String, users: &UserMap, usr_addr: Addr, crypto: &Crypto, key: &Key,
+                         limiter: &LoginLimiter, config: &ServerConfig, f: F) -> Message
+    where F: FnOnce(KnownUser) -> ResponseType {
+    let route = gen_route(&usr_addr, &key);
+    let response = match authenticate(users, &username, &password, usr_addr.0.ip(), limiter, config) {
+        Ok(u) => f(u),
+        Err(e) => ResponseType::Error(e),
+    };
+    Message::new(MessageType::User(ToUser::ServerResponse(response)), route, &crypto)
+}
+
+fn create_broadcast(owner: &KnownUser, name: &str, broadcasts: &BroadcastMap) -> ResponseType {
+    let mut broadcasts = broadcasts.lock().unwrap();
+    let list = format!("{}/{}", owner.handle, name);
+    if name.is_empty() || name.contains('/') {
+        return ResponseType::Error(ErrorCode::BadRequest);
+    }
+    if broadcasts.contains_key(&list) {
+        return ResponseType::Error(ErrorCode::Conflict);
+    }
+    broadcasts.insert(list, HashSet::new());
+    ResponseType::Subscribers(Vec::new())
+}
+
+fn subscribe(user: &KnownUser, list: &str, on: bool, broadcasts: &BroadcastMap) -> ResponseType {
+    match broadcasts.lock().unwrap().get_mut(list) {
+        Some(subscribers) => {
+            if on {
+                subscribers.insert(user.handle.clone());
+            } else {
+                subscribers.remove(&user.handle);
+            }
+            ResponseType::Subscribed(on)
+        },
+        None => ResponseType::Error(ErrorCode::NotFound),
+    }
+}
+
+fn subscribers(owner: &KnownUser, name: &str, broadcasts: &BroadcastMap) -> ResponseType {
+    match broadcasts.lock().unwrap().get(&format!("{}/{}", owner.handle, name)) {
+        Some(subscribers) => ResponseType::Subscribers(subscribers.iter().cloned().collect()),
+        None => ResponseType::Error(ErrorCode::NotFound),
+    }
+}
+
+// Hands the sealed message to every subscriber, so the owner uploads it once.
+fn fan_out(owner: &KnownUser, name: String, sealed: Vec<u8>, users: &UserMap, broadcasts: &BroadcastMap,
+           forwards: &Forwards, crypto: &Crypto) -> ResponseType {
+    let subscribers = match broadcasts.lock().unwrap().get(&format!("{}/{}", owner.handle, name)) {
+        Some(s) => s.clone(),
+        None => return ResponseType::Error(ErrorCode::NotFound),
+    };
+
+    let users = users.lock().unwrap();
+    let msgs: Vec<Message> = subscribers.iter()
+        .filter_map(|s| users.get(s))
+        .map(|u| Message::with_priority(
+            MessageType::User(ToUser::Broadcast(owner.handle.clone(), name.clone(), sealed.clone())),
+            gen_route(&u.addr, &u.public_key),
+            &crypto,
+            Priority::Bulk
+        ))
+        .collect();
+    ResponseType::BroadcastSent(forwards.push(msgs))
+}
+
+fn describe_namespace(name: &str, ns: &Namespace, users: &HashMap<String, KnownUser>) -> ResponseType {
+    let prefix = format!("{}/", name);
+    let mut admins: Vec<String> = ns.admins.iter().cloned().collect();
+    let mut members: Vec<String> = users.keys().filter(|h| h.starts_with(&prefix)).cloned().collect();
+    let mut invited: Vec<String> = ns.invited.iter().cloned().collect();
+    admins.sort();
+    members.sort();
+    invited.sort();
+    let overrides = ns.overrides.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+    ResponseType::Namespace(admins, members, invited, overrides)
+}
+
+// Anyone may start a namespace, and becomes its first admin.
+fn create_namespace(owner: &KnownUser, name: &str, users: &UserMap, namespaces: &NamespaceMap) -> ResponseType {
+    if name.is_empty() || name.contains('/') {
+        return ResponseType::Error(ErrorCode::BadRequest);
+    }
+    let users = users.lock().unwrap();
+    let mut namespaces = namespaces.lock().unwrap();
+    if users.contains_key(name) || namespaces.contains_key(name) {
+        return ResponseType::Error(ErrorCode::HandleTaken);
+    }
+    let ns = Namespace::new(&owner.handle);
+    let response = describe_namespace(name, &ns, &users);
+    namespaces.insert(name.to_string(), ns);
+    response
+}
+
+fn manage_namespace(admin: &KnownUser, name: &str, change: NamespaceChange, shared: &Shared, config: &ServerConfig,
+                    crypto: &Crypto) -> ResponseType {
+    let ref mut users = *shared.users.lock().unwrap();
+    let ref mut namespaces = *shared.namespaces.lock().unwrap();
+    match namespaces.get(name) {
+        Some(ns) if ns.admins.contains(&admin.handle) => {},
+        Some(_) => return ResponseType::Error(ErrorCode::Forbidden),
+        None => return ResponseType::Error(ErrorCode::NotFound),
+    }
+
+    match change {
+        NamespaceChange::Show => {},
+        NamespaceChange::Invite(local) => {
+            if local.is_empty() || local.contains('/') {
+                return ResponseType::Error(ErrorCode::BadRequest);
+            }
+            if users.contains_key(&format!("{}/{}", name, local)) {
+                return ResponseType::Error(ErrorCode::HandleTaken);
+            }
+            namespaces.get_mut(name).unwrap().invited.insert(local);
+        },
+        NamespaceChange::Remove(local) => {
+            let handle = format!("{}/{}", name, local);
+            if !namespaces.get_mut(name).unwrap().invited.remove(&local) {
+                match users.remove(&handle) {
+                    Some(user) => forget_user(&user, shared, namespaces, crypto),
+                    None => return ResponseType::Error(ErrorCode::UserNotFound),
+                }
+            }
+        },
+        NamespaceChange::AddAdmin(handle) => {
+            if !users.contains_key(&handle) {
+                return ResponseType::Error(ErrorCode::UserNotFound);
+            }
+            namespaces.get_mut(name).unwrap().admins.insert(handle);
+        },
+        // Someone has to be left to run it.
+        NamespaceChange::RemoveAdmin(handle) => {
+            let ns = namespaces.get_mut(name).unwrap();
+            if ns.admins.len() == 1 && ns.admins.contains(&handle) {
+                return ResponseType::Error(ErrorCode::Conflict);
+            }
+            ns.admins.remove(&handle);
+        },
+        NamespaceChange::SetPolicy(setting, value) => {
+            let ns = namespaces.get_mut(name).unwrap();
+            if value.is_empty() {
+                ns.overrides.remove(&setting);
+            } else {
+                let mut policy = ns.policy(&config.policy);
+                if let Err(e) = policy.set(&setting, &value) {
+                    return ResponseType::Error(e);
+                }
+                if !policy.within(&config.policy) {
+                    return ResponseType::Error(ErrorCode::Forbidden);
+                }
+                ns.overrides.insert(setting, value);
+            }
+        },
+    }
+    describe_namespace(name, &namespaces[name], users)
+}
+
+// Members and admins can list everyone in a namespace, including those who
+// are not listed in the public directory.
+fn search_namespace(user: &KnownUser, name: &str, prefix: &str, page: usize, shared: &Shared) -> ResponseType {
+    let users = shared.users.lock().unwrap();
+    let scope = format!("{}/", name);
+    match shared.namespaces.lock().unwrap().get(name) {
+        Some(ns) if ns.admins.contains(&user.handle) || user.handle.starts_with(&scope) => {},
+        Some(_) => return ResponseType::Error(ErrorCode::Forbidden),
+        None => return ResponseType::Error(ErrorCode::NotFound),
+    }
+
+    let prefix = format!("{}{}", scope, prefix);
+    page_of(users.keys().filter(|h| h.starts_with(&prefix)).cloned().collect(), page)
+}
+
+// Anyone in a namespace can list its shared files and add to them, but only
+// the member who added an entry or an admin can remove it. Entries count
+// towards the namespace's quota by the size of their file.
+fn shared_files(user: &KnownUser, name: &str, change: SharedChange, shared: &Shared, config: &ServerConfig) -> ResponseType {
+    let users = shared.users.lock().unwrap();
+    let mut namespaces = shared.namespaces.lock().unwrap();
+    let scope = format!("{}/", name);
+    let ns = match namespaces.get_mut(name) {
+        Some(ns) if ns.admins.contains(&user.handle) || user.handle.starts_with(&scope) => ns,
+        Some(_) => return ResponseType::Error(ErrorCode::Forbidden),
+        None => return ResponseType::Error(ErrorCode::NotFound),
+    };
+    let admin = ns.admins.contains(&user.handle);
+
+    match change {
+        SharedChange::List => {},
+        SharedChange::Members => {
+            let mut members: Vec<(String, Key)> = users.values()
+                .filter(|u| u.handle.starts_with(&scope) || ns.admins.contains(&u.handle))
+                .map(|u| (u.handle.clone(), u.public_key))
+                .collect();
+            members.sort();
+            return ResponseType::Members(members);
+        },
+        SharedChange::Add(mut file) => {
+            let used: u64 = ns.files.values().map(|f| f.size).sum();
+            let entry_size = file.meta.len() + file.keys.iter().map(|k| k.0.len() + k.1.len()).sum::<usize>();
+            if ns.files.contains_key(&file.id) {
+                return ResponseType::Error(ErrorCode::Conflict);
+            }
+            if entry_size > MAX_SHARED_ENTRY_SIZE {
+                return ResponseType::Error(ErrorCode::BadRequest);
+            }
+            if ns.files.len() >= MAX_SHARED_FILES || used + file.size > ns.policy(&config.policy).shared_files_quota as u64 {
+                return ResponseType::Error(ErrorCode::QuotaExceeded);
+            }
+            file.owner = user.handle.clone();
+            ns.files.insert(file.id, file);
+        },
+        SharedChange::Get(id) => {
+            return match ns.files.get(&id) {
+                Some(f) => ResponseType::SharedFiles(vec![for_member(f, &user.handle)]),
+                None => ResponseType::Error(ErrorCode::NotFound),
+            };
+        },
+        SharedChange::Remove(id) => {
+            match ns.files.get(&id).map(|f| f.owner == user.handle || admin) {
+                Some(true) => {},
+                Some(false) => return ResponseType::Error(ErrorCode::Forbidden),
+                None => return ResponseType::Error(ErrorCode::NotFound),
+            }
+            ns.files.remove(&id);
+        },
+    }
+    ResponseType::SharedFiles(ns.files.values().map(|f| for_member(f, &user.handle)).collect())
+}
+
+// `file` with only the entry key sealed to `handle`, if there is one.
+fn for_member(file: &SharedFile, handle: &str) -> SharedFile {
+    let mut file = file.clone();
+    file.keys.retain(|k| k.0 == handle);
+    file
+}
+
+// Forwards messages that `username` carried for peers they couldn't reach,
+// as long as the peers are our users, so the server can't be made to send
+// whatever to wherever.
+fn deposit(username: &str, msgs: Vec<Message>, shared: &Shared) -> Result<(), String> {
+    if msgs.iter().any(|m| m.data.len() > MAX_DEPOSITED_SIZE) {
+        return Err("Deposited message too large".to_string());
+    }
+    if !shared.forwards.allow_deposits(username, msgs.len()) {
+        return Err("Too many deposited messages".to_string());
+    }
+    let msgs = {
+        let users = shared.users.lock().unwrap();
+        msgs.into_iter()
+            .filter(|m| users.values().any(|u| Some(u.addr) == m.next_hop && Some(u.public_key) == m.next_key))
+            .collect()
+    };
+    shared.forwards.push(msgs);
+    Ok(())
+}
+
+// Puts `hop` just before the destination, which routes start with.
+fn through(mut route: Vec<(Addr, Key)>, hop: Option<(Addr, Key)>) -> Vec<(Addr, Key)> {
+    if let Some(h) = hop {
+        route.insert(1, h);
+    }
+    route
+}
+
+// The server itself, as the last hop to `name` if they fetch from a mailbox.
+fn mailbox_hop(name: &str, mailboxes: &MailboxMap, stream: &SecureStream, crypto: &Crypto) -> Option<(Addr, Key)> {
+    let fetching = mailboxes.lock().unwrap().get(name).map_or(false, |m| m.fetched + MAILBOX_TTL_SECS >= now());
+    if fetching { server_hop(stream, crypto) } else { None }
+}
+
+// The server as a hop, at the address the client reached it on.
+fn server_hop(stream: &SecureStream, crypto: &Crypto) -> Option<(Addr, Key)> {
+    stream.local_addr().ok().map(|local| (Addr(local), crypto.pub_key))
+}
+
+// Records an update and pushes it to everyone watching for it. Watchers
+// whose connection has closed are dropped.
+fn publish(update: Update, updates: &UpdateMap, crypto: &Crypto) {
+    let mut updates = updates.lock().unwrap();
+    let cursor = updates.next;
+    updates.next += 1;
+    if updates.log.len() == MAX_UPDATES {
+        updates.log.pop_front();
+    }
+    updates.log.push_back((cursor, update.clone()));
+    updates.watchers.retain(|s| {
+        if !s.topic.covers(&update) {
+            return true;
+        }
+        let msg = Message::new(MessageType::User(ToUser::Update(cursor, update.clone())), s.route.clone(), crypto);
+        s.session.1.send(net_lib::session_frame(net_lib::PUSH_ID, &msg.data)).is_ok()
+    });
+}
+
+// Called whenever a user logs in or sends a heartbeat.
+fn announce_online(username: &str, updates: &UpdateMap, crypto: &Crypto) {
+    let new = updates.lock().unwrap().online.insert(username.to_string());
+    if new {
+        publish(Update::Online(username.to_string()), updates, crypto);
+    }
+}
+
+// Users go offline by going quiet, so the server looks for them.
+fn announce_offline(shared: &Shared, crypto: &Crypto) {
+    let gone: Vec<String> = {
+        let presence = shared.presence.lock().unwrap();
+        let mut updates = shared.updates.lock().unwrap();
+        let gone: Vec<String> = updates.online.iter()
+            .filter(|h| presence.get(*h).map_or(true, |s| now().saturating_sub(s.at) > ONLINE_WINDOW_SECS))
+            .cloned()
+            .collect();
+        for h in gone.iter() {
+            updates.online.remove(h);
         }
+        gone
+    };
+    for h in gone {
+        publish(Update::Offline(h), &shared.updates, crypto);
+    }
+}
+
+// Has a persistent connection watch `topic`, replacing anything of the same
+// kind it watched. Returns the updates since `cursor`, or if they are no
+// longer all kept, who is online when watching presence.
+fn watch(username: &str, route: Vec<(Addr, Key)>, topic: Topic, cursor: u64, session: &SessionQueue,
+             shared: &Shared) -> ResponseType {
+    if let Topic::Presence(ref handles) = topic {
+        if handles.len() > MAX_WATCHED {
+            return ResponseType::Error(ErrorCode::BadRequest);
+        }
+    }
+    let mut updates = shared.updates.lock().unwrap();
+    let latest = updates.next - 1;
+    let complete = cursor > 0 && updates.log.front().map_or(cursor >= latest, |&(first, _)| cursor + 1 >= first);
+    let missed: Vec<(u64, Update)> = if complete {
+        updates.log.iter().filter(|&&(c, ref u)| c > cursor && topic.covers(u)).cloned().collect()
     } else {
-        Err(())
+        match topic {
+            Topic::Presence(ref handles) => handles.iter()
+                .filter(|h| updates.online.contains(*h))
+                .map(|h| (latest, Update::Online(h.clone())))
+                .collect(),
+            Topic::Directory => Vec::new(),
+        }
+    };
+    updates.watchers.retain(|s| !(s.session.0 == session.0 && s.handle == username && s.topic.same_kind(&topic)));
+    updates.watchers.push(Watcher { handle: username.to_string(), route: route, topic: topic, session: session.clone() });
+    ResponseType::Updates(missed, latest, complete)
+}
+
+fn unwatch(username: &str, topic: &Topic, session: &SessionQueue, updates: &UpdateMap) -> ResponseType {
+    let mut updates = updates.lock().unwrap();
+    updates.watchers.retain(|s| !(s.session.0 == session.0 && s.handle == username && s.topic.same_kind(topic)));
+    let latest = updates.next - 1;
+    ResponseType::Updates(Vec::new(), latest, true)
+}
+
+// Takes the messages held for `username`, opening a mailbox if they have none.
+// Over a persistent connection, messages that arrive later are pushed on it.
+fn fetch_mailbox(username: &str, shared: &Shared, config: &ServerConfig, session: Option<&SessionQueue>) -> Vec<Message> {
+    let policy = policy_for(username, &shared.namespaces.lock().unwrap(), &config.policy);
+    let mut mailboxes = shared.mailboxes.lock().unwrap();
+    let mailbox = mailboxes.entry(username.to_string())
+        .or_insert(Mailbox { held: VecDeque::new(), fetched: 0, push: None });
+    mailbox.fetched = now();
+    mailbox.push = session.cloned();
+    expire_held(mailbox, &policy);
+    mailbox.held.drain(..).collect()
+}
+
+fn expire_held(mailbox: &mut Mailbox, policy: &Policy) {
+    if policy.retention_secs > 0 {
+        let oldest = now().saturating_sub(policy.retention_secs);
+        mailbox.held.retain(|m| m.sent_at >= oldest);
     }
+}
 
+// Holds a message the server was asked to pass on to a user with a mailbox.
+fn hold_for_mailbox(msg: Message, from: IpAddr, shared: &Shared, config: &ServerConfig) -> Result<(), Undelivered> {
+    let handle = {
+        let users = shared.users.lock().unwrap();
+        let user = try!(users.values()
+            .find(|u| Some(u.addr) == msg.next_hop && Some(u.public_key) == msg.next_key)
+            .ok_or(Undelivered::RecipientUnknown));
+        if sent_by_blocked(&user.public_key, from, &users, &shared.blocks) {
+            return Err(Undelivered::Blocked);
+        }
+        user.handle.clone()
+    };
+    let policy = policy_for(&handle, &shared.namespaces.lock().unwrap(), &config.policy);
+    let mut mailboxes = shared.mailboxes.lock().unwrap();
+    let mailbox = match mailboxes.get_mut(&handle) {
+        Some(m) => m,
+        None if config.last_resort_relay => {
+            return if shared.forwards.push(vec![msg]) > 0 { Ok(()) } else { Err(Undelivered::RouteExhausted) };
+        },
+        None => return Err(Undelivered::RouteExhausted),
+    };
+    if mailbox.push.as_ref().map_or(false, |&(_, ref p)| p.send(net_lib::session_frame(net_lib::PUSH_ID, &msg.data)).is_ok()) {
+        return Ok(());
+    }
+    mailbox.push = None;
+    expire_held(mailbox, &policy);
+    while !mailbox.held.is_empty() && mailbox.held.len() >= policy.mailbox_quota {
+        mailbox.held.pop_front();
+    }
+    if policy.mailbox_quota == 0 {
+        return Err(Undelivered::MailboxFull);
+    }
+    mailbox.held.push_back(msg);
+    Ok(())
 }
 
-fn handler(mut stream: TcpStream, users: UserMap, crypto: Crypto) {
-    let msg: Message = receive_message(&mut stream, &crypto);
-    let response = create_response(msg, &users, &stream, &crypto).unwrap();
-    send_response(stream, response);
+// Returns the response to send, or None if the request only needs an acknowledgement.
+fn create_response(msg: Message, shared: &Shared, stream: &SecureStream, crypto: &Crypto,
+                   config: &ServerConfig, session: Option<&SessionQueue>) -> Result<Option<Message>, String> {
+    let (users, limiter, presence) = (&shared.users, &*shared.limiter, &shared.presence);
+    // Responses go back over this stream, so only the IP matters unless the
+    // client told us its port.
+    let addr = try!(listen_addr(&stream, net_lib::DEFAULT_PORT));
+    match try!(Net::decode_type(&msg.data)) {
+        MessageType::Server(msg) => match msg {
+            ToServer::Login(username, password, key, port) =>
+                Ok(Some(login_response(username, password, &users, &presence, &shared.archived, &shared.updates, try!(listen_addr(&stream, port)),
+                    &crypto, &key, &limiter, &config))),
+            ToServer::Unregister(username, password, key) =>
+                Ok(Some(unregister_response(username, password, &shared, addr, &crypto, &key, &limiter, &config))),
+            ToServer::Register(handle, password, key, port, discoverable) => {
+                let addr = try!(listen_addr(&stream, port));
+                Ok(Some(register_response(handle, password, key, addr, discoverable, &shared, &config, &crypto)))
+            },
+            ToServer::CreateBroadcast(username, password, name, key) =>
+                Ok(Some(authenticated_response(username, password, &users, addr, &crypto, &key, &limiter, &config,
+                    |u| create_broadcast(&u, &name, &shared.broadcasts)))),
+            ToServer::Subscribe(username, password, owner, name, on, key) =>
+                Ok(Some(authenticated_response(username, password, &users, addr, &crypto, &key, &limiter, &config,
+                    |u| subscribe(&u, &format!("{}/{}", owner, name), on, &shared.broadcasts)))),
+            ToServer::GetSubscribers(username, password, name, key) =>
+                Ok(Some(authenticated_response(username, password, &users, addr, &crypto, &key, &limiter, &config,
+                    |u| subscribers(&u, &name, &shared.broadcasts)))),
+            ToServer::Broadcast(username, password, name, sealed, key) =>
+                Ok(Some(authenticated_response(username, password, &users, addr, &crypto, &key, &limiter, &config,
+                    |u| fan_out(&u, name, sealed, &users, &shared.broadcasts, &shared.forwards, &crypto)))),
+            ToServer::CreateNamespace(username, password, name, key) =>
+                Ok(Some(authenticated_response(username, password, &users, addr, &crypto, &key, &limiter, &config,
+                    |u| create_namespace(&u, &name, &users, &shared.namespaces)))),
+            ToServer::ManageNamespace(username, password, name, change, key) =>
+                Ok(Some(authenticated_response(username, password, &users, addr, &crypto, &key, &limiter, &config,
+                    |u| manage_namespace(&u, &name, change, &shared, &config, &crypto)))),
+            ToServer::SharedFiles(username, password, name, change, key) =>
+                Ok(Some(authenticated_response(username, password, &users, addr, &crypto, &key, &limiter, &config,
+                    |u| shared_files(&u, &name, change, &shared, &config)))),
+            ToServer::SearchNamespace(username, password, name, prefix, page, key) =>
+                Ok(Some(authenticated_response(username, password, &users, addr, &crypto, &key, &limiter, &config,
+                    |u| search_namespace(&u, &name, &prefix, page, &shared)))),
+            ToServer::Search(prefix, page, public_key) =>
+                Ok(Some(search_response(prefix, page, &users, gen_route(&addr, &public_key), &crypto))),
+            ToServer::ChangePassword(username, old_password, new_password, key) =>
+                Ok(Some(change_password_response(username, old_password, new_password, &shared, addr, &crypto, &key, session, &limiter, &config))),
+            ToServer::ChangeKey(username, password, new_key, key) =>
+                Ok(Some(change_key_response(username, password, new_key, &users, &shared.blocks, addr, &crypto, &key, &limiter, &config))),
+            ToServer::Rename(username, password, new_handle, key) =>
+                Ok(Some(rename_response(username, password, new_handle, &shared, addr, &crypto, &key, &limiter, &config))),
+            ToServer::Heartbeat(username, time, proof) => {
+                try!(heartbeat(&username, time, &proof, &users, &presence, &crypto));
+                announce_online(&username, &shared.updates, &crypto);
+                Ok(None)
+            },
+            ToServer::GetRelays(have, public_key) =>
+                Ok(Some(relays_response(have, &shared.relays, gen_route(&addr, &public_key), &crypto))),
+            ToServer::Presence(name, public_key) =>
+                Ok(Some(presence_response(name, &users, &presence, &shared.archived, gen_route(&addr, &public_key), &crypto))),
+            ToServer::GetContacts(username, password, key) =>
+                Ok(Some(get_contacts_response(username, password, &users, &shared.contacts, addr, &crypto, &key, &limiter, &config))),
+            ToServer::PutContacts(username, password, version, blob, key) =>
+                Ok(Some(put_contacts_response(username, password, version, blob, &users, &shared.contacts, &shared.namespaces, addr, &crypto, &key, &limiter, &config))),
+            ToServer::Connect(name, public_key, asker) => {
+                let asker = try!(asker_key(asker, &users, &crypto));
+                Ok(Some(connect_response(name.clone(), asker, &users, &shared.blocks, mailbox_hop(&name, &shared.mailboxes, &stream, &crypto),
+                    None, gen_route(&addr, &public_key), &crypto)))
+            },
+            ToServer::ConnectOrRelay(name, public_key, asker) => {
+                let asker = try!(asker_key(asker, &users, &crypto));
+                let relay = if config.last_resort_relay { server_hop(&stream, &crypto) } else { None };
+                Ok(Some(connect_response(name.clone(), asker, &users, &shared.blocks, mailbox_hop(&name, &shared.mailboxes, &stream, &crypto),
+                    relay, gen_route(&addr, &public_key), &crypto)))
+            },
+            ToServer::ConnectDisjoint(name, public_key, count, asker) => {
+                let asker = try!(asker_key(asker, &users, &crypto));
+                Ok(Some(connect_disjoint_response(name.clone(), asker, &users, &shared.blocks, mailbox_hop(&name, &shared.mailboxes, &stream, &crypto),
+                    count, gen_route(&addr, &public_key), &crypto)))
+            },
+            ToServer::Block(username, password, other, key) =>
+                Ok(Some(block_response(username, password, other, true, &users, &shared.blocks, addr, &crypto, &key, &limiter, &config))),
+            ToServer::Unblock(username, password, other, key) =>
+                Ok(Some(block_response(username, password, other, false, &users, &shared.blocks, addr, &crypto, &key, &limiter, &config))),
+            ToServer::RelayTest(key, nonce, size) => {
+                // Test the port the user registered with, if we know it.
+                let addr = users.lock().unwrap().values()
+                    .find(|u| u.public_key == key && u.addr.0.ip() == addr.0.ip())
+                    .map_or(addr, |u| u.addr);
+                Ok(Some(relay_test_response(addr, key, nonce, size, &crypto)))
+            },
+            ToServer::Deposit(username, time, proof, msgs) => {
+                try!(check_proof(&username, time, &proof, &users, &crypto));
+                try!(deposit(&username, msgs, &shared));
+                Ok(None)
+            },
+            ToServer::Cover(_) => Ok(None),
+            ToServer::WhoAmI(key) => {
+                let peer = try!(stream.peer_addr().map_err(|e| e.to_string()));
+                let seen = Addr(SocketAddr::new(net_lib::canonical_ip(peer.ip()), peer.port()));
+                Ok(Some(Message::new(MessageType::User(ToUser::ServerResponse(ResponseType::Reflexive(seen))),
+                    gen_route(&addr, &key), &crypto)))
+            },
+            ToServer::Fetch(username, time, proof) => {
+                let key = try!(check_proof(&username, time, &proof, &users, &crypto));
+                let held = fetch_mailbox(&username, &shared, &config, session);
+                Ok(Some(Message::new(MessageType::User(ToUser::ServerResponse(ResponseType::Mailbox(held))),
+                    gen_route(&addr, &key), &crypto)))
+            },
+            ToServer::Watch(username, time, proof, topic, cursor) => {
+                let key = try!(check_proof(&username, time, &proof, &users, &crypto));
+                let response = match session {
+                    Some(session) => watch(&username, gen_route(&addr, &key), topic, cursor, session, &shared),
+                    None => ResponseType::Error(ErrorCode::BadRequest),
+                };
+                Ok(Some(Message::new(MessageType::User(ToUser::ServerResponse(response)), gen_route(&addr, &key), &crypto)))
+            },
+            ToServer::Unwatch(username, time, proof, topic) => {
+                let key = try!(check_proof(&username, time, &proof, &users, &crypto));
+                let response = match session {
+                    Some(session) => unwatch(&username, &topic, session, &shared.updates),
+                    None => ResponseType::Error(ErrorCode::BadRequest),
+                };
+                Ok(Some(Message::new(MessageType::User(ToUser::ServerResponse(response)), gen_route(&addr, &key), &crypto)))
+            },
+            ToServer::GetSynced(username, time, proof, slot) => {
+                let key = try!(check_proof(&username, time, &proof, &users, &crypto));
+                let response = synced_response(&username, slot, None, &shared.synced);
+                Ok(Some(Message::new(MessageType::User(ToUser::ServerResponse(response)), gen_route(&addr, &key), &crypto)))
+            },
+            ToServer::PutSynced(username, time, proof, slot, version, blob) => {
+                let key = try!(check_proof(&username, time, &proof, &users, &crypto));
+                let response = synced_response(&username, slot, Some((version, blob)), &shared.synced);
+                Ok(Some(Message::new(MessageType::User(ToUser::ServerResponse(response)), gen_route(&addr, &key), &crypto)))
+            },
+            ToServer::PublicKey(_) =>
+                Err("Public key requests belong on the public key port".to_string()),
+        },
+        MessageType::User(_) => Err("Message is not a server request".to_string()),
+    }
 }
 
-fn pub_key_handler(mut stream: TcpStream, pubkey: [u8; 32], crypto: &Crypto) {
-    let usr_ip = addr_to_string(&stream);
-    let msg_type: MessageType = receive_unencrypted_message_type(&mut stream);
+fn handler(stream: TcpStream, shared: Shared, crypto: Crypto, config: ServerConfig) {
+    if let Err(e) = handle_request(stream, &shared, &crypto, &config) {
+        eprintln!("Dropped request: {}", e);
+    }
+}
+
+fn handle_request(stream: TcpStream, shared: &Shared, crypto: &Crypto, config: &ServerConfig) -> Result<(), String> {
+    let mut stream = try!(SecureStream::accept_from(stream, Some(&crypto), config.min_version, &config.suites));
+    stream.set_max_frame_size(config.max_frame_size);
+    let frame = try!(stream.read_frame());
+    if frame == [net_lib::SESSION_START] && stream.version() >= net_lib::SESSION_VERSION {
+        return handle_session(stream, shared, crypto, config);
+    }
+
+    let msg = try!(parse_message(&frame, &crypto));
+    // Older clients can't read SERVER_BUSY, so they are always served.
+    if stream.version() >= net_lib::BUSY_VERSION && should_shed(&msg, shared, config) {
+        return stream.write_frame(&net_lib::busy_frame(config.busy_retry_secs));
+    }
+    match try!(respond(msg, shared, &stream, crypto, config, None)) {
+        Reply::Response(response) => send_response(stream, response),
+        // Requests without a response are acknowledged instead.
+        Reply::Ack => stream.send_ack(),
+        Reply::Undelivered(reason) => stream.send_undelivered(reason),
+    }
+}
+
+// What a request or layer gets back: its response, an acknowledgement, or
+// for a layer the server couldn't pass on, why.
+enum Reply {
+    Response(Message),
+    Ack,
+    Undelivered(Undelivered),
+}
+
+fn respond(msg: Message, shared: &Shared, stream: &SecureStream, crypto: &Crypto, config: &ServerConfig,
+           session: Option<&SessionQueue>) -> Result<Reply, String> {
+    try!(shared.replays.check(&msg));
+    shared.load.started();
+    let started = Instant::now();
+    let response = respond_to(msg, shared, stream, crypto, config, session);
+    shared.load.finished(started.elapsed());
+    response
+}
+
+fn respond_to(msg: Message, shared: &Shared, stream: &SecureStream, crypto: &Crypto, config: &ServerConfig,
+              session: Option<&SessionQueue>) -> Result<Reply, String> {
+    // A layer with a next hop is for a user peers can't reach directly, or
+    // is passing through the relay of last resort.
+    if msg.next_hop.is_some() {
+        let peer = try!(stream.peer_addr().map_err(|e| e.to_string()));
+        return Ok(match hold_for_mailbox(msg, net_lib::canonical_ip(peer.ip()), shared, config) {
+            Ok(()) => Reply::Ack,
+            Err(reason) => Reply::Undelivered(reason),
+        });
+    }
+    create_response(msg, &shared, &stream, &crypto, &config, session).map(|r| r.map_or(Reply::Ack, Reply::Response))
+}
+
+// Answers requests on a persistent connection until the client closes it or
+// goes quiet for longer than the session timeout. Replies carry the id of
+// their request, and layers pushed from mailboxes share the connection
+// through the same queue of outgoing frames.
+fn handle_session(stream: SecureStream, shared: &Shared, crypto: &Crypto, config: &ServerConfig) -> Result<(), String> {
+    let (mut writer, mut reader) = try!(stream.split());
+    try!(reader.set_read_timeout(Some(config.session_timeout)));
+    let (frames, outgoing) = channel::<Vec<u8>>();
+    let session = (rand::random::<u64>(), frames);
+    thread::spawn(move || {
+        // An empty frame asks for the connection to be closed.
+        for frame in outgoing.iter() {
+            if frame.is_empty() || writer.write_frame(&frame).is_err() {
+                break;
+            }
+        }
+        writer.shutdown();
+    });
+
+    let result = serve_session(&mut reader, &session, shared, crypto, config);
+
+    // Nothing more can be pushed once the connection is gone.
+    for mailbox in shared.mailboxes.lock().unwrap().values_mut() {
+        if mailbox.push.as_ref().map_or(false, |&(id, _)| id == session.0) {
+            mailbox.push = None;
+        }
+    }
+    shared.updates.lock().unwrap().watchers.retain(|s| s.session.0 != session.0);
+    reader.shutdown();
+    result
+}
+
+fn serve_session(reader: &mut SecureStream, session: &SessionQueue, shared: &Shared, crypto: &Crypto,
+                 config: &ServerConfig) -> Result<(), String> {
+    let frames = &session.1;
+    loop {
+        let frame = try!(reader.read_frame());
+        if frame == [net_lib::KEEPALIVE_PONG] {
+            continue;
+        }
+        let reply = if frame == [net_lib::KEEPALIVE_PING] {
+            vec![net_lib::KEEPALIVE_PONG]
+        } else {
+            let (id, data) = try!(net_lib::parse_session_frame(&frame));
+            let busy = reader.version() >= net_lib::BUSY_VERSION;
+            let response = parse_message(data, &crypto).and_then(|msg| {
+                if busy && should_shed(&msg, shared, config) {
+                    return Err(String::new());
+                }
+                respond(msg, shared, &reader, crypto, config, Some(session))
+            });
+            net_lib::session_frame(id, &match response {
+                Ok(Reply::Response(response)) => response.data,
+                Ok(Reply::Ack) => vec![net_lib::HOP_ACK],
+                Ok(Reply::Undelivered(reason)) => net_lib::undelivered_frame(reason),
+                Err(ref e) if e.is_empty() => net_lib::busy_frame(config.busy_retry_secs),
+                Err(e) => {
+                    eprintln!("Dropped request: {}", e);
+                    vec![net_lib::REQUEST_DROPPED]
+                },
+            })
+        };
+        if frames.send(reply).is_err() {
+            return Err("The connection closed".to_string());
+        }
+    }
+}
+
+fn pub_key_handler(stream: TcpStream, pubkey: [u8; 32], crypto: &Crypto, config: &ServerConfig) {
+    if let Err(e) = handle_pub_key_request(stream, pubkey, crypto, config) {
+        eprintln!("Dropped public key request: {}", e);
+    }
+}
+
+// Clients can't authenticate us before they have our key, so this port only
+// encrypts. They pin the key it serves, or skip it if configured with one.
+fn handle_pub_key_request(stream: TcpStream, pubkey: [u8; 32], crypto: &Crypto, config: &ServerConfig) -> Result<(), String> {
+    let mut stream = try!(SecureStream::accept_from(stream, None, config.min_version, &config.suites));
+    stream.set_max_frame_size(config.max_frame_size);
+    let usr_addr = try!(listen_addr(&stream, net_lib::DEFAULT_PORT));
+    let msg_type: MessageType = try!(receive_unencrypted_message_type(&mut stream));
     let response = match msg_type {
-        MessageType::Server(mt) => {
-            match mt {
-                ToServer::PublicKey(pk) => {
-                    Message::new(
-                        MessageType::User(
-                            ToUser::ServerResponse(
-                                ResponseType::PublicKey(pubkey)
-                            )
-                        ),
-                        gen_route(&usr_ip, &pk),
-                        &crypto
+        MessageType::Server(ToServer::PublicKey(pk)) => {
+            Message::new(
+                MessageType::User(
+                    ToUser::ServerResponse(
+                        ResponseType::PublicKey(pubkey)
                     )
-                },
-                _ => return
-            }
+                ),
+                gen_route(&usr_addr, &pk),
+                &crypto
+            )
         },
-        _ => return
+        _ => return Err("Expected a public key request".to_string()),
     };
-    send_response(stream, response);
+    send_response(stream, response)
 }
@@ -0,0 +1,411 @@
+#![allow(dead_code)]
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use rustc_serialize::json;
+use rustc_serialize::hex::{FromHex, ToHex};
+
+use io_lib::IOHandler;
+use config_lib::Config;
+use crypto_lib::{self, Key};
+use net_lib::{Net, DEFAULT_SERVER_ADDR};
+use state::User;
+use command;
+use key_store::{self, KeyStore, FileStore, Keyring};
+
+// Everything that can stop a headless client from starting, since there is
+// nobody to answer a prompt.
+pub enum StartupError {
+    NoHomeDirectory,
+    MissingSetting(&'static str),
+    UnreadableFile(String),
+    IncorrectPassphrase,
+    ServerUnreachable(String),
+    LoginFailed(String),
+}
+
+impl ToString for StartupError {
+    fn to_string(&self) -> String {
+        match *self {
+            StartupError::NoHomeDirectory => "Cannot find home directory.".to_string(),
+            StartupError::MissingSetting(s) => format!("The '{}' setting is required in headless mode.", s),
+            StartupError::UnreadableFile(ref f) => format!("Could not read {}.", f),
+            StartupError::IncorrectPassphrase => "The passphrase does not unlock the private key.".to_string(),
+            StartupError::ServerUnreachable(ref e) => format!("Could not reach the server: {}", e),
+            StartupError::LoginFailed(ref e) => format!("Login failed: {}", e),
+        }
+    }
+}
+
+impl StartupError {
+    // Exit codes from sysexits.h so service managers can tell failures apart.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            StartupError::NoHomeDirectory | StartupError::MissingSetting(_) => 78,
+            StartupError::UnreadableFile(_) => 66,
+            StartupError::IncorrectPassphrase | StartupError::LoginFailed(_) => 77,
+            StartupError::ServerUnreachable(_) => 69,
+        }
+    }
+}
+
+pub fn secmsg_dir(io: &IOHandler) -> PathBuf {
+    match env::home_dir() {
+        Some(p) => p.join(".secmsg"),
+        None    => {
+            io.print_error("Cannot find home directory.");
+            process::exit(1);
+        }
+    }
+}
+
+// Users from before the wizard existed have keys but no config.
+pub fn is_first_run(io: &IOHandler) -> bool {
+    let dir = secmsg_dir(io);
+    !dir.join("config").exists() && !dir.join("keys").exists()
+}
+
+// Walks a new user through creating their identity key and config.
+pub fn run_wizard(io: &IOHandler) {
+    io.print_log("It looks like this is your first time using SecMsg, let's get you set up.");
+
+    // Identity key.
+    let priv_key = loop {
+        match &*io.read_prompted_line("Generate a new identity key or import one? [generate/import]: ") {
+            "" | "g" | "generate" => break crypto_lib::gen_key_pair().0,
+            "i" | "import" => match import_key(&io.read_prompted_line("Path to private key: ")) {
+                Ok(key) => break key,
+                Err(e) => io.print_error(&e),
+            },
+            _ => io.print_error("Please enter 'generate' or 'import'."),
+        }
+    };
+
+    // Passphrase.
+    let passphrase = ask_passphrase(io, "Passphrase to protect your key (leave empty for none): ");
+    if let Err(e) = save_keys(io, &priv_key, &passphrase) {
+        io.print_error(&e);
+        process::exit(1);
+    }
+
+    // Server.
+    let mut config = Config::load();
+    let server = io.read_prompted_line(&format!("Server address or domain, fallbacks after commas [{}]: ", DEFAULT_SERVER_ADDR));
+    if !server.is_empty() {
+        config.set("server", &server);
+        let key_server = io.read_prompted_line("Server key address [the next port up]: ");
+        if !key_server.is_empty() {
+            config.set("key_server", &key_server);
+        }
+    }
+
+    // Relaying.
+    config.set("relay", if ask(io, "Relay messages for other users? [y/n]: ") { "true" } else { "false" });
+
+    if let Err(e) = config.save() {
+        io.print_error(&e);
+        process::exit(1);
+    }
+}
+
+// The account step runs once the network is up, returns the command to run.
+pub fn choose_account_action(io: &IOHandler) -> Option<&'static str> {
+    loop {
+        match &*io.read_prompted_line("Register a new account or log in? [register/login/skip]: ") {
+            "r" | "register" => return Some("/register"),
+            "l" | "login" => return Some("/login"),
+            "" | "s" | "skip" => return None,
+            _ => io.print_error("Please enter 'register', 'login' or 'skip'."),
+        }
+    }
+}
+
+fn ask(io: &IOHandler, prompt: &str) -> bool {
+    loop {
+        match &*io.read_prompted_line(prompt) {
+            "y" | "yes" => return true,
+            "n" | "no" => return false,
+            _ => io.print_error("Please enter 'y' or 'n'."),
+        }
+    }
+}
+
+fn import_key(path: &str) -> Result<Key, String> {
+    let mut key = [0u8; 32];
+    let mut file = try!(File::open(path).map_err(|e| e.to_string()));
+    try!(file.read_exact(&mut key).map_err(|_| "Private keys are 32 bytes long.".to_string()));
+    Ok(key)
+}
+
+// Writes the key pair, sealing the private key if a passphrase is given. With
+// the keyring the passphrase is not used and any key file is removed.
+pub fn save_keys(io: &IOHandler, priv_key: &Key, passphrase: &str) -> Result<(), String> {
+    let keydir = secmsg_dir(io).join("keys");
+    try!(fs::create_dir_all(&keydir).map_err(|e| e.to_string()));
+
+    let pub_key = crypto_lib::public_key_of(priv_key);
+    let mut pub_key_file = try!(File::create(keydir.join("public")).map_err(|e| e.to_string()));
+    try!(pub_key_file.write_all(&pub_key).map_err(|e| e.to_string()));
+
+    let files = FileStore { dir: keydir, passphrase: passphrase.to_string() };
+    if uses_keyring() {
+        try!(Keyring::new(&secmsg_dir(io)).store(priv_key));
+        files.remove()
+    } else {
+        files.store(priv_key)
+    }
+}
+
+fn uses_keyring() -> bool {
+    Config::load().get_str("key_store").map_or(false, |s| s == "keyring")
+}
+
+// Asks for the passphrase a key will be sealed with, unless it goes in the keyring.
+pub fn ask_passphrase(io: &IOHandler, prompt: &str) -> String {
+    if uses_keyring() { String::new() } else { io.read_prompted_line(prompt) }
+}
+
+// Seals the private key under a new passphrase, or stores it unsealed if the
+// new one is empty, once the current one is given.
+pub fn change_passphrase(io: &IOHandler) -> Result<(), String> {
+    if uses_keyring() {
+        return Err("Your private key is in the keyring, which protects it instead of a passphrase.".to_string());
+    }
+    let keydir = secmsg_dir(io).join("keys");
+    let passphrase = if keydir.join("private.enc").exists() { io.read_prompted_line("Current passphrase: ") } else { String::new() };
+    let priv_key = try!(try!(FileStore { dir: keydir, passphrase: passphrase }.load()).ok_or("There is no private key.".to_string()));
+    let passphrase = io.read_prompted_line("New passphrase (leave empty for none): ");
+    if !passphrase.is_empty() && io.read_prompted_line("New passphrase again: ") != passphrase {
+        return Err("The passphrases do not match.".to_string());
+    }
+    save_keys(io, &priv_key, &passphrase)
+}
+
+const KEY_FILES: &'static [&'static str] = &["private", "private.enc", "private.dpapi", "public"];
+const CONTACT_KEY_FILES: &'static [&'static str] = &["known_keys", "signing_keys", "key_history"];
+
+// The identity key and the keys pinned for contacts, so a restore doesn't
+// have to trust every contact's key afresh.
+#[derive(RustcEncodable, RustcDecodable)]
+struct Backup {
+    private_key: String, // hex
+    contact_keys: Vec<(String, String)>, // file in ~/.secmsg, its contents
+}
+
+// Writes everything needed to become us on another machine to `path`,
+// sealed by a passphrase. The key is derived with scrypt, not Argon2, since
+// the sealed private key already uses it and there is no Argon2 crate here.
+pub fn backup_identity(io: &IOHandler, path: &str) -> Result<(), String> {
+    let dir = secmsg_dir(io);
+    let (priv_key, _) = load_keys(io);
+    let mut contact_keys = Vec::new();
+    for f in CONTACT_KEY_FILES.iter() {
+        let mut contents = String::new();
+        if let Ok(mut file) = File::open(dir.join(f)) {
+            try!(file.read_to_string(&mut contents).map_err(|e| e.to_string()));
+            contact_keys.push((f.to_string(), contents));
+        }
+    }
+    let backup = Backup { private_key: priv_key.to_hex(), contact_keys: contact_keys };
+
+    let passphrase = io.read_prompted_line("Passphrase to protect the backup: ");
+    if passphrase.is_empty() {
+        return Err("The backup needs a passphrase".to_string());
+    }
+    if io.read_prompted_line("Passphrase again: ") != passphrase {
+        return Err("The passphrases do not match".to_string());
+    }
+    let text = try!(json::encode(&backup).map_err(|e| e.to_string()));
+    let sealed = try!(crypto_lib::seal_with_passphrase(&passphrase, text.as_bytes())
+        .map_err(|_| "Failed to encrypt the backup".to_string()));
+    try!(key_store::create_private(Path::new(path)).and_then(|mut f| f.write_all(&sealed)).map_err(|e| e.to_string()));
+    io.print_log(&format!("Backed up your identity key and your contacts' keys to {}.", path));
+    Ok(())
+}
+
+// Restores a backup made by backup_identity. It won't replace an identity
+// key that is already here.
+pub fn restore_identity(io: &IOHandler, path: &str) -> Result<(), String> {
+    let dir = secmsg_dir(io);
+    let in_keyring = uses_keyring() && Keyring::new(&dir).load().ok().map_or(false, |k| k.is_some());
+    if in_keyring || KEY_FILES.iter().any(|f| dir.join("keys").join(f).exists()) {
+        return Err(format!("There is already an identity key in {}", dir.join("keys").display()));
+    }
+
+    let mut sealed = Vec::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_end(&mut sealed)).map_err(|e| e.to_string()));
+    let passphrase = io.read_prompted_line("Passphrase of the backup: ");
+    let text = try!(crypto_lib::open_with_passphrase(&passphrase, &sealed).ok()
+        .and_then(|t| String::from_utf8(t).ok())
+        .ok_or("The passphrase does not open the backup".to_string()));
+    let backup: Backup = try!(json::decode(&text).map_err(|_| "The backup is corrupt".to_string()));
+    let bytes = try!(backup.private_key.from_hex().ok().filter(|k| k.len() == 32)
+        .ok_or("The backup is corrupt".to_string()));
+    let mut priv_key = [0u8; 32];
+    priv_key.copy_from_slice(&bytes);
+
+    let passphrase = ask_passphrase(io, "Passphrase to protect your key (leave empty for none): ");
+    try!(save_keys(io, &priv_key, &passphrase));
+    for &(ref f, ref contents) in backup.contact_keys.iter().filter(|&&(ref f, _)| CONTACT_KEY_FILES.contains(&&**f)) {
+        try!(key_store::create_private(&dir.join(f)).and_then(|mut file| file.write_all(contents.as_bytes())).map_err(|e| e.to_string()));
+    }
+    io.print_log("Restored your identity key.");
+    Ok(())
+}
+
+// Moves the identity key aside so a new one can be saved in its place. It is
+// put back by restore_keys, or deleted by discard_old_keys. A key in the
+// keyring is set aside as a file, since the keyring holds only one.
+pub fn set_aside_keys(io: &IOHandler) -> Result<(), String> {
+    let keydir = secmsg_dir(io).join("keys");
+    if uses_keyring() {
+        let keyring = Keyring::new(&secmsg_dir(io));
+        if let Some(priv_key) = try!(keyring.load()) {
+            try!(FileStore { dir: keydir.clone(), passphrase: String::new() }.store(&priv_key));
+            try!(keyring.remove());
+        }
+    }
+    for f in KEY_FILES.iter().filter(|f| keydir.join(f).exists()) {
+        try!(fs::rename(keydir.join(f), keydir.join(format!("{}.old", f))).map_err(|e| e.to_string()));
+    }
+    Ok(())
+}
+
+pub fn restore_keys(io: &IOHandler) {
+    let keydir = secmsg_dir(io).join("keys");
+    for f in KEY_FILES {
+        let _ = fs::remove_file(keydir.join(f));
+        let _ = fs::rename(keydir.join(format!("{}.old", f)), keydir.join(f));
+    }
+    if uses_keyring() {
+        let _ = Keyring::new(&secmsg_dir(io)).remove();
+        if let Ok(Some(priv_key)) = (FileStore { dir: keydir, passphrase: String::new() }).load() {
+            let _ = save_keys(io, &priv_key, "");
+        }
+    }
+}
+
+pub fn discard_old_keys(io: &IOHandler) {
+    let keydir = secmsg_dir(io).join("keys");
+    for f in KEY_FILES {
+        let _ = fs::remove_file(keydir.join(format!("{}.old", f)));
+    }
+}
+
+// Loads the key pair, asking for the passphrase if the private key is sealed
+// and generating a new pair if there is none. With the keyring, a key still
+// in a file is moved into it.
+pub fn load_keys(io: &IOHandler) -> (Key, Key) {
+    let keydir = secmsg_dir(io).join("keys");
+
+    if uses_keyring() {
+        match Keyring::new(&secmsg_dir(io)).load() {
+            Ok(Some(priv_key)) => return (priv_key, crypto_lib::public_key_of(&priv_key)),
+            Ok(None) => {},
+            Err(e) => io.print_error(&format!("Could not read the keyring, trying the key file: {}", e)),
+        }
+    }
+
+    let priv_key = if keydir.join("private.enc").exists() {
+        loop {
+            let passphrase = io.read_prompted_line("Passphrase: ");
+            match (FileStore { dir: keydir.clone(), passphrase: passphrase }).load() {
+                Ok(Some(priv_key)) => break Some(priv_key),
+                Ok(None) => break None,
+                Err(e) => io.print_error(&e),
+            }
+        }
+    } else if keydir.join("private").exists() {
+        let priv_key = (FileStore { dir: keydir.clone(), passphrase: String::new() }).load().unwrap().unwrap();
+        if !uses_keyring() {
+            let passphrase = io.read_prompted_line("Your private key is not encrypted. Passphrase to encrypt it (leave empty to keep it as is): ");
+            if !passphrase.is_empty() {
+                if let Err(e) = save_keys(io, &priv_key, &passphrase) {
+                    io.print_error(&format!("Could not encrypt the private key: {}", e));
+                }
+            }
+        }
+        Some(priv_key)
+    } else {
+        None
+    };
+
+    match priv_key {
+        Some(priv_key) => {
+            if uses_keyring() {
+                match save_keys(io, &priv_key, "") {
+                    Ok(()) => io.print_log("Moved your private key into the keyring."),
+                    Err(e) => io.print_error(&format!("Could not move the private key into the keyring: {}", e)),
+                }
+            }
+            (priv_key, crypto_lib::public_key_of(&priv_key))
+        },
+        None => {
+            let (priv_key, pub_key) = crypto_lib::gen_key_pair();
+            let passphrase = ask_passphrase(io, "Passphrase to protect your new key (leave empty for none): ");
+            save_keys(io, &priv_key, &passphrase).unwrap();
+            (priv_key, pub_key)
+        },
+    }
+}
+
+fn read_secret_file(path: &str) -> Result<String, StartupError> {
+    let mut contents = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|_| StartupError::UnreadableFile(path.to_string())));
+    Ok(contents.trim().to_string())
+}
+
+// Loads the key pair without prompting, reading the passphrase of a sealed
+// key from the `passphrase_file` setting.
+pub fn headless_keys(config: &Config) -> Result<(Key, Key), StartupError> {
+    let dir = try!(env::home_dir().ok_or(StartupError::NoHomeDirectory)).join(".secmsg");
+    let keydir = dir.join("keys");
+
+    if config.get_str("key_store").map_or(false, |s| s == "keyring") {
+        if let Ok(Some(priv_key)) = Keyring::new(&dir).load() {
+            return Ok((priv_key, crypto_lib::public_key_of(&priv_key)));
+        }
+    }
+    if keydir.join("private.enc").exists() {
+        let path = try!(config.get_str("passphrase_file").ok_or(StartupError::MissingSetting("passphrase_file")));
+        let passphrase = try!(read_secret_file(&path));
+
+        let mut sealed = Vec::new();
+        try!(File::open(keydir.join("private.enc")).and_then(|mut f| f.read_to_end(&mut sealed))
+            .map_err(|_| StartupError::UnreadableFile(keydir.join("private.enc").display().to_string())));
+        let priv_key = try!(crypto_lib::open_with_passphrase(&passphrase, &sealed).ok()
+            .filter(|k| k.len() == 32)
+            .map(|k| { let mut key = [0u8; 32]; key.copy_from_slice(&k); key })
+            .ok_or(StartupError::IncorrectPassphrase));
+        Ok((priv_key, crypto_lib::public_key_of(&priv_key)))
+    } else if keydir.join("private").exists() {
+        let mut priv_key = [0u8; 32];
+        try!(File::open(keydir.join("private")).and_then(|mut f| f.read_exact(&mut priv_key))
+            .map_err(|_| StartupError::UnreadableFile(keydir.join("private").display().to_string())));
+        Ok((priv_key, crypto_lib::public_key_of(&priv_key)))
+    } else {
+        let (priv_key, pub_key) = crypto_lib::gen_key_pair();
+        let io = IOHandler::quiet();
+        try!(save_keys(&io, &priv_key, "").map_err(|e| StartupError::UnreadableFile(e)));
+        Ok((priv_key, pub_key))
+    }
+}
+
+// Logs in with the `username` setting and the password from `password_file`
+// or the `password` setting (normally given as SECMSG_PASSWORD).
+pub fn headless_login(config: &Config, net: &Net) -> Result<User, StartupError> {
+    let username = try!(config.get_str("username").ok_or(StartupError::MissingSetting("username")));
+    let password = match config.get_str("password_file") {
+        Some(path) => try!(read_secret_file(&path)),
+        None => try!(config.get_str("password").ok_or(StartupError::MissingSetting("password_file"))),
+    };
+
+    let user = try!(command::login_as(username, password, net).map_err(|e| StartupError::LoginFailed(e)));
+    net.set_handle(Some(user.handle.clone()));
+    Ok(user)
+}
@@ -1,31 +1,69 @@
 #![allow(dead_code)]
 
-use std::collections::{HashMap};
+use std::collections::{HashMap, HashSet, VecDeque, BTreeMap, BTreeSet};
 use std::collections::hash_map::Entry;
 use std::sync::{Arc, Mutex, Condvar};
-use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use std::clone::Clone;
+use std::fmt;
+use std::net::SocketAddr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use rustc_serialize::{Encodable, Encoder, Decodable, Decoder};
+use rustc_serialize::json;
+use rustc_serialize::hex::{FromHex, ToHex};
 
 extern crate rand;
 
-use messages::TextMessage;
+use messages::{TextMessage, Priority};
 use net_lib::Net;
-use crypto_lib::Key;
+use io_lib::IOHandler;
+use crypto_lib::{self, Key};
 use mpmc_queue::MpmcQueue;
+use transfer::Transfers;
+use filters::{self, Rule, Action};
+
+// A socket address that can be sent over the wire. It is encoded in its
+// text form, with IPv6 addresses in brackets such as [::1]:5000.
+#[derive(Clone, Copy, Hash, PartialEq, Eq)]
+pub struct Addr(pub SocketAddr);
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Encodable for Addr {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(&self.0.to_string())
+    }
+}
+
+impl Decodable for Addr {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Addr, D::Error> {
+        let text = try!(d.read_str());
+        text.parse().map(Addr).map_err(|_| d.error("invalid socket address"))
+    }
+}
 
-pub type AddrPair = (String, Key);
+pub type AddrPair = (Addr, Key);
 pub type Route = Vec<AddrPair>;
 
 #[derive(Clone, RustcEncodable, RustcDecodable, Hash, PartialEq, Eq)]
 pub struct User {
     pub handle: String,
-    pub addr: String,
+    pub addr: Addr,
     pub public_key: Key,
 }
 
 impl User {
 
-    pub fn new(handle: String, addr: String, key: Key) -> User {
+    pub fn new(handle: String, addr: Addr, key: Key) -> User {
         User {
             handle: handle,
             addr: addr,
@@ -50,6 +88,7 @@ pub struct Conversation {
     new_message_count: usize,
     id: u64,
     priv_id: usize,
+    aliases: Vec<User>, // other identities of the partner, merged in with /merge
     //users: map of all users in conversation. Implement when adding group messages.
 }
 
@@ -62,6 +101,7 @@ impl Conversation {
             new_message_count: 0,
             id: rand::random::<u64>(),
             priv_id: Conversation::next_id(),
+            aliases: Vec::new(),
         }
     }
 
@@ -72,6 +112,7 @@ impl Conversation {
             new_message_count: 0,
             id: id,
             priv_id: Conversation::next_id(),
+            aliases: Vec::new(),
         }
     }
 
@@ -103,6 +144,24 @@ impl Conversation {
     pub fn get_partner(&self) -> &User {
         &self.partner
     }
+
+    pub fn get_aliases(&self) -> &[User] {
+        &self.aliases
+    }
+
+    fn is_alias(&self, user: &User) -> bool {
+        self.aliases.iter().any(|a| a.handle == user.handle && a.public_key == user.public_key)
+    }
+
+    // Once merged, messages from the partner are marked with the key of the
+    // identity they came from.
+    pub fn show(&self, msg: &TextMessage) -> String {
+        let partner = msg.sender.public_key == self.partner.public_key || self.is_alias(&msg.sender);
+        if self.aliases.is_empty() || !partner {
+            return msg.to_string();
+        }
+        format!("{} [{}]: {}", msg.sender.handle, msg.sender.public_key[..4].to_hex(), msg.text)
+    }
 }
 
 type Conversations = HashMap<u64, Conversation>;
@@ -119,12 +178,247 @@ impl<'a> Iterator for NewMessagesIter<'a> {
     }
 }
 
+const MAX_ACTIVITY: usize = 200;
+
+// The ids of messages received recently, so a copy that comes by another
+// route or is resent isn't shown twice. An id is forgotten after
+// SEEN_MESSAGE_SECS, when net_lib would refuse a layer carrying it as too
+// old, or sooner once MAX_SEEN_MESSAGES are remembered.
+const SEEN_MESSAGE_SECS: u64 = 2 * 60 * 60 * 24;
+const MAX_SEEN_MESSAGES: usize = 100000;
+
+struct SeenMessages {
+    ids: HashSet<u64>,
+    order: VecDeque<(Instant, u64)>, // when each id was seen, oldest first
+}
+
+impl SeenMessages {
+    fn new() -> SeenMessages {
+        SeenMessages { ids: HashSet::new(), order: VecDeque::new() }
+    }
+
+    // Returns false if the id was seen already.
+    fn insert(&mut self, id: u64) -> bool {
+        while self.order.front().map_or(false, |&(at, _)| at.elapsed() >= Duration::from_secs(SEEN_MESSAGE_SECS))
+                || self.order.len() >= MAX_SEEN_MESSAGES {
+            let (_, old) = self.order.pop_front().unwrap();
+            self.ids.remove(&old);
+        }
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back((Instant::now(), id));
+        true
+    }
+}
+
+// Messages that arrive ahead of a missing one are held until it turns up,
+// or until REORDER_WAIT_SECS pass or MAX_HELD build up and it is presumed lost.
+// A sender can claim any sequence number, so a gap wider than MAX_GAP is
+// given up on straight away, as is any that would hold more than MAX_HELD,
+// and only MAX_REORDERING senders are tracked at once.
+const REORDER_WAIT_SECS: u64 = 10;
+const MAX_HELD: usize = 64;
+const MAX_GAP: u64 = 256;
+const MAX_REORDERING: usize = 1000;
+
+// A sender whose numbers jump past MAX_GAP, or whose last MAX_STALE messages
+// were all numbered below what we expect, is out of step with us, as after
+// either side restores from a backup. We start over from their numbers and
+// tell them ours with a SessionReset.
+const MAX_STALE: usize = 3;
+
+// The messages from one sender in one conversation waiting to be put in order.
+struct Reorder {
+    expected: u64,
+    held: BTreeMap<u64, TextMessage>,
+    since: Instant, // when the oldest held message arrived
+    stale: usize, // messages in a row numbered below expected
+}
+const MAX_SENT: usize = 200;
+const MAX_UNREADABLE: usize = 64;
+const MAX_SENT_BROADCASTS: usize = 64;
+const MAX_NOTES: usize = 100;
+const HISTORY_CONTEXT: usize = 50;
+
+// What each conversation needs to carry on after a restart: our sequence
+// numbers, where each sender's order had got to, and the keys to broadcast
+// lists. It is sealed under the session key, written a moment after it
+// changes, and numbered so an older client's file can be upgraded.
+const SESSIONS_FORMAT: u64 = 2;
+const SESSIONS_SAVE_MILLIS: u64 = 500;
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct Sessions {
+    version: u64,
+    next_seq: Vec<(u64, u64)>, // conversation, our next sequence number
+    expected: Vec<(u64, String, u64)>, // conversation, sender, their next sequence number
+    broadcast_keys: Vec<(String, String, Key)>, // owner, list, key
+    keyed: Vec<(String, String)>, // our list, subscriber given its key
+    deniable: Vec<String>, // partners whose conversations are deniable
+}
+
+// Conversations are kept in one file per partner, named by a hash of their
+// handle under the history key so the names don't say who we talk to. Each
+// message is a line sealed under the key, and messages we unsent are marked
+// by later lines.
+struct History {
+    dir: PathBuf,
+    key: Key,
+}
+
+// Security events such as key changes and messages that were refused are
+// appended to ~/.secmsg/security.log, one line sealed under the security key
+// per event. Each carries the hash of the line before it, so a line taken out
+// or moved shows when the log is read back. Events before the log is opened
+// wait in `pending`, and past MAX_SECURITY_PER_MINUTE, which only a flood of
+// bad messages would reach, the rest of that minute's are left out.
+const MAX_SECURITY_PER_MINUTE: usize = 30;
+
+#[derive(Clone, Copy, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum SecurityKind {
+    KeyChanged, // a contact's key isn't the one we pinned
+    KeyAccepted, // a contact's new key was verified
+    BadSignature, // a message wasn't signed by its sender
+    DecryptFailed, // a message couldn't be read, from the address in the detail
+    Replayed, // a message was refused as too old or from the future
+    OwnKeyReplaced,
+    PassphraseChanged,
+    SessionReset, // a conversation was out of step and started over
+    Suppressed, // too many events in a minute
+}
+
+impl fmt::Display for SecurityKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            SecurityKind::KeyChanged => "key changed",
+            SecurityKind::KeyAccepted => "key accepted",
+            SecurityKind::BadSignature => "bad signature",
+            SecurityKind::DecryptFailed => "decryption failed",
+            SecurityKind::Replayed => "replay refused",
+            SecurityKind::OwnKeyReplaced => "identity key replaced",
+            SecurityKind::PassphraseChanged => "passphrase changed",
+            SecurityKind::SessionReset => "session reset",
+            SecurityKind::Suppressed => "events left out",
+        })
+    }
+}
+
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct SecurityEvent {
+    pub at: u64, // unix time
+    pub kind: SecurityKind,
+    pub detail: String,
+    prev: Key, // hash of the line before, zeros for the first
+}
+
+struct SecurityLog {
+    store: Option<(PathBuf, Key, Key)>, // the file, key and hash of its last line
+    pending: Vec<SecurityEvent>,
+    minute: (Instant, usize), // when this minute started, events in it
+}
+
+// The labels on conversations, by the partner's handle. They are synced as
+// `handle label...` lines.
+pub type Labels = BTreeMap<String, BTreeSet<String>>;
+
+pub fn parse_labels(data: &[u8]) -> Labels {
+    String::from_utf8_lossy(data).lines()
+        .filter_map(|l| {
+            let mut words = l.split_whitespace();
+            words.next().map(|handle| (handle.to_string(), words.map(|w| w.to_string()).collect::<BTreeSet<String>>()))
+        })
+        .filter(|&(_, ref labels)| !labels.is_empty())
+        .collect()
+}
+
+pub fn labels_text(labels: &Labels) -> Vec<u8> {
+    labels.iter()
+        .map(|(handle, l)| format!("{} {}", handle, l.iter().cloned().collect::<Vec<String>>().join(" ")))
+        .collect::<Vec<String>>()
+        .join("\n")
+        .into_bytes()
+}
+
+// Message filters, the version of the server's copy they came from, and
+// where they are kept on disk, sealed under the key.
+struct Rules {
+    rules: Vec<Rule>,
+    version: u64,
+    store: Option<(PathBuf, Key)>,
+}
+
+// A message as kept in the history, with who the conversation was with and
+// when we sent or received it.
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct Logged {
+    pub at: u64, // unix time
+    pub partner: String,
+    pub msg: TextMessage,
+}
+
+impl History {
+    fn path(&self, partner: &str) -> PathBuf {
+        self.dir.join(crypto_lib::hash(&[&self.key, partner.as_bytes()]).to_hex())
+    }
+}
+
+// Something we sent to our own devices, such as a link to open on another.
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct Note {
+    pub id: u64,
+    pub at: u64, // unix time
+    pub text: String,
+}
+
+// A text message on its way out, kept so it can be retried if it fails.
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct Outgoing {
+    pub msg: TextMessage,
+    pub partner: String,
+    pub reliable: bool,
+    pub priority: Priority,
+}
+
+#[derive(Clone)]
 pub struct State {
     conversations: Arc<(Mutex<Conversations>, Condvar)>,
     current_conversation: Arc<Mutex<Option<u64>>>,
     unseen_message_count: Arc<Mutex<u32>>,
     channel: Arc<MpmcQueue<TextMessage>>,
     users: Arc<Mutex<HashMap<String, Route>>>,
+    disjoint_routes: Arc<Mutex<HashMap<String, Vec<Route>>>>,
+    seen_messages: Arc<Mutex<SeenMessages>>,
+    failed: Arc<Mutex<Vec<Outgoing>>>,
+    contacts_version: Arc<Mutex<u64>>,
+    notes: Arc<Mutex<(u64, Vec<Note>)>>, // the server's version, and our notes to self oldest first
+    activity: Arc<Mutex<VecDeque<TextMessage>>>,
+    quarantine: Arc<Mutex<Vec<TextMessage>>>,
+    unacked: Arc<Mutex<HashMap<u64, (Outgoing, Instant, usize)>>>, // message, last sent, resends
+    journal: Arc<Mutex<Option<File>>>, // where changes to unacked are recorded
+    history: Arc<Mutex<Option<History>>>,
+    rules: Arc<Mutex<Rules>>,
+    labels: Arc<Mutex<(u64, Labels)>>, // the server's version, and labels on conversations
+    folders: Arc<Mutex<BTreeMap<String, Vec<TextMessage>>>>, // messages rules moved out of their conversation
+    alerts: Arc<MpmcQueue<(Action, TextMessage)>>, // rule actions the client carries out
+    sent: Arc<Mutex<VecDeque<Outgoing>>>,
+    displayed: Arc<Mutex<HashSet<u64>>>,
+    next_seq: Arc<Mutex<HashMap<u64, u64>>>,
+    reorder: Arc<Mutex<HashMap<(u64, String), Reorder>>>,
+    gaps: Arc<Mutex<Vec<(String, u64)>>>, // given up on as they arrived, not yet reported
+    diverged: Arc<Mutex<Vec<String>>>, // senders found out of step, not yet sent a reset
+    resets: Arc<Mutex<HashMap<String, u64>>>, // when each sender's last SessionReset was made
+    deniable: Arc<Mutex<HashSet<String>>>, // partners we send MACs to instead of signatures
+    mac_keys: Arc<Mutex<HashMap<u64, (String, Key)>>>, // deniable messages not yet acknowledged, partner and MAC key
+    broadcast_keys: Arc<Mutex<HashMap<(String, String), Key>>>, // by owner and list name
+    unreadable: Arc<Mutex<VecDeque<(String, String, Vec<u8>)>>>, // broadcasts we couldn't open yet
+    sent_broadcasts: Arc<Mutex<VecDeque<(u64, String, TextMessage)>>>, // sealed_id, list name, message
+    keyed: Arc<Mutex<HashSet<(String, String)>>>, // our lists and the subscribers given their key
+    sessions: Arc<(Mutex<Option<(PathBuf, Key)>>, AtomicBool)>, // where session state is saved, whether it changed since
+    security: Arc<Mutex<SecurityLog>>,
+    merged: Arc<Mutex<HashMap<u64, u64>>>, // conversations merged into another, and which
+    server_connected: Arc<AtomicBool>, // whether the persistent connection is up
+    transfers: Transfers,
 }
 
 impl State {
@@ -136,27 +430,517 @@ impl State {
             unseen_message_count: Arc::new(Mutex::new(0)),
             channel: Arc::new(MpmcQueue::new()),
             users: Arc::new(Mutex::new(HashMap::new())),
+            disjoint_routes: Arc::new(Mutex::new(HashMap::new())),
+            seen_messages: Arc::new(Mutex::new(SeenMessages::new())),
+            failed: Arc::new(Mutex::new(Vec::new())),
+            contacts_version: Arc::new(Mutex::new(0)),
+            notes: Arc::new(Mutex::new((0, Vec::new()))),
+            activity: Arc::new(Mutex::new(VecDeque::new())),
+            quarantine: Arc::new(Mutex::new(Vec::new())),
+            unacked: Arc::new(Mutex::new(HashMap::new())),
+            journal: Arc::new(Mutex::new(None)),
+            history: Arc::new(Mutex::new(None)),
+            rules: Arc::new(Mutex::new(Rules { rules: Vec::new(), version: 0, store: None })),
+            labels: Arc::new(Mutex::new((0, BTreeMap::new()))),
+            folders: Arc::new(Mutex::new(BTreeMap::new())),
+            alerts: Arc::new(MpmcQueue::new()),
+            sent: Arc::new(Mutex::new(VecDeque::new())),
+            displayed: Arc::new(Mutex::new(HashSet::new())),
+            next_seq: Arc::new(Mutex::new(HashMap::new())),
+            reorder: Arc::new(Mutex::new(HashMap::new())),
+            gaps: Arc::new(Mutex::new(Vec::new())),
+            diverged: Arc::new(Mutex::new(Vec::new())),
+            resets: Arc::new(Mutex::new(HashMap::new())),
+            deniable: Arc::new(Mutex::new(HashSet::new())),
+            mac_keys: Arc::new(Mutex::new(HashMap::new())),
+            broadcast_keys: Arc::new(Mutex::new(HashMap::new())),
+            unreadable: Arc::new(Mutex::new(VecDeque::new())),
+            sent_broadcasts: Arc::new(Mutex::new(VecDeque::new())),
+            keyed: Arc::new(Mutex::new(HashSet::new())),
+            sessions: Arc::new((Mutex::new(None), AtomicBool::new(false))),
+            security: Arc::new(Mutex::new(SecurityLog { store: None, pending: Vec::new(), minute: (Instant::now(), 0) })),
+            merged: Arc::new(Mutex::new(HashMap::new())),
+            server_connected: Arc::new(AtomicBool::new(false)),
+            transfers: Transfers::new(),
         }
     }
 
-    pub fn add_new_message(&self, msg: TextMessage) {
+    // Returns false if the message was a duplicate.
+    pub fn add_new_message(&self, msg: TextMessage) -> bool {
+        // Reliable messages arrive once per route, only keep the first copy.
+        if !self.seen_messages.lock().unwrap().insert(msg.id) {
+            return false;
+        }
+        self.insert_message(msg);
+        true
+    }
+
+    // Adds a message in its sender's order, returning the messages that are
+    // now in order. A message that arrives after its gap was given up on is
+    // added straight away.
+    pub fn add_in_order(&self, msg: TextMessage) -> Vec<TextMessage> {
+        if !self.seen_messages.lock().unwrap().insert(msg.id) {
+            return Vec::new();
+        }
+
+        let mut reorder = self.reorder.lock().unwrap();
+        let key = (msg.conv_id, msg.sender.handle.clone());
+        if !reorder.contains_key(&key) && reorder.len() >= MAX_REORDERING {
+            // Senders holding nothing only need remembering for their next message.
+            reorder.retain(|_, r| !r.held.is_empty());
+        }
+        if !reorder.contains_key(&key) && reorder.len() >= MAX_REORDERING {
+            self.insert_message(msg.clone());
+            return vec![msg];
+        }
+        let r = reorder.entry(key).or_insert(Reorder { expected: msg.seq, held: BTreeMap::new(), since: Instant::now(), stale: 0 });
+        if msg.seq < r.expected {
+            r.stale += 1;
+            if r.stale == MAX_STALE {
+                r.expected = msg.seq + 1;
+                r.stale = 0;
+                self.sessions_changed();
+                self.diverged.lock().unwrap().push(msg.sender.handle.clone());
+            }
+            self.insert_message(msg.clone());
+            return vec![msg];
+        }
+        r.stale = 0;
+
+        if r.held.is_empty() {
+            r.since = Instant::now();
+        }
+        let sender = msg.sender.handle.clone();
+        self.sessions_changed();
+        r.held.insert(msg.seq, msg);
+        let mut released = State::release_in_order(r);
+        while r.held.len() > MAX_HELD || r.held.keys().next_back().map_or(false, |&last| last - r.expected > MAX_GAP) {
+            if r.held.keys().next_back().map_or(false, |&last| last - r.expected > MAX_GAP) {
+                self.diverged.lock().unwrap().push(sender.clone());
+            }
+            let (skipped, more) = State::give_up(r);
+            self.gaps.lock().unwrap().push((sender.clone(), skipped));
+            released.extend(more);
+        }
+        for m in &released {
+            self.insert_message(m.clone());
+        }
+        released
+    }
+
+    // Gives up on the messages that held ones have waited too long for,
+    // returning the sender, how many were skipped and the messages released.
+    pub fn take_gaps(&self) -> Vec<(String, u64, Vec<TextMessage>)> {
+        // Those given up on as they arrived had their messages returned then.
+        let mut gaps: Vec<(String, u64, Vec<TextMessage>)> = self.gaps.lock().unwrap().drain(..)
+            .map(|(sender, skipped)| (sender, skipped, Vec::new()))
+            .collect();
+        for (&(_, ref sender), r) in self.reorder.lock().unwrap().iter_mut() {
+            if r.held.is_empty() {
+                continue;
+            }
+            if r.since.elapsed() < Duration::from_secs(REORDER_WAIT_SECS) && r.held.len() < MAX_HELD {
+                continue;
+            }
+
+            let (skipped, released) = State::give_up(r);
+            for m in &released {
+                self.insert_message(m.clone());
+            }
+            gaps.push((sender.clone(), skipped, released));
+        }
+        gaps
+    }
+
+    // Skips to the first held message, returning how many were skipped and
+    // the messages released.
+    fn give_up(r: &mut Reorder) -> (u64, Vec<TextMessage>) {
+        let first = *r.held.keys().next().unwrap();
+        let skipped = first - r.expected;
+        r.expected = first;
+        r.since = Instant::now();
+        (skipped, State::release_in_order(r))
+    }
+
+    // The senders found out of step since the last call.
+    pub fn take_diverged(&self) -> Vec<String> {
+        let mut diverged: Vec<String> = self.diverged.lock().unwrap().drain(..).collect();
+        diverged.dedup();
+        diverged
+    }
+
+    // Starts over expecting `seq` next from `sender`, as they asked in a
+    // SessionReset made at `at`. Returns the held messages that are now in
+    // order, or None if a reset as new was already applied.
+    pub fn reset_session(&self, conv_id: u64, sender: &str, seq: u64, at: u64) -> Option<Vec<TextMessage>> {
+        {
+            let mut resets = self.resets.lock().unwrap();
+            if resets.get(sender).map_or(false, |&last| last >= at) {
+                return None;
+            }
+            resets.insert(sender.to_string(), at);
+        }
+        self.sessions_changed();
+        let mut reorder = self.reorder.lock().unwrap();
+        let r = reorder.entry((conv_id, sender.to_string()))
+            .or_insert(Reorder { expected: seq, held: BTreeMap::new(), since: Instant::now(), stale: 0 });
+        // Whatever was held from before the reset was sent, so it is shown
+        // rather than dropped.
+        let earlier: Vec<u64> = r.held.keys().cloned().filter(|&s| s < seq).collect();
+        let mut released: Vec<TextMessage> = earlier.iter().filter_map(|s| r.held.remove(s)).collect();
+        r.expected = seq;
+        r.stale = 0;
+        released.extend(State::release_in_order(r));
+        for m in &released {
+            self.insert_message(m.clone());
+        }
+        Some(released)
+    }
+
+    fn release_in_order(r: &mut Reorder) -> Vec<TextMessage> {
+        let mut released = Vec::new();
+        while let Some(m) = r.held.remove(&r.expected) {
+            r.expected += 1;
+            released.push(m);
+        }
+        released
+    }
+
+    pub fn broadcast_key(&self, owner: &str, name: &str) -> Option<Key> {
+        self.broadcast_keys.lock().unwrap().get(&(owner.to_string(), name.to_string())).cloned()
+    }
+
+    // Returns the broadcasts held for want of this key.
+    pub fn set_broadcast_key(&self, owner: &str, name: &str, key: Key) -> Vec<Vec<u8>> {
+        self.sessions_changed();
+        self.broadcast_keys.lock().unwrap().insert((owner.to_string(), name.to_string()), key);
+        let mut unreadable = self.unreadable.lock().unwrap();
+        let (ready, waiting) = unreadable.drain(..).partition(|&(ref o, ref n, _)| o == owner && n == name);
+        *unreadable = waiting;
+        ready.into_iter().map(|(_, _, sealed)| sealed).collect()
+    }
+
+    // Returns true if it is the first held from this list, so the owner
+    // only needs asking for its key once.
+    pub fn hold_broadcast(&self, owner: String, name: String, sealed: Vec<u8>) -> bool {
+        let mut unreadable = self.unreadable.lock().unwrap();
+        let first = !unreadable.iter().any(|&(ref o, ref n, _)| *o == owner && *n == name);
+        if unreadable.len() == MAX_UNREADABLE {
+            unreadable.pop_front();
+        }
+        unreadable.push_back((owner, name, sealed));
+        first
+    }
+
+    // Returns false if `subscriber` was already given the key to our list.
+    pub fn mark_keyed(&self, name: &str, subscriber: &str) -> bool {
+        self.sessions_changed();
+        self.keyed.lock().unwrap().insert((name.to_string(), subscriber.to_string()))
+    }
+
+    pub fn is_keyed(&self, name: &str, subscriber: &str) -> bool {
+        self.keyed.lock().unwrap().contains(&(name.to_string(), subscriber.to_string()))
+    }
+
+    // Our recent broadcasts, kept so one a subscriber couldn't open can be
+    // sealed again under the list's current key.
+    pub fn add_sent_broadcast(&self, id: u64, name: &str, msg: TextMessage) {
+        let mut sent = self.sent_broadcasts.lock().unwrap();
+        if sent.len() == MAX_SENT_BROADCASTS {
+            sent.pop_front();
+        }
+        sent.push_back((id, name.to_string(), msg));
+    }
+
+    pub fn sent_broadcast(&self, id: u64) -> Option<(String, TextMessage)> {
+        self.sent_broadcasts.lock().unwrap().iter()
+            .find(|&&(i, _, _)| i == id)
+            .map(|&(_, ref name, ref msg)| (name.clone(), msg.clone()))
+    }
+
+    // Whether messages to `partner` are authenticated with MACs either of us
+    // could have made, rather than signed.
+    pub fn is_deniable(&self, partner: &str) -> bool {
+        self.deniable.lock().unwrap().contains(partner)
+    }
+
+    pub fn set_deniable(&self, partner: &str, on: bool) {
+        self.sessions_changed();
+        let mut deniable = self.deniable.lock().unwrap();
+        if on {
+            deniable.insert(partner.to_string());
+        } else {
+            deniable.remove(partner);
+        }
+    }
+
+    // Keeps the MAC key of a deniable message until it is acknowledged.
+    pub fn add_mac_key(&self, id: u64, partner: &str, key: Key) {
+        self.mac_keys.lock().unwrap().insert(id, (partner.to_string(), key));
+    }
+
+    // The partner and MAC key of a deniable message that has arrived, to be
+    // published.
+    pub fn take_mac_key(&self, id: u64) -> Option<(String, Key)> {
+        self.mac_keys.lock().unwrap().remove(&id)
+    }
+
+    // The sequence number our next message in a conversation will have.
+    pub fn peek_seq(&self, conv_id: u64) -> u64 {
+        self.next_seq.lock().unwrap().get(&conv_id).cloned().unwrap_or(0)
+    }
+
+    // The sequence number for our next message in a conversation.
+    pub fn next_seq(&self, conv_id: u64) -> u64 {
+        self.sessions_changed();
+        let mut next = self.next_seq.lock().unwrap();
+        let seq = next.entry(conv_id).or_insert(0);
+        *seq += 1;
+        *seq - 1
+    }
+
+    // Restores the session state saved in `path`, and keeps it saved there
+    // from now on. A file that fails to open or decode is set aside rather
+    // than trusted, so every conversation starts its order afresh, as if it
+    // were new, and the error says so.
+    pub fn open_sessions(&self, path: &Path, key: Key) -> Result<(), String> {
+        let loaded = match File::open(path) {
+            Ok(mut file) => {
+                let mut sealed = Vec::new();
+                try!(file.read_to_end(&mut sealed).map_err(|e| e.to_string()));
+                Some(crypto_lib::open_with_key(&key, &sealed).ok()
+                    .and_then(|t| String::from_utf8(t).ok())
+                    .ok_or("it is corrupt".to_string())
+                    .and_then(|t| json::Json::from_str(&t).map_err(|_| "it is corrupt".to_string()))
+                    .and_then(State::migrate_sessions)
+                    .and_then(|j| json::decode::<Sessions>(&j.to_string()).map_err(|_| "it is corrupt".to_string())))
+            },
+            Err(_) => None,
+        };
+
+        *self.sessions.0.lock().unwrap() = Some((path.to_path_buf(), key));
+        let state = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(SESSIONS_SAVE_MILLIS));
+            if state.sessions.1.swap(false, Ordering::SeqCst) {
+                if let Err(e) = state.save_sessions() {
+                    IOHandler::quiet().print_error(&format!("Could not save the session state: {}", e));
+                }
+            }
+        });
+
+        match loaded {
+            None => Ok(()),
+            Some(Ok(saved)) => {
+                self.next_seq.lock().unwrap().extend(saved.next_seq);
+                let mut reorder = self.reorder.lock().unwrap();
+                for (conv_id, sender, expected) in saved.expected {
+                    reorder.insert((conv_id, sender), Reorder { expected: expected, held: BTreeMap::new(), since: Instant::now(), stale: 0 });
+                }
+                let mut keys = self.broadcast_keys.lock().unwrap();
+                for (owner, name, key) in saved.broadcast_keys {
+                    keys.insert((owner, name), key);
+                }
+                self.keyed.lock().unwrap().extend(saved.keyed);
+                self.deniable.lock().unwrap().extend(saved.deniable);
+                Ok(())
+            },
+            Some(Err(e)) => {
+                let _ = fs::rename(path, path.with_extension("unreadable"));
+                Err(format!("The saved session state was set aside because {}, so conversations start over", e))
+            },
+        }
+    }
+
+    // Starts writing security events to `path`, including those that came
+    // before it was opened.
+    pub fn open_security_log(&self, path: &Path, key: Key) -> Result<(), String> {
+        let mut contents = String::new();
+        if let Ok(mut file) = File::open(path) {
+            try!(file.read_to_string(&mut contents).map_err(|e| e.to_string()));
+        }
+        let last = contents.lines().last().map_or([0u8; 32], |l| crypto_lib::hash(&[l.as_bytes()]));
+
+        let mut log = self.security.lock().unwrap();
+        log.store = Some((path.to_path_buf(), key, last));
+        for event in log.pending.split_off(0) {
+            try!(State::append_security(&mut log, event));
+        }
+        Ok(())
+    }
+
+    pub fn log_security(&self, kind: SecurityKind, detail: &str) {
+        let mut log = self.security.lock().unwrap();
+        if log.minute.0.elapsed() >= Duration::from_secs(60) {
+            log.minute = (Instant::now(), 0);
+        }
+        log.minute.1 += 1;
+        let (kind, detail) = match log.minute.1 {
+            n if n <= MAX_SECURITY_PER_MINUTE => (kind, detail.to_string()),
+            n if n == MAX_SECURITY_PER_MINUTE + 1 => (SecurityKind::Suppressed, "Too many events this minute".to_string()),
+            _ => return,
+        };
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let event = SecurityEvent { at: at, kind: kind, detail: detail, prev: [0u8; 32] };
+        if log.store.is_none() {
+            log.pending.push(event);
+        } else if let Err(e) = State::append_security(&mut log, event) {
+            IOHandler::quiet().print_error(&format!("Could not write to the security log: {}", e));
+        }
+    }
+
+    fn append_security(log: &mut SecurityLog, mut event: SecurityEvent) -> Result<(), String> {
+        let &mut (ref path, ref key, ref mut last) = log.store.as_mut().unwrap();
+        event.prev = *last;
+        let sealed = try!(crypto_lib::seal_with_key(key, json::encode(&event).unwrap().as_bytes())
+            .map_err(|_| "Failed to encrypt the event".to_string()));
+        let line = sealed.to_hex();
+        let mut file = try!(OpenOptions::new().create(true).append(true).open(path).map_err(|e| e.to_string()));
+        try!(writeln!(file, "{}", line).map_err(|e| e.to_string()));
+        *last = crypto_lib::hash(&[line.as_bytes()]);
+        Ok(())
+    }
+
+    // The security log oldest first, and the number of the first entry that
+    // doesn't follow on from the one before, if any do not.
+    pub fn security_log(&self) -> Result<(Vec<SecurityEvent>, Option<usize>), String> {
+        let log = self.security.lock().unwrap();
+        let &(ref path, ref key, _) = try!(log.store.as_ref().ok_or("The security log isn't open".to_string()));
+        let mut contents = String::new();
+        if let Ok(mut file) = File::open(path) {
+            try!(file.read_to_string(&mut contents).map_err(|e| e.to_string()));
+        }
+
+        let (mut events, mut broken, mut prev) = (Vec::new(), None, [0u8; 32]);
+        for line in contents.lines() {
+            let event = line.from_hex().ok()
+                .and_then(|s| crypto_lib::open_with_key(key, &s).ok())
+                .and_then(|e| String::from_utf8(e).ok())
+                .and_then(|e| json::decode::<SecurityEvent>(&e).ok());
+            match event {
+                Some(event) => {
+                    if event.prev != prev && broken.is_none() {
+                        broken = Some(events.len());
+                    }
+                    events.push(event);
+                },
+                None => if broken.is_none() {
+                    broken = Some(events.len());
+                },
+            }
+            prev = crypto_lib::hash(&[line.as_bytes()]);
+        }
+        Ok((events, broken))
+    }
+
+    // Brings session state saved by an older client up to SESSIONS_FORMAT.
+    // Each change to the format adds a step from the one before it here.
+    fn migrate_sessions(saved: json::Json) -> Result<json::Json, String> {
+        match saved.find("version").and_then(|v| v.as_u64()) {
+            Some(SESSIONS_FORMAT) => Ok(saved),
+            // 2 added deniable conversations.
+            Some(1) => {
+                let mut saved = saved;
+                if let json::Json::Object(ref mut fields) = saved {
+                    fields.insert("deniable".to_string(), json::Json::Array(Vec::new()));
+                    fields.insert("version".to_string(), json::Json::U64(2));
+                }
+                State::migrate_sessions(saved)
+            },
+            Some(v) if v > SESSIONS_FORMAT => Err("it was saved by a newer version of secmsg".to_string()),
+            _ => Err("it is in a format this version can't read".to_string()),
+        }
+    }
+
+    fn sessions_changed(&self) {
+        self.sessions.1.store(true, Ordering::SeqCst);
+    }
+
+    fn save_sessions(&self) -> Result<(), String> {
+        let store = self.sessions.0.lock().unwrap().clone();
+        let (path, key) = match store {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        let saved = Sessions {
+            version: SESSIONS_FORMAT,
+            next_seq: self.next_seq.lock().unwrap().iter().map(|(&c, &s)| (c, s)).collect(),
+            expected: self.reorder.lock().unwrap().iter().map(|(&(c, ref h), r)| (c, h.clone(), r.expected)).collect(),
+            broadcast_keys: self.broadcast_keys.lock().unwrap().iter().map(|(&(ref o, ref n), k)| (o.clone(), n.clone(), *k)).collect(),
+            keyed: self.keyed.lock().unwrap().iter().cloned().collect(),
+            deniable: self.deniable.lock().unwrap().iter().cloned().collect(),
+        };
+        let sealed = try!(crypto_lib::seal_with_key(&key, json::encode(&saved).unwrap().as_bytes())
+            .map_err(|_| "Failed to seal the session state".to_string()));
+        let tmp = path.with_extension("tmp");
+        try!(File::create(&tmp).and_then(|mut f| f.write_all(&sealed).and_then(|_| f.sync_all()))
+            .map_err(|e| e.to_string()));
+        fs::rename(&tmp, &path).map_err(|e| e.to_string())
+    }
+
+    fn insert_message(&self, msg: TextMessage) {
+        let actions: Vec<Action> = self.rules.lock().unwrap().rules.iter()
+            .filter(|r| r.matches(&msg))
+            .map(|r| r.action.clone())
+            .collect();
+        for action in actions.iter() {
+            match *action {
+                Action::Loud | Action::Hook(_) => self.alerts.push((action.clone(), msg.clone())),
+                _ => {},
+            }
+        }
+        let folder = actions.iter().filter_map(|a| match *a {
+            Action::Folder(ref name) => Some(name.clone()),
+            _ => None,
+        }).next();
+        if let Some(folder) = folder {
+            self.append_history(&msg.sender.handle, &msg);
+            self.folders.lock().unwrap().entry(folder).or_insert_with(Vec::new).push(msg);
+            return;
+        }
+        let archive = actions.contains(&Action::Archive);
+
+        let &(ref mutex, ref cvar) = &*self.conversations;
+        let conv_id = mutex.lock().and_then(|mut convs| {
+            // Messages for a merged conversation, or from a merged identity
+            // starting a new one, go to the conversation it was merged into.
+            let conv_id = self.merged.lock().unwrap().get(&msg.conv_id).cloned()
+                .or_else(|| if convs.contains_key(&msg.conv_id) { None } else {
+                    convs.values().find(|c| c.is_alias(&msg.sender)).map(|c| c.get_id())
+                })
+                .unwrap_or(msg.conv_id);
+            let conv = convs.entry(conv_id)
+                .or_insert_with(|| self.with_context(Conversation::from_id(msg.sender.clone(), conv_id)));
+            conv.messages.push(msg.clone());
+            if !archive {
+                conv.inc_new_msg_count();
+            }
+            self.append_history(&conv.get_partner().handle, &msg);
+            Ok(conv_id)
+        }).unwrap();
+        if archive {
+            return;
+        }
+
         self.current_conversation.lock().unwrap().map_or_else(
             || *self.unseen_message_count.lock().unwrap() += 1,
             |curr|
-                if curr == msg.conv_id { self.channel.push(msg.clone()); } 
+                if curr == conv_id { self.channel.push(msg.clone()); }
                 else { *self.unseen_message_count.lock().unwrap() += 1; }
         );
 
-        let &(ref mutex, ref cvar) = &*self.conversations;
-        mutex.lock().and_then(|mut convs| {
-            let conv = convs.entry(msg.conv_id)
-                .or_insert(Conversation::from_id(msg.sender.clone(), msg.conv_id));
-            conv.messages.push(msg.clone());
-            conv.inc_new_msg_count();
-            Ok(())
-        }).unwrap();
-
         cvar.notify_one();
+
+        let mut activity = self.activity.lock().unwrap();
+        if activity.len() == MAX_ACTIVITY {
+            activity.pop_front();
+        }
+        activity.push_back(msg);
+    }
+
+    // The most recent messages from every conversation, oldest first.
+    pub fn get_activity(&self) -> Vec<TextMessage> {
+        self.activity.lock().unwrap().iter().cloned().collect()
     }
 
     pub fn get_new_messages(&self) -> NewMessagesIter {
@@ -172,9 +956,145 @@ impl State {
     }
 
     pub fn add_conversation(&self, conv: Conversation) {
+        let conv = self.with_context(conv);
         self.conversations.0.lock().unwrap().insert(conv.get_id(), conv);
     }
 
+    // Starts a conversation with the end of the last one we had with the
+    // partner, which was shown and read back then.
+    fn with_context(&self, mut conv: Conversation) -> Conversation {
+        let mut context = self.recent_history(&conv.get_partner().handle);
+        let mut seen = self.seen_messages.lock().unwrap();
+        let mut displayed = self.displayed.lock().unwrap();
+        for msg in context.iter() {
+            seen.insert(msg.id);
+            displayed.insert(msg.id);
+        }
+        context.append(&mut conv.messages);
+        conv.messages = context;
+        conv
+    }
+
+    // Starts keeping history in `dir`. Until it is called nothing is kept.
+    pub fn open_history(&self, dir: &Path, key: Key) -> Result<(), String> {
+        try!(fs::create_dir_all(dir).map_err(|e| e.to_string()));
+        *self.history.lock().unwrap() = Some(History { dir: dir.to_path_buf(), key: key });
+        Ok(())
+    }
+
+    pub fn append_history(&self, partner: &str, msg: &TextMessage) {
+        if let Some(ref history) = *self.history.lock().unwrap() {
+            let at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            State::append_logged(history, &Logged { at: at, partner: partner.to_string(), msg: msg.clone() });
+        }
+    }
+
+    fn append_logged(history: &History, entry: &Logged) {
+        if let Ok(sealed) = crypto_lib::seal_with_key(&history.key, json::encode(entry).unwrap().as_bytes()) {
+            State::append_history_line(history, &entry.partner, &format!("msg {}", sealed.to_hex()));
+        }
+    }
+
+    // Marks a message we took back, so it isn't shown again.
+    pub fn unsend_history(&self, partner: &str, id: u64) {
+        if let Some(ref history) = *self.history.lock().unwrap() {
+            State::append_history_line(history, partner, &format!("unsent {}", id));
+        }
+    }
+
+    fn append_history_line(history: &History, partner: &str, line: &str) {
+        if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(history.path(partner)) {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    // The last HISTORY_CONTEXT messages with `partner`, oldest first.
+    fn recent_history(&self, partner: &str) -> Vec<TextMessage> {
+        match *self.history.lock().unwrap() {
+            Some(ref history) => State::read_history(history, &history.path(partner), Some(HISTORY_CONTEXT))
+                .into_iter().map(|e| e.msg).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Every message in the history whose text contains `term`, ignoring case,
+    // oldest first.
+    pub fn search_history(&self, term: &str) -> Vec<Logged> {
+        let term = term.to_lowercase();
+        self.export_history(None).unwrap_or_default().into_iter()
+            .filter(|e| e.msg.text.to_lowercase().contains(&term))
+            .collect()
+    }
+
+    // The whole history with `partner`, or with everyone, oldest first.
+    pub fn export_history(&self, partner: Option<&str>) -> Result<Vec<Logged>, String> {
+        let history = self.history.lock().unwrap();
+        let history = try!(history.as_ref().ok_or("History is turned off".to_string()));
+        let mut entries = match partner {
+            Some(partner) => State::read_history(history, &history.path(partner), None),
+            None => fs::read_dir(&history.dir).into_iter()
+                .flat_map(|entries| entries.filter_map(|e| e.ok()))
+                .flat_map(|e| State::read_history(history, &e.path(), None))
+                .collect(),
+        };
+        entries.sort_by_key(|e| e.at);
+        Ok(entries)
+    }
+
+    // Adds exported messages to the history, returning how many we didn't
+    // already have.
+    pub fn import_history(&self, entries: Vec<Logged>) -> Result<usize, String> {
+        let history = self.history.lock().unwrap();
+        let history = try!(history.as_ref().ok_or("History is turned off".to_string()));
+        let mut known: HashMap<String, HashSet<u64>> = HashMap::new();
+        let mut added = 0;
+        for entry in entries {
+            let ids = known.entry(entry.partner.clone()).or_insert_with(|| {
+                State::read_history(history, &history.path(&entry.partner), None).iter().map(|e| e.msg.id).collect()
+            });
+            if ids.insert(entry.msg.id) {
+                State::append_logged(history, &entry);
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    // Reads back a history file, decrypting up to `limit` of the newest
+    // entries. A line cut short by a crash is skipped.
+    fn read_history(history: &History, path: &Path, limit: Option<usize>) -> Vec<Logged> {
+        let mut contents = String::new();
+        if let Ok(mut file) = File::open(path) {
+            let _ = file.read_to_string(&mut contents);
+        }
+
+        let mut unsent = HashSet::new();
+        let mut entries = Vec::new();
+        for line in contents.lines().rev() {
+            if limit == Some(entries.len()) {
+                break;
+            }
+            let mut parts = line.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some("unsent"), Some(id)) => if let Ok(id) = id.parse::<u64>() {
+                    unsent.insert(id);
+                },
+                (Some("msg"), Some(sealed)) => {
+                    let entry = sealed.from_hex().ok()
+                        .and_then(|s| crypto_lib::open_with_key(&history.key, &s).ok())
+                        .and_then(|e| String::from_utf8(e).ok())
+                        .and_then(|e| json::decode::<Logged>(&e).ok());
+                    if let Some(entry) = entry.filter(|e| !unsent.contains(&e.msg.id)) {
+                        entries.push(entry);
+                    }
+                },
+                _ => {},
+            }
+        }
+        entries.reverse();
+        entries
+    }
+
     pub fn get_message_history(&self) -> Option<Vec<TextMessage>> {
         self.current_conversation.lock().unwrap()
             .and_then(|curr| {
@@ -199,18 +1119,211 @@ impl State {
                 .ok_or("Conversation does not exist."))
     }
 
-    pub fn list_conversations(&self) -> Vec<String> {
+    // Lists every conversation, or only those labelled `label`.
+    pub fn list_conversations(&self, label: Option<&str>) -> Vec<String> {
+        let labels = &self.labels.lock().unwrap().1;
+        let none = BTreeSet::new();
         self.conversations.0.lock().unwrap().values()
-            .map(|c| format!("{} [{}]: {}", 
+            .map(|c| (c, labels.get(&c.get_partner().handle).unwrap_or(&none)))
+            .filter(|&(_, l)| label.map_or(true, |label| l.contains(label)))
+            .map(|(c, l)| format!("{} [{}]: {}{}{}", 
                              c.get_priv_id(), 
                              c.new_message_count(), 
-                             c.get_partner().handle))
+                             c.get_partner().handle,
+                             c.get_aliases().iter().map(|a| format!(", also {}", a.handle)).collect::<String>(),
+                             if l.is_empty() { String::new() } else {
+                                 format!(" ({})", l.iter().cloned().collect::<Vec<String>>().join(", "))
+                             }))
+            .collect()
+    }
+
+    pub fn labels(&self) -> Labels {
+        self.labels.lock().unwrap().1.clone()
+    }
+
+    // Replaces the labels with those the server has at `version`, returning
+    // false if they were the ones we had.
+    pub fn set_labels(&self, version: u64, new: Labels) -> bool {
+        let mut labels = self.labels.lock().unwrap();
+        if labels.0 == version && labels.1 == new {
+            return false;
+        }
+        *labels = (version, new);
+        true
+    }
+
+    // Merges the conversation with `other` into the one with `user`, for a
+    // person who moved to a new identity. Both histories are kept, `other`'s
+    // first, and later messages to either end up in the merged conversation.
+    pub fn merge_conversations(&self, user: &str, other: &str) -> Result<u64, &'static str> {
+        let keep = try!(self.conv_name_to_id(user).ok_or("No conversation with that user."));
+        let absorb = try!(self.conv_name_to_id(other).ok_or("No conversation with that user."));
+        if keep == absorb {
+            return Err("Those conversations are already merged.");
+        }
+
+        let mut convs = self.conversations.0.lock().unwrap();
+        let absorbed = convs.remove(&absorb).unwrap();
+        let conv = convs.get_mut(&keep).unwrap();
+        let mut messages = absorbed.messages;
+        messages.extend(conv.messages.drain(..));
+        conv.messages = messages;
+        conv.new_message_count += absorbed.new_message_count;
+        conv.aliases.push(absorbed.partner);
+        conv.aliases.extend(absorbed.aliases);
+
+        let mut merged = self.merged.lock().unwrap();
+        for into in merged.values_mut().filter(|into| **into == absorb) {
+            *into = keep;
+        }
+        merged.insert(absorb, keep);
+
+        let mut current = self.current_conversation.lock().unwrap();
+        if *current == Some(absorb) {
+            *current = Some(keep);
+        }
+        Ok(keep)
+    }
+
+    // The handles of everyone we have a conversation with.
+    pub fn partners(&self) -> Vec<String> {
+        self.conversations.0.lock().unwrap().values()
+            .map(|c| c.get_partner().handle.clone())
             .collect()
     }
 
+    // The version of the server's copy of the contact list we last saw.
+    pub fn contacts_version(&self) -> u64 {
+        *self.contacts_version.lock().unwrap()
+    }
+
+    pub fn set_contacts_version(&self, version: u64) {
+        *self.contacts_version.lock().unwrap() = version;
+    }
+
+    pub fn notes(&self) -> Vec<Note> {
+        self.notes.lock().unwrap().1.clone()
+    }
+
+    pub fn set_notes_version(&self, version: u64) {
+        self.notes.lock().unwrap().0 = version;
+    }
+
+    // Merges in the server's copy of our notes to self, returning the ones we
+    // hadn't seen. Only the newest MAX_NOTES are kept.
+    pub fn merge_notes(&self, version: u64, data: &[u8]) -> Result<Vec<Note>, String> {
+        let theirs: Vec<Note> = if data.is_empty() {
+            Vec::new()
+        } else {
+            let text = try!(String::from_utf8(data.to_vec()).map_err(|_| "Notes to self are corrupt".to_string()));
+            try!(json::decode(&text).map_err(|_| "Notes to self are corrupt".to_string()))
+        };
+        let mut notes = self.notes.lock().unwrap();
+        notes.0 = version;
+        let new: Vec<Note> = theirs.into_iter().filter(|n| !notes.1.iter().any(|m| m.id == n.id)).collect();
+        for note in new.iter() {
+            Self::insert_note(&mut notes.1, note.clone());
+        }
+        Ok(new)
+    }
+
+    pub fn add_note(&self, note: Note) {
+        let mut notes = self.notes.lock().unwrap();
+        if !notes.1.iter().any(|n| n.id == note.id) {
+            Self::insert_note(&mut notes.1, note);
+        }
+    }
+
+    fn insert_note(notes: &mut Vec<Note>, note: Note) {
+        let at = notes.iter().position(|n| n.at > note.at).unwrap_or(notes.len());
+        notes.insert(at, note);
+        if notes.len() > MAX_NOTES {
+            let excess = notes.len() - MAX_NOTES;
+            notes.drain(..excess);
+        }
+    }
+
+    // What is uploaded for our other devices, before it is encrypted.
+    pub fn encode_notes(&self) -> Vec<u8> {
+        json::encode(&self.notes.lock().unwrap().1).unwrap().into_bytes()
+    }
+
+    pub fn rules(&self) -> Vec<Rule> {
+        self.rules.lock().unwrap().rules.clone()
+    }
+
+    pub fn rules_version(&self) -> u64 {
+        self.rules.lock().unwrap().version
+    }
+
+    // Loads the rules kept at `path`, and keeps any changes to them there.
+    pub fn open_rules(&self, path: &Path, key: Key) -> Result<(), String> {
+        let mut rules = self.rules.lock().unwrap();
+        if let Ok(mut file) = File::open(path) {
+            let mut sealed = Vec::new();
+            try!(file.read_to_end(&mut sealed).map_err(|e| e.to_string()));
+            let text = try!(crypto_lib::open_with_key(&key, &sealed).ok()
+                .and_then(|t| String::from_utf8(t).ok())
+                .ok_or("The saved rules are corrupt".to_string()));
+            let mut lines = text.splitn(2, '\n');
+            rules.version = lines.next().and_then(|v| v.parse().ok()).unwrap_or(0);
+            rules.rules = filters::parse_all(lines.next().unwrap_or(""));
+        }
+        rules.store = Some((path.to_path_buf(), key));
+        Ok(())
+    }
+
+    // Replaces the rules with those the server has at `version`. Returns
+    // false if they were the ones we had.
+    pub fn set_rules(&self, version: u64, new: Vec<Rule>) -> Result<bool, String> {
+        let mut rules = self.rules.lock().unwrap();
+        if rules.version == version && rules.rules == new {
+            return Ok(false);
+        }
+        rules.version = version;
+        rules.rules = new;
+        if let Some((ref path, ref key)) = rules.store {
+            let text = format!("{}\n{}", rules.version, filters::to_text(&rules.rules));
+            let sealed = try!(crypto_lib::seal_with_key(key, text.as_bytes()).map_err(|_| "Failed to seal the rules".to_string()));
+            let tmp = path.with_extension("tmp");
+            try!(File::create(&tmp).and_then(|mut f| f.write_all(&sealed).and_then(|_| f.sync_all()))
+                .map_err(|e| e.to_string()));
+            try!(fs::rename(&tmp, path).map_err(|e| e.to_string()));
+        }
+        Ok(true)
+    }
+
+    // Blocks until a rule asks for a message to be shown loudly or passed to
+    // a hook.
+    pub fn get_alert(&self) -> (Action, TextMessage) {
+        self.alerts.pop()
+    }
+
+    pub fn folders(&self) -> Vec<(String, usize)> {
+        self.folders.lock().unwrap().iter().map(|(name, msgs)| (name.clone(), msgs.len())).collect()
+    }
+
+    pub fn folder(&self, name: &str) -> Option<Vec<TextMessage>> {
+        self.folders.lock().unwrap().get(name).cloned()
+    }
+
+    // Returns true if this changes whether we are connected.
+    pub fn set_server_connected(&self, up: bool) -> bool {
+        self.server_connected.swap(up, Ordering::SeqCst) != up
+    }
+
+    pub fn is_server_connected(&self) -> bool {
+        self.server_connected.load(Ordering::SeqCst)
+    }
+
+    pub fn transfers(&self) -> &Transfers {
+        &self.transfers
+    }
+
     pub fn conv_name_to_id(&self, name: &str) -> Option<u64> {
         self.conversations.0.lock().unwrap().values()
-            .find(|&c| c.get_partner().handle.trim() == name.trim())
+            .find(|&c| c.get_partner().handle.trim() == name.trim()
+                || c.get_aliases().iter().any(|a| a.handle.trim() == name.trim()))
             .and_then(|c| Some(c.get_id()))
     }
 
@@ -220,5 +1333,147 @@ impl State {
             Entry::Vacant(v) => net.get_route(&user).map(|ui| v.insert(ui).clone())
         }
     }
+
+    pub fn get_disjoint_routes(&self, user: &str, net: &Net) -> Result<Vec<Route>, String> {
+        match self.disjoint_routes.lock().unwrap().entry(user.to_string()) {
+            Entry::Occupied(o) => Ok(o.get().clone()),
+            Entry::Vacant(v) => net.get_disjoint_routes(&user, 2).map(|r| v.insert(r).clone())
+        }
+    }
+
+    // Drops the cached routes to `user` so the next message asks for new ones.
+    pub fn forget_routes(&self, user: &str) {
+        self.users.lock().unwrap().remove(user);
+        self.disjoint_routes.lock().unwrap().remove(user);
+    }
+
+    // Holds a message sent under a key we haven't verified. Returns true if it
+    // is the first held from its sender.
+    pub fn quarantine(&self, msg: TextMessage) -> bool {
+        let mut held = self.quarantine.lock().unwrap();
+        let first = !held.iter().any(|m| m.sender.handle == msg.sender.handle);
+        held.push(msg);
+        first
+    }
+
+    // Takes the held messages from `handle` that were sent under `key`.
+    pub fn release(&self, handle: &str, key: &Key) -> Vec<TextMessage> {
+        let mut held = self.quarantine.lock().unwrap();
+        let (released, kept) = held.drain(..)
+            .partition(|m| m.sender.handle == handle && m.sender.public_key == *key);
+        *held = kept;
+        released
+    }
+
+    // Remembers our latest messages so read receipts can say what was read.
+    pub fn add_sent(&self, out: Outgoing) {
+        let mut sent = self.sent.lock().unwrap();
+        if sent.len() == MAX_SENT {
+            sent.pop_front();
+        }
+        sent.push_back(out);
+    }
+
+    pub fn take_sent(&self, id: u64) -> Option<Outgoing> {
+        let mut sent = self.sent.lock().unwrap();
+        sent.iter().position(|o| o.msg.id == id).and_then(|i| sent.remove(i))
+    }
+
+    // Returns false if the message has been shown to the user before.
+    pub fn mark_displayed(&self, id: u64) -> bool {
+        self.displayed.lock().unwrap().insert(id)
+    }
+
+    // Tracks a sent message until the recipient acknowledges it. Call it
+    // before sending, so the journal has the message if we stop mid-send.
+    pub fn add_unacked(&self, out: Outgoing) {
+        self.record(&format!("queued {}", json::encode(&out).unwrap()));
+        self.unacked.lock().unwrap().insert(out.msg.id, (out, Instant::now(), 0));
+    }
+
+    // Stops tracking a message, because it was acknowledged or given up on.
+    pub fn take_unacked(&self, id: u64) -> Option<Outgoing> {
+        let out = self.unacked.lock().unwrap().remove(&id).map(|(out, _, _)| out);
+        if out.is_some() {
+            self.record(&format!("done {}", id));
+        }
+        out
+    }
+
+    // Starts journaling unacknowledged messages to `path`, returning those
+    // the journal already had, which were never acknowledged before we last
+    // stopped. They are tracked again, and the journal is compacted to them.
+    pub fn open_journal(&self, path: &Path) -> Result<Vec<Outgoing>, String> {
+        let mut contents = String::new();
+        if let Ok(mut file) = File::open(path) {
+            let _ = file.read_to_string(&mut contents);
+        }
+
+        // A line cut short by a crash fails to decode and is skipped.
+        let mut pending: Vec<Outgoing> = Vec::new();
+        for line in contents.lines() {
+            let mut parts = line.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some("queued"), Some(out)) => if let Ok(out) = json::decode::<Outgoing>(out) {
+                    pending.retain(|p| p.msg.id != out.msg.id);
+                    pending.push(out);
+                },
+                (Some("done"), Some(id)) => if let Ok(id) = id.parse::<u64>() {
+                    pending.retain(|p| p.msg.id != id);
+                },
+                _ => {},
+            }
+        }
+
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = try!(File::create(&tmp).map_err(|e| e.to_string()));
+            for out in pending.iter() {
+                try!(writeln!(file, "queued {}", json::encode(out).unwrap()).map_err(|e| e.to_string()));
+            }
+            try!(file.sync_all().map_err(|e| e.to_string()));
+        }
+        try!(fs::rename(&tmp, path).map_err(|e| e.to_string()));
+
+        let file = try!(OpenOptions::new().append(true).open(path).map_err(|e| e.to_string()));
+        *self.journal.lock().unwrap() = Some(file);
+        let mut unacked = self.unacked.lock().unwrap();
+        for out in pending.iter() {
+            unacked.insert(out.msg.id, (out.clone(), Instant::now(), 0));
+        }
+        Ok(pending)
+    }
+
+    fn record(&self, line: &str) {
+        if let Some(ref mut file) = *self.journal.lock().unwrap() {
+            let _ = writeln!(file, "{}", line).and_then(|_| file.sync_data());
+        }
+    }
+
+    pub fn unacked(&self) -> Vec<Outgoing> {
+        self.unacked.lock().unwrap().values().map(|&(ref out, _, _)| out.clone()).collect()
+    }
+
+    // The messages unacknowledged for longer than `timeout`, with how many
+    // times each has been resent. They are counted as resent now.
+    pub fn overdue(&self, timeout: Duration) -> Vec<(Outgoing, usize)> {
+        self.unacked.lock().unwrap().values_mut()
+            .filter(|&&mut (_, sent, _)| sent.elapsed() > timeout)
+            .map(|&mut (ref out, ref mut sent, ref mut resends)| {
+                *sent = Instant::now();
+                *resends += 1;
+                (out.clone(), *resends - 1)
+            })
+            .collect()
+    }
+
+    pub fn add_failed(&self, out: Outgoing) {
+        self.failed.lock().unwrap().push(out);
+    }
+
+    // Returns the most recent message that could not be delivered.
+    pub fn take_failed(&self) -> Option<Outgoing> {
+        self.failed.lock().unwrap().pop()
+    }
 }
 
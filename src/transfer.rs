@@ -0,0 +1,785 @@
+#![allow(dead_code)]
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand;
+use rustc_serialize::json;
+
+use config_lib::Config;
+use crypto_lib::{self, Key};
+use messages::{Attachment, FileOffer, Message, MessageContainer, MessageType, Priority, ToUser};
+use net_lib::Net;
+
+pub const DEFAULT_CHUNK_BYTES: u64 = 8 * 1024;
+// Thumbnails travel inside the offer, so they are kept small.
+pub const MAX_THUMBNAIL_BYTES: usize = 16 * 1024;
+// A window of chunks is buffered out of order, so offers with bigger chunks
+// are ignored.
+const MAX_CHUNK_BYTES: u64 = 1024 * 1024;
+// Offers of bigger files than max_offer_mb are ignored, as are offers from a
+// peer who already has MAX_PENDING_OFFERS waiting for an answer.
+const DEFAULT_MAX_OFFER_MB: u64 = 4 * 1024;
+const MAX_PENDING_OFFERS: usize = 16;
+
+// At most WINDOW chunks are in flight. The recipient says how far it has
+// got every ACK_EVERY chunks, which lets the sender send more.
+const WINDOW: u64 = 16;
+const ACK_EVERY: u64 = WINDOW / 2;
+
+// A transfer that hears nothing for STALL_SECS asks for the rest again, and
+// is paused after MAX_STALLS tries.
+const STALL_SECS: u64 = 20;
+const MAX_STALLS: usize = 5;
+
+// What is kept in ~/.secmsg/transfers, so a transfer survives a restart.
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+struct Record {
+    offer: FileOffer,
+    peer: String, // the recipient of a file we send, or our own handle for one we receive
+    path: String, // the file we send, or the directory one we receive is saved in
+    accepted: bool,
+}
+
+// A file we added to a namespace's shared files, kept in ~/.secmsg/shared so
+// members can still fetch it from us after a restart.
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+struct Share {
+    id: u64,
+    namespace: String,
+    path: String,
+    members: Vec<String>, // those its entry key was sealed to, who may fetch it
+}
+
+struct Transfer {
+    record: Record,
+    next: u64, // the next chunk to send, or to write
+    acked: u64, // chunks the recipient has, when sending
+    ahead: BTreeMap<u64, Vec<u8>>, // chunks that came before the next one, when receiving
+    heard: Instant,
+    stalls: usize,
+    paused: bool,
+    queued: Option<Instant>, // since when it has waited for an unmetered network
+    forced: bool, // fetched even on a metered network
+}
+
+impl Transfer {
+    fn new(record: Record) -> Transfer {
+        Transfer {
+            record: record,
+            next: 0,
+            acked: 0,
+            ahead: BTreeMap::new(),
+            heard: Instant::now(),
+            stalls: 0,
+            paused: false,
+            queued: None,
+            forced: false,
+        }
+    }
+
+    fn is_receiving(&self) -> bool {
+        self.record.accepted && !self.paused && self.queued.is_none()
+    }
+}
+
+// How the network profile in use treats files offered to us. Files up to
+// auto_download_kb are fetched without asking, and on a metered profile
+// bigger ones wait in a queue even once accepted. Each setting can be given
+// per profile, e.g. `metered.auto_download_kb = 64`, and otherwise falls
+// back to the plain setting.
+#[derive(Clone)]
+pub struct DownloadPolicy {
+    pub profile: String,
+    pub auto_download_bytes: u64,
+    pub metered: bool,
+    pub dir: Option<PathBuf>, // download_dir, or ~/.secmsg/downloads
+    pub max_offer_bytes: u64,
+}
+
+impl DownloadPolicy {
+    pub fn from_config(config: &Config) -> DownloadPolicy {
+        let profile = config.get_str("network_profile").unwrap_or("default".to_string());
+        let setting = |key: &str| config.get_str(&format!("{}.{}", profile, key)).or_else(|| config.get_str(key));
+        DownloadPolicy {
+            auto_download_bytes: setting("auto_download_kb").and_then(|v| v.parse::<u64>().ok()).unwrap_or(0) * 1024,
+            metered: setting("metered").and_then(|v| v.parse().ok()).unwrap_or(false),
+            dir: config.get_str("download_dir").map(PathBuf::from),
+            max_offer_bytes: config.get("max_offer_mb", DEFAULT_MAX_OFFER_MB).saturating_mul(1024 * 1024),
+            profile: profile,
+        }
+    }
+
+    fn defers(&self, offer: &FileOffer) -> bool {
+        self.metered && offer.size > self.auto_download_bytes
+    }
+}
+
+pub enum Event {
+    Offered(FileOffer),
+    Downloading(FileOffer), // small enough to fetch without asking
+    Received(FileOffer, PathBuf),
+    Delivered(FileOffer, String), // to whom
+    Shared(FileOffer, String), // offered to the member who asked for it
+    Paused(FileOffer),
+    Cancelled(FileOffer),
+    Failed(FileOffer, String),
+}
+
+pub struct Status {
+    pub offer: FileOffer,
+    pub sending: bool,
+    pub peer: String,
+    pub chunks_done: u64,
+    pub state: &'static str,
+}
+
+// Files are offered, then sent as chunks sealed under a key from the offer
+// once the recipient accepts. Each chunk is read from or written to disk as
+// it goes, so files of any size fit.
+#[derive(Clone)]
+pub struct Transfers {
+    sending: Arc<Mutex<HashMap<u64, Transfer>>>,
+    receiving: Arc<Mutex<HashMap<u64, Transfer>>>,
+    policy: Arc<Mutex<DownloadPolicy>>,
+    received: Arc<Mutex<Vec<(FileOffer, PathBuf)>>>, // finished this run, oldest first
+    shares: Arc<Mutex<HashMap<u64, Share>>>,
+    expected: Arc<Mutex<HashMap<Key, String>>>, // digests of shared files we asked for, and who holds them
+}
+
+fn secmsg_dir(name: &str) -> Result<PathBuf, String> {
+    let dir = try!(env::home_dir().ok_or("Cannot find home directory".to_string())).join(".secmsg").join(name);
+    try!(fs::create_dir_all(&dir).map_err(|e| e.to_string()));
+    Ok(dir)
+}
+
+// Where received files go unless download_dir is set.
+pub fn default_download_dir() -> Result<PathBuf, String> {
+    secmsg_dir("downloads")
+}
+
+fn record_path(id: u64, sending: bool) -> Result<PathBuf, String> {
+    Ok(try!(secmsg_dir("transfers")).join(format!("{}.{}", id, if sending { "send" } else { "recv" })))
+}
+
+fn save(record: &Record, sending: bool) -> Result<(), String> {
+    let mut file = try!(File::create(try!(record_path(record.offer.id, sending))).map_err(|e| e.to_string()));
+    file.write_all(json::encode(record).unwrap().as_bytes()).map_err(|e| e.to_string())
+}
+
+fn forget(record: &Record, sending: bool) {
+    if let Ok(path) = record_path(record.offer.id, sending) {
+        let _ = fs::remove_file(path);
+    }
+    if !sending && record.accepted {
+        let _ = fs::remove_file(part_path(record));
+    }
+}
+
+fn part_path(record: &Record) -> PathBuf {
+    Path::new(&record.path).join(format!(".{}.part", record.offer.id))
+}
+
+// Returns how many whole chunks the partial file already has, dropping any
+// chunk that was only half written.
+fn open_part(record: &Record) -> Result<u64, String> {
+    let file = try!(OpenOptions::new().write(true).create(true).open(part_path(record)).map_err(|e| e.to_string()));
+    let len = try!(file.metadata().map_err(|e| e.to_string())).len();
+    let written = cmp::min(len / record.offer.chunk_size, record.offer.chunks());
+    try!(file.set_len(written * record.offer.chunk_size).map_err(|e| e.to_string()));
+    Ok(written)
+}
+
+// A path in `dir` for `name` that isn't taken, without letting the sender
+// pick the directory.
+fn free_path(dir: &Path, name: &str) -> PathBuf {
+    let name = Path::new(name).file_name().and_then(|n| n.to_str()).unwrap_or("file").to_string();
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => (name[..i].to_string(), name[i..].to_string()),
+        _ => (name.clone(), String::new()),
+    };
+    let mut path = dir.join(&name);
+    let mut n = 1;
+    while path.exists() {
+        path = dir.join(format!("{} ({}){}", stem, n, ext));
+        n += 1;
+    }
+    path
+}
+
+// Opens an offered image's thumbnail into ~/.secmsg/thumbnails, so it can
+// be shown before the image itself is fetched.
+pub fn open_thumbnail(offer: &FileOffer) -> Option<PathBuf> {
+    let (mime, sealed) = match offer.kind {
+        Attachment::Image(ref mime, ref sealed) if !sealed.is_empty() => (mime, sealed),
+        _ => return None,
+    };
+    let thumb = match crypto_lib::open_with_key(&offer.key, sealed) {
+        Ok(t) => t,
+        Err(_) => return None,
+    };
+    let ext = match &**mime {
+        "image/png" => "png",
+        "image/gif" => "gif",
+        _ => "jpg",
+    };
+    let path = match secmsg_dir("thumbnails") {
+        Ok(dir) => dir.join(format!("{}.{}", offer.id, ext)),
+        Err(_) => return None,
+    };
+    match File::create(&path).and_then(|mut f| f.write_all(&thumb)) {
+        Ok(()) => Some(path),
+        Err(_) => None,
+    }
+}
+
+// Guessed from the extension, for the recipient to decide how to open it.
+pub fn mime_for(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase()).as_ref().map(|e| &**e) {
+        Some("txt") => "text/plain",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("ogg") | Some("oga") | Some("opus") => "audio/ogg",
+        Some("wav") => "audio/wav",
+        Some("mp3") => "audio/mpeg",
+        Some("m4a") => "audio/mp4",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        _ => "application/octet-stream",
+    }
+}
+
+fn send_chunks(record: Record, from: u64, end: u64, net: Net) {
+    thread::spawn(move || {
+        // The recipient asks again when it stalls, so failures are left to it.
+        let route = match net.get_route(&record.peer) {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+        let offer = record.offer;
+        let mut file = match File::open(&record.path) {
+            Ok(f) => f,
+            Err(_) => return,
+        };
+        if file.seek(SeekFrom::Start(from * offer.chunk_size)).is_err() {
+            return;
+        }
+        for index in from..end {
+            let mut chunk = Vec::new();
+            if Read::by_ref(&mut file).take(offer.chunk_size).read_to_end(&mut chunk).is_err() {
+                return;
+            }
+            let sealed = crypto_lib::seal_chunk(&offer.key, offer.id, index, &chunk);
+            net.add_message(MessageContainer::new(
+                Message::with_priority(MessageType::User(ToUser::FileChunk(offer.id, index, sealed)),
+                    route.clone(), &net.crypto, Priority::Bulk),
+                None,
+                false
+            ));
+        }
+    });
+}
+
+impl Transfers {
+
+    pub fn new() -> Transfers {
+        Transfers {
+            sending: Arc::new(Mutex::new(HashMap::new())),
+            receiving: Arc::new(Mutex::new(HashMap::new())),
+            policy: Arc::new(Mutex::new(DownloadPolicy {
+                profile: "default".to_string(),
+                auto_download_bytes: 0,
+                metered: false,
+                dir: None,
+                max_offer_bytes: DEFAULT_MAX_OFFER_MB * 1024 * 1024,
+            })),
+            received: Arc::new(Mutex::new(Vec::new())),
+            shares: Arc::new(Mutex::new(HashMap::new())),
+            expected: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Remembers that `path` is shared in `namespace` as entry `id`, so the
+    // members it was sealed to can ask for it.
+    pub fn share(&self, id: u64, namespace: &str, path: &Path, members: Vec<String>) -> Result<(), String> {
+        let share = Share {
+            id: id,
+            namespace: namespace.to_string(),
+            path: path.to_string_lossy().into_owned(),
+            members: members,
+        };
+        let mut file = try!(File::create(try!(secmsg_dir("shared")).join(format!("{}.json", id))).map_err(|e| e.to_string()));
+        try!(file.write_all(json::encode(&share).unwrap().as_bytes()).map_err(|e| e.to_string()));
+        self.shares.lock().unwrap().insert(id, share);
+        Ok(())
+    }
+
+    pub fn unshare(&self, id: u64) {
+        self.shares.lock().unwrap().remove(&id);
+        if let Ok(dir) = secmsg_dir("shared") {
+            let _ = fs::remove_file(dir.join(format!("{}.json", id)));
+        }
+    }
+
+    // Accepts the next offer of the file with `digest` from `from` as if it
+    // were small enough to download without asking.
+    pub fn expect(&self, digest: Key, from: &str) {
+        self.expected.lock().unwrap().insert(digest, from.to_string());
+    }
+
+    fn load_shares(&self) -> Result<(), String> {
+        for entry in try!(fs::read_dir(try!(secmsg_dir("shared"))).map_err(|e| e.to_string())) {
+            let mut s = String::new();
+            let path = try!(entry.map_err(|e| e.to_string())).path();
+            if File::open(&path).and_then(|mut f| f.read_to_string(&mut s)).is_err() {
+                continue;
+            }
+            if let Ok(share) = json::decode::<Share>(&s) {
+                self.shares.lock().unwrap().insert(share.id, share);
+            }
+        }
+        Ok(())
+    }
+
+    // The newest file received this run that `wanted` picks out.
+    pub fn last_received<F: Fn(&FileOffer) -> bool>(&self, wanted: F) -> Option<(FileOffer, PathBuf)> {
+        self.received.lock().unwrap().iter().rev().find(|&&(ref offer, _)| wanted(offer)).cloned()
+    }
+
+    pub fn policy(&self) -> DownloadPolicy {
+        self.policy.lock().unwrap().clone()
+    }
+
+    // Switches network profile. Downloads the new profile defers are queued,
+    // keeping what they have so far, and queued ones it allows start again.
+    pub fn set_policy(&self, policy: DownloadPolicy, net: &Net) {
+        *self.policy.lock().unwrap() = policy.clone();
+        for t in self.receiving.lock().unwrap().values_mut().filter(|t| t.record.accepted && !t.forced) {
+            if !policy.defers(&t.record.offer) {
+                if t.queued.is_some() {
+                    let _ = Transfers::start(t, net);
+                }
+            } else if t.queued.is_none() {
+                t.queued = Some(Instant::now());
+                t.ahead.clear();
+            }
+        }
+    }
+
+    // Loads the transfers from before secmsg last stopped. Files we were
+    // receiving ask their sender to carry on where they left off. Returns
+    // how many there were.
+    pub fn resume(&self, net: &Net) -> Result<usize, String> {
+        try!(self.load_shares());
+        let dir = try!(secmsg_dir("transfers"));
+        let mut count = 0;
+        for entry in try!(fs::read_dir(&dir).map_err(|e| e.to_string())) {
+            let path = try!(entry.map_err(|e| e.to_string())).path();
+            let sending = match path.extension().and_then(|e| e.to_str()) {
+                Some("send") => true,
+                Some("recv") => false,
+                _ => continue,
+            };
+            let mut s = String::new();
+            let record: Record = match File::open(&path).and_then(|mut f| f.read_to_string(&mut s)) {
+                Ok(_) => match json::decode(&s) {
+                    Ok(r) => r,
+                    Err(_) => continue,
+                },
+                Err(_) => continue,
+            };
+
+            let id = record.offer.id;
+            let mut t = Transfer::new(record);
+            if sending {
+                self.sending.lock().unwrap().insert(id, t);
+            } else {
+                if t.record.accepted {
+                    if self.policy().defers(&t.record.offer) {
+                        t.queued = Some(Instant::now());
+                    } else {
+                        try!(Transfers::start(&mut t, net));
+                    }
+                }
+                self.receiving.lock().unwrap().insert(id, t);
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // Offers the file at `path` to `to`, reading it once to hash it.
+    pub fn offer(&self, path: &Path, from: &str, to: &str, kind: Attachment, chunk_size: u64, net: &Net)
+        -> Result<FileOffer, String> {
+        let path = try!(fs::canonicalize(path).map_err(|e| e.to_string()));
+        let mut file = try!(File::open(&path).map_err(|e| e.to_string()));
+        let size = try!(file.metadata().map_err(|e| e.to_string())).len();
+        if size == 0 {
+            return Err("The file is empty".to_string());
+        }
+        let name = try!(path.file_name().and_then(|n| n.to_str()).ok_or("That is not a file".to_string()));
+
+        // An image's thumbnail is given in the clear and sealed here, under
+        // the same key as the chunks.
+        let key = crypto_lib::gen_key_pair().0;
+        let kind = match kind {
+            Attachment::Image(mime, ref thumb) if !thumb.is_empty() && thumb.len() <= MAX_THUMBNAIL_BYTES =>
+                Attachment::Image(mime, try!(crypto_lib::seal_with_key(&key, thumb).map_err(|e| format!("{:?}", e)))),
+            Attachment::Image(mime, _) => Attachment::Image(mime, Vec::new()),
+            kind => kind,
+        };
+        let offer = FileOffer {
+            id: rand::random::<u64>(),
+            sender: from.to_string(),
+            name: name.to_string(),
+            size: size,
+            chunk_size: cmp::max(1, cmp::min(chunk_size, MAX_CHUNK_BYTES)),
+            digest: try!(crypto_lib::hash_reader(&mut file).map_err(|e| e.to_string())),
+            key: key,
+            mime: mime_for(&path).to_string(),
+            kind: kind,
+        };
+        let record = Record {
+            offer: offer.clone(),
+            peer: to.to_string(),
+            path: path.to_string_lossy().into_owned(),
+            accepted: false,
+        };
+        try!(save(&record, true));
+        self.sending.lock().unwrap().insert(offer.id, Transfer::new(record));
+        net.notify(to, ToUser::FileOffer(offer.clone()));
+        Ok(offer)
+    }
+
+    // Accepts an offer, or resumes a paused or queued transfer. Without an
+    // id it takes the latest offer. Unless `force` is set, a download the
+    // network profile defers is only queued. Returns whether it started.
+    pub fn accept(&self, id: Option<u64>, me: &str, force: bool, net: &Net) -> Result<(FileOffer, bool), String> {
+        let policy = self.policy();
+        let dir = match policy.dir {
+            Some(ref dir) => dir.clone(),
+            None => try!(default_download_dir()),
+        };
+        let mut receiving = self.receiving.lock().unwrap();
+        let id = try!(id.or_else(|| receiving.values()
+                .filter(|t| !t.record.accepted)
+                .max_by_key(|t| t.heard)
+                .map(|t| t.record.offer.id))
+            .ok_or("No file is waiting to be accepted".to_string()));
+        let t = try!(receiving.get_mut(&id).ok_or("No such file transfer".to_string()));
+
+        if !t.record.accepted {
+            try!(fs::create_dir_all(&dir).map_err(|e| e.to_string()));
+            t.record.peer = me.to_string();
+            t.record.path = dir.to_string_lossy().into_owned();
+            t.record.accepted = true;
+            try!(save(&t.record, false));
+        }
+        t.forced = t.forced || force;
+        if !t.forced && policy.defers(&t.record.offer) {
+            t.queued = t.queued.or(Some(Instant::now()));
+            return Ok((t.record.offer.clone(), false));
+        }
+        try!(Transfers::start(t, net));
+        Ok((t.record.offer.clone(), true))
+    }
+
+    // Picks up from the last whole chunk on disk.
+    fn start(t: &mut Transfer, net: &Net) -> Result<(), String> {
+        t.next = try!(open_part(&t.record));
+        t.ahead.clear();
+        t.heard = Instant::now();
+        t.stalls = 0;
+        t.paused = false;
+        t.queued = None;
+        Transfers::ask(t, net);
+        Ok(())
+    }
+
+    // Downloads we accepted, those waiting for an unmetered network first in
+    // the order they were queued.
+    pub fn queue(&self) -> Vec<Status> {
+        let receiving = self.receiving.lock().unwrap();
+        let mut accepted: Vec<&Transfer> = receiving.values().filter(|t| t.record.accepted).collect();
+        accepted.sort_by_key(|t| (t.queued.is_none(), t.queued));
+        accepted.into_iter().map(Transfers::status_of).collect()
+    }
+
+    fn status_of(t: &Transfer) -> Status {
+        Status {
+            offer: t.record.offer.clone(),
+            sending: false,
+            peer: t.record.offer.sender.clone(),
+            chunks_done: t.next,
+            state: if !t.record.accepted { "waiting" }
+                else if t.queued.is_some() { "queued" }
+                else if t.paused { "paused" }
+                else { "receiving" },
+        }
+    }
+
+    pub fn cancel(&self, id: u64, net: &Net) -> Result<FileOffer, String> {
+        if let Some(t) = self.sending.lock().unwrap().remove(&id) {
+            forget(&t.record, true);
+            net.notify(&t.record.peer, ToUser::FileCancel(id));
+            return Ok(t.record.offer);
+        }
+        let t = try!(self.receiving.lock().unwrap().remove(&id).ok_or("No such file transfer".to_string()));
+        forget(&t.record, false);
+        net.notify(&t.record.offer.sender, ToUser::FileCancel(id));
+        Ok(t.record.offer)
+    }
+
+    pub fn list(&self) -> Vec<Status> {
+        let mut list = Vec::new();
+        for t in self.sending.lock().unwrap().values() {
+            list.push(Status {
+                offer: t.record.offer.clone(),
+                sending: true,
+                peer: t.record.peer.clone(),
+                chunks_done: t.acked,
+                state: if t.record.accepted { "sending" } else { "offered" },
+            });
+        }
+        for t in self.receiving.lock().unwrap().values() {
+            list.push(Transfers::status_of(t));
+        }
+        list
+    }
+
+    // Handles a transfer message from Net::get_file_event.
+    pub fn handle(&self, note: ToUser, net: &Net) -> Option<Event> {
+        match note {
+            ToUser::FileOffer(offer) => {
+                if offer.size == 0 || offer.size > self.policy().max_offer_bytes
+                    || offer.chunk_size == 0 || offer.chunk_size > MAX_CHUNK_BYTES {
+                    return None;
+                }
+                {
+                    let mut receiving = self.receiving.lock().unwrap();
+                    let pending = receiving.values()
+                        .filter(|t| !t.record.accepted && t.record.offer.sender == offer.sender)
+                        .count();
+                    if receiving.contains_key(&offer.id) || pending >= MAX_PENDING_OFFERS {
+                        return None;
+                    }
+                    receiving.insert(offer.id, Transfer::new(Record {
+                        offer: offer.clone(),
+                        peer: String::new(),
+                        path: String::new(),
+                        accepted: false,
+                    }));
+                }
+                let me = match net.handle() {
+                    Some(me) => me,
+                    None => return Some(Event::Offered(offer)),
+                };
+                let expected = {
+                    let mut expected = self.expected.lock().unwrap();
+                    match expected.get(&offer.digest) {
+                        Some(from) if *from == offer.sender => expected.remove(&offer.digest).is_some(),
+                        _ => false,
+                    }
+                };
+                if (expected || offer.size <= self.policy().auto_download_bytes) && self.accept(Some(offer.id), &me, false, net).is_ok() {
+                    Some(Event::Downloading(offer))
+                } else {
+                    Some(Event::Offered(offer))
+                }
+            },
+            ToUser::FileAccept(id, by, next) => self.send_window(id, by, next, net),
+            ToUser::FileChunk(id, index, sealed) => self.receive_chunk(id, index, &sealed, net),
+            ToUser::SharedFileRequest(namespace, id, from) => {
+                let share = match self.shares.lock().unwrap().get(&id) {
+                    Some(s) if s.namespace == namespace && s.members.contains(&from) => s.clone(),
+                    _ => return None,
+                };
+                let me = match net.handle() {
+                    Some(me) => me,
+                    None => return None,
+                };
+                let chunk_size = Config::load().get("file_chunk_bytes", DEFAULT_CHUNK_BYTES);
+                self.offer(Path::new(&share.path), &me, &from, Attachment::File, chunk_size, net).ok()
+                    .map(|offer| Event::Shared(offer, from))
+            },
+            ToUser::FileCancel(id) => {
+                if let Some(t) = self.sending.lock().unwrap().remove(&id) {
+                    forget(&t.record, true);
+                    return Some(Event::Cancelled(t.record.offer));
+                }
+                self.receiving.lock().unwrap().remove(&id).map(|t| {
+                    forget(&t.record, false);
+                    Event::Cancelled(t.record.offer)
+                })
+            },
+            _ => None,
+        }
+    }
+
+    // Finishes transfers that have every chunk, and asks again for the rest
+    // of those that have stalled. Called about once a second.
+    pub fn tick(&self, net: &Net) -> Vec<Event> {
+        let mut events = Vec::new();
+        let mut receiving = self.receiving.lock().unwrap();
+        let done: Vec<u64> = receiving.values()
+            .filter(|t| t.record.accepted && t.next == t.record.offer.chunks())
+            .map(|t| t.record.offer.id)
+            .collect();
+        for id in done {
+            let t = receiving.remove(&id).unwrap();
+            events.push(self.finish(t, net));
+        }
+
+        for t in receiving.values_mut() {
+            if !t.is_receiving() || t.heard.elapsed() < Duration::from_secs(STALL_SECS) {
+                continue;
+            }
+            t.stalls += 1;
+            t.heard = Instant::now();
+            if t.stalls > MAX_STALLS {
+                t.paused = true;
+                events.push(Event::Paused(t.record.offer.clone()));
+            } else {
+                Transfers::ask(t, net);
+            }
+        }
+        events
+    }
+
+    // Tells the sender which chunk we need next.
+    fn ask(t: &Transfer, net: &Net) {
+        net.notify(&t.record.offer.sender, ToUser::FileAccept(t.record.offer.id, t.record.peer.clone(), t.next));
+    }
+
+    fn send_window(&self, id: u64, by: String, next: u64, net: &Net) -> Option<Event> {
+        let mut sending = self.sending.lock().unwrap();
+        {
+            let t = match sending.get_mut(&id) {
+                Some(t) => t,
+                None => {
+                    // We no longer have it, e.g. it was cancelled.
+                    net.notify(&by, ToUser::FileCancel(id));
+                    return None;
+                },
+            };
+            if t.record.peer != by {
+                return None;
+            }
+            let chunks = t.record.offer.chunks();
+            if next < chunks {
+                if !t.record.accepted {
+                    t.record.accepted = true;
+                    let _ = save(&t.record, true);
+                }
+                // An acknowledgement that doesn't move forward asks for the
+                // rest again, after a stall or a resume.
+                if next <= t.acked {
+                    t.next = next;
+                }
+                t.acked = next;
+                let end = cmp::min(next + WINDOW, chunks);
+                if t.next < end {
+                    send_chunks(t.record.clone(), cmp::max(t.next, next), end, net.clone());
+                    t.next = end;
+                }
+                return None;
+            }
+        }
+        let t = sending.remove(&id).unwrap();
+        forget(&t.record, true);
+        Some(Event::Delivered(t.record.offer, t.record.peer))
+    }
+
+    fn receive_chunk(&self, id: u64, index: u64, sealed: &[u8], net: &Net) -> Option<Event> {
+        let mut receiving = self.receiving.lock().unwrap();
+        let failed = {
+            let t = match receiving.get_mut(&id) {
+                Some(t) if t.is_receiving() => t,
+                _ => return None,
+            };
+            let offer = t.record.offer.clone();
+            if index < t.next || index >= t.next + WINDOW || index >= offer.chunks() || t.ahead.contains_key(&index) {
+                return None;
+            }
+            let chunk = match crypto_lib::open_chunk(&offer.key, id, index, sealed) {
+                Ok(c) => c,
+                Err(_) => return None,
+            };
+            // Only the last chunk may be short.
+            if chunk.len() as u64 != cmp::min(offer.chunk_size, offer.size - index * offer.chunk_size) {
+                return None;
+            }
+            t.ahead.insert(index, chunk);
+            t.heard = Instant::now();
+            t.stalls = 0;
+
+            let before = t.next;
+            match Transfers::write_ahead(t) {
+                Ok(()) => {
+                    if t.next == offer.chunks() {
+                        None
+                    } else {
+                        if t.next / ACK_EVERY > before / ACK_EVERY {
+                            Transfers::ask(t, net);
+                        }
+                        return None;
+                    }
+                },
+                Err(e) => Some(e),
+            }
+        };
+
+        let t = receiving.remove(&id).unwrap();
+        Some(match failed {
+            None => self.finish(t, net),
+            Some(e) => {
+                forget(&t.record, false);
+                net.notify(&t.record.offer.sender, ToUser::FileCancel(id));
+                Event::Failed(t.record.offer, e)
+            },
+        })
+    }
+
+    // Appends the chunks that are now in order to the partial file.
+    fn write_ahead(t: &mut Transfer) -> Result<(), String> {
+        if !t.ahead.contains_key(&t.next) {
+            return Ok(());
+        }
+        let mut file = try!(OpenOptions::new().append(true).open(part_path(&t.record)).map_err(|e| e.to_string()));
+        while let Some(chunk) = t.ahead.remove(&t.next) {
+            try!(file.write_all(&chunk).map_err(|e| e.to_string()));
+            t.next += 1;
+        }
+        Ok(())
+    }
+
+    // Checks the assembled file against the offer's digest before giving it
+    // its name.
+    fn finish(&self, t: Transfer, net: &Net) -> Event {
+        let (record, part) = (t.record.clone(), part_path(&t.record));
+        let offer = record.offer.clone();
+        let digest = File::open(&part).and_then(|mut f| crypto_lib::hash_reader(&mut f));
+        if digest.ok() != Some(offer.digest) {
+            forget(&record, false);
+            net.notify(&offer.sender, ToUser::FileCancel(offer.id));
+            return Event::Failed(offer, "The file didn't match what was offered".to_string());
+        }
+
+        let dest = free_path(Path::new(&record.path), &offer.name);
+        if let Err(e) = fs::rename(&part, &dest) {
+            return Event::Failed(offer, e.to_string());
+        }
+        forget(&record, false);
+        net.notify(&offer.sender, ToUser::FileAccept(offer.id, record.peer.clone(), offer.chunks()));
+        self.received.lock().unwrap().push((offer.clone(), dest.clone()));
+        Event::Received(offer, dest)
+    }
+}